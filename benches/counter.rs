@@ -0,0 +1,76 @@
+use std::{
+    hint::black_box,
+    sync::{Arc, Mutex},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use hello_axum::{counter::CounterCache, models::Counter};
+
+// Tasks incrementing at once, like concurrent `POST /counter` requests. Each stores the
+// value MongoDB answered with and reads it back, as the handlers and `/metrics` do
+const TASKS: usize = 8;
+const INCREMENTS: usize = 1_000;
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+// The `Arc<Mutex<Counter>>` state the cache replaced, against `CounterCache`. MongoDB is
+// left out, it costs the same either way
+fn increments(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("counter_increment");
+    group.throughput(Throughput::Elements((TASKS * INCREMENTS) as u64));
+
+    group.bench_function("mutex", |b| {
+        let counter = Arc::new(Mutex::new(Counter { value: 0 }));
+        b.iter(|| {
+            runtime.block_on(async {
+                let tasks: Vec<_> = (0..TASKS)
+                    .map(|_| {
+                        let counter = Arc::clone(&counter);
+                        tokio::spawn(async move {
+                            for value in 0..INCREMENTS as u32 {
+                                counter.lock().unwrap().value = value;
+                                black_box(counter.lock().unwrap().value);
+                            }
+                        })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+            })
+        })
+    });
+
+    group.bench_function("counter_cache", |b| {
+        let cache = CounterCache::new(0);
+        b.iter(|| {
+            runtime.block_on(async {
+                let tasks: Vec<_> = (0..TASKS)
+                    .map(|_| {
+                        let cache = cache.clone();
+                        tokio::spawn(async move {
+                            for value in 0..INCREMENTS as u64 {
+                                cache.set(value);
+                                black_box(cache.get());
+                            }
+                        })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, increments);
+criterion_main!(benches);
@@ -0,0 +1,131 @@
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
+};
+use futures_util::{stream, Stream};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, info_span};
+
+use crate::{auth::AuthUser, id::UserId, trace::Traced};
+
+// Events a slow `/events` client may fall behind by before it skips ahead
+pub const ACTIVITY_BUFFER: usize = 256;
+// How often the number of handled requests is reported
+pub const REQUEST_COUNT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Activity {
+    CounterChanged { name: String, value: u64 },
+    UserSignedUp { id: UserId },
+    // Handled since the previous report
+    Requests { count: u64, interval_secs: u64 },
+}
+
+impl Activity {
+    // The SSE event name, so clients can listen for one kind only
+    fn kind(&self) -> &'static str {
+        match self {
+            Activity::CounterChanged { .. } => "counterChanged",
+            Activity::UserSignedUp { .. } => "userSignedUp",
+            Activity::Requests { .. } => "requests",
+        }
+    }
+}
+
+// What happens on this instance, fanned out to every `/events` subscriber. Events caused by
+// a request carry its `requestId`
+#[derive(Debug, Clone)]
+pub struct ActivityFeed {
+    sender: broadcast::Sender<Traced<Activity>>,
+    requests: Arc<AtomicU64>,
+}
+
+impl Default for ActivityFeed {
+    fn default() -> Self {
+        ActivityFeed {
+            sender: broadcast::channel(ACTIVITY_BUFFER).0,
+            requests: Arc::default(),
+        }
+    }
+}
+
+impl ActivityFeed {
+    pub fn publish(&self, activity: Activity) {
+        // Only fails when nobody is listening
+        let _ = self.sender.send(Traced::current(activity));
+    }
+
+    // Reports the request count every `REQUEST_COUNT_INTERVAL`, while anyone listens
+    pub async fn report_requests(self) {
+        let mut interval = tokio::time::interval(REQUEST_COUNT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let count = self.requests.swap(0, Ordering::Relaxed);
+            if self.sender.receiver_count() > 0 {
+                self.publish(Activity::Requests {
+                    count,
+                    interval_secs: REQUEST_COUNT_INTERVAL.as_secs(),
+                });
+            }
+        }
+    }
+}
+
+pub async fn count_requests(
+    State(feed): State<ActivityFeed>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    feed.requests.fetch_add(1, Ordering::Relaxed);
+    next.run(request).await
+}
+
+// Signed-in users only, signups would otherwise be public
+pub async fn events(
+    _user: AuthUser,
+    State(feed): State<ActivityFeed>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = feed.sender.subscribe();
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(activity) => {
+                    let _entered =
+                        info_span!(parent: &activity.context.span, "event_push").entered();
+                    debug!(kind = activity.message.kind(), "Pushing activity");
+                    let event = Event::default()
+                        .event(activity.message.kind())
+                        .json_data(activity.payload());
+                    match event {
+                        Ok(event) => return Some((Ok(event), receiver)),
+                        Err(e) => error!("Error serializing activity : {}", e),
+                    }
+                }
+                // Missed events are gone, carry on with the next one
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::IntoResponse,
+};
+use serde::Serialize;
+
+use crate::{
+    auth::{Authenticator, Credentials},
+    middleware::client_ip,
+};
+
+// Usage is reported over this trailing window
+pub const ANALYTICS_WINDOW: Duration = Duration::from_secs(15 * 60);
+// Keeps a single chatty client from growing its window without bound
+pub const MAX_SAMPLES_PER_CLIENT: usize = 10_000;
+// Past this many clients, idle ones are dropped before a new one is added
+pub const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    latency: Duration,
+    error: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientUsage {
+    pub client: String,
+    pub requests: usize,
+    pub errors: usize,
+    pub error_rate: f64,
+    pub p95_latency_ms: u128,
+    pub window_secs: u64,
+}
+
+// Per client request samples, keyed by username or `ip:<address>` for anonymous callers
+#[derive(Debug, Clone, Default)]
+pub struct Analytics(Arc<Mutex<HashMap<String, VecDeque<Sample>>>>);
+
+fn prune(samples: &mut VecDeque<Sample>, now: Instant) {
+    while samples
+        .front()
+        .is_some_and(|sample| now.duration_since(sample.at) > ANALYTICS_WINDOW)
+    {
+        samples.pop_front();
+    }
+}
+
+fn summarize(client: &str, samples: &VecDeque<Sample>) -> ClientUsage {
+    let requests = samples.len();
+    let errors = samples.iter().filter(|sample| sample.error).count();
+
+    let mut latencies: Vec<Duration> = samples.iter().map(|sample| sample.latency).collect();
+    latencies.sort_unstable();
+    let p95 = match latencies.len() {
+        0 => Duration::ZERO,
+        len => latencies[(len * 95).div_ceil(100) - 1],
+    };
+
+    ClientUsage {
+        client: client.to_string(),
+        requests,
+        errors,
+        error_rate: if requests == 0 {
+            0.0
+        } else {
+            errors as f64 / requests as f64
+        },
+        p95_latency_ms: p95.as_millis(),
+        window_secs: ANALYTICS_WINDOW.as_secs(),
+    }
+}
+
+impl Analytics {
+    fn record(&self, client: String, latency: Duration, error: bool) {
+        let now = Instant::now();
+        let mut clients = self.0.lock().unwrap();
+
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(&client) {
+            clients.retain(|_, samples| {
+                prune(samples, now);
+                !samples.is_empty()
+            });
+        }
+
+        let samples = clients.entry(client).or_default();
+        prune(samples, now);
+        if samples.len() >= MAX_SAMPLES_PER_CLIENT {
+            samples.pop_front();
+        }
+        samples.push_back(Sample {
+            at: now,
+            latency,
+            error,
+        });
+    }
+
+    pub fn usage(&self, client: &str) -> ClientUsage {
+        let mut clients = self.0.lock().unwrap();
+        match clients.get_mut(client) {
+            Some(samples) => {
+                prune(samples, Instant::now());
+                summarize(client, samples)
+            }
+            None => summarize(client, &VecDeque::new()),
+        }
+    }
+
+    // Busiest clients first
+    pub fn clients(&self) -> Vec<ClientUsage> {
+        let now = Instant::now();
+        let mut clients = self.0.lock().unwrap();
+        clients.retain(|_, samples| {
+            prune(samples, now);
+            !samples.is_empty()
+        });
+
+        let mut usage: Vec<ClientUsage> = clients
+            .iter()
+            .map(|(client, samples)| summarize(client, samples))
+            .collect();
+        usage.sort_by_key(|client| std::cmp::Reverse(client.requests));
+        usage
+    }
+}
+
+// Signed in callers are tracked by username, everyone else by address
+pub fn client_key(request: &Request, authenticator: &Authenticator) -> String {
+    let username = authenticator
+        .authenticate(&Credentials::of_request(request))
+        .ok()
+        .map(|claims| claims.sub);
+    match (username, client_ip(request)) {
+        (Some(username), _) => username,
+        (None, Some(ip)) => format!("ip:{}", ip),
+        (None, None) => "unknown".to_string(),
+    }
+}
+
+pub async fn record_analytics(
+    State(analytics): State<Analytics>,
+    State(authenticator): State<Authenticator>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let client = client_key(&request, &authenticator);
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status();
+    analytics.record(
+        client,
+        started.elapsed(),
+        status.is_client_error() || status.is_server_error(),
+    );
+
+    response
+}
@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        StatusCode,
+    },
+    middleware::Next,
+    response::IntoResponse,
+};
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{
+    analytics::client_key, auth::Authenticator, config::AnomalyConfig, trace::spawn_traced,
+};
+
+// Request rates are compared per minute
+const MINUTE: Duration = Duration::from_secs(60);
+// Weight of the last minute in a client's baseline, about the last 10 minutes count
+const BASELINE_WEIGHT: f64 = 0.2;
+// Past this many clients, idle unthrottled ones are dropped before a new one is added
+pub const MAX_TRACKED_CLIENTS: usize = 10_000;
+pub const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+struct Throttle {
+    per_minute: u32,
+    until: Instant,
+    // What set it off, `None` for throttles set through `/admin/throttles`
+    detected: Option<(u64, f64)>,
+}
+
+#[derive(Debug)]
+struct ClientTraffic {
+    minute_started: Instant,
+    this_minute: u64,
+    // Requests per minute, averaged over the minutes the client wasn't throttled in
+    baseline: f64,
+    minutes: u32,
+    throttle: Option<Throttle>,
+    // Detection is off until then, after an operator lifted a throttle
+    exempt_until: Option<Instant>,
+}
+
+impl ClientTraffic {
+    fn new(now: Instant) -> Self {
+        ClientTraffic {
+            minute_started: now,
+            this_minute: 0,
+            baseline: 0.0,
+            minutes: 0,
+            throttle: None,
+            exempt_until: None,
+        }
+    }
+
+    // Folds the minutes gone by into the baseline, quiet ones pulling it down
+    fn roll(&mut self, now: Instant) {
+        let elapsed = (now.duration_since(self.minute_started).as_secs() / MINUTE.as_secs()) as u32;
+        if elapsed == 0 {
+            return;
+        }
+        if self.throttle.is_none() {
+            self.baseline = if self.minutes == 0 {
+                self.this_minute as f64
+            } else {
+                self.baseline * (1.0 - BASELINE_WEIGHT) + self.this_minute as f64 * BASELINE_WEIGHT
+            };
+            // Capped, after an hour of silence the baseline is about 0 anyway
+            self.baseline *= (1.0 - BASELINE_WEIGHT).powi(elapsed.min(60) as i32 - 1);
+            self.minutes = self.minutes.saturating_add(elapsed);
+        }
+        self.minute_started += MINUTE * elapsed;
+        self.this_minute = 0;
+    }
+}
+
+// One line of `/admin/throttles`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottledClient {
+    pub client: String,
+    pub per_minute: u32,
+    pub remaining_secs: u64,
+    // Set by an operator rather than detected
+    pub manual: bool,
+    // The rate that set the throttle off, against the client's baseline at the time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed_per_minute: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline_per_minute: Option<f64>,
+}
+
+// Longest a client can be throttled for, by detection or by an operator. Keeps `until` well
+// within what an `Instant` can hold
+pub const MAX_THROTTLE_SECS: u64 = 30 * 24 * 60 * 60;
+
+// Body of `PUT /admin/throttles/{client}`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleRequest {
+    pub per_minute: u32,
+    pub duration_secs: u64,
+}
+
+// Told to operators when a client gets throttled
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleNotice {
+    pub client: String,
+    pub observed_per_minute: u64,
+    pub baseline_per_minute: f64,
+    pub throttled_per_minute: u32,
+    pub throttle_secs: u64,
+}
+
+enum Verdict {
+    Allowed,
+    Refused(Duration),
+}
+
+// Watches each client's requests per minute against its own baseline, and tightens its
+// limit to `throttled_per_minute` for `throttle_secs` once it goes past `factor` times that.
+// Clients are keyed like in the analytics, by username or `ip:<address>`. Per instance
+#[derive(Clone)]
+pub struct TrafficGuard {
+    config: Arc<AnomalyConfig>,
+    clients: Arc<Mutex<HashMap<String, ClientTraffic>>>,
+    authenticator: Authenticator,
+    http_client: reqwest::Client,
+}
+
+impl TrafficGuard {
+    pub fn new(config: &AnomalyConfig, authenticator: Authenticator) -> Result<Self, String> {
+        let http_client = reqwest::Client::builder()
+            .timeout(NOTIFY_TIMEOUT)
+            .build()
+            .map_err(|e| format!("Error building the notification client : {}", e))?;
+        Ok(TrafficGuard {
+            config: Arc::new(config.clone()),
+            clients: Arc::default(),
+            authenticator,
+            http_client,
+        })
+    }
+
+    // Counts the request, and says whether it may go through. The notice is for a client
+    // this request got throttled
+    fn record(&self, client: &str) -> (Verdict, Option<ThrottleNotice>) {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        // With detection off only manual throttles are enforced, nobody else is tracked
+        if !self.config.enabled && !clients.contains_key(client) {
+            return (Verdict::Allowed, None);
+        }
+
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(client) {
+            clients.retain(|_, traffic| {
+                traffic.throttle.is_some() || now.duration_since(traffic.minute_started) < MINUTE
+            });
+        }
+        let traffic = clients
+            .entry(client.to_string())
+            .or_insert_with(|| ClientTraffic::new(now));
+        traffic.roll(now);
+        traffic.this_minute += 1;
+        if traffic
+            .throttle
+            .is_some_and(|throttle| throttle.until <= now)
+        {
+            traffic.throttle = None;
+        }
+        if traffic.exempt_until.is_some_and(|until| until <= now) {
+            traffic.exempt_until = None;
+        }
+
+        let mut notice = None;
+        let config = &self.config;
+        let anomalous = config.enabled
+            && traffic.throttle.is_none()
+            && traffic.exempt_until.is_none()
+            && traffic.minutes >= config.warmup_minutes
+            && traffic.this_minute >= config.min_per_minute
+            && traffic.this_minute as f64 > traffic.baseline.max(1.0) * config.factor;
+        if anomalous {
+            traffic.throttle = Some(Throttle {
+                per_minute: config.throttled_per_minute,
+                until: now + Duration::from_secs(config.throttle_secs),
+                detected: Some((traffic.this_minute, traffic.baseline)),
+            });
+            notice = Some(ThrottleNotice {
+                client: client.to_string(),
+                observed_per_minute: traffic.this_minute,
+                baseline_per_minute: traffic.baseline,
+                throttled_per_minute: config.throttled_per_minute,
+                throttle_secs: config.throttle_secs,
+            });
+        }
+
+        let verdict = match traffic.throttle {
+            Some(throttle) if traffic.this_minute > u64::from(throttle.per_minute) => {
+                Verdict::Refused(MINUTE.saturating_sub(now.duration_since(traffic.minute_started)))
+            }
+            _ => Verdict::Allowed,
+        };
+        (verdict, notice)
+    }
+
+    pub fn throttled(&self) -> Vec<ThrottledClient> {
+        let now = Instant::now();
+        let clients = self.clients.lock().unwrap();
+        let mut throttled: Vec<ThrottledClient> = clients
+            .iter()
+            .filter_map(|(client, traffic)| {
+                let throttle = traffic.throttle.filter(|throttle| throttle.until > now)?;
+                Some(ThrottledClient {
+                    client: client.clone(),
+                    per_minute: throttle.per_minute,
+                    remaining_secs: throttle.until.duration_since(now).as_secs(),
+                    manual: throttle.detected.is_none(),
+                    observed_per_minute: throttle.detected.map(|(observed, _)| observed),
+                    baseline_per_minute: throttle.detected.map(|(_, baseline)| baseline),
+                })
+            })
+            .collect();
+        throttled.sort_by(|a, b| a.client.cmp(&b.client));
+        throttled
+    }
+
+    pub fn throttle(&self, client: &str, per_minute: u32, duration: Duration) {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let traffic = clients
+            .entry(client.to_string())
+            .or_insert_with(|| ClientTraffic::new(now));
+        traffic.throttle = Some(Throttle {
+            per_minute,
+            until: now + duration,
+            detected: None,
+        });
+    }
+
+    // The client then goes unwatched for `throttle_secs`, so its baseline can catch up with
+    // traffic an operator let through. `false` when it wasn't throttled
+    pub fn lift(&self, client: &str) -> bool {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let Some(traffic) = clients.get_mut(client) else {
+            return false;
+        };
+        if traffic
+            .throttle
+            .take()
+            .is_none_or(|throttle| throttle.until <= now)
+        {
+            return false;
+        }
+        traffic.exempt_until = Some(now + Duration::from_secs(self.config.throttle_secs));
+        true
+    }
+
+    // Logged, and posted to `notify_url` when set. The `text` is for chat webhooks
+    async fn notify(self, notice: ThrottleNotice) {
+        warn!(
+            client = %notice.client,
+            "Throttled to {} requests per minute for {}s after {} in a minute, against a baseline of {:.1}",
+            notice.throttled_per_minute,
+            notice.throttle_secs,
+            notice.observed_per_minute,
+            notice.baseline_per_minute
+        );
+        let Some(url) = self.config.notify_url.as_deref() else {
+            return;
+        };
+        let mut body = serde_json::to_value(&notice).unwrap_or_default();
+        body["event"] = "clientThrottled".into();
+        body["text"] = format!(
+            "{} throttled to {}/min for {}s : {} requests in a minute, baseline {:.1}",
+            notice.client,
+            notice.throttled_per_minute,
+            notice.throttle_secs,
+            notice.observed_per_minute,
+            notice.baseline_per_minute
+        )
+        .into();
+        let result = self
+            .http_client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            error!("Error notifying about a throttled client : {}", e);
+        }
+    }
+}
+
+// Layered on the business routes, refuses throttled clients past their limit with 429
+pub async fn guard_traffic(
+    State(guard): State<TrafficGuard>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let client = client_key(&request, &guard.authenticator);
+    let (verdict, notice) = guard.record(&client);
+    if let Some(notice) = notice {
+        counter!("anomalous_clients_total").increment(1);
+        spawn_traced(guard.clone().notify(notice));
+    }
+
+    match verdict {
+        Verdict::Allowed => next.run(request).await,
+        Verdict::Refused(retry_after) => {
+            counter!("throttled_requests_total").increment(1);
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, seconds.max(1).to_string())],
+                "Too many requests, traffic from this client is throttled",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_quiet_minutes_into_the_baseline() {
+        let started = Instant::now();
+        let mut traffic = ClientTraffic::new(started);
+        traffic.this_minute = 10;
+        traffic.roll(started + Duration::from_secs(30));
+        assert_eq!(traffic.this_minute, 10);
+
+        traffic.roll(started + MINUTE);
+        assert_eq!((traffic.baseline, traffic.minutes), (10.0, 1));
+
+        // A busier minute, then a silent one
+        traffic.this_minute = 20;
+        traffic.roll(started + MINUTE * 3);
+        assert!((traffic.baseline - 9.6).abs() < 1e-9);
+        assert_eq!((traffic.minutes, traffic.this_minute), (3, 0));
+        assert_eq!(traffic.minute_started, started + MINUTE * 3);
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+use axum::{
+    extract::{ConnectInfo, FromRef, FromRequestParts, Request},
+    http::{
+        header::{AUTHORIZATION, COOKIE, HOST, ORIGIN},
+        request::Parts,
+        Extensions, HeaderMap, HeaderName, Method,
+    },
+};
+use ipnet::IpNet;
+use jsonwebtoken::{
+    decode, encode, get_current_timestamp, DecodingKey, EncodingKey, Header, Validation,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{ApiKeyConfig, Config, MtlsConfig, ServiceIdentity},
+    db::Tenants,
+    error::AppError,
+    middleware::parse_cidr,
+    models::{Claims, Role, TokenType},
+    secrets::RotatingSecret,
+    server::ClientAddr,
+};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+const SEC_FETCH_SITE: HeaderName = HeaderName::from_static("sec-fetch-site");
+
+// What providers get to see of a request
+pub struct Credentials<'a> {
+    pub method: &'a Method,
+    pub headers: &'a HeaderMap,
+    // The connected peer, the proxy when there is one in front. `None` outside `serve`
+    pub peer: Option<IpAddr>,
+}
+
+impl<'a> Credentials<'a> {
+    pub fn new(method: &'a Method, headers: &'a HeaderMap, extensions: &Extensions) -> Self {
+        Credentials {
+            method,
+            headers,
+            peer: extensions
+                .get::<ConnectInfo<ClientAddr>>()
+                .map(|ConnectInfo(ClientAddr(addr))| addr.ip()),
+        }
+    }
+
+    pub fn of_parts(parts: &'a Parts) -> Self {
+        Credentials::new(&parts.method, &parts.headers, &parts.extensions)
+    }
+
+    pub fn of_request(request: &'a Request) -> Self {
+        Credentials::new(request.method(), request.headers(), request.extensions())
+    }
+}
+
+// A kind of credential a request can prove its identity with
+pub trait AuthProvider: Send + Sync {
+    // `Ok(None)` means the request carries no credential this provider understands
+    fn authenticate(&self, request: &Credentials) -> Result<Option<Claims>, String>;
+}
+
+pub struct JwtProvider {
+    pub secret: RotatingSecret,
+}
+
+impl AuthProvider for JwtProvider {
+    fn authenticate(&self, request: &Credentials) -> Result<Option<Claims>, String> {
+        let Some(value) = request.headers.get(AUTHORIZATION) else {
+            return Ok(None);
+        };
+
+        let value = value.to_str().map_err(|e| e.to_string())?;
+        // Bare tokens predate the `Bearer` scheme and are still accepted
+        let token = value.strip_prefix("Bearer ").unwrap_or(value);
+        access_claims(token, &self.secret).map(Some)
+    }
+}
+
+// Keys from `auth.api_keys`, looked up by their SHA-256
+pub struct ApiKeyProvider {
+    keys: HashMap<String, ServiceIdentity>,
+}
+
+impl ApiKeyProvider {
+    pub fn new(keys: &[ApiKeyConfig]) -> Self {
+        let keys = keys
+            .iter()
+            .map(|key| (key.sha256.to_ascii_lowercase(), key.identity.clone()))
+            .collect();
+        ApiKeyProvider { keys }
+    }
+}
+
+impl AuthProvider for ApiKeyProvider {
+    fn authenticate(&self, request: &Credentials) -> Result<Option<Claims>, String> {
+        let Some(value) = request.headers.get(API_KEY_HEADER) else {
+            return Ok(None);
+        };
+
+        let hash = hex::encode(Sha256::digest(value.as_bytes()));
+        match self.keys.get(&hash) {
+            Some(identity) => Ok(Some(service_claims(identity))),
+            None => Err("Unknown API key".to_string()),
+        }
+    }
+}
+
+// An access token in the `auth.session_cookie` cookie. Browsers send it along on requests
+// other sites make too, so it only counts on unsafe methods when the request says it came
+// from this site
+pub struct CookieProvider {
+    pub name: String,
+    pub secret: RotatingSecret,
+}
+
+impl AuthProvider for CookieProvider {
+    fn authenticate(&self, request: &Credentials) -> Result<Option<Claims>, String> {
+        let Some(token) = cookie_value(request.headers, &self.name) else {
+            return Ok(None);
+        };
+
+        let is_safe = matches!(
+            *request.method,
+            Method::GET | Method::HEAD | Method::OPTIONS
+        );
+        if !is_safe && !is_same_site(request.headers) {
+            return Err("Session cookies need a same-site request to change anything".to_string());
+        }
+        access_claims(token, &self.secret).map(Some)
+    }
+}
+
+// `Sec-Fetch-Site` when the browser sends it, else an `Origin` naming this host. Requests
+// with neither are refused, they can't be told apart from a cross-site one
+fn is_same_site(headers: &HeaderMap) -> bool {
+    let header = |name: HeaderName| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(site) = header(SEC_FETCH_SITE) {
+        return site == "same-origin" || site == "none";
+    }
+    match (header(ORIGIN), header(HOST)) {
+        (Some(origin), Some(host)) => origin
+            .split_once("://")
+            .is_some_and(|(_, origin_host)| origin_host.eq_ignore_ascii_case(host)),
+        _ => false,
+    }
+}
+
+pub fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find_map(|(cookie, value)| (cookie == name).then_some(value))
+}
+
+// Client certificates verified by the TLS proxy in front, see `MtlsConfig`
+pub struct MtlsProvider {
+    subject_header: HeaderName,
+    verify_header: HeaderName,
+    trusted_proxies: Vec<IpNet>,
+    identities: BTreeMap<String, ServiceIdentity>,
+}
+
+impl MtlsProvider {
+    // `None` when `subject_header` isn't set
+    pub fn new(config: &MtlsConfig) -> Option<Self> {
+        Some(MtlsProvider {
+            subject_header: HeaderName::try_from(config.subject_header.as_deref()?).ok()?,
+            verify_header: HeaderName::try_from(&config.verify_header).ok()?,
+            trusted_proxies: config
+                .trusted_proxies
+                .iter()
+                .filter_map(|cidr| parse_cidr(cidr))
+                .collect(),
+            identities: config.identities.clone(),
+        })
+    }
+}
+
+impl AuthProvider for MtlsProvider {
+    fn authenticate(&self, request: &Credentials) -> Result<Option<Claims>, String> {
+        let headers = request.headers;
+        let Some(subject) = headers.get(&self.subject_header) else {
+            return Ok(None);
+        };
+
+        // Anyone else could send the headers themselves
+        if !request
+            .peer
+            .is_some_and(|peer| self.trusted_proxies.iter().any(|net| net.contains(&peer)))
+        {
+            return Err("Client certificates are only taken from a trusted proxy".to_string());
+        }
+        if headers
+            .get(&self.verify_header)
+            .is_none_or(|verified| verified != "SUCCESS")
+        {
+            return Err("Client certificate is not verified".to_string());
+        }
+        let subject = subject.to_str().map_err(|e| e.to_string())?;
+        match self.identities.get(subject) {
+            Some(identity) => Ok(Some(service_claims(identity))),
+            None => Err("Unknown client certificate".to_string()),
+        }
+    }
+}
+
+pub fn access_claims(token: &str, secret: &RotatingSecret) -> Result<Claims, String> {
+    let claims = decode_rotated(token, secret).map_err(|e| e.to_string())?;
+    if claims.token_type != TokenType::Access {
+        return Err("Not an access token".to_string());
+    }
+    Ok(claims)
+}
+
+// Checked on every request, so they are good for this one only and can't be revoked
+fn service_claims(identity: &ServiceIdentity) -> Claims {
+    Claims {
+        sub: identity.user.clone(),
+        exp: get_current_timestamp(),
+        token_type: TokenType::Access,
+        jti: None,
+        role: identity.role,
+        tenant: identity.tenant.clone(),
+    }
+}
+
+// Ids of access tokens signed out before they expired, each kept until its `exp`.
+// In memory, so with several instances a logout only holds on the one that served it
+#[derive(Debug, Clone, Default)]
+pub struct Denylist(Arc<Mutex<HashMap<String, u64>>>);
+
+impl Denylist {
+    pub fn revoke(&self, jti: &str, exp: u64) {
+        let now = get_current_timestamp();
+        let mut revoked = self.0.lock().unwrap();
+        // Expired tokens are refused anyway, no need to remember them
+        revoked.retain(|_, &mut exp| exp > now);
+        revoked.insert(jti.to_string(), exp);
+    }
+
+    pub fn is_revoked(&self, jti: &str) -> bool {
+        self.0.lock().unwrap().contains_key(jti)
+    }
+}
+
+// Asks each provider in turn, the first one recognising a credential decides
+#[derive(Clone)]
+pub struct Authenticator {
+    providers: Arc<Vec<Box<dyn AuthProvider>>>,
+    pub denylist: Denylist,
+    // Signs access and refresh tokens, rotated by `secrets::refresh_secrets`
+    pub jwt_secret: RotatingSecret,
+}
+
+impl Authenticator {
+    pub fn authenticate(&self, request: &Credentials) -> Result<Claims, String> {
+        for provider in self.providers.iter() {
+            if let Some(claims) = provider.authenticate(request)? {
+                if claims
+                    .jti
+                    .as_deref()
+                    .is_some_and(|jti| self.denylist.is_revoked(jti))
+                {
+                    return Err("Token has been revoked".to_string());
+                }
+                return Ok(claims);
+            }
+        }
+        Err("Missing auth token".to_string())
+    }
+}
+
+impl Authenticator {
+    pub fn new(config: &Config) -> Self {
+        let jwt_secret = RotatingSecret::new(config.jwt_secret.as_bytes());
+        let mut providers: Vec<Box<dyn AuthProvider>> = vec![Box::new(JwtProvider {
+            secret: jwt_secret.clone(),
+        })];
+        if !config.auth.api_keys.is_empty() {
+            providers.push(Box::new(ApiKeyProvider::new(&config.auth.api_keys)));
+        }
+        if let Some(name) = &config.auth.session_cookie {
+            providers.push(Box::new(CookieProvider {
+                name: name.clone(),
+                secret: jwt_secret.clone(),
+            }));
+        }
+        if let Some(mtls) = MtlsProvider::new(&config.auth.mtls) {
+            providers.push(Box::new(mtls));
+        }
+        Authenticator {
+            providers: Arc::new(providers),
+            denylist: Denylist::default(),
+            jwt_secret,
+        }
+    }
+}
+
+// The signed in user, taking it as a handler argument makes the route require a login.
+// Guests are refused, they need `GuestUser`
+#[derive(Debug)]
+pub struct AuthUser(pub Claims);
+
+impl AuthUser {
+    pub fn username(&self) -> &str {
+        &self.0.sub
+    }
+
+    pub fn role(&self) -> Role {
+        self.0.role
+    }
+}
+
+impl<S> FromRequestParts<S> for AuthUser
+where
+    Authenticator: FromRef<S>,
+    Tenants: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let claims = claims_of(parts, state)?;
+        if claims.role == Role::Guest {
+            return Err(AppError::Forbidden(
+                "Guests have to claim an account first".to_string(),
+            ));
+        }
+        Ok(AuthUser(claims))
+    }
+}
+
+// A signed in user or a guest, for the few routes guests may use
+#[derive(Debug)]
+pub struct GuestUser(pub Claims);
+
+impl GuestUser {
+    pub fn is_guest(&self) -> bool {
+        self.0.role == Role::Guest
+    }
+}
+
+impl<S> FromRequestParts<S> for GuestUser
+where
+    Authenticator: FromRef<S>,
+    Tenants: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        claims_of(parts, state).map(GuestUser)
+    }
+}
+
+fn claims_of<S>(parts: &Parts, state: &S) -> Result<Claims, AppError>
+where
+    Authenticator: FromRef<S>,
+    Tenants: FromRef<S>,
+{
+    let claims = Authenticator::from_ref(state)
+        .authenticate(&Credentials::of_parts(parts))
+        .map_err(AppError::Unauthorized)?;
+
+    if claims.tenant != Tenants::from_ref(state).tenant_of(&parts.headers)? {
+        return Err(AppError::Unauthorized(
+            "Token was issued for another tenant".to_string(),
+        ));
+    }
+    Ok(claims)
+}
+
+pub const ACCESS_TOKEN_TTL: Duration = Duration::from_secs(60);
+pub const REFRESH_TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// Long enough to try things out before claiming an account
+pub const GUEST_TOKEN_TTL: Duration = Duration::from_secs(30 * 60);
+// Guest ids can't collide with user names, those have no `:`
+pub const GUEST_PREFIX: &str = "guest:";
+
+pub fn generate_token(
+    username: &str,
+    role: Role,
+    tenant: Option<&str>,
+    key: &[u8],
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let ttl = if role == Role::Guest {
+        GUEST_TOKEN_TTL
+    } else {
+        ACCESS_TOKEN_TTL
+    };
+    let my_claims = Claims {
+        sub: username.to_string(),
+        exp: get_current_timestamp() + ttl.as_secs(),
+        token_type: TokenType::Access,
+        // Random, only used to single the token out on logout
+        jti: Some(hex::encode(rand_bytes())),
+        role,
+        tenant: tenant.map(str::to_string),
+    };
+    encode(
+        &Header::default(),
+        &my_claims,
+        &EncodingKey::from_secret(key),
+    )
+}
+
+// A fresh guest id with its token
+pub fn generate_guest_token(
+    tenant: Option<&str>,
+    key: &[u8],
+) -> Result<(String, String), jsonwebtoken::errors::Error> {
+    let guest_id = format!("{}{}", GUEST_PREFIX, hex::encode(rand_bytes()));
+    let token = generate_token(&guest_id, Role::Guest, tenant, key)?;
+    Ok((guest_id, token))
+}
+
+fn rand_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+pub fn generate_refresh_token(
+    username: &str,
+    role: Role,
+    tenant: Option<&str>,
+    jti: &str,
+    expires_at: u64,
+    key: &[u8],
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let my_claims = Claims {
+        sub: username.to_string(),
+        exp: expires_at,
+        token_type: TokenType::Refresh,
+        jti: Some(jti.to_string()),
+        role,
+        tenant: tenant.map(str::to_string),
+    };
+    encode(
+        &Header::default(),
+        &my_claims,
+        &EncodingKey::from_secret(key),
+    )
+}
+
+// Tries the current secret, then the one it replaced
+pub fn decode_rotated(
+    token: &str,
+    secret: &RotatingSecret,
+) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let mut result = Err(jsonwebtoken::errors::ErrorKind::InvalidSignature.into());
+    for key in secret.verifying() {
+        result = decode_token(token, &key);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+pub fn decode_token(token: &str, key: &[u8]) -> Result<Claims, jsonwebtoken::errors::Error> {
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(key),
+        &Validation::default(),
+    )
+    .map(|token_data| token_data.claims)
+}
@@ -0,0 +1,96 @@
+use std::{fmt, net::SocketAddr};
+
+use tracing::{info, warn};
+
+use crate::config::Config;
+
+// What a route asks of the caller before its handler runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Public,
+    // An HMAC signature, see `middleware::verify_signature`
+    Signed,
+    // A bearer token
+    User,
+    // A bearer token, guest ones from `/auth/guest` included
+    Guest,
+    // An admin token from an allowlisted address
+    Admin,
+    // The identity provider's `scim.bearer_token`
+    Scim,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Access::Public => "public",
+            Access::Signed => "signed",
+            Access::User => "user",
+            Access::Guest => "guest",
+            Access::Admin => "admin",
+            Access::Scim => "scim",
+        })
+    }
+}
+
+// One line of the route table logged on startup. Axum can't list a router's routes,
+// so each module declares its own next to where it registers them
+#[derive(Debug, Clone, Copy)]
+pub struct RouteInfo {
+    pub methods: &'static str,
+    pub path: &'static str,
+    pub access: Access,
+    // Where the route is mounted, e.g. `/api/v1`
+    pub prefix: &'static str,
+}
+
+pub const fn route(methods: &'static str, path: &'static str, access: Access) -> RouteInfo {
+    RouteInfo {
+        methods,
+        path,
+        access,
+        prefix: "",
+    }
+}
+
+impl RouteInfo {
+    pub fn under(&self, prefix: &'static str) -> RouteInfo {
+        RouteInfo { prefix, ..*self }
+    }
+
+    pub fn full_path(&self) -> String {
+        format!("{}{}", self.prefix, self.path)
+    }
+}
+
+// Everything that decides how the server behaves, logged once so a bad value shows right away
+pub fn log_banner(config: &Config, modules: &[&str], address: SocketAddr) {
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        environment = ?config.environment,
+        config = %config.source,
+        modules = ?modules,
+        address = %address,
+        admin_socket = ?config.admin_socket,
+        database = %config.database_name,
+        tenants = config.tenants.enabled,
+        cors_origins = ?config.cors.allowed_origins,
+        read_only = config.read_only.enabled,
+        "Starting hello-axum"
+    );
+    if config.cors.permissive {
+        warn!("CORS is permissive, any website can call this server from a browser");
+    }
+}
+
+pub fn log_route_table(routes: &[RouteInfo]) {
+    for route in routes {
+        info!(
+            methods = route.methods,
+            path = route.full_path(),
+            access = %route.access,
+            "Route"
+        );
+    }
+    info!("{} route(s) registered", routes.len());
+}
@@ -0,0 +1,174 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header::USER_AGENT, HeaderMap},
+    middleware::Next,
+    response::IntoResponse,
+};
+use metrics::counter;
+
+use crate::{config::ClientsConfig, error::AppError};
+
+// Sent by our own apps, anything else is guessed from `User-Agent`
+pub const CLIENT_TYPE_HEADER: &str = "x-client-type";
+pub const CLIENT_VERSION_HEADER: &str = "x-client-version";
+// Reachable by outdated clients, which need `/client-config` to tell them what to do
+pub const MIN_VERSION_EXEMPT: &[&str] = &["/client-config", "/healthz", "/readyz", "/metrics"];
+
+// The kind of client a request comes from, e.g. `ios`, `android`, `web` or `other`
+pub fn client_type(headers: &HeaderMap) -> String {
+    if let Some(declared) = headers
+        .get(CLIENT_TYPE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .filter(|value| !value.is_empty())
+    {
+        return declared;
+    }
+
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let kind = if user_agent.contains("Android") {
+        "android"
+    } else if ["iPhone", "iPad", "iOS"]
+        .iter()
+        .any(|marker| user_agent.contains(marker))
+    {
+        "ios"
+    } else if user_agent.starts_with("Mozilla/") {
+        "web"
+    } else {
+        "other"
+    };
+    kind.to_string()
+}
+
+// A dotted version like `2.3.1`, trailing zeros don't count so `2.3` equals `2.3.0`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientVersion(Vec<u32>);
+
+impl FromStr for ClientVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s
+            .trim()
+            .trim_start_matches('v')
+            .split('.')
+            .map(|part| part.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Invalid version : {:?}", s))?;
+        while parts.len() > 1 && parts.last() == Some(&0) {
+            parts.pop();
+        }
+        Ok(ClientVersion(parts))
+    }
+}
+
+impl Ord for ClientVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl PartialOrd for ClientVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Minimum versions per client type, seeded from `clients.min_versions` and changed at
+// runtime through `/admin/client-versions`. Kept as written, e.g. `2.3.0`
+#[derive(Debug, Clone)]
+pub struct MinVersions {
+    minimums: Arc<RwLock<BTreeMap<String, String>>>,
+    upgrade_urls: Arc<BTreeMap<String, String>>,
+}
+
+impl MinVersions {
+    pub fn new(config: &ClientsConfig) -> Self {
+        MinVersions {
+            minimums: Arc::new(RwLock::new(config.min_versions.clone())),
+            upgrade_urls: Arc::new(config.upgrade_urls.clone()),
+        }
+    }
+
+    pub fn get(&self, client_type: &str) -> Option<String> {
+        self.minimums.read().unwrap().get(client_type).cloned()
+    }
+
+    pub fn all(&self) -> BTreeMap<String, String> {
+        self.minimums.read().unwrap().clone()
+    }
+
+    // `None` lifts the minimum for that client type
+    pub fn set(&self, client_type: &str, minimum: Option<String>) -> Result<(), String> {
+        if let Some(version) = &minimum {
+            version.parse::<ClientVersion>()?;
+        }
+        let mut minimums = self.minimums.write().unwrap();
+        match minimum {
+            Some(version) => minimums.insert(client_type.to_string(), version),
+            None => minimums.remove(client_type),
+        };
+        Ok(())
+    }
+
+    pub fn upgrade_url(&self, client_type: &str) -> Option<String> {
+        self.upgrade_urls.get(client_type).cloned()
+    }
+}
+
+// Answers 426 to clients reporting a version below the minimum of their type. Requests
+// without `X-Client-Version`, like browsers and scripts, aren't checked
+pub async fn enforce_min_version(
+    State(min_versions): State<MinVersions>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let Some(reported) = request
+        .headers()
+        .get(CLIENT_VERSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return next.run(request).await;
+    };
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(request.uri().path(), MatchedPath::as_str);
+    if MIN_VERSION_EXEMPT.contains(&path) {
+        return next.run(request).await;
+    }
+
+    let client_type = client_type(request.headers());
+    let Some(minimum) = min_versions.get(&client_type) else {
+        return next.run(request).await;
+    };
+    let reported = match reported.parse::<ClientVersion>() {
+        Ok(version) => version,
+        Err(e) => return AppError::Validation(e).into_response(),
+    };
+    // Validated when it was configured
+    let Ok(required) = minimum.parse::<ClientVersion>() else {
+        return next.run(request).await;
+    };
+    if reported >= required {
+        return next.run(request).await;
+    }
+
+    counter!("outdated_client_requests_total", "client_type" => client_type.clone()).increment(1);
+    AppError::ClientOutdated {
+        upgrade_url: min_versions.upgrade_url(&client_type),
+        minimum,
+    }
+    .into_response()
+}
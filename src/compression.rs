@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use axum::{
+    body::HttpBody,
+    http::{header::CONTENT_TYPE, Response},
+};
+use tower_http::{
+    compression::{
+        predicate::{And, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    decompression::RequestDecompressionLayer,
+};
+
+use crate::config::CompressionConfig;
+
+// Compresses responses whose `Content-Type` starts with one of the prefixes. Event streams
+// are left alone whatever the list says, a compressor would hold events back
+#[derive(Debug, Clone)]
+pub struct CompressibleTypes(Arc<[String]>);
+
+impl Predicate for CompressibleTypes {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        !content_type.starts_with("text/event-stream")
+            && self
+                .0
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix.as_str()))
+    }
+}
+
+// Ranged responses and ones already encoded, like precompressed static files, are skipped
+pub fn compression_layer(
+    config: &CompressionConfig,
+) -> CompressionLayer<And<SizeAbove, CompressibleTypes>> {
+    let predicate =
+        SizeAbove::new(config.min_size).and(CompressibleTypes(config.content_types.clone().into()));
+    CompressionLayer::new()
+        .gzip(config.enabled)
+        .br(config.enabled)
+        .zstd(config.enabled)
+        .no_deflate()
+        .compress_when(predicate)
+}
+
+// Decoded bodies still go through the handlers' body limits, which count decoded bytes
+pub fn decompression_layer(config: &CompressionConfig) -> RequestDecompressionLayer {
+    RequestDecompressionLayer::new()
+        .gzip(config.enabled)
+        .br(config.enabled)
+        .zstd(config.enabled)
+        .no_deflate()
+        .pass_through_unaccepted(!config.enabled)
+}
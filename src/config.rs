@@ -0,0 +1,1384 @@
+use std::{collections::BTreeMap, env, fs, net::SocketAddr, path::PathBuf, time::Duration};
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    anomaly::MAX_THROTTLE_SECS,
+    client::ClientVersion,
+    crypto::FieldCipher,
+    handlers::files::MULTIPART_BODY_LIMIT,
+    middleware::{parse_cidr, SIGNABLE_ROUTES},
+    models::{ProfileField, Role},
+    versioning::{parse_date, ApiVersion},
+};
+
+// File settings are read from, override with `CONFIG_FILE`
+const CONFIG_FILE: &str = "config.toml";
+// For HMAC-SHA256 keys, shorter ones can be brute forced from a signed request
+pub const MIN_SECRET_LENGTH: usize = 32;
+// Used for unset secrets in development only, they are public
+const DEV_JWT_SECRET: &str = "dev-only-jwt-secret";
+const DEV_SIGNING_SECRET: &str = "dev-only-signing-secret";
+
+// `development` fills in secrets left unset, `production` refuses to start without them
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    Development,
+    #[default]
+    Production,
+}
+
+// What new users, uploads and IP rules get as `_id`. Both start with their creation time,
+// so listings ordered by `_id` are oldest first either way
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdFormat {
+    #[default]
+    ObjectId,
+    UuidV7,
+}
+
+// Settings that used to be hardcoded, environment variables win over `config.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub environment: Environment,
+    pub mongodb_uri: String,
+    // Tenant databases are named `<database_name>_<tenant>`
+    pub database_name: String,
+    pub tenants: TenantConfig,
+    pub id_format: IdFormat,
+    pub bind_address: SocketAddr,
+    // Required outside development, `JWT_SECRET` sets it from the environment
+    pub jwt_secret: String,
+    // Shared with machine clients that sign requests to `signed_routes`, required when there
+    // are any
+    pub signing_secret: Option<String>,
+    // Counter writes that need an HMAC signature on top of their usual access, for machine
+    // clients. Keyed by method and path as registered, e.g. `"PUT /counter"`, in every API
+    // version, see `middleware::SIGNABLE_ROUTES`. None by default, `SIGNED_ROUTES=a,b` sets
+    // them from the environment
+    pub signed_routes: Vec<String>,
+    // Keys `crypto::FieldCipher` seals emails with, as `id:base64key,…` with the one new values
+    // get first. Stored as they are when unset, `FIELD_ENCRYPTION_KEYS` sets them
+    pub field_encryption_keys: Option<String>,
+    pub cors: CorsConfig,
+    pub route_groups: RouteGroups,
+    // Addresses or CIDRs always let through to `/admin`, on top of the allow rules stored in
+    // `ip_rules`. Nothing is allowed by default, not even loopback
+    pub admin_allowlist: Vec<String>,
+    // Unix socket for the debug console, which stays off unless this is set
+    pub admin_socket: Option<PathBuf>,
+    // Applied per client address to everything under `/auth`
+    pub auth_rate_limit: RateLimitConfig,
+    // Applied per client address to `/qr` and `/img`, which render and fetch for anyone
+    pub media_rate_limit: RateLimitConfig,
+    // Public playground : visitors of `/sandbox/counter` without credentials get a guest in a
+    // cookie, see `middleware::demo_session`. `DEMO_MODE` sets it from the environment
+    pub demo_mode: bool,
+    // Applied per client address to `/sandbox/counter` in demo mode, guests or not
+    pub demo_rate_limit: RateLimitConfig,
+    pub anomaly: AnomalyConfig,
+    pub account_lockout: LockoutConfig,
+    pub read_preference: ReadPreferenceConfig,
+    pub read_only: ReadOnlyConfig,
+    pub connections: ConnectionsConfig,
+    pub runtime: RuntimeConfig,
+    pub static_files: StaticConfig,
+    pub compression: CompressionConfig,
+    pub clients: ClientsConfig,
+    pub limits: LimitsConfig,
+    pub versioning: VersioningConfig,
+    pub secrets: SecretsConfig,
+    pub scim: ScimConfig,
+    pub auth: AuthConfig,
+    pub media: MediaConfig,
+    pub password_reset: PasswordResetConfig,
+    // Profile fields a route needs filled in before its handler runs, keyed by method and
+    // path as registered, e.g. `"POST /files/uploads" = ["email"]`, in every API version
+    pub profile_requirements: BTreeMap<String, Vec<ProfileField>>,
+    // The file this was loaded from, or "defaults"
+    #[serde(skip)]
+    pub source: String,
+}
+
+// Browsers only, everything else ignores CORS. `*` in a list allows any value, which
+// browsers refuse together with credentials
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    // Full origins, e.g. `https://app.example.com`
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    // Response headers scripts may read, on top of the always visible ones
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    // How long browsers may cache a preflight answer
+    pub max_age_secs: u64,
+    // Mirrors whatever a request asks for, for local development only
+    pub permissive: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        CorsConfig {
+            allowed_origins: strings(&["http://localhost:4000"]),
+            allowed_methods: strings(&["GET", "POST", "PUT", "PATCH", "DELETE"]),
+            allowed_headers: strings(&[
+                "authorization",
+                "content-type",
+                "x-client-type",
+                "x-client-version",
+                "x-api-key",
+                "x-dry-run",
+                "x-request-id",
+            ]),
+            exposed_headers: strings(&["x-request-id", "retry-after"]),
+            allow_credentials: false,
+            max_age_secs: 600,
+            permissive: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    fn validate(&self) -> Result<(), String> {
+        for origin in self.allowed_origins.iter().filter(|origin| *origin != "*") {
+            let has_scheme = origin.starts_with("http://") || origin.starts_with("https://");
+            if !has_scheme || origin.ends_with('/') || origin.parse::<HeaderValue>().is_err() {
+                return Err(format!(
+                    "Invalid CORS origin {:?}, expected something like https://app.example.com",
+                    origin
+                ));
+            }
+        }
+        for method in self.allowed_methods.iter().filter(|method| *method != "*") {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|_| format!("Invalid CORS method : {}", method))?;
+        }
+        for header in self
+            .allowed_headers
+            .iter()
+            .chain(&self.exposed_headers)
+            .filter(|header| *header != "*")
+        {
+            HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| format!("Invalid CORS header : {}", header))?;
+        }
+        let wildcard = [
+            &self.allowed_origins,
+            &self.allowed_methods,
+            &self.allowed_headers,
+            &self.exposed_headers,
+        ]
+        .iter()
+        .any(|values| values.iter().any(|value| value == "*"));
+        if self.allow_credentials && wildcard {
+            return Err("cors.allow_credentials can't be combined with `*`".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Off by default, every request then works on `database_name`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TenantConfig {
+    pub enabled: bool,
+    // Request header naming the tenant, required on every request once enabled
+    pub header: String,
+    // Database handles kept around, the oldest is dropped past this
+    pub max_cached: usize,
+}
+
+impl Default for TenantConfig {
+    fn default() -> Self {
+        TenantConfig {
+            enabled: false,
+            header: "X-Tenant".to_string(),
+            max_cached: 64,
+        }
+    }
+}
+
+// Refuses every write with 503 while enabled, `/admin/read-only` switches it at runtime
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ReadOnlyConfig {
+    pub enabled: bool,
+    // Route paths as registered, e.g. `/users/{id}`, whose writes stay allowed in every
+    // API version
+    pub exempt: Vec<String>,
+}
+
+// Files under `dir` served at `/static`, with `.br` and `.gz` siblings used when present
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StaticConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    // Sent as `Cache-Control: public, max-age=...`
+    pub max_age_secs: u64,
+    // Unmatched GETs asking for HTML get `<dir>/index.html`, for client side routing
+    pub spa: bool,
+}
+
+impl Default for StaticConfig {
+    fn default() -> Self {
+        StaticConfig {
+            enabled: false,
+            dir: PathBuf::from("static"),
+            max_age_secs: 3600,
+            spa: false,
+        }
+    }
+}
+
+// gzip, br and zstd both ways, picked from `Accept-Encoding` and `Content-Encoding`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // Responses with a smaller `Content-Length` go out as they are
+    pub min_size: u16,
+    // `Content-Type` prefixes worth compressing, e.g. `text/` for every text type
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            min_size: 1024,
+            content_types: vec![
+                "application/json".to_string(),
+                "application/javascript".to_string(),
+                "image/svg+xml".to_string(),
+                "text/".to_string(),
+            ],
+        }
+    }
+}
+
+// What `/client-config` tells frontends, keyed by client type (`ios`, `android`, `web`, ...)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClientsConfig {
+    pub locales: Vec<String>,
+    // On top of one flag per route group, which can be switched off here too
+    pub features: BTreeMap<String, bool>,
+    // Per client type, replacing entries of `features`
+    pub feature_overrides: BTreeMap<String, BTreeMap<String, bool>>,
+    // Oldest version each client type is expected to run, e.g. `ios = "2.3.0"`
+    pub min_versions: BTreeMap<String, String>,
+    // Where clients below their minimum are sent, e.g. a store listing
+    pub upgrade_urls: BTreeMap<String, String>,
+}
+
+impl Default for ClientsConfig {
+    fn default() -> Self {
+        ClientsConfig {
+            locales: vec!["en".to_string()],
+            features: BTreeMap::new(),
+            feature_overrides: BTreeMap::new(),
+            min_versions: BTreeMap::new(),
+            upgrade_urls: BTreeMap::new(),
+        }
+    }
+}
+
+// Per request limits, a module listed under `modules` gets its own instead
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub body_limit: usize,
+    pub timeout_secs: u64,
+    // Keyed by module name, e.g. `auth` or `files`
+    pub modules: BTreeMap<String, ModuleLimits>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct ModuleLimits {
+    pub body_limit: Option<usize>,
+    pub timeout_secs: Option<u64>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        let auth = ModuleLimits {
+            body_limit: Some(64 * 1024),
+            timeout_secs: Some(10),
+        };
+        // Whole files go up in one request on `/upload`
+        let files = ModuleLimits {
+            body_limit: Some(MULTIPART_BODY_LIMIT),
+            timeout_secs: Some(600),
+        };
+        LimitsConfig {
+            body_limit: 2 * 1024 * 1024,
+            timeout_secs: 30,
+            modules: BTreeMap::from([("auth".to_string(), auth), ("files".to_string(), files)]),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn body_limit_for(&self, module: &str) -> usize {
+        self.modules
+            .get(module)
+            .and_then(|limits| limits.body_limit)
+            .unwrap_or(self.body_limit)
+    }
+
+    pub fn default_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub fn timeout_for(&self, module: &str) -> Duration {
+        let secs = self
+            .modules
+            .get(module)
+            .and_then(|limits| limits.timeout_secs)
+            .unwrap_or(self.timeout_secs);
+        Duration::from_secs(secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretsProviderKind {
+    #[default]
+    None,
+    Vault,
+    Aws,
+}
+
+// Where `jwt_secret`, `signing_secret`, `mongodb_uri` and `field_encryption_keys` come from
+// when they are kept out of the file and environment. Vault reads `VAULT_TOKEN`, AWS the
+// usual `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SecretsConfig {
+    pub provider: SecretsProviderKind,
+    // How often they are fetched again, changed keys are rotated in without a restart
+    pub refresh_secs: u64,
+    // e.g. `https://vault.example.com:8200`
+    pub vault_address: Option<String>,
+    // The full API path, e.g. `secret/data/hello-axum` for a KV v2 mount
+    pub vault_path: Option<String>,
+    pub aws_region: Option<String>,
+    // Name or ARN of a secret holding a JSON object
+    pub aws_secret_id: Option<String>,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        SecretsConfig {
+            provider: SecretsProviderKind::None,
+            refresh_secs: 300,
+            vault_address: None,
+            vault_path: None,
+            aws_region: None,
+            aws_secret_id: None,
+        }
+    }
+}
+
+impl SecretsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.refresh_secs == 0 {
+            return Err("secrets.refresh_secs must be above 0".to_string());
+        }
+        let missing = match self.provider {
+            SecretsProviderKind::None => None,
+            SecretsProviderKind::Vault => [
+                ("vault_address", &self.vault_address),
+                ("vault_path", &self.vault_path),
+            ]
+            .into_iter()
+            .find(|(_, value)| value.is_none()),
+            SecretsProviderKind::Aws => [
+                ("aws_region", &self.aws_region),
+                ("aws_secret_id", &self.aws_secret_id),
+            ]
+            .into_iter()
+            .find(|(_, value)| value.is_none()),
+        };
+        match missing {
+            Some((name, _)) => Err(format!("secrets.{} must be set", name)),
+            None => Ok(()),
+        }
+    }
+}
+
+// SCIM 2.0 provisioning under `/scim/v2`, served when the `scim` route group is on
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ScimConfig {
+    // What the identity provider sends as `Authorization: Bearer …`, `SCIM_BEARER_TOKEN` sets
+    // it from the environment
+    pub bearer_token: Option<String>,
+}
+
+// Credentials accepted besides access tokens in `Authorization`, tried after it in this
+// order : API keys, the session cookie, client certificates
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub api_keys: Vec<ApiKeyConfig>,
+    // A cookie holding an access token, for browser apps. It isn't set here, whatever serves
+    // the app has to, `HttpOnly` and `SameSite=Strict` so other sites can't ride on it. Other
+    // than on GET, HEAD and OPTIONS it's only taken from same-site requests
+    pub session_cookie: Option<String>,
+    pub mtls: MtlsConfig,
+    pub ldap: LdapConfig,
+}
+
+// Who a request signed in with an API key or a client certificate is
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceIdentity {
+    pub user: String,
+    #[serde(default)]
+    pub role: Role,
+    // Required when tenants are enabled, like in tokens
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+// Sent as `X-API-Key`. Only its hex SHA-256 is kept, so the config doesn't give keys away
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyConfig {
+    pub sha256: String,
+    #[serde(flatten)]
+    pub identity: ServiceIdentity,
+}
+
+// Client certificates are checked by the TLS proxy in front, which forwards the subject of
+// a verified one in `subject_header`. `server::serve` only speaks plain HTTP, so the proxy is
+// where TLS ends and the only place a certificate can be checked. The headers are only read
+// from connections coming from `trusted_proxies`, and that proxy has to overwrite whatever
+// clients send in them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MtlsConfig {
+    // Off unless set, e.g. to `x-client-cert-subject`
+    pub subject_header: Option<String>,
+    // Has to say `SUCCESS`, like nginx's `$ssl_client_verify`
+    pub verify_header: String,
+    // Addresses or CIDRs of the proxy, e.g. `10.0.0.5`. Required with `subject_header`
+    pub trusted_proxies: Vec<String>,
+    // Keyed by subject, e.g. `"CN=billing,O=Example"`. Other certificates are refused
+    pub identities: BTreeMap<String, ServiceIdentity>,
+}
+
+impl Default for MtlsConfig {
+    fn default() -> Self {
+        MtlsConfig {
+            subject_header: None,
+            verify_header: "x-client-verify".to_string(),
+            trusted_proxies: Vec::new(),
+            identities: BTreeMap::new(),
+        }
+    }
+}
+
+// Passwords checked against a directory in `/auth/signin`, needs the `ldap` feature. A user
+// the directory lets in is created on first sign in, marked `source: "ldap"`, and gets its
+// role from their groups every time. Accounts without the mark are local, the directory is
+// never asked about them. When the directory refuses, or can't be reached, the local
+// password is tried
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LdapConfig {
+    // Off unless set, e.g. `ldaps://ldap.example.com`, `LDAP_URL` sets it from the environment
+    pub url: Option<String>,
+    // The DN to bind as, `{user}` is replaced with the escaped user name
+    pub bind_dn: String,
+    pub starttls: bool,
+    pub timeout_secs: u64,
+    // Read from the user's own entry, holding the DNs of their groups
+    pub group_attribute: String,
+    // Keyed by group DN. The highest role of the user's groups wins, `user` without any
+    pub group_roles: BTreeMap<String, Role>,
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        LdapConfig {
+            url: None,
+            bind_dn: "uid={user},ou=people,dc=example,dc=com".to_string(),
+            starttls: false,
+            timeout_secs: 5,
+            group_attribute: "memberOf".to_string(),
+            group_roles: BTreeMap::new(),
+        }
+    }
+}
+
+impl LdapConfig {
+    // The role of a user in `groups`, group DNs compare regardless of case
+    pub fn role_for<S: AsRef<str>>(&self, groups: &[S]) -> Role {
+        groups
+            .iter()
+            .filter_map(|group| {
+                self.group_roles
+                    .iter()
+                    .find(|(dn, _)| dn.eq_ignore_ascii_case(group.as_ref()))
+                    .map(|(_, &role)| role)
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let Some(url) = &self.url else {
+            return Ok(());
+        };
+        if !cfg!(feature = "ldap") {
+            return Err("auth.ldap.url needs a build with the ldap feature".to_string());
+        }
+        if !url.starts_with("ldap://") && !url.starts_with("ldaps://") {
+            return Err(format!("Invalid auth.ldap.url : {}", url));
+        }
+        if !self.bind_dn.contains("{user}") {
+            return Err("auth.ldap.bind_dn needs a {user} placeholder".to_string());
+        }
+        if self.timeout_secs == 0 {
+            return Err("auth.ldap.timeout_secs must be at least 1".to_string());
+        }
+        if self.group_roles.values().any(|&role| role == Role::Guest) {
+            return Err("auth.ldap.group_roles can't grant the guest role".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl AuthConfig {
+    fn validate(&self) -> Result<(), String> {
+        self.ldap.validate()?;
+        let is_sha256 =
+            |hash: &str| hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit());
+        if let Some(key) = self.api_keys.iter().find(|key| !is_sha256(&key.sha256)) {
+            return Err(format!(
+                "The API key of {} needs a hex SHA-256 as sha256",
+                key.identity.user
+            ));
+        }
+        if self.session_cookie.as_deref().is_some_and(|name| {
+            name.is_empty()
+                || !name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+        }) {
+            return Err("Invalid auth.session_cookie name".to_string());
+        }
+        if let Some(subject_header) = &self.mtls.subject_header {
+            for header in [subject_header, &self.mtls.verify_header] {
+                if HeaderName::try_from(header.as_str()).is_err() {
+                    return Err(format!("Invalid auth.mtls header : {}", header));
+                }
+            }
+            if self.mtls.identities.is_empty() {
+                return Err("auth.mtls.subject_header needs auth.mtls.identities".to_string());
+            }
+            if self.mtls.trusted_proxies.is_empty() {
+                return Err("auth.mtls.subject_header needs auth.mtls.trusted_proxies".to_string());
+            }
+            if let Some(cidr) = self
+                .mtls
+                .trusted_proxies
+                .iter()
+                .find(|cidr| parse_cidr(cidr).is_none())
+            {
+                return Err(format!(
+                    "Invalid auth.mtls.trusted_proxies entry : {}",
+                    cidr
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Where `/auth/forgot-password` sends reset tokens, see `AuthModule::new`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PasswordResetConfig {
+    // Gets a JSON `passwordReset` event with the token, `PASSWORD_RESET_WEBHOOK_URL` sets it
+    // from the environment. Without it the route is only served in development
+    pub webhook_url: Option<String>,
+}
+
+// `/img`, which fetches remote images and keeps them resized in `media::IMAGE_CACHE_DIR`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MediaConfig {
+    // Hosts images are fetched from, over HTTPS, on every redirect too
+    pub image_hosts: Vec<String>,
+    // Past it the earliest cached images are removed, down to 90% of it
+    pub image_cache_max_bytes: u64,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        MediaConfig {
+            image_hosts: vec![
+                "images.unsplash.com".to_string(),
+                "avatars.githubusercontent.com".to_string(),
+            ],
+            image_cache_max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl MediaConfig {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(host) = self.image_hosts.iter().find(|host| {
+            host.is_empty()
+                || !host
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"-.".contains(&b))
+        }) {
+            return Err(format!("Invalid media.image_hosts entry : {:?}", host));
+        }
+        if self.image_cache_max_bytes == 0 {
+            return Err("media.image_cache_max_bytes must be above 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Business routes live under `/api/v1`. The unversioned paths they had before are served
+// too, as the `legacy` version, until `legacy_routes` is turned off
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VersioningConfig {
+    pub legacy_routes: bool,
+    // Keyed by version, e.g. `legacy` or `v1`. Calls to a listed version get `Deprecation`
+    // and `Sunset` headers and show up in `/admin/deprecations`
+    pub deprecations: BTreeMap<String, DeprecationConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DeprecationConfig {
+    // `YYYY-MM-DD`, `Deprecation: true` is sent without it
+    pub deprecated_on: Option<String>,
+    // `YYYY-MM-DD`, the day the routes are expected to go away
+    pub sunset_on: Option<String>,
+}
+
+impl Default for VersioningConfig {
+    fn default() -> Self {
+        VersioningConfig {
+            legacy_routes: true,
+            deprecations: BTreeMap::from([(
+                ApiVersion::Legacy.name().to_string(),
+                DeprecationConfig::default(),
+            )]),
+        }
+    }
+}
+
+impl VersioningConfig {
+    fn validate(&self) -> Result<(), String> {
+        for (version, deprecation) in &self.deprecations {
+            let parsed = version
+                .parse::<ApiVersion>()
+                .map_err(|e| format!("versioning.deprecations : {}", e))?;
+            if parsed == ApiVersion::LATEST {
+                return Err(format!(
+                    "versioning.deprecations : {} is the latest version",
+                    version
+                ));
+            }
+            let date = |date: &Option<String>| {
+                date.as_deref()
+                    .map(parse_date)
+                    .transpose()
+                    .map_err(|e| format!("versioning.deprecations.{} : {}", version, e))
+            };
+            if let (Some(deprecated_on), Some(sunset_on)) = (
+                date(&deprecation.deprecated_on)?,
+                date(&deprecation.sunset_on)?,
+            ) {
+                if sunset_on < deprecated_on {
+                    return Err(format!(
+                        "versioning.deprecations.{} : sunset_on is before deprecated_on",
+                        version
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Where reads that tolerate slightly stale data can go, auth lookups always use the primary
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReadMode {
+    #[default]
+    Primary,
+    SecondaryPreferred,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ReadPreferenceConfig {
+    // User listings under `/users` and `/admin/users`
+    pub listings: ReadMode,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // Requests allowed back to back before the limit kicks in
+    pub burst: u32,
+    pub per_minute: u32,
+    // How long a premium user's request may wait for a token before it is refused too
+    pub max_queue_ms: u64,
+}
+
+// The Tokio runtime the server runs on
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    // One per core unless set
+    pub worker_threads: Option<usize>,
+    pub max_blocking_threads: usize,
+    pub thread_name: String,
+    // Everything on the main thread, handy when debugging
+    pub single_threaded: bool,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            worker_threads: None,
+            max_blocking_threads: 512,
+            thread_name: "hello-axum-worker".to_string(),
+            single_threaded: false,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    // Checked before the runtime is built, Tokio panics on zero threads
+    pub fn validate(&self) -> Result<(), String> {
+        if self.worker_threads == Some(0) || self.max_blocking_threads == 0 {
+            return Err(
+                "runtime.worker_threads and max_blocking_threads must be above 0".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+// Against slow and greedy clients, see `server::serve` and `middleware::enforce_body_throughput`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConnectionsConfig {
+    pub max_connections: usize,
+    pub max_per_ip: usize,
+    // For a connection's first bytes and each request's headers, so it also bounds how long
+    // an idle keep-alive connection is held open
+    pub header_read_timeout_secs: u64,
+    // HTTP/2 connections are pinged this often, and closed when a ping goes unanswered
+    pub http2_keep_alive_interval_secs: u64,
+    pub http2_keep_alive_timeout_secs: u64,
+    // A request body that sends nothing for this long fails
+    pub body_stall_timeout_secs: u64,
+    // After the grace period, so does one arriving slower than `min_body_bytes_per_sec`
+    pub body_throughput_grace_secs: u64,
+    pub min_body_bytes_per_sec: u64,
+}
+
+impl Default for ConnectionsConfig {
+    fn default() -> Self {
+        ConnectionsConfig {
+            max_connections: 1024,
+            max_per_ip: 64,
+            header_read_timeout_secs: 15,
+            http2_keep_alive_interval_secs: 20,
+            http2_keep_alive_timeout_secs: 10,
+            body_stall_timeout_secs: 15,
+            body_throughput_grace_secs: 10,
+            min_body_bytes_per_sec: 1024,
+        }
+    }
+}
+
+impl ConnectionsConfig {
+    fn validate(&self) -> Result<(), String> {
+        let limits = [
+            self.max_connections as u64,
+            self.max_per_ip as u64,
+            self.header_read_timeout_secs,
+            self.http2_keep_alive_interval_secs,
+            self.http2_keep_alive_timeout_secs,
+            self.body_stall_timeout_secs,
+            self.body_throughput_grace_secs,
+            self.min_body_bytes_per_sec,
+        ];
+        if limits.contains(&0) {
+            return Err("Connection limits and timeouts must be above 0".to_string());
+        }
+        if self.max_per_ip > self.max_connections {
+            return Err("connections.max_per_ip can't be above max_connections".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Throttles a client to `throttled_per_minute` for `throttle_secs` once its requests in a
+// minute go past `factor` times its own baseline, see `anomaly::TrafficGuard`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    pub enabled: bool,
+    pub factor: f64,
+    // Fewer requests in a minute are never anomalous, so quiet clients can burst
+    pub min_per_minute: u64,
+    // How many minutes a client has to be seen for before its baseline counts
+    pub warmup_minutes: u32,
+    pub throttled_per_minute: u32,
+    pub throttle_secs: u64,
+    // Sent a JSON notice of every throttled client, e.g. a chat webhook
+    pub notify_url: Option<String>,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            enabled: false,
+            factor: 5.0,
+            min_per_minute: 120,
+            warmup_minutes: 5,
+            throttled_per_minute: 30,
+            throttle_secs: 10 * 60,
+            notify_url: None,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.factor.is_nan() || self.factor <= 1.0 {
+            return Err("anomaly.factor must be above 1".to_string());
+        }
+        if self.throttled_per_minute == 0 || self.throttle_secs == 0 {
+            return Err(
+                "anomaly.throttled_per_minute and throttle_secs must be above 0".to_string(),
+            );
+        }
+        if self.throttle_secs > MAX_THROTTLE_SECS {
+            return Err(format!(
+                "anomaly.throttle_secs can't be above {}",
+                MAX_THROTTLE_SECS
+            ));
+        }
+        if let Some(url) = self
+            .notify_url
+            .as_deref()
+            .filter(|url| reqwest::Url::parse(url).is_err())
+        {
+            return Err(format!("Invalid anomaly.notify_url : {}", url));
+        }
+        Ok(())
+    }
+}
+
+// Locks an account for `lock_secs` once `max_failures` sign-ins in a row failed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    pub max_failures: u32,
+    pub lock_secs: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            max_failures: 5,
+            lock_secs: 15 * 60,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            burst: 5,
+            per_minute: 10,
+            max_queue_ms: 2000,
+        }
+    }
+}
+
+// Route groups a deployment serves, e.g. a public node can drop `admin`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RouteGroups {
+    pub auth: bool,
+    pub users: bool,
+    pub admin: bool,
+    pub files: bool,
+    pub media: bool,
+    pub settings: bool,
+    // Off unless turned on, it needs `scim.bearer_token`
+    pub scim: bool,
+    // `/metrics`, for deployments where the port is reachable by more than the scraper
+    pub metrics: bool,
+}
+
+impl Default for RouteGroups {
+    fn default() -> Self {
+        RouteGroups {
+            auth: true,
+            users: true,
+            admin: true,
+            files: true,
+            media: true,
+            settings: true,
+            scim: false,
+            metrics: true,
+        }
+    }
+}
+
+impl RouteGroups {
+    // Takes a comma separated list like `admin,files`
+    fn disable(&mut self, names: &str) -> Result<(), String> {
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name {
+                "auth" => self.auth = false,
+                "users" => self.users = false,
+                "admin" => self.admin = false,
+                "files" => self.files = false,
+                "media" => self.media = false,
+                "settings" => self.settings = false,
+                "scim" => self.scim = false,
+                "metrics" => self.metrics = false,
+                _ => return Err(format!("Unknown route group : {}", name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mongodb_uri: "mongodb://localhost:27017/".to_string(),
+            database_name: "hello_axum".to_string(),
+            tenants: TenantConfig::default(),
+            id_format: IdFormat::default(),
+            bind_address: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            environment: Environment::default(),
+            jwt_secret: String::new(),
+            signing_secret: None,
+            signed_routes: Vec::new(),
+            field_encryption_keys: None,
+            cors: CorsConfig::default(),
+            route_groups: RouteGroups::default(),
+            admin_allowlist: Vec::new(),
+            admin_socket: None,
+            auth_rate_limit: RateLimitConfig::default(),
+            media_rate_limit: RateLimitConfig::default(),
+            demo_mode: false,
+            demo_rate_limit: RateLimitConfig {
+                burst: 3,
+                per_minute: 6,
+                max_queue_ms: 0,
+            },
+            anomaly: AnomalyConfig::default(),
+            account_lockout: LockoutConfig::default(),
+            read_preference: ReadPreferenceConfig::default(),
+            read_only: ReadOnlyConfig::default(),
+            connections: ConnectionsConfig::default(),
+            runtime: RuntimeConfig::default(),
+            static_files: StaticConfig::default(),
+            compression: CompressionConfig::default(),
+            clients: ClientsConfig::default(),
+            limits: LimitsConfig::default(),
+            versioning: VersioningConfig::default(),
+            secrets: SecretsConfig::default(),
+            scim: ScimConfig::default(),
+            auth: AuthConfig::default(),
+            media: MediaConfig::default(),
+            password_reset: PasswordResetConfig::default(),
+            profile_requirements: BTreeMap::new(),
+            source: "defaults".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config, String> {
+        let path = env::var("CONFIG_FILE").unwrap_or_else(|_| CONFIG_FILE.to_string());
+        let mut config = match fs::read_to_string(&path) {
+            Ok(contents) => Config {
+                source: path.clone(),
+                ..toml::from_str(&contents).map_err(|e| format!("Invalid {} : {}", path, e))?
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(format!("Couldn't read {} : {}", path, e)),
+        };
+
+        if let Ok(environment) = env::var("APP_ENV") {
+            config.environment = match environment.as_str() {
+                "development" => Environment::Development,
+                "production" => Environment::Production,
+                _ => return Err(format!("Invalid APP_ENV {:?}", environment)),
+            };
+        }
+        if let Ok(format) = env::var("ID_FORMAT") {
+            config.id_format = match format.as_str() {
+                "objectid" => IdFormat::ObjectId,
+                "uuidv7" => IdFormat::UuidV7,
+                _ => return Err(format!("Invalid ID_FORMAT {:?}", format)),
+            };
+        }
+        if let Ok(provider) = env::var("SECRETS_PROVIDER") {
+            config.secrets.provider = match provider.as_str() {
+                "none" => SecretsProviderKind::None,
+                "vault" => SecretsProviderKind::Vault,
+                "aws" => SecretsProviderKind::Aws,
+                _ => return Err(format!("Invalid SECRETS_PROVIDER {:?}", provider)),
+            };
+        }
+        if let Ok(url) = env::var("PASSWORD_RESET_WEBHOOK_URL") {
+            config.password_reset.webhook_url = Some(url);
+        }
+        if let Ok(url) = env::var("LDAP_URL") {
+            config.auth.ldap.url = Some(url);
+        }
+        if let Ok(token) = env::var("SCIM_BEARER_TOKEN") {
+            config.scim.bearer_token = Some(token);
+        }
+        if let Ok(uri) = env::var("MONGODB_URI") {
+            config.mongodb_uri = uri;
+        }
+        if let Ok(name) = env::var("DATABASE_NAME") {
+            config.database_name = name;
+        }
+        if let Ok(enabled) = env::var("TENANTS_ENABLED") {
+            config.tenants.enabled = matches!(enabled.as_str(), "1" | "true");
+        }
+        if let Ok(enabled) = env::var("ANOMALY_DETECTION") {
+            config.anomaly.enabled = matches!(enabled.as_str(), "1" | "true");
+        }
+        if let Ok(url) = env::var("ANOMALY_NOTIFY_URL") {
+            config.anomaly.notify_url = Some(url);
+        }
+        if let Ok(enabled) = env::var("READ_ONLY") {
+            config.read_only.enabled = matches!(enabled.as_str(), "1" | "true");
+        }
+        if let Ok(dir) = env::var("STATIC_DIR") {
+            config.static_files.enabled = true;
+            config.static_files.dir = PathBuf::from(dir);
+        }
+        if let Ok(enabled) = env::var("COMPRESSION_ENABLED") {
+            config.compression.enabled = matches!(enabled.as_str(), "1" | "true");
+        }
+        if let Ok(max) = env::var("MAX_CONNECTIONS") {
+            config.connections.max_connections = max
+                .parse()
+                .map_err(|e| format!("Invalid MAX_CONNECTIONS {:?} : {}", max, e))?;
+        }
+        if let Ok(max) = env::var("MAX_CONNECTIONS_PER_IP") {
+            config.connections.max_per_ip = max
+                .parse()
+                .map_err(|e| format!("Invalid MAX_CONNECTIONS_PER_IP {:?} : {}", max, e))?;
+        }
+        if let Ok(secs) = env::var("HEADER_READ_TIMEOUT_SECS") {
+            config.connections.header_read_timeout_secs = secs
+                .parse()
+                .map_err(|e| format!("Invalid HEADER_READ_TIMEOUT_SECS {:?} : {}", secs, e))?;
+        }
+        if let Ok(secs) = env::var("REQUEST_TIMEOUT_SECS") {
+            config.limits.timeout_secs = secs
+                .parse()
+                .map_err(|e| format!("Invalid REQUEST_TIMEOUT_SECS {:?} : {}", secs, e))?;
+        }
+        if let Ok(address) = env::var("BIND_ADDRESS") {
+            config.bind_address = address
+                .parse()
+                .map_err(|e| format!("Invalid BIND_ADDRESS {:?} : {}", address, e))?;
+        }
+        if let Ok(secret) = env::var("JWT_SECRET") {
+            config.jwt_secret = secret;
+        }
+        if let Ok(secret) = env::var("SIGNING_SECRET") {
+            config.signing_secret = Some(secret);
+        }
+        if let Ok(keys) = env::var("FIELD_ENCRYPTION_KEYS") {
+            config.field_encryption_keys = Some(keys);
+        }
+        // Comma separated, e.g. `https://a.example.com,https://b.example.com`
+        if let Ok(origins) = env::var("CORS_ORIGINS") {
+            config.cors.allowed_origins = origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Ok(permissive) = env::var("CORS_PERMISSIVE") {
+            config.cors.permissive = matches!(permissive.as_str(), "1" | "true");
+        }
+        // Comma separated methods and paths, e.g. `PUT /counter,DELETE /counter/{name}`
+        if let Ok(routes) = env::var("SIGNED_ROUTES") {
+            config.signed_routes = routes
+                .split(',')
+                .map(str::trim)
+                .filter(|route| !route.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        // Comma separated, e.g. `127.0.0.1,10.0.0.0/8`
+        if let Ok(allowlist) = env::var("ADMIN_ALLOWLIST") {
+            config.admin_allowlist = allowlist
+                .split(',')
+                .map(str::trim)
+                .filter(|cidr| !cidr.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Ok(path) = env::var("ADMIN_SOCKET") {
+            config.admin_socket = Some(PathBuf::from(path));
+        }
+        if let Ok(threads) = env::var("WORKER_THREADS") {
+            config.runtime.worker_threads = Some(
+                threads
+                    .parse()
+                    .map_err(|e| format!("Invalid WORKER_THREADS {:?} : {}", threads, e))?,
+            );
+        }
+        if let Ok(threads) = env::var("MAX_BLOCKING_THREADS") {
+            config.runtime.max_blocking_threads = threads
+                .parse()
+                .map_err(|e| format!("Invalid MAX_BLOCKING_THREADS {:?} : {}", threads, e))?;
+        }
+        if let Ok(name) = env::var("THREAD_NAME") {
+            config.runtime.thread_name = name;
+        }
+        if let Ok(enabled) = env::var("SINGLE_THREADED") {
+            config.runtime.single_threaded = matches!(enabled.as_str(), "1" | "true");
+        }
+        if let Ok(demo_mode) = env::var("DEMO_MODE") {
+            config.demo_mode = demo_mode
+                .parse()
+                .map_err(|e| format!("Invalid DEMO_MODE {:?} : {}", demo_mode, e))?;
+        }
+        if let Ok(burst) = env::var("AUTH_RATE_LIMIT_BURST") {
+            config.auth_rate_limit.burst = burst
+                .parse()
+                .map_err(|e| format!("Invalid AUTH_RATE_LIMIT_BURST {:?} : {}", burst, e))?;
+        }
+        if let Ok(per_minute) = env::var("AUTH_RATE_LIMIT_PER_MINUTE") {
+            config.auth_rate_limit.per_minute = per_minute.parse().map_err(|e| {
+                format!(
+                    "Invalid AUTH_RATE_LIMIT_PER_MINUTE {:?} : {}",
+                    per_minute, e
+                )
+            })?;
+        }
+        if let Ok(max_failures) = env::var("LOCKOUT_MAX_FAILURES") {
+            config.account_lockout.max_failures = max_failures
+                .parse()
+                .map_err(|e| format!("Invalid LOCKOUT_MAX_FAILURES {:?} : {}", max_failures, e))?;
+        }
+        if let Ok(lock_secs) = env::var("LOCKOUT_SECS") {
+            config.account_lockout.lock_secs = lock_secs
+                .parse()
+                .map_err(|e| format!("Invalid LOCKOUT_SECS {:?} : {}", lock_secs, e))?;
+        }
+        if let Ok(mode) = env::var("LISTINGS_READ_PREFERENCE") {
+            config.read_preference.listings = match mode.as_str() {
+                "primary" => ReadMode::Primary,
+                "secondaryPreferred" => ReadMode::SecondaryPreferred,
+                _ => return Err(format!("Invalid LISTINGS_READ_PREFERENCE {:?}", mode)),
+            };
+        }
+        if let Ok(enabled) = env::var("LEGACY_ROUTES") {
+            config.versioning.legacy_routes = matches!(enabled.as_str(), "1" | "true");
+        }
+        if let Ok(groups) = env::var("DISABLED_ROUTE_GROUPS") {
+            config.route_groups.disable(&groups)?;
+        }
+
+        if config.environment == Environment::Development {
+            if config.jwt_secret.is_empty() {
+                warn!("JWT_SECRET is unset, using the public development key");
+                config.jwt_secret = DEV_JWT_SECRET.to_string();
+            }
+            if config.signing_secret.is_none() && !config.signed_routes.is_empty() {
+                warn!("SIGNING_SECRET is unset, using the public development key");
+                config.signing_secret = Some(DEV_SIGNING_SECRET.to_string());
+            }
+        }
+
+        // Validated by the caller, once `secrets::load_secrets` has filled in the rest
+        Ok(config)
+    }
+
+    // Shortest secret accepted, at startup and from `secrets::refresh_secrets`. Development
+    // keys are short on purpose, they are never meant to be safe
+    pub fn min_secret_length(&self) -> usize {
+        match self.environment {
+            Environment::Development => 1,
+            Environment::Production => MIN_SECRET_LENGTH,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.mongodb_uri.starts_with("mongodb://")
+            && !self.mongodb_uri.starts_with("mongodb+srv://")
+        {
+            return Err(format!("Invalid MongoDB URI : {}", self.mongodb_uri));
+        }
+        if self.database_name.is_empty()
+            || !self
+                .database_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+        {
+            return Err(format!("Invalid database name : {}", self.database_name));
+        }
+        if HeaderName::from_bytes(self.tenants.header.as_bytes()).is_err() {
+            return Err(format!("Invalid tenant header : {}", self.tenants.header));
+        }
+        if self.tenants.max_cached == 0 {
+            return Err("tenants.max_cached must be above 0".to_string());
+        }
+        let min_length = self.min_secret_length();
+        if let Some(route) = self
+            .signed_routes
+            .iter()
+            .find(|route| !SIGNABLE_ROUTES.contains(&route.as_str()))
+        {
+            return Err(format!("signed_routes can't list {}", route));
+        }
+        match &self.signing_secret {
+            None if !self.signed_routes.is_empty() => {
+                return Err("SIGNING_SECRET must be set for signed_routes".to_string())
+            }
+            Some(secret) if secret.len() < min_length => {
+                return Err(format!(
+                    "SIGNING_SECRET must be at least {} bytes",
+                    min_length
+                ))
+            }
+            _ => {}
+        }
+        FieldCipher::new(self.field_encryption_keys.as_deref())?;
+        if self.jwt_secret.is_empty() {
+            return Err("JWT_SECRET must be set".to_string());
+        }
+        if self.jwt_secret.len() < min_length {
+            return Err(format!("JWT_SECRET must be at least {} bytes", min_length));
+        }
+        if self.route_groups.scim {
+            match &self.scim.bearer_token {
+                None => return Err("The scim route group needs scim.bearer_token".to_string()),
+                Some(token) if token.len() < min_length => {
+                    return Err(format!(
+                        "scim.bearer_token must be at least {} bytes",
+                        min_length
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        self.cors.validate()?;
+        self.runtime.validate()?;
+        self.anomaly.validate()?;
+        self.auth.validate()?;
+        self.media.validate()?;
+        if let Some(url) = self.password_reset.webhook_url.as_deref().filter(|url| {
+            reqwest::Url::parse(url).map_or(true, |url| !matches!(url.scheme(), "http" | "https"))
+        }) {
+            return Err(format!("Invalid password_reset.webhook_url : {}", url));
+        }
+        if let Some(cidr) = self
+            .admin_allowlist
+            .iter()
+            .find(|cidr| parse_cidr(cidr).is_none())
+        {
+            return Err(format!("Invalid admin_allowlist entry : {}", cidr));
+        }
+        if self.auth_rate_limit.burst == 0 || self.auth_rate_limit.per_minute == 0 {
+            return Err("Auth rate limit burst and per_minute must be above 0".to_string());
+        }
+        if self.media_rate_limit.burst == 0 || self.media_rate_limit.per_minute == 0 {
+            return Err("Media rate limit burst and per_minute must be above 0".to_string());
+        }
+        if self.demo_mode
+            && (self.demo_rate_limit.burst == 0 || self.demo_rate_limit.per_minute == 0)
+        {
+            return Err("Demo rate limit burst and per_minute must be above 0".to_string());
+        }
+        if let Some(path) = self
+            .read_only
+            .exempt
+            .iter()
+            .find(|path| !path.starts_with('/'))
+        {
+            return Err(format!("Invalid read-only exemption : {}", path));
+        }
+        if let Some(route) = self.profile_requirements.keys().find(|route| {
+            !route.split_once(' ').is_some_and(|(method, path)| {
+                Method::from_bytes(method.as_bytes()).is_ok() && path.starts_with('/')
+            })
+        }) {
+            return Err(format!(
+                "Invalid profile requirement route, expected `METHOD /path` : {}",
+                route
+            ));
+        }
+        if self.static_files.spa && !self.static_files.enabled {
+            return Err("static_files.spa needs static_files.enabled".to_string());
+        }
+        if self.compression.content_types.iter().any(String::is_empty) {
+            return Err("compression.content_types can't contain an empty prefix".to_string());
+        }
+        for (client, version) in &self.clients.min_versions {
+            version
+                .parse::<ClientVersion>()
+                .map_err(|e| format!("clients.min_versions.{} : {}", client, e))?;
+        }
+        let module_limits = self.limits.modules.values();
+        if self.limits.body_limit == 0
+            || self.limits.timeout_secs == 0
+            || module_limits
+                .flat_map(|limits| {
+                    [
+                        limits.body_limit,
+                        limits.timeout_secs.map(|secs| secs as usize),
+                    ]
+                })
+                .any(|limit| limit == Some(0))
+        {
+            return Err("Request body limits and timeouts must be above 0".to_string());
+        }
+        if self.account_lockout.max_failures == 0 {
+            return Err("Account lockout max_failures must be above 0".to_string());
+        }
+        self.connections.validate()?;
+        self.versioning.validate()?;
+        self.secrets.validate()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use std::{path::PathBuf, sync::Arc};
+
+use jsonwebtoken::get_current_timestamp;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    Collection, Database,
+};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use tracing::{error, info, warn};
+
+use crate::{handlers::media::IMAGE_CACHE_DIR, models::RefreshToken, server::ConnectionStats};
+
+const HELP: &str = "\
+commands :
+  sessions              refresh tokens that are still usable
+  connections           open connections, per client address
+  log-level <filter>    replace the log filter, e.g. `hello_axum=debug`
+  flush-cache           delete every cached /img response
+  revoke <token id>     revoke one refresh token
+  revoke-user <name>    revoke every refresh token of a user
+  quit";
+
+// Swaps the active log filter, provided by `main` which owns the subscriber
+pub type SetLogFilter = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct ConsoleContext {
+    pub database: Database,
+    pub connection_stats: ConnectionStats,
+    pub set_log_filter: SetLogFilter,
+}
+
+// Serves the debug console on a Unix socket only the owning user can open
+pub async fn serve_console(path: PathBuf, context: ConsoleContext) {
+    // A socket left behind by an earlier run would make bind fail
+    let _ = fs::remove_file(&path).await;
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Error binding admin console {:?} : {}", path, e);
+            return;
+        }
+    };
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)) {
+            warn!("Error restricting admin console {:?} : {}", path, e);
+        }
+    }
+    info!("Admin console on : {:?}", path);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_session(stream, context.clone()));
+            }
+            Err(e) => warn!("Error accepting admin console connection : {}", e),
+        }
+    }
+}
+
+async fn handle_session(stream: UnixStream, context: ConsoleContext) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let _ = writer
+        .write_all(b"hello-axum console, `help` lists commands\n> ")
+        .await;
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line == "quit" {
+            break;
+        }
+
+        info!("Admin console command : {}", line);
+        let output = run_command(line, &context).await;
+        if writer
+            .write_all(format!("{}\n> ", output).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+async fn run_command(line: &str, context: &ConsoleContext) -> String {
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    let argument = argument.trim();
+    let result = match (command, argument) {
+        ("", _) => Ok(String::new()),
+        ("help", _) => Ok(HELP.to_string()),
+        ("sessions", _) => sessions(&context.database).await,
+        ("connections", _) => {
+            let counts = context.connection_stats.counts();
+            let mut output = format!("{} open", counts.open);
+            for (ip, count) in counts.per_ip {
+                output.push_str(&format!("\n  {} : {}", ip, count));
+            }
+            Ok(output)
+        }
+        ("log-level", filter) if !filter.is_empty() => {
+            (context.set_log_filter)(filter).map(|_| format!("Log filter is now {}", filter))
+        }
+        ("flush-cache", _) => flush_image_cache().await,
+        ("revoke", id) if !id.is_empty() => match ObjectId::parse_str(id) {
+            Ok(id) => revoke(&context.database, doc! { "_id": id }).await,
+            Err(_) => Err("Invalid token id".to_string()),
+        },
+        ("revoke-user", name) if !name.is_empty() => {
+            revoke(&context.database, doc! { "user_name": name }).await
+        }
+        _ => Err(format!("Unknown command `{}`, try `help`", line)),
+    };
+
+    result.unwrap_or_else(|e| format!("error : {}", e))
+}
+
+async fn sessions(database: &Database) -> Result<String, String> {
+    let refresh_tokens_collection: Collection<RefreshToken> = database.collection("refresh_tokens");
+    let mut cursor = refresh_tokens_collection
+        .find(doc! {
+            "revoked": false,
+            "expires_at": { "$gt": get_current_timestamp() as i64 },
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut output = String::new();
+    while cursor.advance().await.map_err(|e| e.to_string())? {
+        let token = cursor.deserialize_current().map_err(|e| e.to_string())?;
+        output.push_str(&format!(
+            "{} {} expires_at={}\n",
+            token.id.map(|id| id.to_hex()).unwrap_or_default(),
+            token.user_name,
+            token.expires_at
+        ));
+    }
+    Ok(if output.is_empty() {
+        "No sessions".to_string()
+    } else {
+        output.trim_end().to_string()
+    })
+}
+
+async fn revoke(database: &Database, mut filter: Document) -> Result<String, String> {
+    let refresh_tokens_collection: Collection<RefreshToken> = database.collection("refresh_tokens");
+    filter.insert("revoked", false);
+    let result = refresh_tokens_collection
+        .update_many(filter, doc! { "$set": { "revoked": true } })
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(format!(
+        "Revoked {} refresh token(s)",
+        result.modified_count
+    ))
+}
+
+async fn flush_image_cache() -> Result<String, String> {
+    let mut entries = match fs::read_dir(IMAGE_CACHE_DIR).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok("Cache is empty".to_string())
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+        if fs::remove_file(entry.path()).await.is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(format!("Removed {} cached image(s)", removed))
+}
@@ -0,0 +1,452 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use mongodb::{
+    action::Action,
+    bson::{self, doc, Document},
+    options::{IndexOptions, ReturnDocument},
+    Collection, Database, IndexModel,
+};
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use tracing::error;
+
+use crate::{
+    activity::{Activity, ActivityFeed},
+    db::{classify, time_left, with_retries, DbErrorKind},
+    error::AppError,
+    models::{CounterDocument, CounterEvent, CounterStats, NamedCounter},
+    trace::Traced,
+};
+
+pub const COUNTERS_COLLECTION: &str = "counters";
+// The `_id` of the document behind `/counter`, also reachable as `/counter/default`
+pub const DEFAULT_COUNTER: &str = "default";
+// What the default counter starts at the first time it is loaded
+const INITIAL_VALUE: i64 = 1;
+// `/counter` serves the default counter as a `u32`, so it can't go past one
+pub const MAX_DEFAULT_VALUE: u64 = u32::MAX as u64;
+pub const MAX_NAME_LENGTH: usize = 64;
+// Updates a slow `/ws/counter` client may fall behind by before it skips ahead
+pub const UPDATE_BUFFER: usize = 64;
+// One document per increment, what `/counter/stats` aggregates over
+pub const COUNTER_EVENTS_COLLECTION: &str = "counter_events";
+// Increments are kept this long, which bounds the longest stats window
+pub const HISTORY_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub const MIN_STATS_WINDOW: Duration = Duration::from_secs(60);
+pub const MAX_STATS_BUCKETS: u64 = 240;
+// Stats are this stale at most, sparklines polling every second don't each run a pipeline
+pub const STATS_CACHE_TTL: Duration = Duration::from_secs(5);
+// Past this many cached (counter, window, buckets) combinations, the cache starts over
+const MAX_CACHED_STATS: usize = 256;
+// Taken by `/counter/stats`
+const RESERVED_NAMES: &[&str] = &["stats"];
+
+// Counter name, window seconds and bucket count
+type StatsKey = (String, u64, u64);
+
+// One `$group` result of the stats pipeline
+#[derive(Debug, Deserialize)]
+struct BucketTotal {
+    #[serde(rename = "_id")]
+    index: i64,
+    total: i64,
+}
+
+// The last value seen of the default counter. Lock free, so readers never wait on a writer
+// and a panicking request can't leave it poisoned
+#[derive(Debug, Clone)]
+pub struct CounterCache(Arc<AtomicU64>);
+
+impl CounterCache {
+    pub fn new(value: u64) -> Self {
+        CounterCache(Arc::new(AtomicU64::new(value)))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+// Counters live in MongoDB so they survive restarts and are shared between instances.
+// `cache` holds the last value seen of the default one, for readers that can do with a
+// slightly stale value
+#[derive(Debug, Clone)]
+pub struct CounterStore {
+    collection: Collection<CounterDocument>,
+    pub cache: CounterCache,
+    // Changes to the default counter made through this instance, with the request making them
+    updates: broadcast::Sender<Traced<NamedCounter>>,
+    // Changes to any counter
+    activity: ActivityFeed,
+    history: Collection<CounterEvent>,
+    stats_cache: Arc<Mutex<HashMap<StatsKey, (Instant, CounterStats)>>>,
+}
+
+impl CounterStore {
+    pub fn new(database: &Database, cache: CounterCache, activity: ActivityFeed) -> Self {
+        CounterStore {
+            collection: database.collection(COUNTERS_COLLECTION),
+            cache,
+            updates: broadcast::channel(UPDATE_BUFFER).0,
+            activity,
+            history: database.collection(COUNTER_EVENTS_COLLECTION),
+            stats_cache: Arc::default(),
+        }
+    }
+
+    // Lets MongoDB drop increments past `HISTORY_RETENTION`
+    pub async fn create_history_indexes(&self) -> mongodb::error::Result<()> {
+        self.history
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "at": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(HISTORY_RETENTION)
+                            .build(),
+                    )
+                    .build(),
+            )
+            .await?;
+        self.history
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "counter": 1, "at": 1 })
+                    .build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Traced<NamedCounter>> {
+        self.updates.subscribe()
+    }
+
+    fn publish(&self, name: &str, value: u64) {
+        self.activity.publish(Activity::CounterChanged {
+            name: name.to_string(),
+            value,
+        });
+        if name == DEFAULT_COUNTER {
+            // Only fails when nobody is listening
+            let _ = self.updates.send(Traced::current(NamedCounter {
+                name: name.to_string(),
+                value,
+            }));
+        }
+    }
+
+    // Creates the default counter if this is the first start, and fills the cache
+    pub async fn load(&self) -> Result<u64, AppError> {
+        let update = doc! { "$setOnInsert": { "value": INITIAL_VALUE } };
+        self.update(DEFAULT_COUNTER, update, true).await
+    }
+
+    // The default counter is created on first use, any other one has to exist
+    pub async fn get(&self, name: &str) -> Result<u64, AppError> {
+        let document = with_retries(|| {
+            self.collection
+                .find_one(doc! { "_id": name })
+                .optional(time_left(), |action, max_time| action.max_time(max_time))
+        })
+        .await?;
+        match document {
+            Some(document) => self.cache_value(name, document.value),
+            None if name == DEFAULT_COUNTER => self.load().await,
+            None => Err(not_found(name)),
+        }
+    }
+
+    pub async fn create(&self, name: &str, value: u64) -> Result<u64, AppError> {
+        check_range(name, value)?;
+        let document = CounterDocument {
+            name: name.to_string(),
+            value: to_stored(value)?,
+        };
+        match self.collection.insert_one(&document).await {
+            Ok(_) => {
+                self.publish(name, value);
+                self.cache_value(name, document.value)
+            }
+            Err(e) if classify(&e) == DbErrorKind::DuplicateKey => Err(AppError::Conflict(
+                format!("Counter {} already exists", name),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Creates the counter if it doesn't exist yet
+    pub async fn set(&self, name: &str, value: u64) -> Result<u64, AppError> {
+        check_range(name, value)?;
+        let update = doc! { "$set": { "value": to_stored(value)? } };
+        let value = self.update(name, update, true).await?;
+        self.publish(name, value);
+        Ok(value)
+    }
+
+    // A single `$inc`, so increases from several instances never overwrite each other
+    pub async fn increment(&self, name: &str, by: u64) -> Result<u64, AppError> {
+        let delta = to_stored(by)?;
+        let update = doc! { "$inc": { "value": delta } };
+        let value = if name == DEFAULT_COUNTER {
+            check_range(name, by)?;
+            // Only matches while there is room. Not an upsert, that would start a missing
+            // counter from zero, so it is created at `INITIAL_VALUE` first and tried again
+            let room = (MAX_DEFAULT_VALUE - by) as i64;
+            let filter = doc! { "_id": name, "value": { "$lte": room } };
+            let result = match self
+                .update_matching(name, filter.clone(), update.clone(), false)
+                .await
+            {
+                Err(AppError::NotFound(_)) => {
+                    self.load().await?;
+                    self.update_matching(name, filter, update, false).await
+                }
+                result => result,
+            };
+            result.map_err(|e| match e {
+                AppError::NotFound(_) => out_of_range(name),
+                e => e,
+            })?
+        } else {
+            self.update(name, update, false).await?
+        };
+        self.publish(name, value);
+
+        // Losing a history entry only makes the stats undercount, the increment stands
+        let event = CounterEvent {
+            counter: name.to_string(),
+            delta,
+            at: bson::DateTime::now(),
+        };
+        if let Err(e) = self.history.insert_one(event).await {
+            error!("Error recording increment of counter {} : {}", name, e);
+        }
+        Ok(value)
+    }
+
+    // Increments per bucket over the trailing `window`, oldest first. Buckets line up with
+    // multiples of their width, the last one is still filling
+    pub async fn stats(
+        &self,
+        name: &str,
+        window: Duration,
+        buckets: u64,
+    ) -> Result<CounterStats, AppError> {
+        let key = (name.to_string(), window.as_secs(), buckets);
+        if let Some((at, stats)) = self.stats_cache.lock()?.get(&key) {
+            if at.elapsed() < STATS_CACHE_TTL {
+                return Ok(stats.clone());
+            }
+        }
+
+        let bucket_ms = (window.as_millis() / u128::from(buckets)) as i64;
+        let now_ms = bson::DateTime::now().timestamp_millis();
+        let start_ms = (now_ms / bucket_ms - (buckets as i64 - 1)) * bucket_ms;
+        let start = bson::DateTime::from_millis(start_ms);
+
+        let pipeline = [
+            doc! { "$match": { "counter": name, "at": { "$gte": start } } },
+            doc! { "$group": {
+                "_id": { "$toLong": { "$floor": {
+                    "$divide": [{ "$subtract": ["$at", start] }, bucket_ms],
+                } } },
+                "total": { "$sum": "$delta" },
+            } },
+        ];
+        let mut cursor = with_retries(|| {
+            self.history
+                .aggregate(pipeline.clone())
+                .optional(time_left(), |action, max_time| action.max_time(max_time))
+                .with_type::<BucketTotal>()
+        })
+        .await?;
+
+        let mut totals = vec![0; buckets as usize];
+        while cursor.advance().await? {
+            let group = cursor.deserialize_current()?;
+            let index = usize::try_from(group.index).ok();
+            if let Some(slot) = index.and_then(|index| totals.get_mut(index)) {
+                *slot = u64::try_from(group.total).unwrap_or(0);
+            }
+        }
+
+        let stats = CounterStats {
+            name: name.to_string(),
+            window_secs: window.as_secs(),
+            bucket_secs: (bucket_ms / 1000) as u64,
+            start: (start_ms / 1000) as u64,
+            buckets: totals,
+        };
+        let mut cache = self.stats_cache.lock()?;
+        if cache.len() >= MAX_CACHED_STATS {
+            cache.clear();
+        }
+        cache.insert(key, (Instant::now(), stats.clone()));
+        Ok(stats)
+    }
+
+    // The default counter would come back at `INITIAL_VALUE` on the next read, so it is
+    // reset to zero like `DELETE /counter` does
+    pub async fn delete(&self, name: &str) -> Result<(), AppError> {
+        if name == DEFAULT_COUNTER {
+            self.set(name, 0).await?;
+            return Ok(());
+        }
+        let result = self.collection.delete_one(doc! { "_id": name }).await?;
+        if result.deleted_count == 0 {
+            return Err(not_found(name));
+        }
+        self.publish(name, 0);
+        Ok(())
+    }
+
+    async fn update(&self, name: &str, update: Document, upsert: bool) -> Result<u64, AppError> {
+        self.update_matching(name, doc! { "_id": name }, update, upsert)
+            .await
+    }
+
+    async fn update_matching(
+        &self,
+        name: &str,
+        filter: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<u64, AppError> {
+        let document = self
+            .collection
+            .find_one_and_update(filter, update)
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+            .upsert(upsert)
+            .return_document(ReturnDocument::After)
+            .await?
+            .ok_or_else(|| not_found(name))?;
+        self.cache_value(name, document.value)
+    }
+
+    fn cache_value(&self, name: &str, value: i64) -> Result<u64, AppError> {
+        let value = u64::try_from(value)
+            .map_err(|_| AppError::Internal(format!("Counter {} is negative : {}", name, value)))?;
+        if name == DEFAULT_COUNTER {
+            self.cache.set(value);
+        }
+        Ok(value)
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<(), AppError> {
+    if RESERVED_NAMES.contains(&name) {
+        return Err(AppError::Validation(format!(
+            "Counter name {} is reserved",
+            name
+        )));
+    }
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(AppError::Validation(format!(
+            "Counter names are 1 to {} letters, digits, `_`, `-` or `.`",
+            MAX_NAME_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+// `30m`, `1h`, `7d` and so on, between `MIN_STATS_WINDOW` and `HISTORY_RETENTION`
+pub fn parse_window(window: &str) -> Result<Duration, AppError> {
+    let invalid = || {
+        AppError::Validation(format!(
+            "Invalid window {:?}, expected e.g. 15m, 1h or 7d, from 1m to 7d",
+            window
+        ))
+    };
+    let split = window.len().checked_sub(1).ok_or_else(invalid)?;
+    let (amount, unit) = window.split_at_checked(split).ok_or_else(invalid)?;
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let unit_secs = match unit {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let window = Duration::from_secs(amount.checked_mul(unit_secs).ok_or_else(invalid)?);
+    if window < MIN_STATS_WINDOW || window > HISTORY_RETENTION {
+        return Err(invalid());
+    }
+    Ok(window)
+}
+
+// Buckets have to be whole seconds wide, and there can't be too many for a sparkline
+pub fn check_buckets(window: Duration, buckets: u64) -> Result<(), AppError> {
+    if buckets == 0 || buckets > MAX_STATS_BUCKETS {
+        return Err(AppError::Validation(format!(
+            "Buckets must be 1 to {}",
+            MAX_STATS_BUCKETS
+        )));
+    }
+    if !window.as_secs().is_multiple_of(buckets) {
+        return Err(AppError::Validation(format!(
+            "A {}s window can't be split into {} whole-second buckets",
+            window.as_secs(),
+            buckets
+        )));
+    }
+    Ok(())
+}
+
+pub fn to_stored(value: u64) -> Result<i64, AppError> {
+    i64::try_from(value)
+        .map_err(|_| AppError::Validation(format!("{} is too large for a counter", value)))
+}
+
+fn check_range(name: &str, value: u64) -> Result<(), AppError> {
+    if name == DEFAULT_COUNTER && value > MAX_DEFAULT_VALUE {
+        return Err(out_of_range(name));
+    }
+    Ok(())
+}
+
+fn out_of_range(name: &str) -> AppError {
+    AppError::Validation(format!(
+        "Counter {} can't go past {} while /counter serves it",
+        name, MAX_DEFAULT_VALUE
+    ))
+}
+
+fn not_found(name: &str) -> AppError {
+    AppError::NotFound(format!("Counter {} not found", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_documented_names() {
+        for name in [
+            "default",
+            "page.views",
+            "sign-ups_2026",
+            &"a".repeat(MAX_NAME_LENGTH),
+        ] {
+            assert!(validate_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", "a b", "a/b", "stats", &"a".repeat(MAX_NAME_LENGTH + 1)] {
+            assert!(validate_name(name).is_err(), "{}", name);
+        }
+    }
+}
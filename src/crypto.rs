@@ -0,0 +1,177 @@
+use std::{collections::HashMap, sync::Arc};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{error::AppError, models::User};
+
+// Marks a sealed value, `enc:<key id>:<base64 of nonce and ciphertext>`. Anything else was
+// stored before encryption was turned on and is read as it is
+const SEALED_PREFIX: &str = "enc:";
+const NONCE_LENGTH: usize = 12;
+
+// AES-256-GCM for personal fields at rest. Keys come as `id:base64key,…` from
+// `field_encryption_keys`, usually through the secrets provider. The first one seals new
+// values, the others only open what they sealed, so a key can be rotated by putting the new
+// one first. Without keys values are stored as they are
+#[derive(Clone, Default)]
+pub struct FieldCipher {
+    keys: Arc<HashMap<String, Aes256Gcm>>,
+    current: Option<String>,
+}
+
+impl FieldCipher {
+    pub fn new(keys: Option<&str>) -> Result<Self, String> {
+        let Some(keys) = keys.filter(|keys| !keys.trim().is_empty()) else {
+            return Ok(FieldCipher::default());
+        };
+
+        let mut ciphers = HashMap::new();
+        let mut current = None;
+        for entry in keys.split(',').map(str::trim) {
+            let Some((id, key)) = entry.split_once(':') else {
+                return Err("Field encryption keys are id:base64key pairs".to_string());
+            };
+            let valid_id = !id.is_empty()
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+            if !valid_id {
+                return Err(format!("Invalid field encryption key id : {}", id));
+            }
+            let cipher = STANDARD
+                .decode(key)
+                .ok()
+                .and_then(|key| Aes256Gcm::new_from_slice(&key).ok())
+                .ok_or_else(|| format!("Field encryption key {} is not 32 bytes of base64", id))?;
+            if ciphers.insert(id.to_string(), cipher).is_some() {
+                return Err(format!("Field encryption key {} is listed twice", id));
+            }
+            current.get_or_insert_with(|| id.to_string());
+        }
+        Ok(FieldCipher {
+            keys: Arc::new(ciphers),
+            current,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // `field` is bound in, so a value can't be moved to another field
+    pub fn seal(&self, field: &str, value: &str) -> Result<String, AppError> {
+        let Some(id) = &self.current else {
+            return Ok(value.to_string());
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: field.as_bytes(),
+        };
+        let ciphertext = self.keys[id]
+            .encrypt(&nonce, payload)
+            .map_err(|_| AppError::Internal(format!("Error encrypting {}", field)))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!(
+            "{}{}:{}",
+            SEALED_PREFIX,
+            id,
+            STANDARD.encode(sealed)
+        ))
+    }
+
+    pub fn open(&self, field: &str, value: &str) -> Result<String, AppError> {
+        let Some(sealed) = value.strip_prefix(SEALED_PREFIX) else {
+            return Ok(value.to_string());
+        };
+        let unreadable =
+            |reason: &str| AppError::Internal(format!("Can't decrypt {} : {}", field, reason));
+        let (id, encoded) = sealed
+            .split_once(':')
+            .ok_or_else(|| unreadable("no key id"))?;
+        let cipher = self
+            .keys
+            .get(id)
+            .ok_or_else(|| unreadable(&format!("key {} is not configured", id)))?;
+        let bytes = STANDARD
+            .decode(encoded)
+            .map_err(|_| unreadable("not base64"))?;
+        if bytes.len() < NONCE_LENGTH {
+            return Err(unreadable("too short"));
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: field.as_bytes(),
+        };
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| unreadable("wrong key or tampered value"))?;
+        String::from_utf8(plaintext).map_err(|_| unreadable("not UTF-8"))
+    }
+
+    // The user's encrypted fields, as read from MongoDB
+    pub fn open_user(&self, user: &mut User) -> Result<(), AppError> {
+        if let Some(email) = &user.email {
+            user.email = Some(self.open("email", email)?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const KEY_B: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+    #[test]
+    fn seals_with_the_first_key_and_opens_with_any() {
+        let old = FieldCipher::new(Some(&format!("a:{}", KEY_A))).unwrap();
+        let sealed = old.seal("email", "alice@example.com").unwrap();
+        assert!(sealed.starts_with("enc:a:"));
+        assert!(!sealed.contains("alice"));
+        assert_ne!(sealed, old.seal("email", "alice@example.com").unwrap());
+
+        let rotated = FieldCipher::new(Some(&format!("b:{},a:{}", KEY_B, KEY_A))).unwrap();
+        assert_eq!(rotated.open("email", &sealed).unwrap(), "alice@example.com");
+        assert!(rotated
+            .seal("email", "alice@example.com")
+            .unwrap()
+            .starts_with("enc:b:"));
+        // Bound to the field, and to the keys configured
+        assert!(rotated.open("display_name", &sealed).is_err());
+        assert!(FieldCipher::new(Some(&format!("b:{}", KEY_B)))
+            .unwrap()
+            .open("email", &sealed)
+            .is_err());
+    }
+
+    #[test]
+    fn leaves_values_alone_without_keys() {
+        let cipher = FieldCipher::new(None).unwrap();
+        assert!(!cipher.is_enabled());
+        assert_eq!(cipher.seal("email", "a@b.c").unwrap(), "a@b.c");
+        // Stored before encryption was turned on
+        let enabled = FieldCipher::new(Some(&format!("a:{}", KEY_A))).unwrap();
+        assert_eq!(enabled.open("email", "a@b.c").unwrap(), "a@b.c");
+    }
+
+    #[test]
+    fn refuses_bad_keys() {
+        for keys in [
+            "a",
+            "a:short",
+            &format!(":{}", KEY_A),
+            &format!("a:{},a:{}", KEY_A, KEY_B),
+        ] {
+            assert!(FieldCipher::new(Some(keys)).is_err(), "{}", keys);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::IntoFuture,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+
+use mongodb::{
+    bson::{doc, Document},
+    error::{
+        Error, ErrorKind, InsertManyError, WriteFailure, RETRYABLE_WRITE_ERROR,
+        TRANSIENT_TRANSACTION_ERROR,
+    },
+    options::{IndexOptions, ReadPreference, SelectionCriteria},
+    Client, ClientSession, Collection, Database, IndexModel,
+};
+use tokio::time::sleep;
+use tracing::{error, warn};
+
+use crate::{
+    config::{Config, ReadMode, TenantConfig},
+    error::AppError,
+    handlers::sandbox::{SANDBOX_COLLECTION, SANDBOX_TEARDOWN},
+    middleware::{current_deadline, parse_cidr, IpRules},
+    models::IpRule,
+    trace::spawn_traced,
+};
+
+// Attempts per operation in `with_retries`, the first one included
+pub const MAX_ATTEMPTS: u32 = 3;
+// Doubled after every failed attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+// The `max_time` left for an operation made while handling a request, for `Action::optional`.
+// `None` outside of one, background work has no deadline
+pub fn time_left() -> Option<Duration> {
+    current_deadline().map(|deadline| deadline.remaining())
+}
+
+pub fn read_criteria(mode: ReadMode) -> SelectionCriteria {
+    SelectionCriteria::ReadPreference(match mode {
+        ReadMode::Primary => ReadPreference::Primary,
+        ReadMode::SecondaryPreferred => ReadPreference::SecondaryPreferred { options: None },
+    })
+}
+
+// Reads in this session see its earlier writes, even when they go to a secondary
+pub async fn causal_session(database: &Database) -> mongodb::error::Result<ClientSession> {
+    database
+        .client()
+        .start_session()
+        .causal_consistency(true)
+        .await
+}
+
+pub async fn db(config: &Config) -> mongodb::error::Result<Database> {
+    // Create a new client and connect to the server
+    let client = Client::with_uri_str(&config.mongodb_uri).await?;
+    Ok(client.database(&config.database_name))
+}
+
+#[derive(Default)]
+struct TenantCache {
+    databases: HashMap<String, Database>,
+    // Insertion order, the front is evicted first
+    order: VecDeque<String>,
+}
+
+// Picks the database a request works on, one per tenant when `tenants.enabled` is set
+#[derive(Clone)]
+pub struct Tenants {
+    default: Database,
+    config: TenantConfig,
+    cache: Arc<Mutex<TenantCache>>,
+}
+
+impl Tenants {
+    pub fn new(default: Database, config: &TenantConfig) -> Self {
+        Tenants {
+            default,
+            config: config.clone(),
+            cache: Arc::default(),
+        }
+    }
+
+    // `None` while tenants are off, otherwise the tenant named by the request
+    pub fn tenant_of(&self, headers: &HeaderMap) -> Result<Option<String>, AppError> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+        let header = &self.config.header;
+        let tenant = headers
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| AppError::Validation(format!("Missing {} header", header)))?;
+        let valid = (1..=32).contains(&tenant.len())
+            && tenant
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid {
+            return Err(AppError::Validation(format!("Invalid tenant : {}", tenant)));
+        }
+        Ok(Some(tenant.to_string()))
+    }
+
+    pub fn database(&self, tenant: Option<&str>) -> Database {
+        let Some(tenant) = tenant else {
+            return self.default.clone();
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(database) = cache.databases.get(tenant) {
+            return database.clone();
+        }
+        if cache.order.len() >= self.config.max_cached {
+            if let Some(oldest) = cache.order.pop_front() {
+                cache.databases.remove(&oldest);
+            }
+        }
+
+        let name = format!("{}_{}", self.default.name(), tenant);
+        let database = self.default.client().database(&name);
+        cache.databases.insert(tenant.to_string(), database.clone());
+        cache.order.push_back(tenant.to_string());
+
+        // A tenant can be new, its indexes are only made once it is first seen
+        spawn_traced({
+            let database = database.clone();
+            async move {
+                if let Err(e) = create_tenant_indexes(&database).await {
+                    error!("Error creating indexes in {} : {}", database.name(), e);
+                }
+            }
+        });
+        database
+    }
+}
+
+// The database of the requesting tenant, and the tenant's name when tenants are on
+pub struct TenantDb(pub Database, pub Option<String>);
+
+impl<S> FromRequestParts<S> for TenantDb
+where
+    Tenants: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let tenants = Tenants::from_ref(state);
+        let tenant = tenants.tenant_of(&parts.headers)?;
+        Ok(TenantDb(tenants.database(tenant.as_deref()), tenant))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DbErrorKind {
+    DuplicateKey,
+    WriteConflict,
+    // Network blips and errors the server labels as safe to retry
+    Transient,
+    // The operation ran out of time on the server, usually its `max_time`
+    Timeout,
+    // No server could be selected, retrying right away won't help
+    Unavailable,
+    Other,
+}
+
+impl DbErrorKind {
+    pub fn is_retryable(self) -> bool {
+        matches!(self, DbErrorKind::Transient | DbErrorKind::WriteConflict)
+    }
+}
+
+pub fn classify(e: &Error) -> DbErrorKind {
+    let code = match e.kind.as_ref() {
+        ErrorKind::Command(error) => Some(error.code),
+        ErrorKind::Write(WriteFailure::WriteError(error)) => Some(error.code),
+        ErrorKind::Write(WriteFailure::WriteConcernError(error)) => Some(error.code),
+        ErrorKind::InsertMany(InsertManyError {
+            write_errors: Some(errors),
+            ..
+        }) => errors.first().map(|error| error.code),
+        _ => None,
+    };
+
+    match code {
+        Some(11000) => DbErrorKind::DuplicateKey,
+        Some(112) => DbErrorKind::WriteConflict,
+        // MaxTimeMSExpired, NetworkTimeout, ExceededTimeLimit
+        Some(50 | 89 | 262) => DbErrorKind::Timeout,
+        _ if e.contains_label(TRANSIENT_TRANSACTION_ERROR)
+            || e.contains_label(RETRYABLE_WRITE_ERROR) =>
+        {
+            DbErrorKind::Transient
+        }
+        _ => match e.kind.as_ref() {
+            ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } => DbErrorKind::Transient,
+            ErrorKind::ServerSelection { .. } => DbErrorKind::Unavailable,
+            _ => DbErrorKind::Other,
+        },
+    }
+}
+
+// Runs an operation again after transient failures and write conflicts, with backoff.
+// Only for operations that are safe to repeat, like reads and idempotent updates
+pub async fn with_retries<T, F, Fut>(mut operation: F) -> mongodb::error::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: IntoFuture<Output = mongodb::error::Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(e) if attempt < MAX_ATTEMPTS && classify(&e).is_retryable() => {
+                warn!(
+                    "Retrying database operation, attempt {} failed : {}",
+                    attempt, e
+                );
+                sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// User names are unique, which also makes signin's lookup an index hit. So are the SCIM
+// external ids of the users that have one
+pub async fn create_user_indexes(database: &Database) -> mongodb::error::Result<()> {
+    let users_collection: Collection<Document> = database.collection("users");
+    users_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "user_name": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        )
+        .await?;
+    users_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "external_id": 1 })
+                .options(IndexOptions::builder().unique(true).sparse(true).build())
+                .build(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Group names are unique, and deleting a user finds its groups by member
+pub async fn create_group_indexes(database: &Database) -> mongodb::error::Result<()> {
+    let groups_collection: Collection<Document> = database.collection("groups");
+    groups_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "display_name": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        )
+        .await?;
+    groups_collection
+        .create_index(IndexModel::builder().keys(doc! { "members": 1 }).build())
+        .await?;
+    Ok(())
+}
+
+// One setting per user and key, upserts rely on it to never create a second one
+pub async fn create_settings_indexes(database: &Database) -> mongodb::error::Result<()> {
+    let settings_collection: Collection<Document> = database.collection("settings");
+    settings_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "user_name": 1, "key": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Guest sandboxes go an hour after they were started, their token expired long before.
+// Claimed ones have no `created_at` and stay
+pub async fn create_sandbox_indexes(database: &Database) -> mongodb::error::Result<()> {
+    let sandbox_collection: Collection<Document> = database.collection(SANDBOX_COLLECTION);
+    sandbox_collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "created_at": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .expire_after(SANDBOX_TEARDOWN)
+                        .build(),
+                )
+                .build(),
+        )
+        .await?;
+    Ok(())
+}
+
+// The indexes of every collection handlers reach through `TenantDb`, so a new tenant's
+// database gets the ones the modules create in the default one at startup
+pub async fn create_tenant_indexes(database: &Database) -> mongodb::error::Result<()> {
+    create_user_indexes(database).await?;
+    create_settings_indexes(database).await?;
+    create_group_indexes(database).await?;
+    create_sandbox_indexes(database).await?;
+    Ok(())
+}
+
+pub async fn reload_ip_rules(
+    database: &Database,
+    ip_rules: &IpRules,
+) -> mongodb::error::Result<()> {
+    let ip_rules_collection: Collection<IpRule> = database.collection("ip_rules");
+    let mut cursor = with_retries(|| ip_rules_collection.find(doc! {})).await?;
+
+    let mut rules = Vec::new();
+    while cursor.advance().await? {
+        let rule = cursor.deserialize_current()?;
+        match parse_cidr(&rule.cidr) {
+            Some(net) => rules.push((net, rule.action)),
+            None => warn!("Skipping invalid IP rule : {}", rule.cidr),
+        }
+    }
+
+    ip_rules.replace(rules);
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use std::{sync::Mutex, time::Duration};
+
+use futures_util::future::BoxFuture;
+use reqwest::header::CONTENT_TYPE;
+use serde_json::json;
+use tracing::info;
+
+use crate::handlers::auth::RESET_TOKEN_TTL;
+
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How a password reset token reaches its user. `AuthModule` takes any implementation,
+// so a deployment can plug in email and tests can keep the tokens they are sent
+pub trait TokenDelivery: Send + Sync {
+    fn send_reset_token<'a>(
+        &'a self,
+        user_name: &'a str,
+        email: Option<&'a str>,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>>;
+}
+
+// Posts the token to `password_reset.webhook_url`, for a mail service or automation to send
+// on. The receiver has to be trusted like a mailbox, the token resets the password
+pub struct WebhookDelivery {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebhookDelivery {
+    pub fn new(url: &str) -> Self {
+        WebhookDelivery {
+            url: url.to_string(),
+            client: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl TokenDelivery for WebhookDelivery {
+    fn send_reset_token<'a>(
+        &'a self,
+        user_name: &'a str,
+        email: Option<&'a str>,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let body = json!({
+                "event": "passwordReset",
+                "userName": user_name,
+                "email": email,
+                "resetToken": token,
+                "expiresIn": RESET_TOKEN_TTL.as_secs(),
+            });
+            self.client
+                .post(&self.url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|e| format!("Error posting the reset token : {}", e))
+        })
+    }
+}
+
+// Writes the token to the log, for development setups without a mail server. Redacted logs
+// mask it, so `AuthModule` only falls back to it in development
+#[derive(Debug, Default)]
+pub struct LogDelivery;
+
+impl TokenDelivery for LogDelivery {
+    fn send_reset_token<'a>(
+        &'a self,
+        user_name: &'a str,
+        email: Option<&'a str>,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            // Masked as a whole unless log redaction is off
+            info!(
+                user_name,
+                email,
+                reset_token = token,
+                "Password reset token"
+            );
+            Ok(())
+        })
+    }
+}
+
+// Keeps every token it is given, so tests can reset a password the way a user would
+#[derive(Debug, Default)]
+pub struct MemoryDelivery {
+    sent: Mutex<Vec<(String, String)>>,
+}
+
+impl MemoryDelivery {
+    // The most recent token sent to `user_name`
+    pub fn last_token(&self, user_name: &str) -> Option<String> {
+        let sent = self.sent.lock().unwrap();
+        sent.iter()
+            .rev()
+            .find(|(user, _)| user == user_name)
+            .map(|(_, token)| token.clone())
+    }
+
+    pub fn sent_count(&self) -> usize {
+        self.sent.lock().unwrap().len()
+    }
+}
+
+impl TokenDelivery for MemoryDelivery {
+    fn send_reset_token<'a>(
+        &'a self,
+        user_name: &'a str,
+        _email: Option<&'a str>,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut sent = self.sent.lock().unwrap();
+            sent.push((user_name.to_string(), token.to_string()));
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_delivery_keeps_the_latest_token() {
+        let delivery = MemoryDelivery::default();
+        delivery
+            .send_reset_token("alice", None, "first")
+            .await
+            .unwrap();
+        delivery
+            .send_reset_token("bob", Some("bob@example.com"), "other")
+            .await
+            .unwrap();
+        delivery
+            .send_reset_token("alice", None, "second")
+            .await
+            .unwrap();
+
+        assert_eq!(delivery.last_token("alice").as_deref(), Some("second"));
+        assert_eq!(delivery.last_token("bob").as_deref(), Some("other"));
+        assert_eq!(delivery.last_token("carol"), None);
+        assert_eq!(delivery.sent_count(), 3);
+    }
+}
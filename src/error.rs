@@ -0,0 +1,208 @@
+use std::{io, sync::PoisonError};
+
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tracing::{error, warn};
+
+use crate::{
+    db::{classify, DbErrorKind},
+    models::{ProfileField, ResponseData},
+    validation::FieldError,
+};
+
+// Everything a handler can fail with, rendered as a `ResponseData` with a matching status
+#[derive(Debug)]
+pub enum AppError {
+    Db(mongodb::error::Error),
+    Hash(argon2::password_hash::Error),
+    Jwt(jsonwebtoken::errors::Error),
+    Io(io::Error),
+    Serialization(serde_json::Error),
+    Internal(String),
+    Validation(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    // Too many failed sign-ins, with the seconds left until the lock lifts
+    Locked(u64),
+    PayloadTooLarge(String),
+    Unprocessable(String),
+    // Rejected input, with what is wrong with each field
+    InvalidFields(Vec<FieldError>),
+    Upstream(String),
+    // The client didn't finish sending its body in time
+    RequestTimeout(String),
+    // The handler didn't answer in time
+    Timeout(String),
+    // A write refused while the service is in read-only mode
+    ReadOnly,
+    // The caller's profile lacks fields the route requires, see `require_profile_fields`
+    IncompleteProfile(Vec<ProfileField>),
+    // The client reported a version below the minimum for its type
+    ClientOutdated {
+        minimum: String,
+        upgrade_url: Option<String>,
+    },
+}
+
+impl AppError {
+    pub fn status_and_message(self) -> (StatusCode, String) {
+        match self {
+            // Database failures the client can act on get their own status
+            AppError::Db(ref e) if classify(e) != DbErrorKind::Other => {
+                warn!("Database error : {}", e);
+                let (status, message) = match classify(e) {
+                    DbErrorKind::DuplicateKey => (StatusCode::CONFLICT, "Already exists"),
+                    DbErrorKind::WriteConflict => {
+                        (StatusCode::CONFLICT, "Conflicting update, try again")
+                    }
+                    DbErrorKind::Timeout => (StatusCode::GATEWAY_TIMEOUT, "Database timed out"),
+                    _ => (StatusCode::SERVICE_UNAVAILABLE, "Database unavailable"),
+                };
+                (status, message.to_string())
+            }
+            AppError::Validation(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::Unauthorized(message) => (StatusCode::UNAUTHORIZED, message),
+            AppError::Forbidden(message) => (StatusCode::FORBIDDEN, message),
+            AppError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            AppError::Conflict(message) => (StatusCode::CONFLICT, message),
+            AppError::Locked(seconds) => (
+                StatusCode::LOCKED,
+                format!("Account locked, try again in {} seconds", seconds),
+            ),
+            AppError::PayloadTooLarge(message) => (StatusCode::PAYLOAD_TOO_LARGE, message),
+            AppError::Unprocessable(message) => (StatusCode::UNPROCESSABLE_ENTITY, message),
+            AppError::Upstream(message) => (StatusCode::BAD_GATEWAY, message),
+            AppError::RequestTimeout(message) => (StatusCode::REQUEST_TIMEOUT, message),
+            AppError::Timeout(message) => (StatusCode::SERVICE_UNAVAILABLE, message),
+            // Internal details are logged, never sent to the client
+            internal => {
+                error!("Internal error : {:?}", internal);
+                let message = match internal {
+                    AppError::Db(_) => "Database error",
+                    AppError::Hash(_) => "Password hashing error",
+                    AppError::Jwt(_) => "Error generating token",
+                    AppError::Io(_) => "Storage error",
+                    AppError::Serialization(_) => "Serialization error",
+                    _ => "Internal server error",
+                };
+                (StatusCode::INTERNAL_SERVER_ERROR, message.to_string())
+            }
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if let AppError::InvalidFields(errors) = self {
+            let status = StatusCode::UNPROCESSABLE_ENTITY;
+            let body = ResponseData {
+                status: status.as_u16(),
+                message: "Invalid input".to_string(),
+                data: errors,
+            };
+            return (status, body).into_response();
+        }
+        // Carries a code so clients can tell maintenance apart from an outage
+        if let AppError::ReadOnly = self {
+            let status = StatusCode::SERVICE_UNAVAILABLE;
+            let body = ResponseData {
+                status: status.as_u16(),
+                message: "Read-only mode, writes are disabled for now".to_string(),
+                data: serde_json::json!({ "code": "READ_ONLY" }),
+            };
+            return (status, body).into_response();
+        }
+        if let AppError::IncompleteProfile(missing) = self {
+            let status = StatusCode::FORBIDDEN;
+            let body = ResponseData {
+                status: status.as_u16(),
+                message: "Complete your profile first".to_string(),
+                data: serde_json::json!({
+                    "code": "PROFILE_INCOMPLETE",
+                    "missingFields": missing,
+                }),
+            };
+            return (status, body).into_response();
+        }
+        if let AppError::ClientOutdated {
+            minimum,
+            upgrade_url,
+        } = self
+        {
+            let status = StatusCode::UPGRADE_REQUIRED;
+            let body = ResponseData {
+                status: status.as_u16(),
+                message: format!(
+                    "This version is no longer supported, update to {} or later",
+                    minimum
+                ),
+                data: serde_json::json!({
+                    "code": "CLIENT_OUTDATED",
+                    "minimumVersion": minimum,
+                    "upgradeUrl": upgrade_url,
+                }),
+            };
+            return (status, body).into_response();
+        }
+
+        // Tells the client when to try again
+        if let AppError::Locked(seconds) = self {
+            let (status, message) = self.status_and_message();
+            let body = ResponseData {
+                status: status.as_u16(),
+                message,
+                data: (),
+            };
+            return (status, [(RETRY_AFTER, seconds.to_string())], body).into_response();
+        }
+
+        let (status, message) = self.status_and_message();
+        let body = ResponseData {
+            status: status.as_u16(),
+            message,
+            data: (),
+        };
+        (status, body).into_response()
+    }
+}
+
+impl From<mongodb::error::Error> for AppError {
+    fn from(e: mongodb::error::Error) -> Self {
+        AppError::Db(e)
+    }
+}
+
+impl From<argon2::password_hash::Error> for AppError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        AppError::Hash(e)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AppError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        AppError::Jwt(e)
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        AppError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        AppError::Serialization(e)
+    }
+}
+
+// A poisoned lock means another request panicked while holding it
+impl<T> From<PoisonError<T>> for AppError {
+    fn from(_: PoisonError<T>) -> Self {
+        AppError::Internal("Lock poisoned".to_string())
+    }
+}
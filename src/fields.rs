@@ -0,0 +1,162 @@
+use std::marker::PhantomData;
+
+use axum::{
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::error::AppError;
+
+// A resource that supports `?fields=`. Only the listed fields are ever sent, so a field
+// added to the type later stays private until it is listed here too
+pub trait Sparse: Serialize {
+    // Used in "Unknown field" errors
+    const RESOURCE: &'static str;
+    // As serialized, in camelCase
+    const FIELDS: &'static [&'static str];
+    // Related resources `?include=` can embed
+    const INCLUDES: &'static [&'static str] = &[];
+}
+
+#[derive(Debug, Deserialize)]
+struct FieldsQuery {
+    fields: Option<String>,
+    include: Option<String>,
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+// JSON:API style sparse fieldsets, `?fields=userName,email` or `?fields=user_name,email`.
+// Without the parameter every listed field is sent, `id` always is. Unknown fields are
+// rejected before the handler runs
+pub struct Fields<T> {
+    fields: Option<Vec<String>>,
+    resource: PhantomData<fn() -> T>,
+}
+
+impl<T: Sparse, S: Send + Sync> FromRequestParts<S> for Fields<T> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<FieldsQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| AppError::Validation(e.body_text()))?;
+        let fields: Option<Vec<String>> = query
+            .fields
+            .map(|fields| split_list(&fields).map(to_camel_case).collect());
+        if let Some(field) = fields
+            .iter()
+            .flatten()
+            .find(|field| !T::FIELDS.contains(&field.as_str()))
+        {
+            return Err(AppError::Validation(format!(
+                "Unknown field {} for {}, available are {}",
+                field,
+                T::RESOURCE,
+                T::FIELDS.join(", ")
+            )));
+        }
+        Ok(Fields {
+            fields,
+            resource: PhantomData,
+        })
+    }
+}
+
+fn to_camel_case(field: &str) -> String {
+    let mut camel = String::with_capacity(field.len());
+    let mut upper = false;
+    for c in field.chars() {
+        match c {
+            '_' => upper = true,
+            c if upper => {
+                camel.push(c.to_ascii_uppercase());
+                upper = false;
+            }
+            c => camel.push(c),
+        }
+    }
+    camel
+}
+
+impl<T: Sparse> Fields<T> {
+    fn keep(&self, field: &str) -> bool {
+        T::FIELDS.contains(&field)
+            && (field == "id"
+                || self
+                    .fields
+                    .as_ref()
+                    .is_none_or(|fields| fields.iter().any(|f| f == field)))
+    }
+
+    pub fn apply(&self, value: &T) -> Result<Value, AppError> {
+        let Value::Object(object) = serde_json::to_value(value)? else {
+            return Err(AppError::Internal(format!(
+                "{} doesn't serialize to an object",
+                T::RESOURCE
+            )));
+        };
+        let object: Map<String, Value> = object
+            .into_iter()
+            .filter(|(field, _)| self.keep(field))
+            .collect();
+        Ok(Value::Object(object))
+    }
+
+    pub fn apply_all(&self, values: &[T]) -> Result<Value, AppError> {
+        values
+            .iter()
+            .map(|value| self.apply(value))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Array)
+    }
+}
+
+// `?include=files`, related resources the handler embeds in the same response so
+// clients don't make a request per item
+pub struct Include<T> {
+    includes: Vec<String>,
+    resource: PhantomData<fn() -> T>,
+}
+
+impl<T: Sparse, S: Send + Sync> FromRequestParts<S> for Include<T> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<FieldsQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| AppError::Validation(e.body_text()))?;
+        let includes: Vec<String> = query
+            .include
+            .as_deref()
+            .map(|include| split_list(include).map(to_camel_case).collect())
+            .unwrap_or_default();
+        if let Some(include) = includes
+            .iter()
+            .find(|include| !T::INCLUDES.contains(&include.as_str()))
+        {
+            return Err(AppError::Validation(format!(
+                "Can't include {} with {}, available are {}",
+                include,
+                T::RESOURCE,
+                T::INCLUDES.join(", ")
+            )));
+        }
+        Ok(Include {
+            includes,
+            resource: PhantomData,
+        })
+    }
+}
+
+impl<T> Include<T> {
+    pub fn contains(&self, include: &str) -> bool {
+        self.includes.iter().any(|included| included == include)
+    }
+}
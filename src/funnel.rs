@@ -0,0 +1,159 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use jsonwebtoken::get_current_timestamp;
+use metrics::counter;
+use serde::Serialize;
+
+// The summary covers this many trailing minutes, counted in one bucket per minute
+pub const FUNNEL_WINDOW_MINUTES: u64 = 60;
+
+// Steps of signing up and signing in. There is no email verification yet, so a signup
+// ends when the user is stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunnelStage {
+    // A valid signup request reached the handler
+    SignupStarted,
+    SignupCompleted,
+    SigninAttempted,
+    SigninSucceeded,
+    // Refused because the account is locked, the attempt that locks it included
+    SigninLockedOut,
+}
+
+impl FunnelStage {
+    pub const ALL: [FunnelStage; 5] = [
+        FunnelStage::SignupStarted,
+        FunnelStage::SignupCompleted,
+        FunnelStage::SigninAttempted,
+        FunnelStage::SigninSucceeded,
+        FunnelStage::SigninLockedOut,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FunnelStage::SignupStarted => "signup_started",
+            FunnelStage::SignupCompleted => "signup_completed",
+            FunnelStage::SigninAttempted => "signin_attempted",
+            FunnelStage::SigninSucceeded => "signin_succeeded",
+            FunnelStage::SigninLockedOut => "signin_locked_out",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    // Minutes since the Unix epoch
+    minute: u64,
+    counts: [u64; FunnelStage::ALL.len()],
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignupFunnel {
+    pub started: u64,
+    pub completed: u64,
+    pub conversion: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SigninFunnel {
+    pub attempted: u64,
+    pub succeeded: u64,
+    pub locked_out: u64,
+    pub success_rate: f64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthFunnelSummary {
+    pub window_secs: u64,
+    pub signups: SignupFunnel,
+    pub signins: SigninFunnel,
+}
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+// Auth funnel counts over a sliding window, per instance. Every event also goes to the
+// `auth_funnel_events_total` counter, for totals across instances and restarts
+#[derive(Debug, Clone, Default)]
+pub struct AuthFunnel(Arc<Mutex<VecDeque<Bucket>>>);
+
+fn prune(buckets: &mut VecDeque<Bucket>, minute: u64) {
+    while buckets
+        .front()
+        .is_some_and(|bucket| bucket.minute + FUNNEL_WINDOW_MINUTES <= minute)
+    {
+        buckets.pop_front();
+    }
+}
+
+impl AuthFunnel {
+    pub fn record(&self, stage: FunnelStage) {
+        counter!("auth_funnel_events_total", "stage" => stage.as_str()).increment(1);
+
+        let minute = get_current_timestamp() / 60;
+        let mut buckets = self.0.lock().unwrap();
+        prune(&mut buckets, minute);
+        if buckets.back().is_none_or(|bucket| bucket.minute != minute) {
+            buckets.push_back(Bucket {
+                minute,
+                counts: [0; FunnelStage::ALL.len()],
+            });
+        }
+        if let Some(bucket) = buckets.back_mut() {
+            bucket.counts[stage as usize] += 1;
+        }
+    }
+
+    // Per stage, in the order of `FunnelStage::ALL`
+    pub fn window_counts(&self) -> [u64; FunnelStage::ALL.len()] {
+        let mut buckets = self.0.lock().unwrap();
+        prune(&mut buckets, get_current_timestamp() / 60);
+
+        let mut counts = [0; FunnelStage::ALL.len()];
+        for bucket in buckets.iter() {
+            for (total, count) in counts.iter_mut().zip(bucket.counts) {
+                *total += count;
+            }
+        }
+        counts
+    }
+
+    pub fn summary(&self) -> AuthFunnelSummary {
+        let counts = self.window_counts();
+        let count = |stage: FunnelStage| counts[stage as usize];
+
+        let (started, completed) = (
+            count(FunnelStage::SignupStarted),
+            count(FunnelStage::SignupCompleted),
+        );
+        let (attempted, succeeded) = (
+            count(FunnelStage::SigninAttempted),
+            count(FunnelStage::SigninSucceeded),
+        );
+        AuthFunnelSummary {
+            window_secs: FUNNEL_WINDOW_MINUTES * 60,
+            signups: SignupFunnel {
+                started,
+                completed,
+                conversion: ratio(completed, started),
+            },
+            signins: SigninFunnel {
+                attempted,
+                succeeded,
+                locked_out: count(FunnelStage::SigninLockedOut),
+                success_rate: ratio(succeeded, attempted),
+            },
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use mongodb::{
+    action::Action,
+    bson::{doc, Document},
+    Collection, Database,
+};
+use serde_json::Value;
+use tracing::info;
+
+use crate::{
+    analytics::{Analytics, ClientUsage},
+    anomaly::{ThrottleRequest, ThrottledClient, TrafficGuard, MAX_THROTTLE_SECS},
+    client::MinVersions,
+    config::Config,
+    counter::CounterCache,
+    crypto::FieldCipher,
+    db::{reload_ip_rules, time_left},
+    error::AppError,
+    fields::Fields,
+    funnel::{AuthFunnel, AuthFunnelSummary},
+    handlers::users,
+    id::{IpRuleId, RawId},
+    middleware::{parse_cidr, DryRun, IpRules, ReadOnly},
+    models::{
+        IpRule, IpRuleEntry, MinVersionUpdate, Pagination, ReadOnlyMode, ResponseData, UserProfile,
+    },
+    schema::decode,
+    server::ConnectionStats,
+    versioning::{DeprecatedCalls, Deprecations},
+};
+
+pub async fn list_ip_rules(
+    State(database): State<Database>,
+) -> Result<ResponseData<Vec<IpRuleEntry>>, AppError> {
+    let ip_rules_collection: Collection<Document> = database.collection("ip_rules");
+    let mut cursor = ip_rules_collection
+        .find(doc! {})
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .await?;
+
+    let mut rules = Vec::new();
+    while cursor.advance().await? {
+        let rule: IpRule = decode(&database, "ip_rules", cursor.deserialize_current()?)?;
+        rules.push(IpRuleEntry::from(rule));
+    }
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "IP rules".to_string(),
+        data: rules,
+    })
+}
+
+pub async fn create_ip_rule(
+    State(ip_rules): State<IpRules>,
+    State(database): State<Database>,
+    State(config): State<Arc<Config>>,
+    dry_run: DryRun,
+    Json(input): Json<IpRule>,
+) -> Result<ResponseData<Option<IpRuleId>>, AppError> {
+    if parse_cidr(&input.cidr).is_none() {
+        return Err(AppError::Validation("Invalid CIDR".to_string()));
+    }
+
+    if dry_run.0 {
+        return Ok(ResponseData {
+            status: StatusCode::OK.as_u16(),
+            message: "Dry run : IP rule would be created".to_string(),
+            data: None,
+        });
+    }
+
+    let ip_rules_collection: Collection<IpRule> = database.collection("ip_rules");
+    let id = RawId::generate(config.id_format);
+    ip_rules_collection
+        .insert_one(IpRule {
+            id: Some(id),
+            cidr: input.cidr,
+            action: input.action,
+        })
+        .await?;
+    reload_ip_rules(&database, &ip_rules).await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "IP rule created".to_string(),
+        data: Some(id.into()),
+    })
+}
+
+pub async fn delete_ip_rule(
+    State(ip_rules): State<IpRules>,
+    State(database): State<Database>,
+    dry_run: DryRun,
+    Path(id): Path<String>,
+) -> Result<ResponseData<()>, AppError> {
+    let id = id.parse::<IpRuleId>()?.raw();
+
+    let ip_rules_collection: Collection<IpRule> = database.collection("ip_rules");
+    if dry_run.0 {
+        if ip_rules_collection
+            .count_documents(doc! { "_id": id })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+            .await?
+            == 0
+        {
+            return Err(AppError::NotFound("IP rule does not exist".to_string()));
+        }
+        return Ok(ResponseData {
+            status: StatusCode::OK.as_u16(),
+            message: "Dry run : IP rule would be deleted".to_string(),
+            data: (),
+        });
+    }
+
+    let result = ip_rules_collection.delete_one(doc! { "_id": id }).await?;
+    reload_ip_rules(&database, &ip_rules).await?;
+
+    if result.deleted_count == 0 {
+        return Err(AppError::NotFound("IP rule does not exist".to_string()));
+    }
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "IP rule deleted".to_string(),
+        data: (),
+    })
+}
+
+pub async fn list_all_users(
+    State(database): State<Database>,
+    State(config): State<Arc<Config>>,
+    State(cipher): State<FieldCipher>,
+    Query(pagination): Query<Pagination>,
+    fields: Fields<UserProfile>,
+) -> Result<ResponseData<Value>, AppError> {
+    let users = users::list_page(
+        &database,
+        &cipher,
+        &pagination,
+        config.read_preference.listings,
+    )
+    .await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "All users".to_string(),
+        data: fields.apply_all(&users)?,
+    })
+}
+
+// Unlike `DELETE /users/{id}` this works on any account
+pub async fn delete_any_user(
+    State(database): State<Database>,
+    State(cipher): State<FieldCipher>,
+    dry_run: DryRun,
+    Path(id): Path<String>,
+) -> Result<ResponseData<()>, AppError> {
+    let id = users::parse_user_id(&id)?;
+    let found = users::find_user(&database, &cipher, id).await?;
+
+    if dry_run.0 {
+        return Ok(ResponseData {
+            status: StatusCode::OK.as_u16(),
+            message: format!("Dry run : user {} would be deleted", found.user_name),
+            data: (),
+        });
+    }
+    users::remove_user(&database, id, &found.user_name).await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "User deleted".to_string(),
+        data: (),
+    })
+}
+
+pub async fn view_counters(State(counter): State<CounterCache>) -> ResponseData<u64> {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Counter".to_string(),
+        data: counter.get(),
+    }
+}
+
+pub async fn get_read_only(State(read_only): State<ReadOnly>) -> ResponseData<ReadOnlyMode> {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Read-only mode".to_string(),
+        data: ReadOnlyMode {
+            enabled: read_only.is_enabled(),
+        },
+    }
+}
+
+// Per instance, each one behind a load balancer has to be switched on its own
+pub async fn set_read_only(
+    State(read_only): State<ReadOnly>,
+    Json(mode): Json<ReadOnlyMode>,
+) -> ResponseData<ReadOnlyMode> {
+    read_only.set(mode.enabled);
+    info!("Read-only mode {}", if mode.enabled { "on" } else { "off" });
+
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Read-only mode updated".to_string(),
+        data: mode,
+    }
+}
+
+// Clients held to a tighter limit, detected or set below. Per instance, like the analytics
+pub async fn list_throttles(
+    State(guard): State<TrafficGuard>,
+) -> ResponseData<Vec<ThrottledClient>> {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Throttled clients".to_string(),
+        data: guard.throttled(),
+    }
+}
+
+// Holds a client, a username or `ip:<address>`, to a limit of the operator's choosing
+pub async fn set_throttle(
+    State(guard): State<TrafficGuard>,
+    Path(client): Path<String>,
+    Json(request): Json<ThrottleRequest>,
+) -> Result<ResponseData<Vec<ThrottledClient>>, AppError> {
+    if request.per_minute == 0 || request.duration_secs == 0 {
+        return Err(AppError::Validation(
+            "perMinute and durationSecs must be above 0".to_string(),
+        ));
+    }
+    if request.duration_secs > MAX_THROTTLE_SECS {
+        return Err(AppError::Validation(format!(
+            "durationSecs can't be above {}",
+            MAX_THROTTLE_SECS
+        )));
+    }
+    guard.throttle(
+        &client,
+        request.per_minute,
+        Duration::from_secs(request.duration_secs),
+    );
+    info!(
+        client = %client,
+        "Throttled to {} requests per minute for {}s by an operator",
+        request.per_minute,
+        request.duration_secs
+    );
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Client throttled".to_string(),
+        data: guard.throttled(),
+    })
+}
+
+// Lets a throttled client through again, and stops watching it for `anomaly.throttle_secs`
+pub async fn lift_throttle(
+    State(guard): State<TrafficGuard>,
+    Path(client): Path<String>,
+) -> Result<ResponseData<()>, AppError> {
+    if !guard.lift(&client) {
+        return Err(AppError::NotFound(format!("{} isn't throttled", client)));
+    }
+    info!(client = %client, "Throttle lifted by an operator");
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Throttle lifted".to_string(),
+        data: (),
+    })
+}
+
+// Who still calls deprecated versions, per route and client, since this instance started
+pub async fn deprecated_calls(
+    State(deprecations): State<Deprecations>,
+) -> ResponseData<Vec<DeprecatedCalls>> {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Deprecated API calls".to_string(),
+        data: deprecations.report(),
+    }
+}
+
+pub async fn get_client_versions(
+    State(min_versions): State<MinVersions>,
+) -> ResponseData<BTreeMap<String, String>> {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Minimum client versions".to_string(),
+        data: min_versions.all(),
+    }
+}
+
+// Per instance like read-only mode, and back to `clients.min_versions` on restart
+pub async fn set_client_version(
+    State(min_versions): State<MinVersions>,
+    Path(client_type): Path<String>,
+    Json(update): Json<MinVersionUpdate>,
+) -> Result<ResponseData<BTreeMap<String, String>>, AppError> {
+    let client_type = client_type.to_ascii_lowercase();
+    min_versions
+        .set(&client_type, update.minimum_version.clone())
+        .map_err(AppError::Validation)?;
+    info!(
+        "Minimum {} version set to {}",
+        client_type,
+        update.minimum_version.as_deref().unwrap_or("none")
+    );
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Minimum client version updated".to_string(),
+        data: min_versions.all(),
+    })
+}
+
+pub async fn auth_funnel(State(funnel): State<AuthFunnel>) -> ResponseData<AuthFunnelSummary> {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Auth funnel".to_string(),
+        data: funnel.summary(),
+    }
+}
+
+pub async fn client_analytics(
+    State(analytics): State<Analytics>,
+) -> ResponseData<Vec<ClientUsage>> {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Client analytics".to_string(),
+        data: analytics.clients(),
+    }
+}
+
+pub async fn runtime_stats(
+    Extension(connection_stats): Extension<ConnectionStats>,
+) -> impl IntoResponse {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Runtime stats".to_string(),
+        data: serde_json::json!({
+            "maxConnections": connection_stats.max_connections,
+            "maxConnectionsPerIp": connection_stats.max_per_ip,
+            "connections": connection_stats.counts(),
+            "allocator": allocator_stats(),
+        }),
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+pub fn allocator_stats() -> serde_json::Value {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // jemalloc only refreshes its statistics when the epoch is advanced
+    let _ = epoch::advance();
+    serde_json::json!({
+        "name": "jemalloc",
+        "allocated": stats::allocated::read().ok(),
+        "active": stats::active::read().ok(),
+        "resident": stats::resident::read().ok(),
+        "retained": stats::retained::read().ok(),
+    })
+}
+
+#[cfg(feature = "mimalloc")]
+pub fn allocator_stats() -> serde_json::Value {
+    serde_json::json!({ "name": "mimalloc" })
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn allocator_stats() -> serde_json::Value {
+    serde_json::json!({ "name": "system" })
+}
@@ -0,0 +1,834 @@
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use jsonwebtoken::get_current_timestamp;
+use mongodb::{
+    action::Action,
+    bson::{self, doc, oid::ObjectId, Document},
+    options::ReturnDocument,
+    Collection, Database,
+};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+
+use crate::{
+    activity::{Activity, ActivityFeed},
+    analytics::{Analytics, ClientUsage},
+    auth::{
+        decode_rotated, generate_guest_token, generate_refresh_token, generate_token, AuthUser,
+        Authenticator, GuestUser, GUEST_TOKEN_TTL, REFRESH_TOKEN_TTL,
+    },
+    config::{Config, ReadMode},
+    crypto::FieldCipher,
+    db::{classify, read_criteria, time_left, with_retries, DbErrorKind, TenantDb},
+    delivery::TokenDelivery,
+    error::AppError,
+    funnel::{AuthFunnel, FunnelStage},
+    handlers::sandbox,
+    id::{RawId, UserId},
+    ldap::{self, Bind},
+    models::{
+        ChangePasswordRequest, Credentials, ForgotPasswordRequest, GuestToken, PasswordReset,
+        RefreshRequest, RefreshToken, ResetPasswordRequest, ResponseData, Role, TokenPair,
+        TokenType, User, UserSource,
+    },
+    openapi::{Empty, ErrorBody},
+    schema::decode,
+    secrets::RotatingSecret,
+    trace::spawn_traced,
+    validation::ValidJson,
+};
+
+// How long a token from `/auth/forgot-password` can be redeemed
+pub const RESET_TOKEN_TTL: Duration = Duration::from_secs(30 * 60);
+
+pub fn hash_password(password: &str) -> Result<String, AppError> {
+    let salt: SaltString = SaltString::generate(&mut OsRng);
+
+    // Argon2 with default params (Argon2id v19)
+    let argon2: Argon2<'_> = Argon2::default();
+
+    // Hash password to PHC string ($argon2id$v=19$...)
+    Ok(argon2
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn hash_reset_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/signup",
+    tag = "auth",
+    request_body = Credentials,
+    responses(
+        (status = 200, description = "The new user's id", body = ResponseData<UserId>),
+        (status = 409, description = "The user name is taken", body = ErrorBody),
+        (status = 422, description = "Invalid input"),
+    )
+)]
+pub async fn signup(
+    TenantDb(database, _): TenantDb,
+    State(config): State<Arc<Config>>,
+    State(funnel): State<AuthFunnel>,
+    State(activity): State<ActivityFeed>,
+    ValidJson(input): ValidJson<Credentials>,
+) -> Result<ResponseData<UserId>, AppError> {
+    funnel.record(FunnelStage::SignupStarted);
+    let user = create_user(&database, &config, &input).await?;
+    let id = user.id.expect("Inserted users have an id");
+
+    funnel.record(FunnelStage::SignupCompleted);
+    activity.publish(Activity::UserSignedUp { id: id.into() });
+    // (StatusCode::OK, "User signed up")
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "User signed up".to_string(),
+        data: id.into(),
+    })
+}
+
+// Stores a new user with the given credentials, shared by signup and guest claims
+async fn create_user(
+    database: &Database,
+    config: &Config,
+    input: &Credentials,
+) -> Result<User, AppError> {
+    let password_hash = hash_password(&input.password)?;
+    insert_user(
+        database,
+        config,
+        &input.user_name,
+        password_hash,
+        Role::User,
+        None,
+    )
+    .await
+}
+
+async fn insert_user(
+    database: &Database,
+    config: &Config,
+    user_name: &str,
+    password_hash: String,
+    role: Role,
+    source: Option<UserSource>,
+) -> Result<User, AppError> {
+    let users_collection: Collection<User> = database.collection("users");
+    let taken = || AppError::Conflict(format!("User name {} is already taken", user_name));
+    // The unique index on `user_name` settles concurrent signups, this gives the common case
+    // a clear answer without relying on it
+    if users_collection
+        .count_documents(doc! { "user_name": user_name })
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .await?
+        > 0
+    {
+        return Err(taken());
+    }
+
+    let id = RawId::generate(config.id_format);
+    let user = User {
+        id: Some(id),
+        user_name: user_name.to_string(),
+        password_hash,
+        email: None,
+        display_name: None,
+        role,
+        failed_signins: 0,
+        locked_until: None,
+        external_id: None,
+        disabled: false,
+        source,
+    };
+    users_collection
+        .insert_one(&user)
+        .await
+        .map_err(|e| match classify(&e) {
+            DbErrorKind::DuplicateKey => taken(),
+            _ => e.into(),
+        })?;
+
+    info!("Inserted a document with _id: {}", id);
+    Ok(user)
+}
+
+// Tries the API out without an account, `/auth/claim` turns the guest into one
+#[utoipa::path(
+    post,
+    path = "/auth/guest",
+    tag = "auth",
+    responses((status = 200, body = ResponseData<GuestToken>))
+)]
+pub async fn guest(
+    TenantDb(_, tenant): TenantDb,
+    State(authenticator): State<Authenticator>,
+) -> Result<ResponseData<GuestToken>, AppError> {
+    let (guest_id, access_token) =
+        generate_guest_token(tenant.as_deref(), &authenticator.jwt_secret.current())?;
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Signed in as a guest".to_string(),
+        data: GuestToken {
+            access_token,
+            guest_id,
+            expires_in: GUEST_TOKEN_TTL.as_secs(),
+        },
+    })
+}
+
+// Signs a guest up under the given credentials, keeping its sandbox counter. The guest
+// token is revoked, the new account signs in with the returned pair
+#[utoipa::path(
+    post,
+    path = "/auth/claim",
+    tag = "auth",
+    request_body = Credentials,
+    responses(
+        (status = 200, body = ResponseData<TokenPair>),
+        (status = 403, description = "Not a guest token", body = ErrorBody),
+        (status = 409, description = "The user name is taken", body = ErrorBody),
+        (status = 422, description = "Invalid input"),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn claim(
+    TenantDb(database, tenant): TenantDb,
+    State(config): State<Arc<Config>>,
+    State(authenticator): State<Authenticator>,
+    State(funnel): State<AuthFunnel>,
+    State(activity): State<ActivityFeed>,
+    guest: GuestUser,
+    ValidJson(input): ValidJson<Credentials>,
+) -> Result<ResponseData<TokenPair>, AppError> {
+    if !guest.is_guest() {
+        return Err(AppError::Forbidden(
+            "Only guest tokens can be claimed".to_string(),
+        ));
+    }
+
+    funnel.record(FunnelStage::SignupStarted);
+    let user = create_user(&database, &config, &input).await?;
+    sandbox::transfer(&database, &guest.0.sub, &user.user_name).await?;
+    if let Some(jti) = guest.0.jti.as_deref() {
+        authenticator.denylist.revoke(jti, guest.0.exp);
+    }
+    let id = user.id.expect("Inserted users have an id");
+    funnel.record(FunnelStage::SignupCompleted);
+    activity.publish(Activity::UserSignedUp { id: id.into() });
+
+    let tokens = issue_tokens(
+        &database,
+        &user,
+        tenant.as_deref(),
+        &authenticator.jwt_secret,
+    )
+    .await?;
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Guest account claimed".to_string(),
+        data: tokens,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/signin",
+    tag = "auth",
+    request_body = Credentials,
+    responses(
+        (status = 200, body = ResponseData<TokenPair>),
+        (
+            status = 401,
+            description = "Wrong user name or password, or a disabled or locked account",
+            body = ErrorBody
+        ),
+        (
+            status = 423,
+            description = "Locked after too many failed attempts, with the time left in `Retry-After`",
+            body = ErrorBody
+        ),
+    )
+)]
+pub async fn signin(
+    TenantDb(database, tenant): TenantDb,
+    State(config): State<Arc<Config>>,
+    State(authenticator): State<Authenticator>,
+    State(funnel): State<AuthFunnel>,
+    Json(input): Json<Credentials>,
+) -> Result<ResponseData<TokenPair>, AppError> {
+    funnel.record(FunnelStage::SigninAttempted);
+    let users_collection: Collection<Document> = database.collection("users");
+
+    let Some(document) = with_retries(|| {
+        users_collection
+            .find_one(doc! {
+                "user_name": &input.user_name
+            })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+            // Pinned, whatever the connection string asks for, so a fresh signup can sign in
+            .selection_criteria(read_criteria(ReadMode::Primary))
+    })
+    .await?
+    else {
+        let Bind::Accepted(role) = directory_bind(&config, &input).await else {
+            check_decoy_password(&input.password);
+            return Err(invalid_signin());
+        };
+        // First sign in of a directory user. The password stays with the directory, the
+        // local one is random so only the directory lets them in
+        let mut password = [0u8; 32];
+        OsRng.fill_bytes(&mut password);
+        let password_hash = hash_password(&hex::encode(password))?;
+        let user = insert_user(
+            &database,
+            &config,
+            &input.user_name,
+            password_hash,
+            role,
+            Some(UserSource::Ldap),
+        )
+        .await?;
+        info!("Provisioned {} from the directory", input.user_name);
+        return signed_in(&database, &user, tenant.as_deref(), &authenticator, &funnel).await;
+    };
+    let mut result: User = decode(&database, "users", document)?;
+    // Checked anyway, so these take as long as a wrong password
+    if result.disabled {
+        password_matches(&input.password, &result.password_hash);
+        return Err(invalid_signin());
+    }
+    let now = get_current_timestamp();
+    // Only someone who knows the password learns the account is locked, anyone else gets
+    // the same answer as for a wrong one
+    if let Some(locked_until) = result.locked_until.filter(|&until| until > now) {
+        funnel.record(FunnelStage::SigninLockedOut);
+        if password_matches(&input.password, &result.password_hash) {
+            return Err(AppError::Locked(locked_until - now));
+        }
+        return Err(invalid_signin());
+    }
+
+    // Local accounts never ask the directory, so a directory user of the same name can't
+    // sign in to them
+    let bind = match result.source {
+        Some(UserSource::Ldap) => directory_bind(&config, &input).await,
+        None => Bind::Refused,
+    };
+    let parsed_hash = PasswordHash::new(&result.password_hash)?;
+    if let Bind::Accepted(role) = bind {
+        // The directory decides the roles of the users it lets in
+        if result.role != role {
+            let encoded = bson::to_bson(&role)
+                .map_err(|e| AppError::Internal(format!("Error encoding role : {}", e)))?;
+            let users_collection: Collection<User> = database.collection("users");
+            users_collection
+                .update_one(
+                    doc! { "_id": result.id },
+                    doc! { "$set": { "role": encoded } },
+                )
+                .await?;
+            result.role = role;
+        }
+    } else if Argon2::default()
+        .verify_password(input.password.as_bytes(), &parsed_hash)
+        .is_err()
+    {
+        if record_failed_signin(&database, &result, &config).await? {
+            funnel.record(FunnelStage::SigninLockedOut);
+        }
+        return Err(invalid_signin());
+    }
+
+    if result.failed_signins > 0 || result.locked_until.is_some() {
+        let users_collection: Collection<User> = database.collection("users");
+        users_collection
+            .update_one(
+                doc! { "_id": result.id },
+                doc! { "$unset": { "failed_signins": "", "locked_until": "" } },
+            )
+            .await?;
+    }
+
+    signed_in(
+        &database,
+        &result,
+        tenant.as_deref(),
+        &authenticator,
+        &funnel,
+    )
+    .await
+}
+
+// Every refused sign-in gets this, so unknown, disabled and locked accounts can't be told
+// apart from a wrong password. Locked ones get 423 only once the password checks out
+fn invalid_signin() -> AppError {
+    AppError::Unauthorized("Invalid user name or password".to_string())
+}
+
+fn password_matches(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+// Takes as long as checking a real password, for user names that don't exist
+fn check_decoy_password(password: &str) {
+    static DECOY_HASH: OnceLock<String> = OnceLock::new();
+    let decoy = DECOY_HASH
+        .get_or_init(|| hash_password("decoy-password").expect("Hashing a fixed password works"));
+    password_matches(password, decoy);
+}
+
+async fn directory_bind(config: &Config, input: &Credentials) -> Bind {
+    ldap::bind(&config.auth.ldap, &input.user_name, &input.password)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("{}, trying the local password", e);
+            Bind::Refused
+        })
+}
+
+async fn signed_in(
+    database: &Database,
+    user: &User,
+    tenant: Option<&str>,
+    authenticator: &Authenticator,
+    funnel: &AuthFunnel,
+) -> Result<ResponseData<TokenPair>, AppError> {
+    let tokens = issue_tokens(database, user, tenant, &authenticator.jwt_secret).await?;
+    funnel.record(FunnelStage::SigninSucceeded);
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Signed in".to_string(),
+        data: tokens,
+    })
+}
+
+// Counts a wrong password, locking the account once there were too many in a row. `true`
+// when this one locked it
+async fn record_failed_signin(
+    database: &Database,
+    user: &User,
+    config: &Config,
+) -> Result<bool, AppError> {
+    let users_collection: Collection<User> = database.collection("users");
+    // Incremented in the database, so concurrent attempts can't undercount
+    let Some(updated) = users_collection
+        .find_one_and_update(
+            doc! { "_id": user.id },
+            doc! { "$inc": { "failed_signins": 1 } },
+        )
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .return_document(ReturnDocument::After)
+        .await?
+    else {
+        return Ok(false);
+    };
+    let failures = updated.failed_signins;
+    if failures < config.account_lockout.max_failures {
+        return Ok(false);
+    }
+
+    let lockout = &config.account_lockout;
+    let locked_until = get_current_timestamp() + lockout.lock_secs;
+    warn!(
+        user_name = %user.user_name,
+        "Locking account for {}s after {} failed sign-ins",
+        lockout.lock_secs,
+        failures
+    );
+    users_collection
+        .update_one(
+            doc! { "_id": user.id },
+            doc! {
+                "$set": { "locked_until": locked_until as i64 },
+                "$unset": { "failed_signins": "" },
+            },
+        )
+        .await?;
+    Ok(true)
+}
+
+// Stores a new refresh token and hands it out together with a fresh access token
+async fn issue_tokens(
+    database: &Database,
+    user: &User,
+    tenant: Option<&str>,
+    secret: &RotatingSecret,
+) -> Result<TokenPair, AppError> {
+    let refresh_tokens_collection: Collection<RefreshToken> = database.collection("refresh_tokens");
+    let expires_at = get_current_timestamp() + REFRESH_TOKEN_TTL.as_secs();
+    let result = refresh_tokens_collection
+        .insert_one(RefreshToken {
+            id: None,
+            user_name: user.user_name.clone(),
+            expires_at,
+            revoked: false,
+        })
+        .await?;
+    let jti = result
+        .inserted_id
+        .as_object_id()
+        .ok_or_else(|| AppError::Internal("Refresh token id is not an ObjectId".to_string()))?
+        .to_hex();
+
+    let key = secret.current();
+    Ok(TokenPair {
+        access_token: generate_token(&user.user_name, user.role, tenant, &key)?,
+        refresh_token: generate_refresh_token(
+            &user.user_name,
+            user.role,
+            tenant,
+            &jti,
+            expires_at,
+            &key,
+        )?,
+    })
+}
+
+// Revokes the access token the request was made with, refresh tokens go through `/auth/revoke`
+#[utoipa::path(
+    post,
+    path = "/auth/logout",
+    tag = "auth",
+    responses(
+        (status = 200, body = ResponseData<Empty>),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn logout(
+    State(authenticator): State<Authenticator>,
+    user: AuthUser,
+) -> Result<ResponseData<()>, AppError> {
+    let Some(jti) = user.0.jti.as_deref() else {
+        return Err(AppError::Validation(
+            "Token predates logout and can't be revoked".to_string(),
+        ));
+    };
+    authenticator.denylist.revoke(jti, user.0.exp);
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Signed out".to_string(),
+        data: (),
+    })
+}
+
+pub async fn forgot_password(
+    TenantDb(database, _): TenantDb,
+    State(cipher): State<FieldCipher>,
+    Extension(delivery): Extension<Arc<dyn TokenDelivery>>,
+    Json(input): Json<ForgotPasswordRequest>,
+) -> Result<ResponseData<()>, AppError> {
+    // The same answer either way, so this can't be used to find out which accounts exist
+    let accepted = ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "If the account exists, a reset token has been sent".to_string(),
+        data: (),
+    };
+
+    let users_collection: Collection<Document> = database.collection("users");
+    let Some(document) = with_retries(|| {
+        users_collection
+            .find_one(doc! { "user_name": &input.user_name })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+            .selection_criteria(read_criteria(ReadMode::Primary))
+    })
+    .await?
+    else {
+        return Ok(accepted);
+    };
+    // In the background, so existing accounts don't answer slower than unknown ones
+    spawn_traced(async move {
+        if let Err(e) = send_reset_token(&database, &cipher, delivery.as_ref(), document).await {
+            error!("Error issuing a reset token : {:?}", e);
+        }
+    });
+    Ok(accepted)
+}
+
+// Retires the user's earlier reset tokens and delivers a new one
+async fn send_reset_token(
+    database: &Database,
+    cipher: &FieldCipher,
+    delivery: &dyn TokenDelivery,
+    document: Document,
+) -> Result<(), AppError> {
+    let mut user: User = decode(database, "users", document)?;
+    cipher.open_user(&mut user)?;
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+
+    // Requesting a new token retires the ones sent before
+    let resets_collection: Collection<PasswordReset> = database.collection("password_resets");
+    resets_collection
+        .update_many(
+            doc! { "user_name": &user.user_name, "used": false },
+            doc! { "$set": { "used": true } },
+        )
+        .await?;
+    resets_collection
+        .insert_one(PasswordReset {
+            id: None,
+            user_name: user.user_name.clone(),
+            token_hash: hash_reset_token(&token),
+            expires_at: get_current_timestamp() + RESET_TOKEN_TTL.as_secs(),
+            used: false,
+        })
+        .await?;
+
+    if let Err(e) = delivery
+        .send_reset_token(&user.user_name, user.email.as_deref(), &token)
+        .await
+    {
+        error!(user_name = %user.user_name, "Error delivering reset token : {}", e);
+    }
+    Ok(())
+}
+
+pub async fn reset_password(
+    TenantDb(database, _): TenantDb,
+    ValidJson(input): ValidJson<ResetPasswordRequest>,
+) -> Result<ResponseData<()>, AppError> {
+    let resets_collection: Collection<PasswordReset> = database.collection("password_resets");
+    // Marked used in the same step it is checked, so a token can't be redeemed twice
+    let Some(reset) = resets_collection
+        .find_one_and_update(
+            doc! {
+                "token_hash": hash_reset_token(&input.token),
+                "used": false,
+                "expires_at": { "$gt": get_current_timestamp() as i64 },
+            },
+            doc! { "$set": { "used": true } },
+        )
+        .await?
+    else {
+        return Err(AppError::Validation(
+            "Invalid or expired reset token".to_string(),
+        ));
+    };
+
+    let password_hash = hash_password(&input.new_password)?;
+    let users_collection: Collection<User> = database.collection("users");
+    let result = users_collection
+        .update_one(
+            doc! { "user_name": &reset.user_name },
+            doc! {
+                "$set": { "password": password_hash },
+                "$unset": { "failed_signins": "", "locked_until": "" },
+            },
+        )
+        .await?;
+    if result.matched_count == 0 {
+        return Err(AppError::NotFound("User does not exist".to_string()));
+    }
+
+    // Whoever knew the old password may still hold a session
+    revoke_sessions(&database, &reset.user_name).await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Password reset".to_string(),
+        data: (),
+    })
+}
+
+pub async fn change_password(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+    ValidJson(input): ValidJson<ChangePasswordRequest>,
+) -> Result<ResponseData<()>, AppError> {
+    let users_collection: Collection<Document> = database.collection("users");
+    let Some(document) = with_retries(|| {
+        users_collection
+            .find_one(doc! { "user_name": user.username() })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+            .selection_criteria(read_criteria(ReadMode::Primary))
+    })
+    .await?
+    else {
+        return Err(AppError::Unauthorized("User no longer exists".to_string()));
+    };
+    let found: User = decode(&database, "users", document)?;
+
+    let parsed_hash = PasswordHash::new(&found.password_hash)?;
+    if Argon2::default()
+        .verify_password(input.current_password.as_bytes(), &parsed_hash)
+        .is_err()
+    {
+        return Err(AppError::Unauthorized(
+            "Current password is wrong".to_string(),
+        ));
+    }
+
+    let password_hash = hash_password(&input.new_password)?;
+    users_collection
+        .update_one(
+            doc! { "_id": found.id },
+            doc! { "$set": { "password": password_hash } },
+        )
+        .await?;
+    // Access tokens already handed out stay valid until they expire, a minute at most
+    if input.revoke_sessions {
+        revoke_sessions(&database, &found.user_name).await?;
+    }
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Password changed".to_string(),
+        data: (),
+    })
+}
+
+// Revokes every refresh token of a user, returning how many were still usable
+pub async fn revoke_sessions(database: &Database, user_name: &str) -> Result<u64, AppError> {
+    let refresh_tokens_collection: Collection<RefreshToken> = database.collection("refresh_tokens");
+    let result = refresh_tokens_collection
+        .update_many(
+            doc! { "user_name": user_name, "revoked": false },
+            doc! { "$set": { "revoked": true } },
+        )
+        .await?;
+    Ok(result.modified_count)
+}
+
+// Marks a refresh token as revoked, failing if it was already revoked or never existed
+async fn revoke_refresh_token(
+    database: &Database,
+    token: &str,
+    secret: &RotatingSecret,
+) -> Result<String, AppError> {
+    let invalid = || AppError::Unauthorized("Invalid refresh token".to_string());
+    let claims = decode_rotated(token, secret).map_err(|_| invalid())?;
+    if claims.token_type != TokenType::Refresh {
+        return Err(invalid());
+    }
+    let id = claims
+        .jti
+        .and_then(|jti| ObjectId::parse_str(jti).ok())
+        .ok_or_else(invalid)?;
+
+    let refresh_tokens_collection: Collection<RefreshToken> = database.collection("refresh_tokens");
+    let result = refresh_tokens_collection
+        .update_one(
+            doc! { "_id": id, "user_name": &claims.sub, "revoked": false },
+            doc! { "$set": { "revoked": true } },
+        )
+        .await?;
+    if result.modified_count == 0 {
+        return Err(AppError::Unauthorized(
+            "Refresh token has been revoked".to_string(),
+        ));
+    }
+
+    Ok(claims.sub)
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/refresh",
+    tag = "auth",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "A new pair, the refresh token sent is spent", body = ResponseData<TokenPair>),
+        (status = 401, description = "Invalid, expired or already used refresh token", body = ErrorBody),
+    )
+)]
+pub async fn refresh(
+    TenantDb(database, tenant): TenantDb,
+    State(authenticator): State<Authenticator>,
+    Json(input): Json<RefreshRequest>,
+) -> Result<ResponseData<TokenPair>, AppError> {
+    // Refresh tokens are single use, a replayed one is refused once it has been swapped
+    let username =
+        revoke_refresh_token(&database, &input.refresh_token, &authenticator.jwt_secret).await?;
+
+    // Read the user again so a changed role or deleted account takes effect
+    let users_collection: Collection<Document> = database.collection("users");
+    let Some(document) = with_retries(|| {
+        users_collection
+            .find_one(doc! { "user_name": &username })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+            .selection_criteria(read_criteria(ReadMode::Primary))
+    })
+    .await?
+    else {
+        return Err(AppError::Unauthorized("User no longer exists".to_string()));
+    };
+    let user: User = decode(&database, "users", document)?;
+    if user.disabled {
+        return Err(AppError::Unauthorized("Account is disabled".to_string()));
+    }
+    let tokens = issue_tokens(
+        &database,
+        &user,
+        tenant.as_deref(),
+        &authenticator.jwt_secret,
+    )
+    .await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Tokens refreshed".to_string(),
+        data: tokens,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/revoke",
+    tag = "auth",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, body = ResponseData<Empty>),
+        (status = 401, description = "Invalid or expired refresh token", body = ErrorBody),
+    )
+)]
+pub async fn revoke(
+    TenantDb(database, _): TenantDb,
+    State(authenticator): State<Authenticator>,
+    Json(input): Json<RefreshRequest>,
+) -> Result<ResponseData<()>, AppError> {
+    revoke_refresh_token(&database, &input.refresh_token, &authenticator.jwt_secret).await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Refresh token revoked".to_string(),
+        data: (),
+    })
+}
+
+// Lets a signed in integrator see their own recent consumption
+pub async fn usage(
+    user: AuthUser,
+    State(analytics): State<Analytics>,
+) -> ResponseData<ClientUsage> {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Usage".to_string(),
+        data: analytics.usage(user.username()),
+    }
+}
+
+pub async fn protected(user: AuthUser) -> impl IntoResponse {
+    let response = format!("Hello {}", user.username());
+    (StatusCode::OK, response)
+}
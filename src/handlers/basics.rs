@@ -0,0 +1,120 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Extension, Form,
+};
+use serde_json::to_string_pretty;
+use tracing::info;
+
+use crate::{
+    counter::CounterCache,
+    error::AppError,
+    models::Identity,
+    redact::Redacted,
+    validation::{check, ValidJson},
+};
+
+pub async fn hello_world() -> &'static str {
+    "Hello World!"
+}
+
+pub async fn call_with_id(Path(id): Path<u32>) -> impl IntoResponse {
+    info!("Id : {id}");
+    (StatusCode::OK, format!("Hello from {id}")).into_response()
+}
+
+pub async fn call_with_query_params(Query(params): Query<HashMap<String, String>>) -> &'static str {
+    for (name, age) in &params {
+        info!("The name is {} and the age is {}", name, age);
+    }
+
+    "Hello"
+}
+
+#[utoipa::path(
+    post,
+    path = "/identity",
+    tag = "basics",
+    request_body = Identity,
+    responses(
+        (status = 200, description = "The identity, echoed back", body = Identity),
+        (status = 422, description = "Invalid input"),
+    )
+)]
+pub async fn parse_json(ValidJson(identity): ValidJson<Identity>) -> Result<Response, AppError> {
+    info!(
+        "The name is {} and the age is {}",
+        identity.name, identity.age
+    );
+    // Json(json!({
+    //    "name": identity.name,
+    //    "age":identity.age
+    // }))
+
+    let json_data = to_string_pretty(&identity)?;
+
+    Ok(Response::new(Body::new(json_data)))
+}
+
+pub async fn returns_with_status_code() -> impl IntoResponse {
+    (StatusCode::OK, "Okay!")
+}
+
+pub async fn parse_headers(req: Request) -> impl IntoResponse {
+    let headers = req.headers();
+    let method = req.method();
+    let uri = req.uri();
+    let version = req.version();
+
+    info!(
+        "The header details are : {:#?}, {:#?}, {:#?}, {:#?}",
+        headers, method, uri, version
+    );
+}
+
+pub async fn not_found() -> impl IntoResponse {
+    (StatusCode::NOT_FOUND, "404 | Not Found").into_response()
+}
+
+pub async fn hello(Extension(identity): Extension<Arc<Identity>>) -> &'static str {
+    info!("Identity : {}", Redacted(&*identity));
+    "Hello"
+}
+
+pub async fn redirect() -> impl IntoResponse {
+    Redirect::to("/hello")
+}
+
+pub async fn profile() -> impl IntoResponse {
+    (StatusCode::OK, "Profile")
+}
+
+pub async fn about() -> impl IntoResponse {
+    (StatusCode::OK, "About")
+}
+
+pub async fn wildcard_route(Path(wildcard): Path<String>) -> impl IntoResponse {
+    info!("Wildcard route : {}", wildcard);
+
+    (StatusCode::OK, wildcard)
+}
+
+pub async fn get_uri(uri: Uri) -> impl IntoResponse {
+    info!("The uri is : {:#?}", uri);
+    (StatusCode::OK, uri.to_string())
+}
+
+pub async fn submit_form(Form(identity): Form<Identity>) -> Result<StatusCode, AppError> {
+    check(&identity)?;
+    info!("The form is : {}", Redacted(&identity));
+    Ok(StatusCode::OK)
+}
+
+pub async fn nested_shared_route(State(state): State<CounterCache>) -> impl IntoResponse {
+    info!("The shared state is : {:?}", state);
+    (StatusCode::OK, "Okay")
+}
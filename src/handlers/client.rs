@@ -0,0 +1,72 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{
+        header::{CACHE_CONTROL, VARY},
+        HeaderMap, StatusCode,
+    },
+    response::IntoResponse,
+};
+
+use crate::{
+    client::{client_type, MinVersions},
+    config::Config,
+    handlers::{files, settings, users},
+    models::{ClientConfig, ClientLimits, ResponseData},
+};
+
+// Public, so frontends can read it before signing in
+pub async fn client_config(
+    State(config): State<Arc<Config>>,
+    State(min_versions): State<MinVersions>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let client_type = client_type(&headers);
+    let groups = &config.route_groups;
+
+    let mut features = [
+        ("auth", groups.auth),
+        ("users", groups.users),
+        ("admin", groups.admin),
+        ("files", groups.files),
+        ("media", groups.media),
+        ("settings", groups.settings),
+    ]
+    .into_iter()
+    .map(|(name, enabled)| (name.to_string(), enabled))
+    .collect::<BTreeMap<_, _>>();
+    features.extend(config.clients.features.clone());
+    if let Some(overrides) = config.clients.feature_overrides.get(&client_type) {
+        features.extend(overrides.clone());
+    }
+
+    let body = ClientConfig {
+        minimum_version: min_versions.get(&client_type),
+        upgrade_url: min_versions.upgrade_url(&client_type),
+        client_type,
+        features,
+        limits: ClientLimits {
+            max_upload_bytes: files::MAX_MULTIPART_SIZE,
+            max_chunk_bytes: files::MAX_CHUNK_SIZE,
+            max_page_size: users::MAX_PAGE_SIZE,
+            max_settings: settings::MAX_SETTINGS_PER_USER,
+            max_setting_value_bytes: settings::MAX_VALUE_SIZE,
+            auth_requests_per_minute: config.auth_rate_limit.per_minute,
+        },
+        locales: config.clients.locales.clone(),
+    };
+
+    // Differs by client, so shared caches have to key on what the type is read from
+    (
+        [
+            (CACHE_CONTROL, "private, max-age=300"),
+            (VARY, "User-Agent, X-Client-Type"),
+        ],
+        ResponseData {
+            status: StatusCode::OK.as_u16(),
+            message: "Client configuration".to_string(),
+            data: body,
+        },
+    )
+}
@@ -0,0 +1,312 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::to_string_pretty;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info_span, Instrument};
+
+use crate::{
+    counter::{check_buckets, parse_window, validate_name, CounterStore, DEFAULT_COUNTER},
+    error::AppError,
+    models::{
+        Counter, CounterIncrement, CounterStats, CounterStatsQuery, CounterValue, NamedCounter,
+        ResponseData,
+    },
+    openapi::{Empty, ErrorBody},
+    trace::Traced,
+};
+
+pub const DEFAULT_STATS_WINDOW: &str = "1h";
+pub const DEFAULT_STATS_BUCKETS: u64 = 60;
+
+// The store keeps the default counter within `u32`, a value stored before it did shows as
+// `u32::MAX` here and in full through `/counter/default`
+fn legacy_value(value: u64) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+#[utoipa::path(
+    get,
+    path = "/counter",
+    tag = "counter",
+    responses((status = 200, description = "The default counter, as text", body = String, content_type = "text/plain", example = json!("The count is : Counter { value: 42 }")))
+)]
+pub async fn get_counter(State(counter): State<CounterStore>) -> Result<Response, AppError> {
+    let count = Counter {
+        value: legacy_value(counter.get(DEFAULT_COUNTER).await?),
+    };
+    Ok((StatusCode::OK, format!("The count is : {:?}", count)).into_response())
+}
+
+#[utoipa::path(
+    put,
+    path = "/counter",
+    tag = "counter",
+    request_body = Counter,
+    responses(
+        (status = 200, description = "The new value", body = Counter),
+    )
+)]
+pub async fn put_counter(
+    State(counter): State<CounterStore>,
+    Json(c): Json<Counter>,
+) -> Result<Response, AppError> {
+    let counter = Counter {
+        value: legacy_value(counter.set(DEFAULT_COUNTER, c.value.into()).await?),
+    };
+
+    let json_data = to_string_pretty(&counter)?;
+
+    Ok(Response::new(Body::new(json_data)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/counter",
+    tag = "counter",
+    responses(
+        (status = 200, description = "Reset to zero", body = String, content_type = "text/plain", example = json!("The counter has been deleted.")),
+    )
+)]
+pub async fn delete_counter(State(counter): State<CounterStore>) -> Result<Response, AppError> {
+    counter.set(DEFAULT_COUNTER, 0).await?;
+
+    Ok((StatusCode::OK, "The counter has been deleted.").into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/counter",
+    tag = "counter",
+    responses(
+        (status = 200, description = "Increased by one", body = String, content_type = "text/plain", example = json!("The count has been increased.")),
+    )
+)]
+pub async fn increase_counter(State(counter): State<CounterStore>) -> Result<Response, AppError> {
+    counter.increment(DEFAULT_COUNTER, 1).await?;
+
+    Ok((StatusCode::OK, "The count has been increased.").into_response())
+}
+
+fn named(name: String, value: u64, message: &str) -> ResponseData<NamedCounter> {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: message.to_string(),
+        data: NamedCounter { name, value },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/counter/{name}",
+    tag = "counter",
+    params(("name" = String, Path, description = "1 to 64 letters, digits, `_`, `-` or `.`")),
+    responses(
+        (status = 200, body = ResponseData<NamedCounter>),
+        (status = 404, description = "No such counter", body = ErrorBody),
+    )
+)]
+pub async fn get_named_counter(
+    State(counter): State<CounterStore>,
+    Path(name): Path<String>,
+) -> Result<ResponseData<NamedCounter>, AppError> {
+    validate_name(&name)?;
+    let value = counter.get(&name).await?;
+    Ok(named(name, value, "Counter"))
+}
+
+// Starts at zero, or at `value` when a body is sent
+#[utoipa::path(
+    post,
+    path = "/counter/{name}",
+    tag = "counter",
+    params(("name" = String, Path, description = "1 to 64 letters, digits, `_`, `-` or `.`")),
+    request_body(content = Option<CounterValue>, description = "Optional, the counter starts at zero without it"),
+    responses(
+        (status = 201, body = ResponseData<NamedCounter>),
+        (status = 409, description = "The counter already exists", body = ErrorBody),
+    )
+)]
+pub async fn create_named_counter(
+    State(counter): State<CounterStore>,
+    Path(name): Path<String>,
+    body: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    validate_name(&name)?;
+    let initial = if body.is_empty() {
+        0
+    } else {
+        serde_json::from_slice::<CounterValue>(&body)
+            .map_err(|e| AppError::Validation(format!("Invalid counter : {}", e)))?
+            .value
+    };
+    let value = counter.create(&name, initial).await?;
+
+    let mut response = named(name, value, "Counter created");
+    response.status = StatusCode::CREATED.as_u16();
+    Ok((StatusCode::CREATED, response))
+}
+
+#[utoipa::path(
+    put,
+    path = "/counter/{name}",
+    tag = "counter",
+    params(("name" = String, Path, description = "1 to 64 letters, digits, `_`, `-` or `.`")),
+    request_body = CounterValue,
+    responses(
+        (status = 200, body = ResponseData<NamedCounter>),
+        (status = 400, description = "The default counter can't go past `u32::MAX`", body = ErrorBody)
+    )
+)]
+pub async fn set_named_counter(
+    State(counter): State<CounterStore>,
+    Path(name): Path<String>,
+    Json(c): Json<CounterValue>,
+) -> Result<ResponseData<NamedCounter>, AppError> {
+    validate_name(&name)?;
+    let value = counter.set(&name, c.value).await?;
+    Ok(named(name, value, "Counter set"))
+}
+
+#[utoipa::path(
+    post,
+    path = "/counter/{name}/increment",
+    tag = "counter",
+    params(("name" = String, Path, description = "1 to 64 letters, digits, `_`, `-` or `.`")),
+    request_body = CounterIncrement,
+    responses(
+        (status = 200, body = ResponseData<NamedCounter>),
+        (status = 400, description = "The default counter can't go past `u32::MAX`", body = ErrorBody)
+    )
+)]
+pub async fn increment_named_counter(
+    State(counter): State<CounterStore>,
+    Path(name): Path<String>,
+    Json(increment): Json<CounterIncrement>,
+) -> Result<ResponseData<NamedCounter>, AppError> {
+    validate_name(&name)?;
+    let value = counter.increment(&name, increment.by).await?;
+    Ok(named(name, value, "Counter increased"))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/counter/{name}",
+    tag = "counter",
+    params(("name" = String, Path, description = "1 to 64 letters, digits, `_`, `-` or `.`")),
+    responses(
+        (status = 200, description = "Deleted, or for `default` reset to zero", body = ResponseData<Empty>),
+        (status = 404, description = "No such counter", body = ErrorBody),
+    )
+)]
+pub async fn delete_named_counter(
+    State(counter): State<CounterStore>,
+    Path(name): Path<String>,
+) -> Result<ResponseData<()>, AppError> {
+    validate_name(&name)?;
+    counter.delete(&name).await?;
+    let message = if name == DEFAULT_COUNTER {
+        "Counter reset"
+    } else {
+        "Counter deleted"
+    };
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: message.to_string(),
+        data: (),
+    })
+}
+
+// Sends the current value of the default counter, then every change made through this
+// instance, as `{ "name": "default", "value": n }`. Changes carry the `requestId` of the
+// request that made them
+pub async fn counter_updates(
+    ws: WebSocketUpgrade,
+    State(counter): State<CounterStore>,
+) -> Response {
+    ws.on_upgrade(move |socket| push_counter_updates(socket, counter))
+}
+
+async fn push_counter_updates(mut socket: WebSocket, counter: CounterStore) {
+    let mut updates = counter.subscribe();
+    let mut update = current_update(&counter);
+
+    while send_counter(&mut socket, &update).await {
+        update = loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(update) => break update,
+                    // Fell behind, the current value is all a client needs
+                    Err(RecvError::Lagged(_)) => break current_update(&counter),
+                    Err(RecvError::Closed) => return,
+                },
+                // Incoming messages are ignored, this only notices the client leaving
+                incoming = socket.recv() => match incoming {
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                    Some(Ok(_)) => {}
+                },
+            }
+        };
+    }
+}
+
+fn current_update(counter: &CounterStore) -> Traced<NamedCounter> {
+    Traced::current(NamedCounter {
+        name: DEFAULT_COUNTER.to_string(),
+        value: counter.cache.get(),
+    })
+}
+
+// False once the client is gone. Pushes are traced as part of the request behind the change
+async fn send_counter(socket: &mut WebSocket, update: &Traced<NamedCounter>) -> bool {
+    let span = info_span!(parent: &update.context.span, "counter_push");
+    async {
+        match serde_json::to_string(&update.payload()) {
+            Ok(message) => {
+                debug!(value = update.message.value, "Pushing counter update");
+                socket.send(Message::Text(message.into())).await.is_ok()
+            }
+            Err(e) => {
+                debug!("Error serializing counter update : {}", e);
+                false
+            }
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+// `?window=1h&buckets=60` by default, of the default counter unless `name` is given
+#[utoipa::path(
+    get,
+    path = "/counter/stats",
+    tag = "counter",
+    params(CounterStatsQuery),
+    responses(
+        (status = 200, body = ResponseData<CounterStats>),
+        (status = 400, description = "Invalid window or bucket count", body = ErrorBody),
+    )
+)]
+pub async fn counter_stats(
+    State(counter): State<CounterStore>,
+    Query(query): Query<CounterStatsQuery>,
+) -> Result<ResponseData<CounterStats>, AppError> {
+    let name = query.name.as_deref().unwrap_or(DEFAULT_COUNTER);
+    validate_name(name)?;
+    let window = parse_window(query.window.as_deref().unwrap_or(DEFAULT_STATS_WINDOW))?;
+    let buckets = query.buckets.unwrap_or(DEFAULT_STATS_BUCKETS);
+    check_buckets(window, buckets)?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Counter stats".to_string(),
+        data: counter.stats(name, window, buckets).await?,
+    })
+}
@@ -0,0 +1,594 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{
+        multipart::{Multipart, MultipartError},
+        Path, Request, State,
+    },
+    http::{
+        header::{
+            ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+            RANGE, X_CONTENT_TYPE_OPTIONS,
+        },
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures_util::stream;
+use jsonwebtoken::get_current_timestamp;
+use mongodb::{
+    action::Action,
+    bson::{doc, Document},
+    Collection, Database,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{
+    auth::AuthUser,
+    config::Config,
+    db::{time_left, with_retries, TenantDb},
+    error::AppError,
+    id::{FileId, RawId},
+    models::{FileEntry, NewUpload, ResponseData, StoredFile},
+    schema::decode,
+};
+
+pub const UPLOAD_DIR: &str = "uploads";
+pub const MAX_UPLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+// Longer than the default `files` timeout, so only a crashed request's lease runs out
+pub const CHUNK_LEASE_SECS: i64 = 15 * 60;
+// For `POST /upload`, which takes a whole file in one request
+pub const MAX_MULTIPART_SIZE: u64 = 100 * 1024 * 1024;
+// Room for the multipart boundaries and headers around the file itself
+pub const MULTIPART_BODY_LIMIT: usize = MAX_MULTIPART_SIZE as usize + 64 * 1024;
+// Also what downloads show in place, so nothing here may run scripts
+pub const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "application/pdf",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/webp",
+    "text/plain",
+];
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+// The most recent ones, per user, embedded by `?include=files`
+pub const MAX_INCLUDED_FILES: i32 = 20;
+
+fn upload_path(id: &RawId) -> std::path::PathBuf {
+    std::path::Path::new(UPLOAD_DIR).join(id.to_hex())
+}
+
+async fn find_upload(database: &Database, id: &str, owner: &str) -> Result<StoredFile, AppError> {
+    let not_found = || AppError::NotFound("Upload does not exist".to_string());
+    let id = id.parse::<FileId>().map_err(|_| not_found())?.raw();
+    let files_collection: Collection<Document> = database.collection("files");
+    let document = with_retries(|| {
+        files_collection
+            .find_one(doc! { "_id": id, "owner": owner })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+    })
+    .await?
+    .ok_or_else(not_found)?;
+    decode(database, "files", document)
+}
+
+pub async fn create_upload(
+    TenantDb(database, _): TenantDb,
+    State(config): State<Arc<Config>>,
+    user: AuthUser,
+    Json(input): Json<NewUpload>,
+) -> Result<ResponseData<FileId>, AppError> {
+    let valid_sha256 = hex::decode(&input.sha256).is_ok_and(|digest| digest.len() == 32);
+    if input.size > MAX_UPLOAD_SIZE || !valid_sha256 {
+        return Err(AppError::Validation(
+            "Invalid upload size or checksum".to_string(),
+        ));
+    }
+
+    let id = RawId::generate(config.id_format);
+    let files_collection: Collection<StoredFile> = database.collection("files");
+    files_collection
+        .insert_one(StoredFile {
+            id: Some(id),
+            owner: user.username().to_string(),
+            size: input.size,
+            sha256: input.sha256.to_lowercase(),
+            offset: 0,
+            finalized: false,
+            file_name: None,
+            content_type: None,
+            chunk_lease_until: None,
+        })
+        .await?;
+
+    fs::create_dir_all(UPLOAD_DIR).await?;
+    File::create(upload_path(&id)).await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Upload created".to_string(),
+        data: id.into(),
+    })
+}
+
+// Lets a client that lost its connection find out where to resume from
+pub async fn upload_offset(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let upload = find_upload(&database, &id, user.username()).await?;
+    Ok((
+        StatusCode::OK,
+        [
+            ("Upload-Offset", upload.offset.to_string()),
+            ("Upload-Length", upload.size.to_string()),
+        ],
+    )
+        .into_response())
+}
+
+// The offset the upload is really at, for a chunk that lost the race for its offset
+async fn offset_conflict(database: &Database, id: &str, owner: &str) -> AppError {
+    match find_upload(database, id, owner).await {
+        Ok(upload) => AppError::Conflict(format!(
+            "Upload offset mismatch, expected {}",
+            upload.offset
+        )),
+        Err(e) => e,
+    }
+}
+
+pub async fn upload_chunk(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+    Path(id): Path<String>,
+    request: Request,
+) -> Result<ResponseData<u64>, AppError> {
+    let upload = find_upload(&database, &id, user.username()).await?;
+
+    // Chunks must arrive in order, starting exactly where the last one ended
+    let offset = request
+        .headers()
+        .get("Upload-Offset")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if upload.finalized || offset != Some(upload.offset) {
+        return Err(AppError::Conflict(format!(
+            "Upload offset mismatch, expected {}",
+            upload.offset
+        )));
+    }
+
+    // tus style checksum header : `Upload-Checksum: sha256 <base64 digest>`
+    let checksum = request
+        .headers()
+        .get("Upload-Checksum")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("sha256 "))
+        .and_then(|value| BASE64.decode(value).ok());
+
+    let Ok(chunk) = to_bytes(request.into_body(), MAX_CHUNK_SIZE).await else {
+        return Err(AppError::PayloadTooLarge(
+            "Chunk too large or incomplete".to_string(),
+        ));
+    };
+
+    if checksum.as_deref() != Some(Sha256::digest(&chunk).as_slice()) {
+        return Err(AppError::Validation("Chunk checksum mismatch".to_string()));
+    }
+
+    let new_offset = upload.offset + chunk.len() as u64;
+    if new_offset > upload.size {
+        return Err(AppError::Validation(
+            "Chunk exceeds the declared size".to_string(),
+        ));
+    }
+
+    let upload_id = upload
+        .id
+        .ok_or_else(|| AppError::Internal("Upload has no id".to_string()))?;
+    let owner = user.username();
+
+    // Claimed before anything is written, so two chunks sent for the same offset can't
+    // both land on disk. The loser gets a 409 with the offset to resume from
+    let files_collection: Collection<StoredFile> = database.collection("files");
+    let now = get_current_timestamp() as i64;
+    let claimed = files_collection
+        .update_one(
+            doc! {
+                "_id": upload_id,
+                "offset": upload.offset as i64,
+                "finalized": false,
+                "$or": [
+                    { "chunk_lease_until": { "$exists": false } },
+                    { "chunk_lease_until": { "$lt": now } },
+                ],
+            },
+            doc! { "$set": { "chunk_lease_until": now + CHUNK_LEASE_SECS } },
+        )
+        .await?;
+    if claimed.matched_count != 1 {
+        return Err(offset_conflict(&database, &id, owner).await);
+    }
+
+    // Writing at the offset (rather than appending) keeps a retried chunk from being stored twice
+    let written: io::Result<()> = async {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(upload_path(&upload_id))
+            .await?;
+        file.seek(io::SeekFrom::Start(upload.offset)).await?;
+        file.write_all(&chunk).await?;
+        file.flush().await
+    }
+    .await;
+    if let Err(e) = written {
+        files_collection
+            .update_one(
+                doc! { "_id": upload_id },
+                doc! { "$unset": { "chunk_lease_until": "" } },
+            )
+            .await?;
+        return Err(e.into());
+    }
+
+    let stored = files_collection
+        .update_one(
+            doc! { "_id": upload_id, "offset": upload.offset as i64 },
+            doc! {
+                "$set": { "offset": new_offset as i64 },
+                "$unset": { "chunk_lease_until": "" },
+            },
+        )
+        .await?;
+    if stored.matched_count != 1 {
+        return Err(offset_conflict(&database, &id, owner).await);
+    }
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Chunk stored".to_string(),
+        data: new_offset,
+    })
+}
+
+pub async fn finalize_upload(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<ResponseData<String>, AppError> {
+    let upload = find_upload(&database, &id, user.username()).await?;
+
+    if upload.offset != upload.size {
+        return Err(AppError::Conflict("Upload is incomplete".to_string()));
+    }
+
+    let id = upload
+        .id
+        .ok_or_else(|| AppError::Internal("Upload has no id".to_string()))?;
+    let mut file = File::open(upload_path(&id)).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    let digest = hex::encode(hasher.finalize());
+    if digest != upload.sha256 {
+        return Err(AppError::Validation(format!(
+            "File checksum mismatch, got {}",
+            digest
+        )));
+    }
+
+    let files_collection: Collection<StoredFile> = database.collection("files");
+    files_collection
+        .update_one(doc! { "_id": id }, doc! { "$set": { "finalized": true } })
+        .await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Upload finalized".to_string(),
+        data: digest,
+    })
+}
+
+// Streams the `file` field of a multipart form to disk, hashing it on the way
+pub async fn upload_multipart(
+    TenantDb(database, _): TenantDb,
+    State(config): State<Arc<Config>>,
+    user: AuthUser,
+    mut multipart: Multipart,
+) -> Result<ResponseData<FileEntry>, AppError> {
+    let invalid = |e: MultipartError| AppError::Validation(format!("Invalid upload : {}", e));
+    let mut field = loop {
+        match multipart.next_field().await.map_err(invalid)? {
+            Some(field) if field.name() == Some("file") => break field,
+            Some(_) => continue,
+            None => return Err(AppError::Validation("Missing `file` field".to_string())),
+        }
+    };
+
+    let content_type = field
+        .content_type()
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_lowercase()
+        })
+        .unwrap_or_default();
+    if !ALLOWED_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(AppError::Validation(format!(
+            "Content type {:?} isn't accepted, allowed are {}",
+            content_type,
+            ALLOWED_CONTENT_TYPES.join(", ")
+        )));
+    }
+    // Only the last path component, browsers on some platforms send a full path
+    let file_name = field
+        .file_name()
+        .and_then(|name| name.rsplit(['/', '\\']).next())
+        .filter(|name| !name.is_empty())
+        .map(str::to_string);
+
+    let id = RawId::generate(config.id_format);
+    let path = upload_path(&id);
+    fs::create_dir_all(UPLOAD_DIR).await?;
+    let mut file = File::create(&path).await?;
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+
+    let written: Result<(), AppError> = async {
+        while let Some(chunk) = field.chunk().await.map_err(invalid)? {
+            size += chunk.len() as u64;
+            if size > MAX_MULTIPART_SIZE {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "Files are limited to {} bytes",
+                    MAX_MULTIPART_SIZE
+                )));
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = written {
+        // Nothing refers to a partial file, so it would never be cleaned up otherwise
+        let _ = fs::remove_file(&path).await;
+        return Err(e);
+    }
+
+    let stored = StoredFile {
+        id: Some(id),
+        owner: user.username().to_string(),
+        size,
+        sha256: hex::encode(hasher.finalize()),
+        offset: size,
+        finalized: true,
+        file_name,
+        content_type: Some(content_type),
+        chunk_lease_until: None,
+    };
+    let files_collection: Collection<StoredFile> = database.collection("files");
+    if let Err(e) = files_collection.insert_one(&stored).await {
+        let _ = fs::remove_file(&path).await;
+        return Err(e.into());
+    }
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "File uploaded".to_string(),
+        data: FileEntry {
+            id: id.into(),
+            file_name: stored.file_name,
+            content_type: stored.content_type,
+            size,
+            sha256: stored.sha256,
+        },
+    })
+}
+
+// A single `bytes=start-end`, `bytes=start-` or `bytes=-suffix` range, clamped to the file.
+// `None` when the header can't be honoured, `Some(Err)` when it asks for nothing in the file
+fn parse_range(header: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    // Several ranges would need a multipart response, the whole file is served instead
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(size.checked_sub(1)?),
+        ),
+    };
+    if start > end || start >= size {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+// Finalized files only, with `Range` support so large downloads can resume
+pub async fn download_file(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let upload = find_upload(&database, &id, user.username()).await?;
+    if !upload.finalized {
+        return Err(AppError::Conflict("Upload is incomplete".to_string()));
+    }
+    let object_id = upload
+        .id
+        .ok_or_else(|| AppError::Internal("Upload has no id".to_string()))?;
+
+    let range = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_range(value, upload.size));
+    let (status, start, end) = match range {
+        Some(Ok((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+        Some(Err(())) => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(CONTENT_RANGE, format!("bytes */{}", upload.size))],
+            )
+                .into_response());
+        }
+        None => (StatusCode::OK, 0, upload.size.saturating_sub(1)),
+    };
+    let length = if upload.size == 0 { 0 } else { end - start + 1 };
+
+    let mut file = File::open(upload_path(&object_id)).await?;
+    file.seek(io::SeekFrom::Start(start)).await?;
+    let body = Body::from_stream(stream::unfold(
+        (file, length),
+        |(mut file, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            let mut buffer = vec![0; DOWNLOAD_CHUNK_SIZE.min(remaining as usize)];
+            match file.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    buffer.truncate(read);
+                    Some((Ok(Bytes::from(buffer)), (file, remaining - read as u64)))
+                }
+                Err(e) => Some((Err(e), (file, 0))),
+            }
+        },
+    ));
+
+    let content_type = upload
+        .content_type
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let inline = is_inline(&content_type);
+    let mut response = (
+        status,
+        [
+            (CONTENT_TYPE, content_type),
+            (CONTENT_LENGTH, length.to_string()),
+            (ACCEPT_RANGES, "bytes".to_string()),
+            (ETAG, format!("\"{}\"", upload.sha256)),
+            // Served as declared, never sniffed into something else
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        body,
+    )
+        .into_response();
+    if status == StatusCode::PARTIAL_CONTENT {
+        if let Ok(value) = format!("bytes {}-{}/{}", start, end, upload.size).parse() {
+            response.headers_mut().insert(CONTENT_RANGE, value);
+        }
+    }
+    // Named files are always downloaded, unnamed ones unless they are safe to show
+    let disposition = upload
+        .file_name
+        .map(|name| name.replace(['"', '\\'], "_"))
+        .and_then(|name| format!("attachment; filename=\"{}\"", name).parse().ok())
+        .or_else(|| (!inline).then(|| HeaderValue::from_static("attachment")));
+    if let Some(value) = disposition {
+        response.headers_mut().insert(CONTENT_DISPOSITION, value);
+    }
+    Ok(response)
+}
+
+// Ignoring parameters like `charset`. Anything else, like uploads stored before the allowlist,
+// is only ever downloaded
+fn is_inline(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    ALLOWED_CONTENT_TYPES
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(essence))
+}
+
+#[derive(Debug, Deserialize)]
+struct OwnerFiles {
+    #[serde(rename = "_id")]
+    owner: String,
+    files: Vec<StoredFile>,
+}
+
+// Finalized uploads of several owners in one query, for `?include=files`
+pub async fn files_by_owner(
+    database: &Database,
+    owners: &[String],
+) -> Result<HashMap<String, Vec<FileEntry>>, AppError> {
+    if owners.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let files_collection: Collection<Document> = database.collection("files");
+    let pipeline = vec![
+        doc! { "$match": { "owner": { "$in": owners }, "finalized": true } },
+        doc! { "$sort": { "_id": -1 } },
+        doc! { "$group": { "_id": "$owner", "files": { "$push": "$$ROOT" } } },
+        doc! { "$project": { "files": { "$slice": ["$files", MAX_INCLUDED_FILES] } } },
+    ];
+    let mut cursor = with_retries(|| {
+        files_collection
+            .aggregate(pipeline.clone())
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+            .with_type::<OwnerFiles>()
+    })
+    .await?;
+
+    let mut by_owner = HashMap::new();
+    while cursor.advance().await? {
+        let group = cursor.deserialize_current()?;
+        let entries = group
+            .files
+            .into_iter()
+            .filter_map(|file| {
+                Some(FileEntry {
+                    id: file.id?.into(),
+                    file_name: file.file_name,
+                    content_type: file.content_type,
+                    size: file.size,
+                    sha256: file.sha256,
+                })
+            })
+            .collect();
+        by_owner.insert(group.owner, entries);
+    }
+    Ok(by_owner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_shows_safe_types_inline() {
+        assert!(is_inline("image/png"));
+        assert!(is_inline("Text/Plain; charset=utf-8"));
+        for content_type in ["text/html", "image/svg+xml", "application/octet-stream", ""] {
+            assert!(!is_inline(content_type), "{}", content_type);
+        }
+    }
+}
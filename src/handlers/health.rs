@@ -0,0 +1,47 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use tracing::warn;
+
+use crate::{
+    health::HealthRegistry,
+    models::{HealthStatus, Readiness, ResponseData},
+};
+
+// Liveness only says the process is serving requests, it never touches dependencies
+pub async fn healthz() -> impl IntoResponse {
+    ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Alive".to_string(),
+        data: (),
+    }
+}
+
+// Answers 503 until every registered check passes, so orchestrators hold traffic back
+pub async fn readyz(State(registry): State<HealthRegistry>) -> impl IntoResponse {
+    let dependencies = registry.run_all().await;
+
+    let ready = dependencies
+        .values()
+        .all(|dependency| dependency.status == HealthStatus::Up);
+    let (status, message) = if ready {
+        (StatusCode::OK, "Ready")
+    } else {
+        warn!("Readiness check failed : {:?}", dependencies);
+        (StatusCode::SERVICE_UNAVAILABLE, "Not ready")
+    };
+
+    (
+        status,
+        ResponseData {
+            status: status.as_u16(),
+            message: message.to_string(),
+            data: Readiness {
+                status: if ready {
+                    HealthStatus::Up
+                } else {
+                    HealthStatus::Down
+                },
+                dependencies,
+            },
+        },
+    )
+}
@@ -0,0 +1,360 @@
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    extract::{Query, State},
+    http::header::{CACHE_CONTROL, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    error::AppError,
+    models::{ImageParams, QrFormat, QrParams},
+    trace::spawn_traced,
+};
+
+pub const IMAGE_CACHE_DIR: &str = "image_cache";
+pub const MAX_REMOTE_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+pub const MAX_IMAGE_DIMENSION: u32 = 2048;
+pub const IMAGE_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+pub const MAX_QR_DATA_LENGTH: usize = 512;
+pub const MAX_QR_SIZE: u32 = 1024;
+pub const MAX_IMAGE_REDIRECTS: usize = 5;
+
+// `hosts` is `MediaConfig::image_hosts`
+fn is_allowed_image_url(url: &reqwest::Url, hosts: &[String]) -> bool {
+    url.scheme() == "https"
+        && url
+            .host_str()
+            .is_some_and(|host| hosts.iter().any(|allowed| allowed == host))
+}
+
+// For `/img`. Every redirect hop is checked against the allowlist too, or an allowed host
+// could send the fetch on to an internal address
+pub fn image_client(hosts: &[String]) -> reqwest::Result<reqwest::Client> {
+    let hosts: Arc<[String]> = hosts.into();
+    let redirects = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_IMAGE_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if is_allowed_image_url(attempt.url(), &hosts) {
+            attempt.follow()
+        } else {
+            attempt.stop()
+        }
+    });
+    reqwest::Client::builder()
+        .timeout(IMAGE_FETCH_TIMEOUT)
+        .redirect(redirects)
+        .build()
+}
+
+async fn fetch_remote_image(client: &reqwest::Client, url: reqwest::Url) -> Option<Vec<u8>> {
+    let mut response = client.get(url).send().await.ok()?.error_for_status().ok()?;
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_REMOTE_IMAGE_SIZE as u64)
+    {
+        return None;
+    }
+
+    // The length header can't be trusted, so the limit is enforced while reading as well
+    let mut image = Vec::new();
+    while let Some(chunk) = response.chunk().await.ok()? {
+        if image.len() + chunk.len() > MAX_REMOTE_IMAGE_SIZE {
+            return None;
+        }
+        image.extend_from_slice(&chunk);
+    }
+    Some(image)
+}
+
+// Fits the image in `width` by `height`, a missing one is the image's own. Never scales up,
+// an image already inside the box is only converted to PNG
+fn resize_image(image: &[u8], width: Option<u32>, height: Option<u32>) -> Option<Vec<u8>> {
+    let mut reader = image::ImageReader::new(io::Cursor::new(image))
+        .with_guessed_format()
+        .ok()?;
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(8192);
+    limits.max_image_height = Some(8192);
+    reader.limits(limits);
+
+    let decoded = reader.decode().ok()?;
+    let width = width.map_or(decoded.width(), |width| width.min(decoded.width()));
+    let height = height.map_or(decoded.height(), |height| height.min(decoded.height()));
+    let resized = if (width, height) == (decoded.width(), decoded.height()) {
+        decoded
+    } else {
+        decoded.resize(width, height, image::imageops::FilterType::Lanczos3)
+    };
+
+    let mut encoded = Vec::new();
+    resized
+        .write_to(&mut io::Cursor::new(&mut encoded), image::ImageFormat::Png)
+        .ok()?;
+    Some(encoded)
+}
+
+// Bytes held in `IMAGE_CACHE_DIR`, added up as images are written so the directory is only
+// scanned once it goes past the limit. Unknown until the first scan, images can be left
+// from an earlier run
+#[derive(Clone, Default)]
+pub struct ImageCache(Arc<Mutex<CacheSize>>);
+
+#[derive(Default)]
+struct CacheSize {
+    bytes: Option<u64>,
+    evicting: bool,
+}
+
+impl ImageCache {
+    // Counts a written image, `true` when the caller should run `evict`
+    fn record(&self, written: u64, max_bytes: u64) -> bool {
+        let mut size = self.0.lock().unwrap();
+        if let Some(bytes) = &mut size.bytes {
+            *bytes += written;
+        }
+        if size.evicting || size.bytes.is_some_and(|bytes| bytes <= max_bytes) {
+            return false;
+        }
+        size.evicting = true;
+        true
+    }
+
+    // Trims to 90% of `max_bytes`, so the next few writes don't need a scan of their own
+    async fn evict(&self, max_bytes: u64) {
+        let remaining = evict_images(max_bytes / 10 * 9).await;
+        let mut size = self.0.lock().unwrap();
+        size.evicting = false;
+        match remaining {
+            Ok(remaining) => size.bytes = Some(remaining),
+            Err(e) => {
+                size.bytes = None;
+                warn!("Error trimming the image cache : {}", e);
+            }
+        }
+    }
+}
+
+// Stores a resized image under its final name in one step, so concurrent requests never
+// read a partial file, then trims the cache back to `max_bytes` when it grew past it
+async fn cache_image(
+    cache: &ImageCache,
+    path: &Path,
+    image: &[u8],
+    max_bytes: u64,
+) -> io::Result<()> {
+    fs::create_dir_all(IMAGE_CACHE_DIR).await?;
+    let mut suffix = [0u8; 8];
+    OsRng.fill_bytes(&mut suffix);
+    let temporary = path.with_extension(format!("{}.tmp", hex::encode(suffix)));
+    fs::write(&temporary, image).await?;
+    if let Err(e) = fs::rename(&temporary, path).await {
+        let _ = fs::remove_file(&temporary).await;
+        return Err(e);
+    }
+    if cache.record(image.len() as u64, max_bytes) {
+        let cache = cache.clone();
+        spawn_traced(async move { cache.evict(max_bytes).await });
+    }
+    Ok(())
+}
+
+// Removes the earliest written images until the rest fit in `max_bytes`, returning the size
+// of the rest
+async fn evict_images(max_bytes: u64) -> io::Result<u64> {
+    let mut entries = fs::read_dir(IMAGE_CACHE_DIR).await?;
+    let mut images = Vec::new();
+    let mut total = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            total += metadata.len();
+            images.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+    images.sort();
+    for (_, size, path) in images {
+        if total <= max_bytes {
+            break;
+        }
+        if fs::remove_file(&path).await.is_ok() {
+            total -= size;
+        }
+    }
+    Ok(total)
+}
+
+pub async fn image_proxy(
+    State(client): State<reqwest::Client>,
+    State(cache): State<ImageCache>,
+    State(config): State<Arc<Config>>,
+    Query(params): Query<ImageParams>,
+) -> Result<Response, AppError> {
+    let media = &config.media;
+    let Some(url) = reqwest::Url::parse(&params.url)
+        .ok()
+        .filter(|url| is_allowed_image_url(url, &media.image_hosts))
+    else {
+        return Err(AppError::Validation(
+            "Image host is not allowed".to_string(),
+        ));
+    };
+
+    // A missing dimension keeps the image's own along that axis
+    let (width, height) = (params.w, params.h);
+    if [width, height]
+        .into_iter()
+        .flatten()
+        .any(|size| size == 0 || size > MAX_IMAGE_DIMENSION)
+    {
+        return Err(AppError::Validation("Invalid image dimensions".to_string()));
+    }
+
+    let cache_key = hex::encode(Sha256::digest(format!("{}|{:?}|{:?}", url, width, height)));
+    let cache_path = std::path::Path::new(IMAGE_CACHE_DIR).join(cache_key);
+    let headers = [
+        (CONTENT_TYPE, "image/png"),
+        (CACHE_CONTROL, "public, max-age=86400"),
+    ];
+
+    if let Ok(cached) = fs::read(&cache_path).await {
+        return Ok((headers, cached).into_response());
+    }
+
+    let Some(image) = fetch_remote_image(&client, url).await else {
+        return Err(AppError::Upstream("Unable to fetch image".to_string()));
+    };
+
+    // Decoding and resizing is CPU bound, so keep it off the async workers
+    let resized = tokio::task::spawn_blocking(move || resize_image(&image, width, height))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let Some(resized) = resized else {
+        return Err(AppError::Unprocessable("Unsupported image".to_string()));
+    };
+
+    if let Err(e) = cache_image(&cache, &cache_path, &resized, media.image_cache_max_bytes).await {
+        warn!("Error caching an image : {}", e);
+    }
+
+    Ok((headers, resized).into_response())
+}
+
+pub async fn qr_code(Query(params): Query<QrParams>) -> Result<Response, AppError> {
+    if params.data.is_empty() || params.data.len() > MAX_QR_DATA_LENGTH {
+        return Err(AppError::Validation(
+            "Invalid QR code data length".to_string(),
+        ));
+    }
+
+    let size = params.size.unwrap_or(256);
+    if size == 0 || size > MAX_QR_SIZE {
+        return Err(AppError::Validation("Invalid QR code size".to_string()));
+    }
+
+    let ec_level = match params.ec.map(|ec| ec.to_ascii_uppercase()) {
+        Some('L') => qrcode::EcLevel::L,
+        None | Some('M') => qrcode::EcLevel::M,
+        Some('Q') => qrcode::EcLevel::Q,
+        Some('H') => qrcode::EcLevel::H,
+        Some(_) => {
+            return Err(AppError::Validation(
+                "Invalid error correction level".to_string(),
+            ))
+        }
+    };
+
+    let Ok(code) = qrcode::QrCode::with_error_correction_level(&params.data, ec_level) else {
+        return Err(AppError::Validation(
+            "Data too long for a QR code".to_string(),
+        ));
+    };
+
+    match params.format {
+        QrFormat::Svg => {
+            let svg = code
+                .render::<qrcode::render::svg::Color>()
+                .min_dimensions(size, size)
+                .build();
+            Ok(([(CONTENT_TYPE, "image/svg+xml")], svg).into_response())
+        }
+        QrFormat::Png => {
+            let image = code
+                .render::<image::Luma<u8>>()
+                .min_dimensions(size, size)
+                .build();
+
+            let mut png = Vec::new();
+            image
+                .write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png)
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+            Ok(([(CONTENT_TYPE, "image/png")], png).into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        image::DynamicImage::new_rgb8(width, height)
+            .write_to(&mut io::Cursor::new(&mut encoded), image::ImageFormat::Png)
+            .unwrap();
+        encoded
+    }
+
+    fn size_of(image: &[u8]) -> (u32, u32) {
+        let image = image::load_from_memory(image).unwrap();
+        (image.width(), image.height())
+    }
+
+    #[test]
+    fn never_scales_images_up() {
+        let avatar = png(100, 50);
+        let resized = |width, height| size_of(&resize_image(&avatar, width, height).unwrap());
+        assert_eq!(resized(None, None), (100, 50));
+        assert_eq!(resized(Some(2048), None), (100, 50));
+        assert_eq!(resized(Some(50), None), (50, 25));
+        assert_eq!(resized(None, Some(10)), (20, 10));
+        assert_eq!(resized(Some(40), Some(40)), (40, 20));
+    }
+
+    #[test]
+    fn scans_the_cache_only_past_its_limit() {
+        let cache = ImageCache::default();
+        // Nothing is known about the directory yet
+        assert!(cache.record(10, 100));
+        assert!(!cache.record(10, 100));
+
+        *cache.0.lock().unwrap() = CacheSize {
+            bytes: Some(60),
+            evicting: false,
+        };
+        assert!(!cache.record(30, 100));
+        assert!(cache.record(30, 100));
+        // One scan at a time
+        assert!(!cache.record(30, 100));
+        assert_eq!(cache.0.lock().unwrap().bytes, Some(150));
+    }
+
+    #[test]
+    fn only_allows_the_configured_hosts() {
+        let hosts = ["images.example.com".to_string()];
+        let allowed = |url: &str| is_allowed_image_url(&url.parse().unwrap(), &hosts);
+        assert!(allowed("https://images.example.com/a.png"));
+        assert!(!allowed("http://images.example.com/a.png"));
+        assert!(!allowed("https://images.unsplash.com/a.png"));
+    }
+}
@@ -0,0 +1,12 @@
+pub mod admin;
+pub mod auth;
+pub mod basics;
+pub mod client;
+pub mod counter;
+pub mod files;
+pub mod health;
+pub mod media;
+pub mod sandbox;
+pub mod scim;
+pub mod settings;
+pub mod users;
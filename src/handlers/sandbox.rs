@@ -0,0 +1,115 @@
+use axum::{http::StatusCode, Json};
+use std::time::Duration;
+
+use mongodb::{
+    action::Action,
+    bson::{doc, DateTime},
+    options::ReturnDocument,
+    Collection, Database,
+};
+
+use crate::{
+    auth::GuestUser,
+    counter::to_stored,
+    db::{time_left, TenantDb},
+    error::AppError,
+    models::{CounterDocument, CounterIncrement, NamedCounter, ResponseData},
+    openapi::ErrorBody,
+};
+
+// One counter per guest or user, kept apart from the shared ones in `counters`
+pub const SANDBOX_COLLECTION: &str = "sandbox_counters";
+const SANDBOX_COUNTER: &str = "sandbox";
+// How long a guest's sandbox is kept after its first increment, see `create_sandbox_indexes`
+pub const SANDBOX_TEARDOWN: Duration = Duration::from_secs(60 * 60);
+
+fn sandbox(database: &Database) -> Collection<CounterDocument> {
+    database.collection(SANDBOX_COLLECTION)
+}
+
+fn answer(value: i64, message: &str) -> Result<ResponseData<NamedCounter>, AppError> {
+    let value = u64::try_from(value)
+        .map_err(|_| AppError::Internal(format!("Sandbox counter is negative : {}", value)))?;
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: message.to_string(),
+        data: NamedCounter {
+            name: SANDBOX_COUNTER.to_string(),
+            value,
+        },
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/sandbox/counter",
+    tag = "sandbox",
+    responses(
+        (status = 200, description = "The caller's own counter, 0 until increased", body = ResponseData<NamedCounter>),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn get_sandbox_counter(
+    TenantDb(database, _): TenantDb,
+    user: GuestUser,
+) -> Result<ResponseData<NamedCounter>, AppError> {
+    let document = sandbox(&database)
+        .find_one(doc! { "_id": &user.0.sub })
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .await?;
+    answer(
+        document.map_or(0, |document| document.value),
+        "Sandbox counter",
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/sandbox/counter",
+    tag = "sandbox",
+    request_body = CounterIncrement,
+    responses(
+        (status = 200, description = "The increased value", body = ResponseData<NamedCounter>),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn increment_sandbox_counter(
+    TenantDb(database, _): TenantDb,
+    user: GuestUser,
+    Json(increment): Json<CounterIncrement>,
+) -> Result<ResponseData<NamedCounter>, AppError> {
+    let mut update = doc! { "$inc": { "value": to_stored(increment.by)? } };
+    if user.is_guest() {
+        update.insert("$setOnInsert", doc! { "created_at": DateTime::now() });
+    }
+    let document = sandbox(&database)
+        .find_one_and_update(doc! { "_id": &user.0.sub }, update)
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or_else(|| AppError::Internal("Upserted sandbox counter is missing".to_string()))?;
+    answer(document.value, "Sandbox counter increased")
+}
+
+// Hands a guest's counter over to the account it claimed. `_id` can't be updated, so the
+// value moves to a document of its own
+pub async fn transfer(database: &Database, guest: &str, user_name: &str) -> Result<(), AppError> {
+    let Some(document) = sandbox(database)
+        .find_one_and_delete(doc! { "_id": guest })
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .await?
+    else {
+        return Ok(());
+    };
+    sandbox(database)
+        .update_one(
+            doc! { "_id": user_name },
+            doc! { "$inc": { "value": document.value } },
+        )
+        .upsert(true)
+        .await?;
+    Ok(())
+}
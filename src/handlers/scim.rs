@@ -0,0 +1,602 @@
+use std::sync::Arc;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use mongodb::{
+    action::Action,
+    bson::{doc, Document},
+    Collection, Database,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    config::Config,
+    crypto::FieldCipher,
+    db::{classify, time_left, with_retries, DbErrorKind, TenantDb},
+    handlers::{
+        auth::{hash_password, revoke_sessions},
+        users::{find_user, remove_user},
+    },
+    id::{GroupId, RawId, UserId},
+    models::{Group, Role, User},
+    schema::decode,
+    scim::{
+        apply_patch, parse_filter, Attribute, PatchRequest, ScimError, ScimJson, GROUP_ATTRIBUTES,
+        GROUP_SCHEMA, LIST_SCHEMA, MAX_COUNT, MAX_FILTER_LENGTH, SEALED_USER_ATTRIBUTES,
+        SERVICE_PROVIDER_SCHEMA, USER_ATTRIBUTES, USER_SCHEMA,
+    },
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListQuery {
+    pub filter: Option<String>,
+    // 1-based, as SCIM numbers results
+    pub start_index: Option<u64>,
+    pub count: Option<u64>,
+}
+
+// What a create, a replace or a patched resource sets on a user, anything else is ignored
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UserInput {
+    user_name: String,
+    #[serde(default)]
+    external_id: Option<String>,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    name: Option<NameInput>,
+    #[serde(default)]
+    emails: Vec<EmailInput>,
+    #[serde(default = "active_by_default")]
+    active: bool,
+    // Provisioned users usually sign in through their identity provider and never get one
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NameInput {
+    formatted: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmailInput {
+    value: String,
+    #[serde(default)]
+    primary: bool,
+}
+
+fn active_by_default() -> bool {
+    true
+}
+
+impl UserInput {
+    fn display_name(&self) -> Option<String> {
+        let name = self.name.as_ref();
+        self.display_name
+            .clone()
+            .or_else(|| name.and_then(|name| name.formatted.clone()))
+            .or_else(|| {
+                let parts: Vec<&str> = name
+                    .into_iter()
+                    .flat_map(|name| [&name.given_name, &name.family_name])
+                    .flatten()
+                    .map(String::as_str)
+                    .collect();
+                (!parts.is_empty()).then(|| parts.join(" "))
+            })
+    }
+
+    // The primary one, or the first, users only have one email
+    fn email(&self) -> Option<String> {
+        self.emails
+            .iter()
+            .find(|email| email.primary)
+            .or(self.emails.first())
+            .map(|email| email.value.clone())
+    }
+
+    fn validate(&self) -> Result<(), ScimError> {
+        if self.user_name.trim().is_empty() {
+            return Err(ScimError::bad_request(
+                "invalidValue",
+                "userName can't be empty",
+            ));
+        }
+        if self.email().is_some_and(|email| !email.contains('@')) {
+            return Err(ScimError::bad_request("invalidValue", "Invalid email"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupInput {
+    display_name: String,
+    #[serde(default)]
+    external_id: Option<String>,
+    #[serde(default)]
+    members: Vec<MemberInput>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MemberInput {
+    value: String,
+}
+
+fn parse_input<T: DeserializeOwned>(value: Value) -> Result<T, ScimError> {
+    serde_json::from_value(value).map_err(|e| ScimError::bad_request("invalidValue", e.to_string()))
+}
+
+// Ids that aren't ours can't name anything stored, so they are answered like missing ones
+fn parse_user_id(id: &str) -> Result<RawId, ScimError> {
+    id.parse::<UserId>()
+        .map(|id| id.raw())
+        .map_err(|_| ScimError::not_found("User does not exist"))
+}
+
+fn parse_group_id(id: &str) -> Result<RawId, ScimError> {
+    id.parse::<GroupId>()
+        .map(|id| id.raw())
+        .map_err(|_| ScimError::not_found("Group does not exist"))
+}
+
+fn duplicate(what: &str) -> impl Fn(mongodb::error::Error) -> ScimError + '_ {
+    move |e| match classify(&e) {
+        DbErrorKind::DuplicateKey => ScimError::conflict(format!("{} already exists", what)),
+        _ => e.into(),
+    }
+}
+
+fn user_resource(user: &User) -> Value {
+    let mut resource = json!({
+        "schemas": [USER_SCHEMA],
+        "id": user.id.map(UserId::from),
+        "userName": user.user_name,
+        "active": !user.disabled,
+        "meta": { "resourceType": "User" },
+    });
+    if let Some(external_id) = &user.external_id {
+        resource["externalId"] = json!(external_id);
+    }
+    if let Some(display_name) = &user.display_name {
+        resource["displayName"] = json!(display_name);
+        resource["name"] = json!({ "formatted": display_name });
+    }
+    if let Some(email) = &user.email {
+        resource["emails"] = json!([{ "value": email, "primary": true }]);
+    }
+    resource
+}
+
+fn group_resource(group: &Group) -> Value {
+    let members: Vec<Value> = group
+        .members
+        .iter()
+        .map(|&id| json!({ "value": UserId::from(id), "type": "User" }))
+        .collect();
+    let mut resource = json!({
+        "schemas": [GROUP_SCHEMA],
+        "id": group.id.map(GroupId::from),
+        "displayName": group.display_name,
+        "members": members,
+        "meta": { "resourceType": "Group" },
+    });
+    if let Some(external_id) = &group.external_id {
+        resource["externalId"] = json!(external_id);
+    }
+    resource
+}
+
+// One page of a collection as a ListResponse, filtered through `attributes`
+async fn list<T: DeserializeOwned + Serialize>(
+    database: &Database,
+    collection: &str,
+    attributes: &[Attribute],
+    query: ListQuery,
+    resource: impl Fn(T) -> Result<Value, ScimError>,
+) -> Result<ScimJson, ScimError> {
+    let filter = match &query.filter {
+        Some(filter) if filter.len() > MAX_FILTER_LENGTH => {
+            return Err(ScimError::bad_request(
+                "invalidFilter",
+                "Filter is too long",
+            ));
+        }
+        Some(filter) => parse_filter(filter)?.to_document(attributes)?,
+        None => Document::new(),
+    };
+    let start_index = query.start_index.unwrap_or(1).max(1);
+    let count = query.count.unwrap_or(MAX_COUNT).min(MAX_COUNT);
+
+    let documents: Collection<Document> = database.collection(collection);
+    let total = with_retries(|| {
+        documents
+            .count_documents(filter.clone())
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+    })
+    .await?;
+    let mut resources = Vec::new();
+    if count > 0 {
+        let mut cursor = documents
+            .find(filter)
+            .sort(doc! { "_id": 1 })
+            .skip(start_index - 1)
+            .limit(count as i64)
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+            .await?;
+        while cursor.advance().await? {
+            let item: T = decode(database, collection, cursor.deserialize_current()?)?;
+            resources.push(resource(item)?);
+        }
+    }
+
+    Ok(ScimJson(
+        StatusCode::OK,
+        json!({
+            "schemas": [LIST_SCHEMA],
+            "totalResults": total,
+            "startIndex": start_index,
+            "itemsPerPage": resources.len(),
+            "Resources": resources,
+        }),
+    ))
+}
+
+pub async fn service_provider_config() -> ScimJson {
+    ScimJson(
+        StatusCode::OK,
+        json!({
+            "schemas": [SERVICE_PROVIDER_SCHEMA],
+            "patch": { "supported": true },
+            "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+            "filter": { "supported": true, "maxResults": MAX_COUNT },
+            "changePassword": { "supported": true },
+            "sort": { "supported": false },
+            "etag": { "supported": false },
+            "authenticationSchemes": [{
+                "type": "oauthbearertoken",
+                "name": "Bearer token",
+                "description": "The token set as scim.bearer_token",
+            }],
+        }),
+    )
+}
+
+// Sealed emails can't be compared in a query, so they can't be filtered on either
+pub async fn list_users(
+    TenantDb(database, _): TenantDb,
+    State(cipher): State<FieldCipher>,
+    Query(query): Query<ListQuery>,
+) -> Result<ScimJson, ScimError> {
+    let attributes = if cipher.is_enabled() {
+        SEALED_USER_ATTRIBUTES
+    } else {
+        USER_ATTRIBUTES
+    };
+    list(&database, "users", attributes, query, |mut user: User| {
+        cipher.open_user(&mut user)?;
+        Ok(user_resource(&user))
+    })
+    .await
+}
+
+pub async fn create_user(
+    TenantDb(database, _): TenantDb,
+    State(config): State<Arc<Config>>,
+    State(cipher): State<FieldCipher>,
+    Json(resource): Json<Value>,
+) -> Result<ScimJson, ScimError> {
+    let input: UserInput = parse_input(resource)?;
+    input.validate()?;
+    let password = match &input.password {
+        Some(password) => password.clone(),
+        // Nobody knows it, so the account can't be signed in to with a password
+        None => {
+            let mut bytes = [0u8; 32];
+            OsRng.fill_bytes(&mut bytes);
+            hex::encode(bytes)
+        }
+    };
+
+    let user = User {
+        id: Some(RawId::generate(config.id_format)),
+        user_name: input.user_name.clone(),
+        password_hash: hash_password(&password)?,
+        email: input
+            .email()
+            .map(|email| cipher.seal("email", &email))
+            .transpose()?,
+        display_name: input.display_name(),
+        role: Role::User,
+        failed_signins: 0,
+        locked_until: None,
+        external_id: input.external_id.clone(),
+        disabled: !input.active,
+        source: None,
+    };
+    let users_collection: Collection<User> = database.collection("users");
+    if users_collection
+        .count_documents(doc! { "user_name": &user.user_name })
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .await?
+        > 0
+    {
+        return Err(ScimError::conflict(format!(
+            "User {} already exists",
+            user.user_name
+        )));
+    }
+    users_collection
+        .insert_one(&user)
+        .await
+        .map_err(duplicate("User"))?;
+
+    let mut user = user;
+    cipher.open_user(&mut user)?;
+    Ok(ScimJson(StatusCode::CREATED, user_resource(&user)))
+}
+
+pub async fn get_user(
+    TenantDb(database, _): TenantDb,
+    State(cipher): State<FieldCipher>,
+    Path(id): Path<String>,
+) -> Result<ScimJson, ScimError> {
+    let user = find_user(&database, &cipher, parse_user_id(&id)?).await?;
+    Ok(ScimJson(StatusCode::OK, user_resource(&user)))
+}
+
+// Replaces everything SCIM manages on a user, attributes left out are cleared
+async fn save_user(
+    database: &Database,
+    cipher: &FieldCipher,
+    found: &User,
+    input: UserInput,
+) -> Result<ScimJson, ScimError> {
+    input.validate()?;
+    // Settings, uploads and sessions are keyed by user name
+    if input.user_name != found.user_name {
+        return Err(ScimError::bad_request(
+            "mutability",
+            "userName can't be changed",
+        ));
+    }
+
+    let mut set = Document::new();
+    let mut unset = Document::new();
+    let optional = [
+        ("display_name", input.display_name()),
+        (
+            "email",
+            input
+                .email()
+                .map(|email| cipher.seal("email", &email))
+                .transpose()?,
+        ),
+        ("external_id", input.external_id.clone()),
+    ];
+    for (field, value) in optional {
+        match value {
+            Some(value) => set.insert(field, value),
+            None => unset.insert(field, ""),
+        };
+    }
+    if input.active {
+        unset.insert("disabled", "");
+    } else {
+        set.insert("disabled", true);
+    }
+    if let Some(password) = &input.password {
+        set.insert("password", hash_password(password)?);
+    }
+
+    let mut update = doc! { "$set": set };
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    let users_collection: Collection<Document> = database.collection("users");
+    users_collection
+        .update_one(doc! { "_id": found.id }, update)
+        .await
+        .map_err(duplicate("A user with that externalId"))?;
+    // A deprovisioned user's refresh tokens stop working straight away
+    if !input.active && !found.disabled {
+        revoke_sessions(database, &found.user_name).await?;
+    }
+
+    let id = found
+        .id
+        .ok_or_else(|| ScimError::not_found("User does not exist"))?;
+    let updated = find_user(database, cipher, id).await?;
+    Ok(ScimJson(StatusCode::OK, user_resource(&updated)))
+}
+
+pub async fn replace_user(
+    TenantDb(database, _): TenantDb,
+    State(cipher): State<FieldCipher>,
+    Path(id): Path<String>,
+    Json(resource): Json<Value>,
+) -> Result<ScimJson, ScimError> {
+    let found = find_user(&database, &cipher, parse_user_id(&id)?).await?;
+    save_user(&database, &cipher, &found, parse_input(resource)?).await
+}
+
+pub async fn patch_user(
+    TenantDb(database, _): TenantDb,
+    State(cipher): State<FieldCipher>,
+    Path(id): Path<String>,
+    Json(request): Json<PatchRequest>,
+) -> Result<ScimJson, ScimError> {
+    let found = find_user(&database, &cipher, parse_user_id(&id)?).await?;
+    let mut resource = user_resource(&found);
+    apply_patch(&mut resource, request)?;
+    save_user(&database, &cipher, &found, parse_input(resource)?).await
+}
+
+pub async fn delete_user(
+    TenantDb(database, _): TenantDb,
+    State(cipher): State<FieldCipher>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ScimError> {
+    let id = parse_user_id(&id)?;
+    let found = find_user(&database, &cipher, id).await?;
+    remove_user(&database, id, &found.user_name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn find_group(database: &Database, id: RawId) -> Result<Group, ScimError> {
+    let groups_collection: Collection<Document> = database.collection("groups");
+    let document = with_retries(|| {
+        groups_collection
+            .find_one(doc! { "_id": id })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+    })
+    .await?
+    .ok_or_else(|| ScimError::not_found("Group does not exist"))?;
+    Ok(decode(database, "groups", document)?)
+}
+
+// Member ids, refusing any that isn't a stored user
+async fn member_ids(database: &Database, members: &[MemberInput]) -> Result<Vec<RawId>, ScimError> {
+    let mut ids: Vec<RawId> = Vec::new();
+    for member in members {
+        let id = member.value.parse::<UserId>().map_err(|_| {
+            ScimError::bad_request("invalidValue", format!("Unknown member {}", member.value))
+        })?;
+        if !ids.contains(&id.raw()) {
+            ids.push(id.raw());
+        }
+    }
+
+    let users_collection: Collection<Document> = database.collection("users");
+    let found = users_collection
+        .count_documents(doc! { "_id": { "$in": &ids } })
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .await?;
+    if found != ids.len() as u64 {
+        return Err(ScimError::bad_request(
+            "invalidValue",
+            "Some members aren't users",
+        ));
+    }
+    Ok(ids)
+}
+
+pub async fn list_groups(
+    TenantDb(database, _): TenantDb,
+    Query(query): Query<ListQuery>,
+) -> Result<ScimJson, ScimError> {
+    list(
+        &database,
+        "groups",
+        GROUP_ATTRIBUTES,
+        query,
+        |group: Group| Ok(group_resource(&group)),
+    )
+    .await
+}
+
+pub async fn create_group(
+    TenantDb(database, _): TenantDb,
+    State(config): State<Arc<Config>>,
+    Json(resource): Json<Value>,
+) -> Result<ScimJson, ScimError> {
+    let input: GroupInput = parse_input(resource)?;
+    if input.display_name.trim().is_empty() {
+        return Err(ScimError::bad_request(
+            "invalidValue",
+            "displayName can't be empty",
+        ));
+    }
+    let group = Group {
+        id: Some(RawId::generate(config.id_format)),
+        members: member_ids(&database, &input.members).await?,
+        display_name: input.display_name,
+        external_id: input.external_id,
+    };
+    let groups_collection: Collection<Group> = database.collection("groups");
+    groups_collection
+        .insert_one(&group)
+        .await
+        .map_err(duplicate("Group"))?;
+
+    Ok(ScimJson(StatusCode::CREATED, group_resource(&group)))
+}
+
+pub async fn get_group(
+    TenantDb(database, _): TenantDb,
+    Path(id): Path<String>,
+) -> Result<ScimJson, ScimError> {
+    let group = find_group(&database, parse_group_id(&id)?).await?;
+    Ok(ScimJson(StatusCode::OK, group_resource(&group)))
+}
+
+async fn save_group(
+    database: &Database,
+    found: &Group,
+    input: GroupInput,
+) -> Result<ScimJson, ScimError> {
+    if input.display_name.trim().is_empty() {
+        return Err(ScimError::bad_request(
+            "invalidValue",
+            "displayName can't be empty",
+        ));
+    }
+    let group = Group {
+        id: found.id,
+        members: member_ids(database, &input.members).await?,
+        display_name: input.display_name,
+        external_id: input.external_id,
+    };
+    let groups_collection: Collection<Group> = database.collection("groups");
+    groups_collection
+        .replace_one(doc! { "_id": found.id }, &group)
+        .await
+        .map_err(duplicate("Group"))?;
+
+    Ok(ScimJson(StatusCode::OK, group_resource(&group)))
+}
+
+pub async fn replace_group(
+    TenantDb(database, _): TenantDb,
+    Path(id): Path<String>,
+    Json(resource): Json<Value>,
+) -> Result<ScimJson, ScimError> {
+    let found = find_group(&database, parse_group_id(&id)?).await?;
+    save_group(&database, &found, parse_input(resource)?).await
+}
+
+pub async fn patch_group(
+    TenantDb(database, _): TenantDb,
+    Path(id): Path<String>,
+    Json(request): Json<PatchRequest>,
+) -> Result<ScimJson, ScimError> {
+    let found = find_group(&database, parse_group_id(&id)?).await?;
+    let mut resource = group_resource(&found);
+    apply_patch(&mut resource, request)?;
+    save_group(&database, &found, parse_input(resource)?).await
+}
+
+pub async fn delete_group(
+    TenantDb(database, _): TenantDb,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ScimError> {
+    let id = parse_group_id(&id)?;
+    let groups_collection: Collection<Document> = database.collection("groups");
+    let result = groups_collection.delete_one(doc! { "_id": id }).await?;
+    if result.deleted_count == 0 {
+        return Err(ScimError::not_found("Group does not exist"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
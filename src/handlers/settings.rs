@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::{extract::Path, http::StatusCode, Json};
+use mongodb::{
+    action::Action,
+    bson::{self, doc, DateTime, Document},
+    Collection, Database,
+};
+use serde_json::Value;
+
+use crate::{
+    auth::AuthUser,
+    db::{time_left, with_retries, TenantDb},
+    error::AppError,
+    models::{ResponseData, Setting, SettingDocument},
+    schema::decode,
+};
+
+pub const SETTINGS_COLLECTION: &str = "settings";
+// Keys nobody defined a schema for are accepted up to these limits
+pub const MAX_KEY_LENGTH: usize = 64;
+pub const MAX_VALUE_SIZE: usize = 4 * 1024;
+pub const MAX_SETTINGS_PER_USER: usize = 100;
+
+#[derive(Debug, Clone, Copy)]
+enum Schema {
+    // One of the listed strings, the second field is the default
+    OneOf(&'static [&'static str], &'static str),
+    // A language tag like `en` or `pt-BR`
+    Locale(&'static str),
+    Toggle(bool),
+}
+
+impl Schema {
+    fn default_value(self) -> Value {
+        match self {
+            Schema::OneOf(_, default) | Schema::Locale(default) => Value::from(default),
+            Schema::Toggle(default) => Value::from(default),
+        }
+    }
+
+    fn check(self, value: &Value) -> Result<(), String> {
+        match (self, value) {
+            (Schema::OneOf(allowed, _), Value::String(s)) if allowed.contains(&s.as_str()) => {
+                Ok(())
+            }
+            (Schema::OneOf(allowed, _), _) => Err(format!("Must be one of {}", allowed.join(", "))),
+            (Schema::Locale(_), Value::String(s)) if is_language_tag(s) => Ok(()),
+            (Schema::Locale(_), _) => {
+                Err("Must be a language tag like `en` or `pt-BR`".to_string())
+            }
+            (Schema::Toggle(_), Value::Bool(_)) => Ok(()),
+            (Schema::Toggle(_), _) => Err("Must be true or false".to_string()),
+        }
+    }
+}
+
+// Reading one of these without having set it gives its default
+const KNOWN_SETTINGS: &[(&str, Schema)] = &[
+    (
+        "theme",
+        Schema::OneOf(&["light", "dark", "system"], "system"),
+    ),
+    ("locale", Schema::Locale("en")),
+    ("notifications.email", Schema::Toggle(true)),
+    ("notifications.push", Schema::Toggle(false)),
+];
+
+fn known_schema(key: &str) -> Option<Schema> {
+    KNOWN_SETTINGS
+        .iter()
+        .find(|(known, _)| *known == key)
+        .map(|(_, schema)| *schema)
+}
+
+fn is_language_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let language = parts.next().unwrap_or_default();
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && tag.len() <= 35
+        && parts.all(|part| {
+            (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+fn check_key(key: &str) -> Result<(), AppError> {
+    if key.is_empty()
+        || key.len() > MAX_KEY_LENGTH
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err(AppError::Validation(format!(
+            "Invalid setting key {:?}, use up to {} of `a-z`, `0-9`, `.`, `_` and `-`",
+            key, MAX_KEY_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+fn check_setting(key: &str, value: &Value) -> Result<(), AppError> {
+    check_key(key)?;
+    match known_schema(key) {
+        Some(schema) => schema
+            .check(value)
+            .map_err(|message| AppError::Unprocessable(format!("{} : {}", key, message))),
+        None if serde_json::to_vec(value)?.len() > MAX_VALUE_SIZE => {
+            Err(AppError::PayloadTooLarge(format!(
+                "{} : values are limited to {} bytes",
+                key, MAX_VALUE_SIZE
+            )))
+        }
+        None => Ok(()),
+    }
+}
+
+fn settings_collection(database: &Database) -> Collection<SettingDocument> {
+    database.collection(SETTINGS_COLLECTION)
+}
+
+// Everything the user stored, on top of the defaults of known keys
+async fn load_settings(
+    database: &Database,
+    user_name: &str,
+) -> Result<BTreeMap<String, Value>, AppError> {
+    let mut settings: BTreeMap<String, Value> = KNOWN_SETTINGS
+        .iter()
+        .map(|(key, schema)| (key.to_string(), schema.default_value()))
+        .collect();
+
+    let collection: Collection<Document> = database.collection(SETTINGS_COLLECTION);
+    let mut cursor = with_retries(|| {
+        collection
+            .find(doc! { "user_name": user_name })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+    })
+    .await?;
+    while cursor.advance().await? {
+        let setting: SettingDocument =
+            decode(database, SETTINGS_COLLECTION, cursor.deserialize_current()?)?;
+        settings.insert(setting.key, setting.value);
+    }
+    Ok(settings)
+}
+
+// Validates every entry before writing any, so a rejected batch changes nothing.
+// `null` resets a key, known keys then read as their default again
+async fn store_settings(
+    database: &Database,
+    user_name: &str,
+    changes: &BTreeMap<String, Value>,
+) -> Result<(), AppError> {
+    for (key, value) in changes {
+        if !value.is_null() {
+            check_setting(key, value)?;
+        } else {
+            check_key(key)?;
+        }
+    }
+
+    let collection = settings_collection(database);
+    let added: BTreeSet<&str> = changes
+        .iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(key, _)| key.as_str())
+        .collect();
+    if !added.is_empty() {
+        let stored = collection
+            .distinct("key", doc! { "user_name": user_name })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+            .await?;
+        let kept = stored
+            .iter()
+            .filter_map(|key| key.as_str())
+            .filter(|key| changes.get(*key).is_none_or(|value| !value.is_null()));
+        let total: BTreeSet<&str> = kept.chain(added).collect();
+        if total.len() > MAX_SETTINGS_PER_USER {
+            return Err(AppError::Unprocessable(format!(
+                "Users can store up to {} settings",
+                MAX_SETTINGS_PER_USER
+            )));
+        }
+    }
+
+    let now = DateTime::now();
+    for (key, value) in changes {
+        let filter = doc! { "user_name": user_name, "key": key };
+        if value.is_null() {
+            collection.delete_one(filter).await?;
+            continue;
+        }
+        let value = bson::to_bson(value)
+            .map_err(|e| AppError::Internal(format!("Error encoding setting {} : {}", key, e)))?;
+        collection
+            .update_one(
+                filter,
+                doc! { "$set": { "value": value, "updated_at": now } },
+            )
+            .upsert(true)
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn get_settings(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+) -> Result<ResponseData<BTreeMap<String, Value>>, AppError> {
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Settings".to_string(),
+        data: load_settings(&database, user.username()).await?,
+    })
+}
+
+pub async fn set_settings(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+    Json(changes): Json<BTreeMap<String, Value>>,
+) -> Result<ResponseData<BTreeMap<String, Value>>, AppError> {
+    if changes.len() > MAX_SETTINGS_PER_USER {
+        return Err(AppError::Validation(format!(
+            "Up to {} settings can be changed at once",
+            MAX_SETTINGS_PER_USER
+        )));
+    }
+    store_settings(&database, user.username(), &changes).await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Settings updated".to_string(),
+        data: load_settings(&database, user.username()).await?,
+    })
+}
+
+pub async fn get_setting(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+    Path(key): Path<String>,
+) -> Result<ResponseData<Setting>, AppError> {
+    check_key(&key)?;
+    let collection: Collection<Document> = database.collection(SETTINGS_COLLECTION);
+    let stored = with_retries(|| {
+        collection
+            .find_one(doc! { "user_name": user.username(), "key": &key })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+    })
+    .await?;
+    let value = match (stored, known_schema(&key)) {
+        (Some(document), _) => {
+            decode::<SettingDocument>(&database, SETTINGS_COLLECTION, document)?.value
+        }
+        (None, Some(schema)) => schema.default_value(),
+        (None, None) => return Err(AppError::NotFound("Setting is not set".to_string())),
+    };
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Setting".to_string(),
+        data: Setting { key, value },
+    })
+}
+
+pub async fn set_setting(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+    Path(key): Path<String>,
+    Json(value): Json<Value>,
+) -> Result<ResponseData<Setting>, AppError> {
+    if value.is_null() {
+        return Err(AppError::Validation(
+            "Use DELETE to reset a setting".to_string(),
+        ));
+    }
+    let changes = BTreeMap::from([(key, value)]);
+    store_settings(&database, user.username(), &changes).await?;
+    let (key, value) = changes.into_iter().next().unwrap_or_default();
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Setting updated".to_string(),
+        data: Setting { key, value },
+    })
+}
+
+pub async fn delete_setting(
+    TenantDb(database, _): TenantDb,
+    user: AuthUser,
+    Path(key): Path<String>,
+) -> Result<ResponseData<()>, AppError> {
+    check_key(&key)?;
+    settings_collection(&database)
+        .delete_one(doc! { "user_name": user.username(), "key": &key })
+        .await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "Setting reset".to_string(),
+        data: (),
+    })
+}
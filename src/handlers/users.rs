@@ -0,0 +1,340 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use mongodb::{
+    action::Action,
+    bson::{doc, Document},
+    Collection, Database,
+};
+use serde_json::Value;
+
+use crate::{
+    auth::AuthUser,
+    config::{Config, ReadMode},
+    crypto::FieldCipher,
+    db::{causal_session, read_criteria, time_left, with_retries, TenantDb},
+    error::AppError,
+    fields::{Fields, Include},
+    handlers::{auth::revoke_sessions, files},
+    id::{RawId, UserId},
+    middleware::DryRun,
+    models::{Pagination, ResponseData, Role, UpdateProfile, User, UserProfile},
+    schema::decode,
+};
+
+pub const DEFAULT_PAGE_SIZE: u64 = 20;
+pub const MAX_PAGE_SIZE: u64 = 100;
+// Only shown to the user themself and to admins, `/admin/users` lists them for everyone
+pub const PRIVATE_FIELDS: &[&str] = &["email", "role"];
+
+pub fn parse_user_id(id: &str) -> Result<RawId, AppError> {
+    Ok(id.parse::<UserId>()?.raw())
+}
+
+// With its personal fields decrypted
+pub async fn find_user(
+    database: &Database,
+    cipher: &FieldCipher,
+    id: RawId,
+) -> Result<User, AppError> {
+    let users_collection: Collection<Document> = database.collection("users");
+    let document = with_retries(|| {
+        users_collection
+            .find_one(doc! { "_id": id })
+            .optional(time_left(), |action, max_time| action.max_time(max_time))
+    })
+    .await?
+    .ok_or_else(|| AppError::NotFound("User does not exist".to_string()))?;
+    let mut user: User = decode(database, "users", document)?;
+    cipher.open_user(&mut user)?;
+    Ok(user)
+}
+
+pub async fn remove_user(database: &Database, id: RawId, user_name: &str) -> Result<(), AppError> {
+    let users_collection: Collection<User> = database.collection("users");
+    users_collection.delete_one(doc! { "_id": id }).await?;
+    let settings_collection: Collection<Document> = database.collection("settings");
+    settings_collection
+        .delete_many(doc! { "user_name": user_name })
+        .await?;
+
+    let groups_collection: Collection<Document> = database.collection("groups");
+    groups_collection
+        .update_many(doc! { "members": id }, doc! { "$pull": { "members": id } })
+        .await?;
+
+    // Outstanding refresh tokens would otherwise keep minting access tokens
+    revoke_sessions(database, user_name).await?;
+    Ok(())
+}
+
+// Accounts can only be changed by the user who owns them
+async fn find_own_user(
+    database: &Database,
+    cipher: &FieldCipher,
+    id: RawId,
+    user: &AuthUser,
+) -> Result<User, AppError> {
+    let found = find_user(database, cipher, id).await?;
+    if found.user_name != user.username() {
+        return Err(AppError::Forbidden(
+            "Only the account owner can do that".to_string(),
+        ));
+    }
+    Ok(found)
+}
+
+pub async fn list_page(
+    database: &Database,
+    cipher: &FieldCipher,
+    pagination: &Pagination,
+    read_mode: ReadMode,
+) -> Result<Vec<UserProfile>, AppError> {
+    let page = pagination.page.unwrap_or(1).max(1);
+    let per_page = pagination
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    // Ids start with their creation time, so ordering by `_id` lists users oldest first and
+    // lets `after` resume from an index seek instead of skipping documents. BSON orders
+    // binary before ObjectIds, so where `id_format` was switched to UUIDv7 on existing data
+    // the UUID users come first, and everything with an ObjectId follows a UUID cursor
+    let (filter, skip) = match &pagination.after {
+        Some(after) => match after.parse::<UserId>()?.raw() {
+            after @ RawId::ObjectId(_) => (doc! { "_id": { "$gt": after } }, 0),
+            after @ RawId::Uuid(_) => (
+                doc! { "$or": [
+                    { "_id": { "$gt": after } },
+                    { "_id": { "$type": "objectId" } },
+                ] },
+                0,
+            ),
+        },
+        None => {
+            let skip = (page - 1)
+                .checked_mul(per_page)
+                .filter(|&skip| skip <= i64::MAX as u64)
+                .ok_or_else(|| AppError::Validation("page is too large".to_string()))?;
+            (doc! {}, skip)
+        }
+    };
+
+    let users_collection: Collection<Document> = database.collection("users");
+    let mut cursor = users_collection
+        .find(filter)
+        .sort(doc! { "_id": 1 })
+        .selection_criteria(read_criteria(read_mode))
+        .skip(skip)
+        .limit(per_page as i64)
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .await?;
+
+    let mut users = Vec::new();
+    while cursor.advance().await? {
+        let mut user: User = decode(database, "users", cursor.deserialize_current()?)?;
+        cipher.open_user(&mut user)?;
+        users.push(user.into());
+    }
+    Ok(users)
+}
+
+fn is_visible_to(user: &AuthUser, profile: &UserProfile) -> bool {
+    user.role() == Role::Admin || profile.user_name == user.username()
+}
+
+// Drops `PRIVATE_FIELDS` from every row but the caller's own, unless they are an admin
+fn redact_private(user: &AuthUser, profiles: &[UserProfile], items: &mut [Value]) {
+    for (profile, item) in profiles.iter().zip(items) {
+        if is_visible_to(user, profile) {
+            continue;
+        }
+        if let Value::Object(object) = item {
+            object.retain(|field, _| !PRIVATE_FIELDS.contains(&field.as_str()));
+        }
+    }
+}
+
+// Uploads are private, so only the caller's own row gets them, or every row for admins
+async fn embed_files(
+    database: &Database,
+    user: &AuthUser,
+    profiles: &[UserProfile],
+    items: &mut [Value],
+) -> Result<(), AppError> {
+    let visible: Vec<String> = profiles
+        .iter()
+        .filter(|profile| is_visible_to(user, profile))
+        .map(|profile| profile.user_name.clone())
+        .collect();
+    let mut by_owner = files::files_by_owner(database, &visible).await?;
+
+    for (profile, item) in profiles.iter().zip(items) {
+        if !visible.contains(&profile.user_name) {
+            continue;
+        }
+        let entries = by_owner.remove(&profile.user_name).unwrap_or_default();
+        if let Value::Object(object) = item {
+            object.insert("files".to_string(), serde_json::to_value(entries)?);
+        }
+    }
+    Ok(())
+}
+
+pub async fn list_users(
+    TenantDb(database, _): TenantDb,
+    State(config): State<Arc<Config>>,
+    State(cipher): State<FieldCipher>,
+    user: AuthUser,
+    Query(pagination): Query<Pagination>,
+    fields: Fields<UserProfile>,
+    include: Include<UserProfile>,
+) -> Result<ResponseData<Value>, AppError> {
+    let page = pagination.page.unwrap_or(1).max(1);
+    let users = list_page(
+        &database,
+        &cipher,
+        &pagination,
+        config.read_preference.listings,
+    )
+    .await?;
+    let mut data = fields.apply_all(&users)?;
+    if let Value::Array(items) = &mut data {
+        redact_private(&user, &users, items);
+        if include.contains("files") {
+            embed_files(&database, &user, &users, items).await?;
+        }
+    }
+    let message = match &pagination.after {
+        Some(after) => format!("Users after {}", after),
+        None => format!("Users, page {}", page),
+    };
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message,
+        data,
+    })
+}
+
+pub async fn get_user(
+    TenantDb(database, _): TenantDb,
+    State(cipher): State<FieldCipher>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    fields: Fields<UserProfile>,
+    include: Include<UserProfile>,
+) -> Result<ResponseData<Value>, AppError> {
+    let found = UserProfile::from(find_user(&database, &cipher, parse_user_id(&id)?).await?);
+    let mut data = fields.apply(&found)?;
+    redact_private(
+        &user,
+        std::slice::from_ref(&found),
+        std::slice::from_mut(&mut data),
+    );
+    if include.contains("files") {
+        embed_files(
+            &database,
+            &user,
+            std::slice::from_ref(&found),
+            std::slice::from_mut(&mut data),
+        )
+        .await?;
+    }
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "User".to_string(),
+        data,
+    })
+}
+
+pub async fn update_user(
+    TenantDb(database, _): TenantDb,
+    State(config): State<Arc<Config>>,
+    State(cipher): State<FieldCipher>,
+    user: AuthUser,
+    dry_run: DryRun,
+    Path(id): Path<String>,
+    Json(input): Json<UpdateProfile>,
+) -> Result<ResponseData<UserProfile>, AppError> {
+    let id = parse_user_id(&id)?;
+    let mut found = find_own_user(&database, &cipher, id, &user).await?;
+
+    let mut changes = Document::new();
+    if let Some(email) = input.email {
+        if !email.contains('@') {
+            return Err(AppError::Validation("Invalid email".to_string()));
+        }
+        changes.insert("email", cipher.seal("email", &email)?);
+        found.email = Some(email);
+    }
+    if let Some(display_name) = input.display_name {
+        changes.insert("display_name", &display_name);
+        found.display_name = Some(display_name);
+    }
+    if changes.is_empty() {
+        return Err(AppError::Validation("Nothing to update".to_string()));
+    }
+
+    if dry_run.0 {
+        return Ok(ResponseData {
+            status: StatusCode::OK.as_u16(),
+            message: "Dry run : user would be updated".to_string(),
+            data: found.into(),
+        });
+    }
+
+    // Read back in the same causal session, so a secondary can't answer with the old profile
+    let mut session = causal_session(&database).await?;
+    let users_collection: Collection<Document> = database.collection("users");
+    users_collection
+        .update_one(doc! { "_id": id }, doc! { "$set": changes })
+        .session(&mut session)
+        .await?;
+    let document = users_collection
+        .find_one(doc! { "_id": id })
+        .selection_criteria(read_criteria(config.read_preference.listings))
+        .optional(time_left(), |action, max_time| action.max_time(max_time))
+        .session(&mut session)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User does not exist".to_string()))?;
+    let mut updated: User = decode(&database, "users", document)?;
+    cipher.open_user(&mut updated)?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "User updated".to_string(),
+        data: updated.into(),
+    })
+}
+
+pub async fn delete_user(
+    TenantDb(database, _): TenantDb,
+    State(cipher): State<FieldCipher>,
+    user: AuthUser,
+    dry_run: DryRun,
+    Path(id): Path<String>,
+) -> Result<ResponseData<()>, AppError> {
+    let id = parse_user_id(&id)?;
+    let found = find_own_user(&database, &cipher, id, &user).await?;
+
+    if dry_run.0 {
+        return Ok(ResponseData {
+            status: StatusCode::OK.as_u16(),
+            message: "Dry run : user would be deleted".to_string(),
+            data: (),
+        });
+    }
+    remove_user(&database, id, &found.user_name).await?;
+
+    Ok(ResponseData {
+        status: StatusCode::OK.as_u16(),
+        message: "User deleted".to_string(),
+        data: (),
+    })
+}
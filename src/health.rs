@@ -0,0 +1,123 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use futures_util::future::{join_all, BoxFuture};
+use mongodb::{bson::doc, Database};
+use tokio::time::timeout;
+
+use crate::{
+    handlers::files::UPLOAD_DIR,
+    models::{DependencyHealth, HealthStatus},
+    warmup::WarmUp,
+};
+
+// A check that hangs is reported down after this, unless it sets its own timeout
+pub const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Something `/readyz` has to see working before the instance takes traffic
+pub trait HealthCheck: Send + Sync {
+    // Key of the check in the `/readyz` body
+    fn name(&self) -> &'static str;
+
+    fn timeout(&self) -> Duration {
+        DEFAULT_CHECK_TIMEOUT
+    }
+
+    fn check(&self) -> BoxFuture<'_, Result<(), String>>;
+}
+
+// Checks registered by the subsystems, modules add theirs through `ModuleContext::health`
+#[derive(Clone, Default)]
+pub struct HealthRegistry(Arc<RwLock<Vec<Arc<dyn HealthCheck>>>>);
+
+impl HealthRegistry {
+    pub fn register(&self, check: impl HealthCheck + 'static) {
+        self.0.write().unwrap().push(Arc::new(check));
+    }
+
+    // Runs every check at once, so the slowest one bounds the whole probe
+    pub async fn run_all(&self) -> BTreeMap<String, DependencyHealth> {
+        let checks = self.0.read().unwrap().clone();
+        let results = join_all(checks.iter().map(|check| async move {
+            let started = Instant::now();
+            let error = match timeout(check.timeout(), check.check()).await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e),
+                Err(_) => Some(format!("Timed out after {}ms", check.timeout().as_millis())),
+            };
+            let health = DependencyHealth {
+                status: if error.is_none() {
+                    HealthStatus::Up
+                } else {
+                    HealthStatus::Down
+                },
+                latency_ms: started.elapsed().as_millis(),
+                error,
+            };
+            (check.name().to_string(), health)
+        }))
+        .await;
+        results.into_iter().collect()
+    }
+}
+
+pub struct MongoHealthCheck(pub Database);
+
+impl HealthCheck for MongoHealthCheck {
+    fn name(&self) -> &'static str {
+        "mongodb"
+    }
+
+    fn check(&self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            self.0
+                .run_command(doc! { "ping": 1 })
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+pub struct WarmUpHealthCheck(pub WarmUp);
+
+impl HealthCheck for WarmUpHealthCheck {
+    fn name(&self) -> &'static str {
+        "warmUp"
+    }
+
+    fn check(&self) -> BoxFuture<'_, Result<(), String>> {
+        let done = self.0.is_done();
+        Box::pin(async move {
+            if done {
+                Ok(())
+            } else {
+                Err("Still warming up".to_string())
+            }
+        })
+    }
+}
+
+// Registered by the files module, uploads fail once their directory is gone or read only
+pub struct UploadDirHealthCheck;
+
+impl HealthCheck for UploadDirHealthCheck {
+    fn name(&self) -> &'static str {
+        "uploads"
+    }
+
+    fn check(&self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let metadata = tokio::fs::metadata(UPLOAD_DIR)
+                .await
+                .map_err(|e| format!("{} : {}", UPLOAD_DIR, e))?;
+            if !metadata.is_dir() || metadata.permissions().readonly() {
+                return Err(format!("{} is not a writable directory", UPLOAD_DIR));
+            }
+            Ok(())
+        })
+    }
+}
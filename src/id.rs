@@ -0,0 +1,333 @@
+use std::{borrow::Cow, fmt, marker::PhantomData, str::FromStr};
+
+use mongodb::bson::{oid::ObjectId, spec::BinarySubtype, Binary, Bson, Uuid};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use utoipa::{
+    openapi::{schema::Type, ObjectBuilder, RefOr, Schema},
+    PartialSchema, ToSchema,
+};
+
+use crate::{config::IdFormat, error::AppError};
+
+// A document's `_id`, an ObjectId or, for resources created under `IdFormat::UuidV7`, a
+// UUIDv7 stored as BSON binary. Written as 24 or 32 hex digits, so both parse back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RawId {
+    ObjectId(ObjectId),
+    Uuid(Uuid),
+}
+
+impl RawId {
+    pub fn generate(format: IdFormat) -> Self {
+        match format {
+            IdFormat::ObjectId => RawId::ObjectId(ObjectId::new()),
+            IdFormat::UuidV7 => RawId::Uuid(Uuid::from_bytes(uuid::Uuid::now_v7().into_bytes())),
+        }
+    }
+
+    pub fn to_hex(self) -> String {
+        match self {
+            RawId::ObjectId(object_id) => object_id.to_hex(),
+            RawId::Uuid(uuid) => hex::encode(uuid.bytes()),
+        }
+    }
+
+    fn parse_hex(hex: &str) -> Option<Self> {
+        match hex.len() {
+            24 => ObjectId::parse_str(hex).ok().map(RawId::ObjectId),
+            32 => {
+                let bytes: [u8; 16] = hex::decode(hex).ok()?.try_into().ok()?;
+                Some(RawId::Uuid(Uuid::from_bytes(bytes)))
+            }
+            _ => None,
+        }
+    }
+
+    // The `_id` of an inserted document, `None` when it is neither kind
+    pub fn from_bson(bson: &Bson) -> Option<Self> {
+        match bson {
+            Bson::ObjectId(object_id) => Some(RawId::ObjectId(*object_id)),
+            Bson::Binary(binary) if binary.subtype == BinarySubtype::Uuid => {
+                let bytes: [u8; 16] = binary.bytes.as_slice().try_into().ok()?;
+                Some(RawId::Uuid(Uuid::from_bytes(bytes)))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<ObjectId> for RawId {
+    fn from(object_id: ObjectId) -> Self {
+        RawId::ObjectId(object_id)
+    }
+}
+
+impl From<RawId> for Bson {
+    fn from(id: RawId) -> Self {
+        match id {
+            RawId::ObjectId(object_id) => Bson::ObjectId(object_id),
+            RawId::Uuid(uuid) => Bson::Binary(Binary {
+                subtype: BinarySubtype::Uuid,
+                bytes: uuid.bytes().to_vec(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for RawId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+// Through `Bson`, so documents keep native ObjectIds and binary UUIDs
+impl Serialize for RawId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Bson::from(*self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RawId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bson = Bson::deserialize(deserializer)?;
+        RawId::from_bson(&bson).ok_or_else(|| de::Error::custom(format!("invalid _id {}", bson)))
+    }
+}
+
+// Which resource an id belongs to, and the prefix it carries on the wire
+pub trait IdKind {
+    const PREFIX: &'static str;
+    // Used in "Invalid <name> id" errors
+    const NAME: &'static str;
+}
+
+#[derive(Debug)]
+pub enum UserKind {}
+
+impl IdKind for UserKind {
+    const PREFIX: &'static str = "usr";
+    const NAME: &'static str = "user";
+}
+
+#[derive(Debug)]
+pub enum FileKind {}
+
+impl IdKind for FileKind {
+    const PREFIX: &'static str = "file";
+    const NAME: &'static str = "upload";
+}
+
+#[derive(Debug)]
+pub enum IpRuleKind {}
+
+impl IdKind for IpRuleKind {
+    const PREFIX: &'static str = "iprule";
+    const NAME: &'static str = "IP rule";
+}
+
+#[derive(Debug)]
+pub enum GroupKind {}
+
+impl IdKind for GroupKind {
+    const PREFIX: &'static str = "grp";
+    const NAME: &'static str = "group";
+}
+
+// A `RawId` as clients see it, e.g. `usr_65ab…`. Clients treat it as an opaque
+// string, so the backing store can change without breaking the API
+pub struct PublicId<K> {
+    raw: RawId,
+    kind: PhantomData<fn() -> K>,
+}
+
+pub type UserId = PublicId<UserKind>;
+pub type FileId = PublicId<FileKind>;
+pub type IpRuleId = PublicId<IpRuleKind>;
+pub type GroupId = PublicId<GroupKind>;
+
+impl<K> PublicId<K> {
+    pub fn raw(&self) -> RawId {
+        self.raw
+    }
+}
+
+// Written out because derives would require `K` itself to implement them
+impl<K> Clone for PublicId<K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K> Copy for PublicId<K> {}
+
+impl<K> PartialEq for PublicId<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl<K> Eq for PublicId<K> {}
+
+impl<K> From<RawId> for PublicId<K> {
+    fn from(raw: RawId) -> Self {
+        PublicId {
+            raw,
+            kind: PhantomData,
+        }
+    }
+}
+
+impl<K> From<ObjectId> for PublicId<K> {
+    fn from(object_id: ObjectId) -> Self {
+        RawId::ObjectId(object_id).into()
+    }
+}
+
+impl<K> From<PublicId<K>> for RawId {
+    fn from(id: PublicId<K>) -> Self {
+        id.raw
+    }
+}
+
+impl<K: IdKind> fmt::Display for PublicId<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", K::PREFIX, self.raw)
+    }
+}
+
+impl<K: IdKind> fmt::Debug for PublicId<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// An id of the wrong kind, say a file id passed as a user id, is rejected like any other typo
+impl<K: IdKind> FromStr for PublicId<K> {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix(K::PREFIX)
+            .and_then(|rest| rest.strip_prefix('_'))
+            .and_then(RawId::parse_hex)
+            .map(PublicId::from)
+            .ok_or_else(|| AppError::Validation(format!("Invalid {} id", K::NAME)))
+    }
+}
+
+impl<K: IdKind> Serialize for PublicId<K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<K: IdKind> PartialSchema for PublicId<K> {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .description(Some(format!(
+                "A {} id, `{}_` and 24 or 32 hex digits",
+                K::NAME,
+                K::PREFIX
+            )))
+            .examples([format!("{}_0123456789abcdef01234567", K::PREFIX)])
+            .into()
+    }
+}
+
+// Named per kind like `UserId`, the default would give every kind the same `PublicId` name
+impl<K: IdKind> ToSchema for PublicId<K> {
+    fn name() -> Cow<'static, str> {
+        let mut name: String = K::NAME
+            .split(' ')
+            .flat_map(|word| {
+                let mut chars = word.chars();
+                chars
+                    .next()
+                    .map(|c| c.to_ascii_uppercase())
+                    .into_iter()
+                    .chain(chars)
+            })
+            .collect();
+        name.push_str("Id");
+        Cow::Owned(name)
+    }
+}
+
+impl<'de, K: IdKind> Deserialize<'de> for PublicId<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("invalid {} id `{}`", K::NAME, s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, from_document, to_document};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Document {
+        #[serde(rename = "_id")]
+        id: RawId,
+    }
+
+    #[test]
+    fn both_formats_round_trip_through_the_wire_format() {
+        for format in [IdFormat::ObjectId, IdFormat::UuidV7] {
+            let id = UserId::from(RawId::generate(format));
+            let wire = id.to_string();
+            assert!(wire.starts_with("usr_"));
+            assert_eq!(wire.parse::<UserId>().unwrap(), id);
+        }
+        assert_eq!(
+            UserId::from(RawId::generate(IdFormat::UuidV7))
+                .to_string()
+                .len(),
+            "usr_".len() + 32
+        );
+    }
+
+    #[test]
+    fn ids_are_stored_as_native_bson() {
+        let object_id = ObjectId::new();
+        let document = to_document(&Document {
+            id: RawId::ObjectId(object_id),
+        })
+        .unwrap();
+        assert_eq!(document, doc! { "_id": object_id });
+
+        let raw = RawId::generate(IdFormat::UuidV7);
+        let document = to_document(&Document { id: raw }).unwrap();
+        assert!(matches!(
+            document.get("_id"),
+            Some(Bson::Binary(Binary {
+                subtype: BinarySubtype::Uuid,
+                ..
+            }))
+        ));
+        assert_eq!(from_document::<Document>(document).unwrap().id, raw);
+    }
+
+    #[test]
+    fn uuids_sort_by_creation_time() {
+        let first = RawId::generate(IdFormat::UuidV7);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = RawId::generate(IdFormat::UuidV7);
+        assert!(first.to_hex() < second.to_hex());
+    }
+
+    #[test]
+    fn malformed_ids_are_rejected() {
+        for id in [
+            "usr_",
+            "usr_0123",
+            "usr_zz0123456789abcdef0123456789ab",
+            "file_0123456789abcdef01234567",
+            "0123456789abcdef01234567",
+        ] {
+            assert!(id.parse::<UserId>().is_err(), "{}", id);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{config::LdapConfig, models::Role};
+
+// What the directory said about a sign in
+#[derive(Debug, PartialEq)]
+pub enum Bind {
+    // Not configured, or the password is wrong there
+    Refused,
+    Accepted(Role),
+}
+
+// Binds as the user with their password, reading their groups once in. Errors are the
+// directory being unreachable or misbehaving, not wrong passwords
+#[cfg(feature = "ldap")]
+pub async fn bind(config: &LdapConfig, user_name: &str, password: &str) -> Result<Bind, String> {
+    use std::time::Duration;
+
+    use ldap3::{dn_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+    // RFC 4513 : an empty password is an unauthenticated bind, which succeeds for any DN
+    let Some(url) = config.url.as_deref().filter(|_| !password.is_empty()) else {
+        return Ok(Bind::Refused);
+    };
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(timeout)
+        .set_starttls(config.starttls);
+    let (connection, mut ldap) = LdapConnAsync::with_settings(settings, url)
+        .await
+        .map_err(|e| format!("Error connecting to {} : {}", url, e))?;
+    ldap3::drive!(connection);
+
+    let dn = config.bind_dn.replace("{user}", &dn_escape(user_name));
+    let result = ldap
+        .with_timeout(timeout)
+        .simple_bind(&dn, password)
+        .await
+        .map_err(|e| format!("Error binding as {} : {}", dn, e))?;
+    // invalidCredentials
+    if result.rc == 49 {
+        let _ = ldap.unbind().await;
+        return Ok(Bind::Refused);
+    }
+    result
+        .success()
+        .map_err(|e| format!("Error binding as {} : {}", dn, e))?;
+
+    let (entries, _) = ldap
+        .with_timeout(timeout)
+        .search(
+            &dn,
+            Scope::Base,
+            "(objectClass=*)",
+            vec![config.group_attribute.as_str()],
+        )
+        .await
+        .and_then(|result| result.success())
+        .map_err(|e| format!("Error reading the groups of {} : {}", dn, e))?;
+    let _ = ldap.unbind().await;
+
+    let groups: Vec<String> = entries
+        .into_iter()
+        .map(SearchEntry::construct)
+        .flat_map(|mut entry| {
+            entry
+                .attrs
+                .remove(&config.group_attribute)
+                .unwrap_or_default()
+        })
+        .collect();
+    Ok(Bind::Accepted(config.role_for(&groups)))
+}
+
+// Built without the feature `auth.ldap.url` doesn't validate, so there is never a directory
+#[cfg(not(feature = "ldap"))]
+pub async fn bind(_: &LdapConfig, _: &str, _: &str) -> Result<Bind, String> {
+    Ok(Bind::Refused)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn maps_groups_to_the_highest_role() {
+        let config = LdapConfig {
+            group_roles: BTreeMap::from([
+                ("cn=admins,dc=example,dc=com".to_string(), Role::Admin),
+                ("cn=sponsors,dc=example,dc=com".to_string(), Role::Premium),
+            ]),
+            ..LdapConfig::default()
+        };
+        assert_eq!(
+            config.role_for(&[
+                "cn=sponsors,dc=example,dc=com",
+                "CN=Admins,DC=example,DC=com"
+            ]),
+            Role::Admin
+        );
+        assert_eq!(
+            config.role_for(&[
+                "cn=sponsors,dc=example,dc=com",
+                "cn=staff,dc=example,dc=com"
+            ]),
+            Role::Premium
+        );
+        assert_eq!(config.role_for::<&str>(&[]), Role::User);
+    }
+
+    #[tokio::test]
+    async fn refuses_without_a_directory() {
+        let bind = bind(&LdapConfig::default(), "alice", "secret").await;
+        assert_eq!(bind, Ok(Bind::Refused));
+    }
+}
@@ -0,0 +1,43 @@
+pub mod activity;
+pub mod analytics;
+pub mod anomaly;
+pub mod auth;
+pub mod banner;
+pub mod client;
+pub mod compression;
+pub mod config;
+#[cfg(unix)]
+pub mod console;
+pub mod counter;
+pub mod crypto;
+pub mod db;
+pub mod delivery;
+pub mod error;
+pub mod fields;
+pub mod funnel;
+pub mod handlers;
+pub mod health;
+pub mod id;
+pub mod ldap;
+pub mod middleware;
+pub mod mock;
+pub mod models;
+pub mod modules;
+pub mod openapi;
+pub mod rate_limit;
+pub mod redact;
+pub mod routes;
+pub mod schema;
+pub mod scim;
+pub mod secrets;
+pub mod server;
+pub mod state;
+pub mod static_files;
+pub mod telemetry;
+pub mod trace;
+pub mod validation;
+pub mod versioning;
+pub mod warmup;
+
+pub use db::db;
+pub use routes::{app, app_with_modules};
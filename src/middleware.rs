@@ -0,0 +1,688 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    io,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, FromRef, FromRequestParts, MatchedPath, OriginalUri, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_LENGTH, SET_COOKIE, TRANSFER_ENCODING},
+        request::Parts,
+        HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::{stream, StreamExt};
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use jsonwebtoken::get_current_timestamp;
+use mongodb::{bson::doc, Collection};
+use sha2::Sha256;
+use tower_http::request_id::RequestId;
+use tracing::debug;
+
+use crate::{
+    auth::{
+        access_claims, cookie_value, generate_guest_token, AuthUser, Authenticator, GUEST_TOKEN_TTL,
+    },
+    config::{ConnectionsConfig, ReadOnlyConfig},
+    db::{TenantDb, Tenants},
+    error::AppError,
+    models::{Identity, IpRuleAction, ProfileField, Role, User},
+    secrets::RotatingSecret,
+    server::ClientAddr,
+    state::AppState,
+    versioning::unversioned,
+};
+
+// How far a signed request's timestamp may drift from the server clock
+pub const SIGNATURE_MAX_AGE_SECS: u64 = 300;
+// Nonces are remembered for the signature window, past this many signed requests are
+// refused until older nonces expire
+pub const MAX_TRACKED_NONCES: usize = 100_000;
+pub const SIGNED_BODY_LIMIT: usize = 2 * 1024 * 1024;
+// What `Config::signed_routes` can list, the counter writes `verify_signature` wraps
+pub const SIGNABLE_ROUTES: &[&str] = &[
+    "POST /counter",
+    "PUT /counter",
+    "DELETE /counter",
+    "POST /counter/{name}",
+    "PUT /counter/{name}",
+    "DELETE /counter/{name}",
+    "POST /counter/{name}/increment",
+];
+
+// In-memory copy of the `ip_rules` collection, so the filters don't hit Mongo on every request
+#[derive(Debug, Clone, Default)]
+pub struct IpRules {
+    rules: Arc<RwLock<Vec<(IpNet, IpRuleAction)>>>,
+    // `admin_allowlist`, allowed whatever the collection holds
+    always_allowed: Arc<[IpNet]>,
+}
+
+impl IpRules {
+    // Entries were checked by `Config::validate`
+    pub fn new(admin_allowlist: &[String]) -> Self {
+        IpRules {
+            rules: Arc::default(),
+            always_allowed: admin_allowlist
+                .iter()
+                .filter_map(|cidr| parse_cidr(cidr))
+                .collect(),
+        }
+    }
+
+    fn matches(&self, ip: IpAddr, action: IpRuleAction) -> bool {
+        if action == IpRuleAction::Allow && self.always_allowed.iter().any(|net| net.contains(&ip))
+        {
+            return true;
+        }
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .any(|(net, rule_action)| *rule_action == action && net.contains(&ip))
+    }
+
+    pub fn replace(&self, rules: Vec<(IpNet, IpRuleAction)>) {
+        *self.rules.write().unwrap() = rules;
+    }
+}
+
+// Always writable in read-only mode, so the switches can be turned back off
+pub const READ_ONLY_EXEMPT: &[&str] = &[
+    "/admin/read-only",
+    "/admin/client-versions/{client_type}",
+    "/auth/logout",
+];
+
+// The global read-only switch, plus the routes it leaves alone
+#[derive(Debug, Clone)]
+pub struct ReadOnly {
+    enabled: Arc<AtomicBool>,
+    exempt: Arc<Vec<String>>,
+}
+
+impl ReadOnly {
+    pub fn new(config: &ReadOnlyConfig) -> Self {
+        let exempt = READ_ONLY_EXEMPT
+            .iter()
+            .map(|path| path.to_string())
+            .chain(config.exempt.iter().cloned())
+            .collect();
+        ReadOnly {
+            enabled: Arc::new(AtomicBool::new(config.enabled)),
+            exempt: Arc::new(exempt),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+// When the matched module's timeout runs out, shared by every Mongo operation the request makes
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub fn remaining(&self) -> Duration {
+        // Mongo treats a max_time of zero as "no limit", so never hand it out
+        self.0
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_millis(1))
+    }
+}
+
+// Set by `X-Dry-Run: true` or `?dry_run=1`, handlers that honour it validate but don't persist
+#[derive(Debug, Clone, Copy)]
+pub struct DryRun(pub bool);
+
+impl<S: Send + Sync> FromRequestParts<S> for DryRun {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let is_set = |value: &str| matches!(value, "1" | "true");
+        let header = parts
+            .headers
+            .get("X-Dry-Run")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(is_set);
+        let query = parts.uri.query().is_some_and(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .any(|(key, value)| key == "dry_run" && is_set(value))
+        });
+        Ok(DryRun(header || query))
+    }
+}
+
+tokio::task_local! {
+    pub(crate) static REQUEST_ID: String;
+}
+
+// The id of the request being handled on this task, if `scope_request_id` set one
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// Makes the `x-request-id` (set or honoured by `SetRequestIdLayer`) available to response bodies
+pub async fn scope_request_id(request: Request, next: Next) -> impl IntoResponse {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_string);
+
+    match request_id {
+        Some(request_id) => REQUEST_ID.scope(request_id, next.run(request)).await,
+        None => next.run(request).await,
+    }
+}
+
+pub async fn global_middleware(request: Request, next: Next) -> impl IntoResponse {
+    debug!("Hello from global middleware");
+    next.run(request).await
+}
+
+pub async fn call_with_id_middleware(request: Request, next: Next) -> impl IntoResponse {
+    let req_body = request.uri().path().trim_matches('/');
+    let result = req_body.parse::<u32>();
+    if result.is_ok() {
+        next.run(request).await
+    } else {
+        (StatusCode::OK, "Wrong input").into_response()
+    }
+}
+
+pub async fn middleware_to_request(mut request: Request, next: Next) -> impl IntoResponse {
+    let identity = Identity {
+        name: String::from("John Doe"),
+        age: 29,
+    };
+
+    request.extensions_mut().insert(Arc::new(identity));
+    next.run(request).await
+}
+
+// The lowest role `require_role` lets through, and what `AuthUser` needs to find the caller
+#[derive(Clone)]
+pub struct RequiredRole {
+    pub role: Role,
+    pub authenticator: Authenticator,
+    pub tenants: Tenants,
+}
+
+impl RequiredRole {
+    pub fn new(role: Role, state: &AppState) -> Self {
+        RequiredRole {
+            role,
+            authenticator: state.authenticator.clone(),
+            tenants: state.tenants.clone(),
+        }
+    }
+}
+
+impl FromRef<RequiredRole> for Authenticator {
+    fn from_ref(required: &RequiredRole) -> Self {
+        required.authenticator.clone()
+    }
+}
+
+impl FromRef<RequiredRole> for Tenants {
+    fn from_ref(required: &RequiredRole) -> Self {
+        required.tenants.clone()
+    }
+}
+
+// Used as `from_fn_with_state(RequiredRole::new(Role::Admin, state), require_role)`,
+// answers 403 below that role
+pub async fn require_role(
+    State(required): State<RequiredRole>,
+    user: AuthUser,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    if user.role() < required.role {
+        return AppError::Forbidden("Insufficient role".to_string()).into_response();
+    }
+    next.run(request).await
+}
+
+// Holds the guest token of a demo visitor
+pub const DEMO_COOKIE: &str = "demo_session";
+
+// Demo mode : a visitor without credentials becomes a guest kept in `DEMO_COOKIE`, so the
+// sandbox works from a browser with nothing set up. Goes inside the demo rate limit, which
+// keys these requests on the address since the token isn't in `Authorization` yet
+pub async fn demo_session(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if request.headers().contains_key(AUTHORIZATION) {
+        return next.run(request).await;
+    }
+    let guest = cookie_value(request.headers(), DEMO_COOKIE).filter(|token| {
+        access_claims(token, &state.authenticator.jwt_secret)
+            .is_ok_and(|claims| claims.role == Role::Guest)
+    });
+    let (token, issued) = match guest {
+        Some(token) => (token.to_string(), false),
+        // Expired, or not a guest's, the visitor starts over
+        None => {
+            let tenant = match state.tenants.tenant_of(request.headers()) {
+                Ok(tenant) => tenant,
+                Err(e) => return e.into_response(),
+            };
+            match generate_guest_token(tenant.as_deref(), &state.authenticator.jwt_secret.current())
+            {
+                Ok((_, token)) => (token, true),
+                Err(e) => return AppError::from(e).into_response(),
+            }
+        }
+    };
+
+    let Ok(bearer) = HeaderValue::try_from(format!("Bearer {}", token)) else {
+        return next.run(request).await;
+    };
+    request.headers_mut().insert(AUTHORIZATION, bearer);
+    let mut response = next.run(request).await;
+    if issued {
+        let cookie = format!(
+            "{}={}; Max-Age={}; Path=/; HttpOnly; Secure; SameSite=Strict",
+            DEMO_COOKIE,
+            token,
+            GUEST_TOKEN_TTL.as_secs()
+        );
+        if let Ok(cookie) = HeaderValue::try_from(cookie) {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+    response
+}
+
+// Answers 403 with what's missing when the route is in `Config::profile_requirements`
+// and the caller's profile lacks some of the fields it lists
+pub async fn require_profile_fields(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(request.uri().path(), MatchedPath::as_str);
+    let route = format!("{} {}", request.method(), unversioned(path));
+    let Some(required) = state.config.profile_requirements.get(&route) else {
+        return next.run(request).await;
+    };
+
+    let (mut parts, body) = request.into_parts();
+    let missing = match missing_fields(&mut parts, &state, required).await {
+        Ok(missing) => missing,
+        Err(e) => return e.into_response(),
+    };
+    if !missing.is_empty() {
+        return AppError::IncompleteProfile(missing).into_response();
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+async fn missing_fields(
+    parts: &mut Parts,
+    state: &AppState,
+    required: &[ProfileField],
+) -> Result<Vec<ProfileField>, AppError> {
+    let user = AuthUser::from_request_parts(parts, state).await?;
+    let TenantDb(database, _) = TenantDb::from_request_parts(parts, state).await?;
+    let users: Collection<User> = database.collection("users");
+    let Some(profile) = users
+        .find_one(doc! { "user_name": user.username() })
+        .await?
+    else {
+        return Err(AppError::Unauthorized("User no longer exists".to_string()));
+    };
+    Ok(required
+        .iter()
+        .copied()
+        .filter(|field| !field.is_set(&profile))
+        .collect())
+}
+
+// Lets safe methods through and answers 503 to everything else while read-only mode is on
+pub async fn enforce_read_only(
+    State(read_only): State<ReadOnly>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let is_safe = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if is_safe || !read_only.is_enabled() {
+        return next.run(request).await;
+    }
+
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(request.uri().path(), MatchedPath::as_str);
+    if read_only
+        .exempt
+        .iter()
+        .any(|exempt| exempt == unversioned(path))
+    {
+        return next.run(request).await;
+    }
+    AppError::ReadOnly.into_response()
+}
+
+pub fn parse_cidr(cidr: &str) -> Option<IpNet> {
+    // A bare address is treated as a single host network
+    cidr.parse::<IpNet>()
+        .ok()
+        .or_else(|| cidr.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+pub fn client_ip(request: &Request) -> Option<IpAddr> {
+    request
+        .extensions()
+        .get::<ConnectInfo<ClientAddr>>()
+        .map(|ConnectInfo(ClientAddr(addr))| addr.ip())
+}
+
+pub async fn deny_listed_ips(
+    State(ip_rules): State<IpRules>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    match client_ip(&request) {
+        Some(ip) if ip_rules.matches(ip, IpRuleAction::Deny) => {
+            (StatusCode::FORBIDDEN, "IP address denied").into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+pub async fn allow_listed_ips(
+    State(ip_rules): State<IpRules>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    match client_ip(&request) {
+        Some(ip) if ip_rules.matches(ip, IpRuleAction::Allow) => next.run(request).await,
+        _ => (StatusCode::FORBIDDEN, "IP address not allowed").into_response(),
+    }
+}
+
+// The `signing_secret` key, the routes that need it, and the nonces of signed requests still
+// inside the window so a captured request can't be sent again
+#[derive(Clone)]
+pub struct RequestSigner {
+    pub secret: RotatingSecret,
+    // `signed_routes`, e.g. `PUT /counter`
+    routes: Arc<HashSet<String>>,
+    // Nonce to the Unix second it can be forgotten at
+    nonces: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl RequestSigner {
+    pub fn new(secret: RotatingSecret, routes: &[String]) -> Self {
+        RequestSigner {
+            secret,
+            routes: Arc::new(routes.iter().cloned().collect()),
+            nonces: Arc::default(),
+        }
+    }
+
+    // Whether `method` on `path`, as registered without a version prefix, has to be signed
+    pub fn signs(&self, method: &str, path: &str) -> bool {
+        self.routes.contains(&format!("{} {}", method, path))
+    }
+
+    // Over the timestamp, nonce, method and path with its query, one per line, then the body
+    pub fn sign(
+        key: &[u8],
+        timestamp: u64,
+        nonce: &str,
+        method: &str,
+        path_and_query: &str,
+        body: &[u8],
+    ) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(
+            format!("{}\n{}\n{}\n{}\n", timestamp, nonce, method, path_and_query).as_bytes(),
+        );
+        mac.update(body);
+        mac
+    }
+
+    // False when the nonce was used already, or too many are tracked to take another
+    fn remember(&self, nonce: &str, timestamp: u64) -> bool {
+        let now = get_current_timestamp();
+        let mut nonces = self.nonces.lock().unwrap();
+        if nonces.contains_key(nonce) {
+            return false;
+        }
+        if nonces.len() >= MAX_TRACKED_NONCES {
+            nonces.retain(|_, expires| *expires > now);
+            if nonces.len() >= MAX_TRACKED_NONCES {
+                return false;
+            }
+        }
+        nonces.insert(nonce.to_string(), timestamp + SIGNATURE_MAX_AGE_SECS);
+        true
+    }
+}
+
+// 16 to 128 of `A-Z`, `a-z`, `0-9`, `-` and `_`, e.g. a UUID
+fn is_valid_nonce(nonce: &str) -> bool {
+    (16..=128).contains(&nonce.len())
+        && nonce
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+// Lets the routes that aren't in `signed_routes` through unchecked
+pub async fn verify_signature(
+    State(signer): State<RequestSigner>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(request.uri().path(), MatchedPath::as_str);
+    if !signer.signs(request.method().as_str(), unversioned(path)) {
+        return next.run(request).await;
+    }
+
+    let headers = request.headers();
+    let timestamp = headers
+        .get("X-Timestamp")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let nonce = headers
+        .get("X-Nonce")
+        .and_then(|value| value.to_str().ok())
+        .filter(|nonce| is_valid_nonce(nonce))
+        .map(str::to_string);
+    let signature = headers
+        .get("X-Signature")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| hex::decode(value).ok());
+
+    let (Some(timestamp), Some(nonce), Some(signature)) = (timestamp, nonce, signature) else {
+        return (StatusCode::UNAUTHORIZED, "Missing request signature").into_response();
+    };
+
+    if get_current_timestamp().abs_diff(timestamp) > SIGNATURE_MAX_AGE_SECS {
+        return (StatusCode::UNAUTHORIZED, "Stale request signature").into_response();
+    }
+
+    // The body has to be buffered to be signed, then handed back to the handler
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, SIGNED_BODY_LIMIT).await else {
+        return (StatusCode::BAD_REQUEST, "Unreadable request body").into_response();
+    };
+
+    // Signed as sent, with the `/api/v1` prefix nested routers strip
+    let uri = parts
+        .extensions
+        .get::<OriginalUri>()
+        .map_or(&parts.uri, |uri| &uri.0);
+    let path_and_query = uri
+        .path_and_query()
+        .map_or(uri.path(), |path_and_query| path_and_query.as_str());
+    // Signatures made with the key a rotation just replaced still pass
+    let valid = signer.secret.verifying().iter().any(|key| {
+        RequestSigner::sign(
+            key,
+            timestamp,
+            &nonce,
+            parts.method.as_str(),
+            path_and_query,
+            &body,
+        )
+        .verify_slice(&signature)
+        .is_ok()
+    });
+    if !valid {
+        return (StatusCode::UNAUTHORIZED, "Invalid request signature").into_response();
+    }
+    if !signer.remember(&nonce, timestamp) {
+        return (StatusCode::UNAUTHORIZED, "Replayed request signature").into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+// Answers 408 when the client was still sending its body, 503 when the handler was slow.
+// Streamed responses only have to start in time
+pub async fn enforce_timeout(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let (parts, body) = request.into_parts();
+    // From the headers, the body has usually been wrapped by the throughput check already
+    let has_body = parts.headers.contains_key(TRANSFER_ENCODING)
+        || parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|length| length != "0");
+    let body_done = Arc::new(AtomicBool::new(!has_body));
+    let done = Arc::clone(&body_done);
+    let chunks = stream::unfold(body.into_data_stream(), move |mut chunks| {
+        let done = Arc::clone(&done);
+        async move {
+            let chunk = chunks.next().await;
+            if chunk.is_none() {
+                done.store(true, Ordering::Relaxed);
+            }
+            Some((chunk?, chunks))
+        }
+    });
+    let request = Request::from_parts(parts, Body::from_stream(chunks));
+
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) if !body_done.load(Ordering::Relaxed) => {
+            AppError::RequestTimeout("Request body took too long".to_string()).into_response()
+        }
+        Err(_) => AppError::Timeout("Request took too long".to_string()).into_response(),
+    }
+}
+
+tokio::task_local! {
+    static DEADLINE: Deadline;
+}
+
+// The deadline of the request being handled on this task, if `attach_deadline` set one
+pub fn current_deadline() -> Option<Deadline> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+// Layered with `enforce_timeout` and the same timeout, so Mongo gives up when the request does
+pub async fn attach_deadline(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let deadline = Deadline(Instant::now() + timeout);
+    DEADLINE.scope(deadline, next.run(request)).await
+}
+
+// The body limits of `connections`
+#[derive(Debug, Clone, Copy)]
+pub struct BodyThroughput {
+    pub stall_timeout: Duration,
+    pub grace: Duration,
+    pub min_bytes_per_sec: f64,
+}
+
+impl From<&ConnectionsConfig> for BodyThroughput {
+    fn from(config: &ConnectionsConfig) -> Self {
+        BodyThroughput {
+            stall_timeout: Duration::from_secs(config.body_stall_timeout_secs),
+            grace: Duration::from_secs(config.body_throughput_grace_secs),
+            min_bytes_per_sec: config.min_body_bytes_per_sec as f64,
+        }
+    }
+}
+
+// Fails the request body when a client stalls or drips it in too slowly
+pub async fn enforce_body_throughput(
+    State(limits): State<BodyThroughput>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let (parts, body) = request.into_parts();
+    let started = Instant::now();
+
+    let chunks = stream::unfold(
+        Some((body.into_data_stream(), 0usize)),
+        move |state| async move {
+            let (mut chunks, received) = state?;
+            let chunk = match tokio::time::timeout(limits.stall_timeout, chunks.next()).await {
+                Ok(Some(Ok(chunk))) => chunk,
+                Ok(Some(Err(e))) => return Some((Err(io::Error::other(e)), None)),
+                Ok(None) => return None,
+                Err(_) => {
+                    let e = io::Error::new(io::ErrorKind::TimedOut, "Request body stalled");
+                    return Some((Err(e), None));
+                }
+            };
+
+            let received = received + chunk.len();
+            let elapsed = started.elapsed();
+            if elapsed > limits.grace
+                && (received as f64 / elapsed.as_secs_f64()) < limits.min_bytes_per_sec
+            {
+                let e = io::Error::new(io::ErrorKind::TimedOut, "Request body too slow");
+                return Some((Err(e), None));
+            }
+
+            Some((Ok(chunk), Some((chunks, received))))
+        },
+    );
+
+    next.run(Request::from_parts(parts, Body::from_stream(chunks)))
+        .await
+}
@@ -0,0 +1,277 @@
+use axum::{
+    body::Bytes,
+    http::{header::CONTENT_TYPE, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{MethodFilter, MethodRouter},
+    Router,
+};
+use serde_json::{Map, Value};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    error::AppError,
+    openapi::{docs_router, documented},
+    routes::cors_layer,
+};
+
+// Set on every mocked answer, so nobody mistakes one for the real thing
+pub const MOCK_HEADER: HeaderName = HeaderName::from_static("x-mock");
+// Deeper schemas are cut off, a recursive one would never end
+const MAX_DEPTH: usize = 8;
+
+// What a mocked operation answers with, worked out once at startup
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: StatusCode,
+    pub content_type: Option<String>,
+    pub body: Bytes,
+}
+
+impl IntoResponse for MockResponse {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, self.body).into_response();
+        let headers = response.headers_mut();
+        headers.insert(MOCK_HEADER, HeaderValue::from_static("true"));
+        if let Some(value) = self
+            .content_type
+            .and_then(|content_type| HeaderValue::try_from(content_type).ok())
+        {
+            headers.insert(CONTENT_TYPE, value);
+        }
+        response
+    }
+}
+
+// An example for `schema`, the spec's own where there is one, otherwise made up from the
+// types. `components` resolves `$ref`s
+pub fn example(schema: &Value, components: &Value) -> Value {
+    example_at(schema, components, 0)
+}
+
+fn example_at(schema: &Value, components: &Value, depth: usize) -> Value {
+    if depth > MAX_DEPTH {
+        return Value::Null;
+    }
+    let next = |schema: &Value| example_at(schema, components, depth + 1);
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.rsplit('/').next().unwrap_or_default();
+        return next(&components["schemas"][name]);
+    }
+    if let Some(example) = schema.get("example") {
+        return example.clone();
+    }
+    if let Some(example) = schema["examples"].get(0).or_else(|| schema.get("default")) {
+        return example.clone();
+    }
+    if let Some(value) = schema["enum"].get(0) {
+        return value.clone();
+    }
+    if let Some(parts) = schema["allOf"].as_array() {
+        let mut merged = Map::new();
+        for part in parts {
+            if let Value::Object(fields) = next(part) {
+                merged.extend(fields);
+            }
+        }
+        return Value::Object(merged);
+    }
+    for alternatives in ["oneOf", "anyOf"] {
+        if let Some(alternatives) = schema[alternatives].as_array() {
+            return alternatives
+                .iter()
+                .map(next)
+                .find(|value| !value.is_null())
+                .unwrap_or(Value::Null);
+        }
+    }
+
+    // 3.1 writes nullable types as `["string", "null"]`
+    let kind = match &schema["type"] {
+        Value::Array(kinds) => kinds
+            .iter()
+            .filter_map(Value::as_str)
+            .find(|kind| *kind != "null")
+            .unwrap_or("null"),
+        kind => kind.as_str().unwrap_or_else(|| {
+            if schema.get("properties").is_some() {
+                "object"
+            } else {
+                "null"
+            }
+        }),
+    };
+    match kind {
+        "object" => Value::Object(
+            schema["properties"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(name, property)| (name.clone(), next(property)))
+                .collect(),
+        ),
+        "array" => Value::Array(vec![next(&schema["items"])]),
+        "string" => Value::from(match schema["format"].as_str() {
+            Some("date-time") => "2026-01-01T00:00:00Z",
+            Some("date") => "2026-01-01",
+            Some("email") => "alice@example.com",
+            Some("uri") => "https://example.com",
+            Some("uuid") => "00000000-0000-4000-8000-000000000000",
+            _ => "string",
+        }),
+        "integer" => schema["minimum"].as_i64().unwrap_or(0).into(),
+        "number" => schema["minimum"].as_f64().unwrap_or(0.0).into(),
+        "boolean" => Value::Bool(true),
+        _ => Value::Null,
+    }
+}
+
+// The first success an operation documents, with the example of its first content type
+pub fn mock_response(operation: &Value, components: &Value) -> MockResponse {
+    let responses = operation["responses"].as_object();
+    let success = responses
+        .into_iter()
+        .flatten()
+        .find_map(|(code, response)| {
+            let status = code.parse::<StatusCode>().ok()?;
+            status.is_success().then_some((status, response))
+        });
+    let Some((status, response)) = success else {
+        return MockResponse {
+            status: StatusCode::NO_CONTENT,
+            content_type: None,
+            body: Bytes::new(),
+        };
+    };
+    let Some((content_type, content)) = response["content"]
+        .as_object()
+        .and_then(|content| content.iter().next())
+    else {
+        return MockResponse {
+            status,
+            content_type: None,
+            body: Bytes::new(),
+        };
+    };
+
+    let example = content
+        .get("example")
+        .cloned()
+        .unwrap_or_else(|| example(&content["schema"], components));
+    // Text bodies are sent as is, not as a JSON string
+    let body = match (&example, content_type.starts_with("application/")) {
+        (Value::String(text), false) => text.clone(),
+        _ => example.to_string(),
+    };
+    MockResponse {
+        status,
+        content_type: Some(content_type.clone()),
+        body: Bytes::from(body),
+    }
+}
+
+// `--mock` : every documented operation answers with an example from the OpenAPI spec
+// instead of running its handler, so frontends can build against the API before a feature
+// lands. Requests are not looked at, nothing is stored and MongoDB isn't needed
+pub fn mock_app(config: &Config) -> Router {
+    let spec = documented();
+    let json = serde_json::to_value(&spec).unwrap_or_else(|e| {
+        warn!("Error serializing the OpenAPI spec : {}", e);
+        Value::Null
+    });
+    let components = &json["components"];
+
+    let mut router = Router::new();
+    for (path, item) in json["paths"].as_object().into_iter().flatten() {
+        let mut methods = MethodRouter::new();
+        let mut mocked = false;
+        for (method, operation) in item.as_object().into_iter().flatten() {
+            let filter = match method.as_str() {
+                "get" => MethodFilter::GET,
+                "put" => MethodFilter::PUT,
+                "post" => MethodFilter::POST,
+                "delete" => MethodFilter::DELETE,
+                "patch" => MethodFilter::PATCH,
+                "head" => MethodFilter::HEAD,
+                "options" => MethodFilter::OPTIONS,
+                "trace" => MethodFilter::TRACE,
+                // Path level `parameters` and the like
+                _ => continue,
+            };
+            let response = mock_response(operation, components);
+            let handler = move || async move { response };
+            methods = methods.on(filter, handler);
+            mocked = true;
+        }
+        if mocked {
+            router = router.route(path, methods);
+        }
+    }
+
+    router
+        .merge(docs_router(&spec))
+        .fallback(|| async { AppError::NotFound("Not documented, so not mocked".to_string()) })
+        .layer(cors_layer(config))
+        .layer(TraceLayer::new_for_http())
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn makes_up_examples_from_types() {
+        let components = json!({ "schemas": {
+            "Node": {
+                "type": "object",
+                "properties": {
+                    "label": { "type": ["string", "null"] },
+                    "next": { "$ref": "#/components/schemas/Node" },
+                },
+            },
+        }});
+        let schema = json!({
+            "allOf": [
+                { "type": "object", "properties": { "at": { "type": "string", "format": "date-time" } } },
+                { "type": "object", "properties": {
+                    "kind": { "type": "string", "enum": ["a", "b"] },
+                    "sizes": { "type": "array", "items": { "type": "integer", "minimum": 1 } },
+                    "node": { "$ref": "#/components/schemas/Node" },
+                } },
+            ],
+        });
+
+        let example = example(&schema, &components);
+        assert_eq!(example["at"], "2026-01-01T00:00:00Z");
+        assert_eq!(example["kind"], "a");
+        assert_eq!(example["sizes"], json!([1]));
+        assert_eq!(example["node"]["label"], "string");
+        // Recursion stops at `MAX_DEPTH`
+        let mut node = &example["node"];
+        let mut levels = 0;
+        while node.is_object() {
+            node = &node["next"];
+            levels += 1;
+        }
+        assert!((1..=MAX_DEPTH).contains(&levels));
+    }
+
+    #[test]
+    fn answers_with_the_first_success() {
+        let operation = json!({ "responses": {
+            "409": { "description": "Taken" },
+            "201": { "description": "Created" },
+        }});
+        let response = mock_response(&operation, &Value::Null);
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(response.body.is_empty());
+    }
+}
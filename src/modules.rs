@@ -0,0 +1,444 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware::from_fn_with_state,
+    routing::{delete, get, post, put},
+    Extension, Router,
+};
+use tracing::{error, warn};
+
+use crate::{
+    banner::{route, Access, RouteInfo},
+    config::{Config, Environment},
+    db::{
+        create_group_indexes, create_sandbox_indexes, create_settings_indexes, create_user_indexes,
+    },
+    delivery::{LogDelivery, TokenDelivery, WebhookDelivery},
+    handlers::{admin, auth, files, media, sandbox, scim, settings, users},
+    health::UploadDirHealthCheck,
+    middleware::{allow_listed_ips, demo_session, require_role, RequiredRole},
+    models::Role,
+    rate_limit::{rate_limit, RateLimiter},
+    scim::{require_scim_token, ScimToken},
+    state::AppState,
+};
+
+// A self-contained feature, `app_with_modules` merges its routes into the app
+pub trait AppModule: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Routes are mounted under every served API version, `/api/v1` and the legacy root, so
+    // a module nests its own prefix inside that. `app_with_modules` provides the state,
+    // `state` is there for what the routes are built with
+    fn routes(&self, state: &AppState) -> Router<AppState>;
+
+    // Runs once while the app is built, inside the runtime, so tasks can be spawned here
+    fn on_startup(&self, _state: &AppState) {}
+
+    // For the route table logged on startup, kept in step with `routes`
+    fn route_table(&self) -> &'static [RouteInfo] {
+        &[]
+    }
+}
+
+pub struct AuthModule {
+    // Where `/auth/forgot-password` sends reset tokens, which isn't served without any
+    pub delivery: Option<Arc<dyn TokenDelivery>>,
+}
+
+impl AuthModule {
+    // Tokens go to `password_reset.webhook_url`, or to the log in development. Anywhere else
+    // logging them would either hide them behind redaction or leak them
+    pub fn new(config: &Config) -> Self {
+        let delivery: Option<Arc<dyn TokenDelivery>> = match &config.password_reset.webhook_url {
+            Some(url) => Some(Arc::new(WebhookDelivery::new(url))),
+            None if config.environment == Environment::Development => Some(Arc::new(LogDelivery)),
+            None => {
+                warn!("password_reset.webhook_url is unset, /auth/forgot-password is off");
+                None
+            }
+        };
+        AuthModule { delivery }
+    }
+}
+
+impl AppModule for AuthModule {
+    fn name(&self) -> &'static str {
+        "auth"
+    }
+
+    fn routes(&self, state: &AppState) -> Router<AppState> {
+        let auth_router = Router::new()
+            .route("/signup", post(auth::signup))
+            .route("/signin", post(auth::signin))
+            .route("/guest", post(auth::guest))
+            .route("/claim", post(auth::claim))
+            .route("/refresh", post(auth::refresh))
+            .route("/revoke", post(auth::revoke))
+            .route("/usage", get(auth::usage))
+            .route("/protected", get(auth::protected))
+            .route("/reset-password", post(auth::reset_password))
+            .route("/change-password", post(auth::change_password))
+            .route("/logout", post(auth::logout));
+        let auth_router = match &self.delivery {
+            Some(delivery) => auth_router.route(
+                "/forgot-password",
+                post(auth::forgot_password).layer(Extension(Arc::clone(delivery))),
+            ),
+            None => auth_router,
+        };
+
+        // Limited per address, so signin can't be brute forced
+        let auth_router = auth_router.route_layer(from_fn_with_state(
+            RateLimiter::new(&state.config.auth_rate_limit, state.authenticator.clone()),
+            rate_limit,
+        ));
+
+        let mut sandbox_router = Router::new().route(
+            "/sandbox/counter",
+            get(sandbox::get_sandbox_counter).post(sandbox::increment_sandbox_counter),
+        );
+        if state.config.demo_mode {
+            sandbox_router = sandbox_router
+                .route_layer(from_fn_with_state(state.clone(), demo_session))
+                .route_layer(from_fn_with_state(
+                    RateLimiter::new(&state.config.demo_rate_limit, state.authenticator.clone()),
+                    rate_limit,
+                ));
+        }
+
+        Router::new()
+            .nest("/auth", auth_router)
+            .merge(sandbox_router)
+    }
+
+    fn route_table(&self) -> &'static [RouteInfo] {
+        const ROUTES: &[RouteInfo] = &[
+            route("POST", "/auth/signup", Access::Public),
+            route("POST", "/auth/signin", Access::Public),
+            route("POST", "/auth/guest", Access::Public),
+            route("POST", "/auth/claim", Access::Guest),
+            route("POST", "/auth/refresh", Access::Public),
+            route("POST", "/auth/revoke", Access::Public),
+            route("GET", "/auth/usage", Access::User),
+            route("GET", "/auth/protected", Access::User),
+            route("POST", "/auth/reset-password", Access::Public),
+            route("POST", "/auth/change-password", Access::User),
+            route("POST", "/auth/logout", Access::User),
+            route("GET", "/sandbox/counter", Access::Guest),
+            route("POST", "/sandbox/counter", Access::Guest),
+            // Last, so it can be left out
+            route("POST", "/auth/forgot-password", Access::Public),
+        ];
+        match self.delivery {
+            Some(_) => ROUTES,
+            None => &ROUTES[..ROUTES.len() - 1],
+        }
+    }
+
+    fn on_startup(&self, state: &AppState) {
+        let database = state.database.clone();
+        tokio::spawn(async move {
+            // Fails while duplicate names are stored, signup still checks up front then
+            if let Err(e) = create_user_indexes(&database).await {
+                error!("Error creating user indexes : {}", e);
+            }
+            // Without it guest sandboxes are never torn down
+            if let Err(e) = create_sandbox_indexes(&database).await {
+                error!("Error creating sandbox indexes : {}", e);
+            }
+        });
+    }
+}
+
+pub struct AdminModule;
+
+impl AppModule for AdminModule {
+    fn name(&self) -> &'static str {
+        "admin"
+    }
+
+    fn routes(&self, state: &AppState) -> Router<AppState> {
+        // Only reachable by an admin from an allowlisted address, see `admin_allowlist`
+        let admin_router = Router::new()
+            .route(
+                "/ip-rules",
+                get(admin::list_ip_rules).post(admin::create_ip_rule),
+            )
+            .route("/ip-rules/{id}", delete(admin::delete_ip_rule))
+            .route("/runtime", get(admin::runtime_stats))
+            .route("/analytics/clients", get(admin::client_analytics))
+            .route("/metrics/auth", get(admin::auth_funnel))
+            .route("/deprecations", get(admin::deprecated_calls))
+            .route("/throttles", get(admin::list_throttles))
+            .route(
+                "/throttles/{client}",
+                put(admin::set_throttle).delete(admin::lift_throttle),
+            )
+            .route("/users", get(admin::list_all_users))
+            .route("/users/{id}", delete(admin::delete_any_user))
+            .route("/counters", get(admin::view_counters))
+            .route(
+                "/read-only",
+                get(admin::get_read_only).put(admin::set_read_only),
+            )
+            .route("/client-versions", get(admin::get_client_versions))
+            .route(
+                "/client-versions/{client_type}",
+                put(admin::set_client_version),
+            )
+            .route_layer(from_fn_with_state(
+                RequiredRole::new(Role::Admin, state),
+                require_role,
+            ))
+            .route_layer(from_fn_with_state(state.ip_rules.clone(), allow_listed_ips));
+
+        Router::new().nest("/admin", admin_router)
+    }
+
+    fn route_table(&self) -> &'static [RouteInfo] {
+        const ROUTES: &[RouteInfo] = &[
+            route("GET, POST", "/admin/ip-rules", Access::Admin),
+            route("DELETE", "/admin/ip-rules/{id}", Access::Admin),
+            route("GET", "/admin/runtime", Access::Admin),
+            route("GET", "/admin/analytics/clients", Access::Admin),
+            route("GET", "/admin/metrics/auth", Access::Admin),
+            route("GET", "/admin/deprecations", Access::Admin),
+            route("GET", "/admin/throttles", Access::Admin),
+            route("PUT, DELETE", "/admin/throttles/{client}", Access::Admin),
+            route("GET", "/admin/users", Access::Admin),
+            route("DELETE", "/admin/users/{id}", Access::Admin),
+            route("GET", "/admin/counters", Access::Admin),
+            route("GET, PUT", "/admin/read-only", Access::Admin),
+            route("GET", "/admin/client-versions", Access::Admin),
+            route("PUT", "/admin/client-versions/{client_type}", Access::Admin),
+        ];
+        ROUTES
+    }
+}
+
+pub struct FilesModule;
+
+impl AppModule for FilesModule {
+    fn name(&self) -> &'static str {
+        "files"
+    }
+
+    fn routes(&self, _state: &AppState) -> Router<AppState> {
+        let files_router = Router::new()
+            .route("/", post(files::create_upload))
+            .route(
+                "/{id}/content",
+                put(files::upload_chunk).head(files::upload_offset),
+            )
+            .route("/{id}/finalize", post(files::finalize_upload))
+            .route("/{id}", get(files::download_file));
+
+        Router::new().nest("/files", files_router).route(
+            "/upload",
+            post(files::upload_multipart).layer(DefaultBodyLimit::max(files::MULTIPART_BODY_LIMIT)),
+        )
+    }
+
+    fn route_table(&self) -> &'static [RouteInfo] {
+        const ROUTES: &[RouteInfo] = &[
+            route("POST", "/files", Access::User),
+            route("PUT, HEAD", "/files/{id}/content", Access::User),
+            route("POST", "/files/{id}/finalize", Access::User),
+            route("GET", "/files/{id}", Access::User),
+            route("POST", "/upload", Access::User),
+        ];
+        ROUTES
+    }
+
+    fn on_startup(&self, state: &AppState) {
+        if let Err(e) = std::fs::create_dir_all(files::UPLOAD_DIR) {
+            error!("Error creating {} : {}", files::UPLOAD_DIR, e);
+        }
+        state.health.register(UploadDirHealthCheck);
+    }
+}
+
+pub struct UsersModule;
+
+impl AppModule for UsersModule {
+    fn name(&self) -> &'static str {
+        "users"
+    }
+
+    fn routes(&self, _state: &AppState) -> Router<AppState> {
+        let users_router = Router::new().route("/", get(users::list_users)).route(
+            "/{id}",
+            get(users::get_user)
+                .patch(users::update_user)
+                .delete(users::delete_user),
+        );
+
+        Router::new().nest("/users", users_router)
+    }
+
+    fn route_table(&self) -> &'static [RouteInfo] {
+        const ROUTES: &[RouteInfo] = &[
+            route("GET", "/users", Access::User),
+            route("GET, PATCH, DELETE", "/users/{id}", Access::User),
+        ];
+        ROUTES
+    }
+}
+
+pub struct MediaModule;
+
+impl AppModule for MediaModule {
+    fn name(&self) -> &'static str {
+        "media"
+    }
+
+    fn routes(&self, state: &AppState) -> Router<AppState> {
+        // Fetching and rendering is work anyone can ask for. `/qr` and `/img` share buckets
+        let limiter = RateLimiter::new(&state.config.media_rate_limit, state.authenticator.clone());
+        Router::new()
+            .route("/qr", get(media::qr_code))
+            .route("/img", get(media::image_proxy))
+            .route_layer(from_fn_with_state(limiter, rate_limit))
+    }
+
+    fn route_table(&self) -> &'static [RouteInfo] {
+        const ROUTES: &[RouteInfo] = &[
+            route("GET", "/qr", Access::Public),
+            route("GET", "/img", Access::Public),
+        ];
+        ROUTES
+    }
+}
+
+pub struct SettingsModule;
+
+impl AppModule for SettingsModule {
+    fn name(&self) -> &'static str {
+        "settings"
+    }
+
+    fn routes(&self, _state: &AppState) -> Router<AppState> {
+        let settings_router = Router::new()
+            .route("/", get(settings::get_settings).put(settings::set_settings))
+            .route(
+                "/{key}",
+                get(settings::get_setting)
+                    .put(settings::set_setting)
+                    .delete(settings::delete_setting),
+            );
+
+        Router::new().nest("/settings", settings_router)
+    }
+
+    fn route_table(&self) -> &'static [RouteInfo] {
+        const ROUTES: &[RouteInfo] = &[
+            route("GET, PUT", "/settings", Access::User),
+            route("GET, PUT, DELETE", "/settings/{key}", Access::User),
+        ];
+        ROUTES
+    }
+
+    fn on_startup(&self, state: &AppState) {
+        let database = state.database.clone();
+        tokio::spawn(async move {
+            if let Err(e) = create_settings_indexes(&database).await {
+                error!("Error creating settings indexes : {}", e);
+            }
+        });
+    }
+}
+
+pub struct ScimModule {
+    pub token: ScimToken,
+}
+
+impl AppModule for ScimModule {
+    fn name(&self) -> &'static str {
+        "scim"
+    }
+
+    fn routes(&self, _state: &AppState) -> Router<AppState> {
+        let scim_router = Router::new()
+            .route("/ServiceProviderConfig", get(scim::service_provider_config))
+            .route("/Users", get(scim::list_users).post(scim::create_user))
+            .route(
+                "/Users/{id}",
+                get(scim::get_user)
+                    .put(scim::replace_user)
+                    .patch(scim::patch_user)
+                    .delete(scim::delete_user),
+            )
+            .route("/Groups", get(scim::list_groups).post(scim::create_group))
+            .route(
+                "/Groups/{id}",
+                get(scim::get_group)
+                    .put(scim::replace_group)
+                    .patch(scim::patch_group)
+                    .delete(scim::delete_group),
+            )
+            .route_layer(from_fn_with_state(self.token.clone(), require_scim_token));
+
+        Router::new().nest("/scim/v2", scim_router)
+    }
+
+    fn route_table(&self) -> &'static [RouteInfo] {
+        const ROUTES: &[RouteInfo] = &[
+            route("GET", "/scim/v2/ServiceProviderConfig", Access::Scim),
+            route("GET, POST", "/scim/v2/Users", Access::Scim),
+            route(
+                "GET, PUT, PATCH, DELETE",
+                "/scim/v2/Users/{id}",
+                Access::Scim,
+            ),
+            route("GET, POST", "/scim/v2/Groups", Access::Scim),
+            route(
+                "GET, PUT, PATCH, DELETE",
+                "/scim/v2/Groups/{id}",
+                Access::Scim,
+            ),
+        ];
+        ROUTES
+    }
+
+    fn on_startup(&self, state: &AppState) {
+        let database = state.database.clone();
+        tokio::spawn(async move {
+            if let Err(e) = create_group_indexes(&database).await {
+                error!("Error creating group indexes : {}", e);
+            }
+        });
+    }
+}
+
+// The built-in route groups that the configuration leaves switched on
+pub fn builtin_modules(config: &Config) -> Vec<Box<dyn AppModule>> {
+    let groups = &config.route_groups;
+    let mut modules: Vec<Box<dyn AppModule>> = Vec::new();
+    if groups.auth {
+        modules.push(Box::new(AuthModule::new(config)));
+    }
+    if groups.admin {
+        modules.push(Box::new(AdminModule));
+    }
+    if groups.files {
+        modules.push(Box::new(FilesModule));
+    }
+    if groups.users {
+        modules.push(Box::new(UsersModule));
+    }
+    if groups.media {
+        modules.push(Box::new(MediaModule));
+    }
+    if groups.settings {
+        modules.push(Box::new(SettingsModule));
+    }
+    // Checked by `Config::validate`
+    if let Some(token) = config.scim.bearer_token.as_deref().filter(|_| groups.scim) {
+        modules.push(Box::new(ScimModule {
+            token: ScimToken::new(token),
+        }));
+    }
+    modules
+}
@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    body::Bytes,
+    http::header::{CONTENT_SECURITY_POLICY, CONTENT_TYPE},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tracing::warn;
+use utoipa::{
+    openapi::{
+        path::{Operation, PathItem},
+        schema::Type,
+        security::{
+            ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme,
+        },
+        ContentBuilder, ObjectBuilder, RefOr, ResponseBuilder, Schema,
+    },
+    Modify, OpenApi, PartialSchema, ToSchema,
+};
+
+use crate::{
+    banner::{Access, RouteInfo},
+    handlers::{auth, basics, counter, sandbox},
+    middleware::SIGNATURE_MAX_AGE_SECS,
+    models::ResponseData,
+};
+
+pub const SPEC_PATH: &str = "/api-docs/openapi.json";
+pub const SWAGGER_UI_PATH: &str = "/swagger-ui";
+
+// Stands in for `()` in documented bodies, it serializes as `null`
+pub struct Empty;
+
+impl PartialSchema for Empty {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new().schema_type(Type::Null).into()
+    }
+}
+
+impl ToSchema for Empty {}
+
+// What every `AppError` but validation, read-only and outdated client failures looks like
+pub type ErrorBody = ResponseData<Empty>;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .description(Some("An access token from `/auth/signin`"))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "signature",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-Signature",
+                "Hex HMAC-SHA256 of the timestamp, nonce, method, path with query and body, one per line",
+            ))),
+        );
+        components.add_security_scheme(
+            "timestamp",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-Timestamp",
+                &format!(
+                    "Unix seconds the signature was made at, within {} seconds of the server clock",
+                    SIGNATURE_MAX_AGE_SECS
+                ),
+            ))),
+        );
+        components.add_security_scheme(
+            "nonce",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-Nonce",
+                "16 to 128 of `A-Z`, `a-z`, `0-9`, `-` and `_`, accepted once",
+            ))),
+        );
+    }
+}
+
+// The business routes, documented at their `/api/v1` paths only. The legacy unversioned
+// copies are deprecated and left out
+#[derive(OpenApi)]
+#[openapi(paths(
+    auth::signup,
+    auth::signin,
+    auth::guest,
+    auth::claim,
+    auth::refresh,
+    auth::revoke,
+    auth::logout,
+    counter::get_counter,
+    counter::put_counter,
+    counter::delete_counter,
+    counter::increase_counter,
+    counter::counter_stats,
+    counter::get_named_counter,
+    counter::create_named_counter,
+    counter::set_named_counter,
+    counter::increment_named_counter,
+    counter::delete_named_counter,
+    sandbox::get_sandbox_counter,
+    sandbox::increment_sandbox_counter,
+))]
+struct ApiV1;
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(basics::parse_json),
+    nest((path = "/api/v1", api = ApiV1)),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "auth", description = "Accounts and tokens"),
+        (name = "counter", description = "The default counter and named ones"),
+        (name = "sandbox", description = "A counter of the caller's own, guests included"),
+        (name = "basics", description = "Examples"),
+    )
+)]
+struct ApiDoc;
+
+fn operation<'a>(item: &'a mut PathItem, method: &str) -> Option<&'a mut Option<Operation>> {
+    Some(match method {
+        "GET" => &mut item.get,
+        "PUT" => &mut item.put,
+        "POST" => &mut item.post,
+        "DELETE" => &mut item.delete,
+        "OPTIONS" => &mut item.options,
+        "HEAD" => &mut item.head,
+        "PATCH" => &mut item.patch,
+        "TRACE" => &mut item.trace,
+        _ => return None,
+    })
+}
+
+const METHODS: &[&str] = &[
+    "GET", "PUT", "POST", "DELETE", "OPTIONS", "HEAD", "PATCH", "TRACE",
+];
+
+// Everything documented, whether or not this configuration serves it
+pub fn documented() -> utoipa::openapi::OpenApi {
+    let mut spec = ApiDoc::openapi();
+    spec.info.title = env!("CARGO_PKG_NAME").to_string();
+    spec.info.version = env!("CARGO_PKG_VERSION").to_string();
+    spec.info.description = None;
+    spec.info.license = None;
+    spec
+}
+
+// The documented operations the router actually serves. Anything documented for a route
+// that a disabled group or a rename left out is dropped with a warning, so the spec can't
+// advertise what would 404
+pub fn spec(route_table: &[RouteInfo]) -> utoipa::openapi::OpenApi {
+    let mut served: BTreeMap<String, Vec<(&str, Access)>> = BTreeMap::new();
+    for route in route_table {
+        served.entry(route.full_path()).or_default().extend(
+            route
+                .methods
+                .split(',')
+                .map(|method| (method.trim(), route.access)),
+        );
+    }
+
+    let mut spec = documented();
+    spec.paths.paths.retain(|path, item| {
+        let methods = served.get(path.as_str()).cloned().unwrap_or_default();
+        for method in METHODS {
+            let Some(operation) = operation(item, method) else {
+                continue;
+            };
+            match methods.iter().find(|(served, _)| served == method) {
+                None if operation.is_some() => {
+                    if !methods.is_empty() {
+                        warn!("{} {} is documented but not routed", method, path);
+                    }
+                    *operation = None;
+                }
+                Some((_, Access::Signed)) => {
+                    if let Some(operation) = operation {
+                        require_signature(operation);
+                    }
+                }
+                _ => {}
+            }
+        }
+        METHODS
+            .iter()
+            .any(|method| operation(item, method).is_some_and(|operation| operation.is_some()))
+    });
+    spec
+}
+
+// For the routes in `signed_routes`, the others don't check signatures
+fn require_signature(operation: &mut Operation) {
+    let headers = ["signature", "timestamp", "nonce"];
+    operation.security = Some(vec![headers
+        .iter()
+        .fold(SecurityRequirement::default(), |requirement, header| {
+            requirement.add(*header, Vec::<String>::new())
+        })]);
+    operation.responses.responses.insert(
+        "401".to_string(),
+        RefOr::T(
+            ResponseBuilder::new()
+                .description("Missing or invalid signature")
+                .content(
+                    "text/plain",
+                    ContentBuilder::new().schema(Some(String::schema())).build(),
+                )
+                .build(),
+        ),
+    );
+}
+
+// `{nonce}` is filled in per request, see `swagger_ui`
+const SWAGGER_UI: &str = r##"<!doctype html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8">
+    <title>hello-axum API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script nonce="{nonce}" src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script nonce="{nonce}">
+      window.ui = SwaggerUIBundle({ url: "/api-docs/openapi.json", dom_id: "#swagger-ui" });
+    </script>
+  </body>
+</html>
+"##;
+
+// Only scripts carrying the nonce run, so nothing injected into the page can. Swagger UI sets
+// style attributes as it renders, hence the inline styles
+fn swagger_ui() -> Response {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    let nonce = STANDARD.encode(bytes);
+    let policy = format!(
+        "default-src 'none'; script-src 'nonce-{nonce}'; \
+         style-src https://unpkg.com 'unsafe-inline'; img-src 'self' data:; connect-src 'self'; \
+         base-uri 'none'; form-action 'none'; frame-ancestors 'none'"
+    );
+    (
+        [(CONTENT_SECURITY_POLICY, policy)],
+        Html(SWAGGER_UI.replace("{nonce}", &nonce)),
+    )
+        .into_response()
+}
+
+// The spec is rendered once, the UI is a page loading Swagger UI from a CDN
+pub fn docs_router<S: Clone + Send + Sync + 'static>(spec: &utoipa::openapi::OpenApi) -> Router<S> {
+    let json = Bytes::from(spec.to_json().unwrap_or_else(|e| {
+        warn!("Error serializing the OpenAPI spec : {}", e);
+        "{}".to_string()
+    }));
+    Router::new()
+        .route(
+            SPEC_PATH,
+            get(
+                move || async move { ([(CONTENT_TYPE, "application/json")], json).into_response() },
+            ),
+        )
+        .route(SWAGGER_UI_PATH, get(|| async { swagger_ui() }))
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use metrics::{counter, histogram};
+use tracing::warn;
+
+use crate::{
+    auth::{Authenticator, Credentials},
+    config::RateLimitConfig,
+    middleware::client_ip,
+    models::Role,
+};
+
+// Past this many clients, buckets that have refilled are dropped before a new one is added
+pub const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+// Resolved from the caller's token. Anonymous callers and plain users are refused at the
+// limit, premium ones wait for a token for up to `RateLimitConfig::max_queue_ms` instead
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tier {
+    Anonymous,
+    User,
+    Premium,
+}
+
+impl Tier {
+    pub fn of(role: Option<Role>) -> Self {
+        match role {
+            None | Some(Role::Guest) => Tier::Anonymous,
+            Some(Role::User) => Tier::User,
+            Some(Role::Premium | Role::Admin) => Tier::Premium,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Tier::Anonymous => "anonymous",
+            Tier::User => "user",
+            Tier::Premium => "premium",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token buckets per client address, or per signed in user when the address isn't known, each
+// holding up to `burst` requests and refilling at `per_minute`
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    capacity: f64,
+    refill_per_sec: f64,
+    max_queue: Duration,
+    authenticator: Authenticator,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, authenticator: Authenticator) -> Self {
+        RateLimiter {
+            buckets: Arc::default(),
+            capacity: config.burst as f64,
+            refill_per_sec: config.per_minute as f64 / 60.0,
+            max_queue: Duration::from_millis(config.max_queue_ms),
+            authenticator,
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+    }
+
+    // Takes a token, waiting up to `max_delay` for one, and says how long to wait. Waiting
+    // reserves the token, so the bucket runs negative and later callers wait longer. Refused
+    // with how long until a token is available
+    fn check(&self, key: &str, max_delay: Duration) -> Result<Duration, Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| {
+                self.refill(bucket, now);
+                bucket.tokens < self.capacity
+            });
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        self.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(Duration::ZERO);
+        }
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec);
+        if wait <= max_delay {
+            bucket.tokens -= 1.0;
+            return Ok(wait);
+        }
+        Err(wait)
+    }
+
+    // The tier and bucket a request counts against, `None` when there is nothing to key on.
+    // Always the address when there is one, tokens are easy to come by and would otherwise
+    // each get a bucket of their own. The role only picks the tier. Guests are anonymous and
+    // never get a bucket by id, `/auth/guest` hands those out to anyone
+    fn resolve(&self, request: &Request) -> Option<(Tier, String)> {
+        let claims = self
+            .authenticator
+            .authenticate(&Credentials::of_request(request))
+            .ok();
+        let tier = Tier::of(claims.as_ref().map(|claims| claims.role));
+        if let Some(ip) = client_ip(request) {
+            return Some((tier, format!("ip:{}", ip)));
+        }
+        claims.filter(|_| tier != Tier::Anonymous).map(|claims| {
+            (
+                tier,
+                format!(
+                    "user:{}:{}",
+                    claims.tenant.as_deref().unwrap_or_default(),
+                    claims.sub
+                ),
+            )
+        })
+    }
+}
+
+pub async fn rate_limit(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let Some((tier, key)) = limiter.resolve(&request) else {
+        return next.run(request).await;
+    };
+    let max_delay = match tier {
+        Tier::Premium => limiter.max_queue,
+        Tier::Anonymous | Tier::User => Duration::ZERO,
+    };
+
+    match limiter.check(&key, max_delay) {
+        Ok(delay) => {
+            let outcome = if delay.is_zero() { "allowed" } else { "queued" };
+            counter!("rate_limit_requests_total", "tier" => tier.as_str(), "outcome" => outcome)
+                .increment(1);
+            if !delay.is_zero() {
+                histogram!("rate_limit_queue_seconds", "tier" => tier.as_str())
+                    .record(delay.as_secs_f64());
+                tokio::time::sleep(delay).await;
+            }
+            next.run(request).await
+        }
+        Err(retry_after) => {
+            counter!("rate_limit_requests_total", "tier" => tier.as_str(), "outcome" => "rejected")
+                .increment(1);
+            warn!(
+                "Rate limited a {} caller on {}",
+                tier.as_str(),
+                request.uri().path()
+            );
+            // Rounded up, a client retrying after 0s would just be refused again
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, seconds.to_string())],
+                "Too many requests",
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::Request,
+    middleware::{from_fn, from_fn_with_state},
+    routing::{get, post},
+    Router,
+};
+use mongodb::Database;
+use tower_http::{
+    cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer, ExposeHeaders},
+    limit::RequestBodyLimitLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{DefaultOnResponse, TraceLayer},
+};
+use tracing::{info, info_span, Level};
+
+use crate::{
+    activity::{count_requests, events, ActivityFeed},
+    analytics::{record_analytics, Analytics},
+    anomaly::{guard_traffic, TrafficGuard},
+    auth::Authenticator,
+    banner::{log_route_table, route, Access, RouteInfo},
+    client::{enforce_min_version, MinVersions},
+    compression::{compression_layer, decompression_layer},
+    config::Config,
+    counter::{CounterCache, CounterStore},
+    crypto::FieldCipher,
+    db::Tenants,
+    funnel::AuthFunnel,
+    handlers::{basics, client, counter, health, media},
+    health::{HealthRegistry, MongoHealthCheck, WarmUpHealthCheck},
+    middleware::{
+        attach_deadline, call_with_id_middleware, deny_listed_ips, enforce_body_throughput,
+        enforce_read_only, enforce_timeout, global_middleware, middleware_to_request,
+        require_profile_fields, scope_request_id, verify_signature, BodyThroughput, IpRules,
+        ReadOnly, RequestSigner,
+    },
+    modules::{builtin_modules, AppModule},
+    openapi::{docs_router, spec, SPEC_PATH, SWAGGER_UI_PATH},
+    secrets::{self, refresh_secrets, RotatingSecret},
+    state::AppState,
+    static_files::{self, static_router},
+    telemetry::{metrics_endpoint, prometheus_handle, record_metrics, RouteMetrics},
+    versioning::{api_version, ApiVersion, Deprecations, Versioned},
+    warmup::{warm_up, WarmUp},
+};
+
+// The routes registered here rather than by a module, for the startup route table
+const BASE_ROUTES: &[RouteInfo] = &[
+    route("GET", "/healthz", Access::Public),
+    route("GET", "/readyz", Access::Public),
+    route("GET", "/client-config", Access::Public),
+    route("GET", "/", Access::Public),
+    route("GET", "/user/profile", Access::Public),
+    route("GET", "/about", Access::Public),
+    route("GET", "/hello", Access::Public),
+    route("GET", "/wildcard/{*rest}", Access::Public),
+    route("GET", "/{id}", Access::Public),
+    route("GET", "/id", Access::Public),
+    route("POST", "/identity", Access::Public),
+    route("POST", "/headers", Access::Public),
+    route("POST", "/status-code", Access::Public),
+    route("GET", "/redirect-to-hello", Access::Public),
+    route("GET", "/a/big/uri", Access::Public),
+    route("POST", "/submit-form", Access::Public),
+    route("GET", "/nested/new", Access::Public),
+    route("GET", SPEC_PATH, Access::Public),
+    route("GET", SWAGGER_UI_PATH, Access::Public),
+];
+
+// The business routes registered here, mounted under each API version like module routes
+const API_ROUTES: &[RouteInfo] = &[
+    // Signed instead when listed in `signed_routes`
+    route("GET", "/counter", Access::Public),
+    route("POST", "/counter", Access::Public),
+    route("PUT", "/counter", Access::Public),
+    route("DELETE", "/counter", Access::Public),
+    route("GET", "/counter/stats", Access::Public),
+    route("GET", "/counter/{name}", Access::Public),
+    route("POST", "/counter/{name}", Access::Public),
+    route("PUT", "/counter/{name}", Access::Public),
+    route("DELETE", "/counter/{name}", Access::Public),
+    route("POST", "/counter/{name}/increment", Access::Public),
+    route("GET", "/ws/counter", Access::Public),
+    route("GET", "/events", Access::User),
+];
+
+// Every value was checked by `Config::validate`
+pub fn cors_layer(config: &Config) -> CorsLayer {
+    let cors = &config.cors;
+    if cors.permissive {
+        return CorsLayer::very_permissive();
+    }
+    let is_any = |values: &[String]| values.iter().any(|value| value == "*");
+    let mut allowed_headers = cors.allowed_headers.clone();
+    if config.tenants.enabled {
+        allowed_headers.push(config.tenants.header.to_ascii_lowercase());
+    }
+
+    let layer = CorsLayer::new()
+        .allow_credentials(cors.allow_credentials)
+        .max_age(Duration::from_secs(cors.max_age_secs));
+    let layer = if is_any(&cors.allowed_origins) {
+        layer.allow_origin(Any)
+    } else {
+        layer.allow_origin(AllowOrigin::list(
+            cors.allowed_origins
+                .iter()
+                .filter_map(|origin| origin.parse().ok()),
+        ))
+    };
+    let layer = if is_any(&cors.allowed_methods) {
+        layer.allow_methods(Any)
+    } else {
+        layer.allow_methods(AllowMethods::list(
+            cors.allowed_methods
+                .iter()
+                .filter_map(|method| method.parse().ok()),
+        ))
+    };
+    let layer = if is_any(&allowed_headers) {
+        layer.allow_headers(Any)
+    } else {
+        layer.allow_headers(AllowHeaders::list(
+            allowed_headers
+                .iter()
+                .filter_map(|header| header.parse().ok()),
+        ))
+    };
+    if is_any(&cors.exposed_headers) {
+        layer.expose_headers(Any)
+    } else {
+        layer.expose_headers(ExposeHeaders::list(
+            cors.exposed_headers
+                .iter()
+                .filter_map(|header| header.parse().ok()),
+        ))
+    }
+}
+
+pub fn app(database: Database, config: Config) -> Result<Router, String> {
+    let modules = builtin_modules(&config);
+    app_with_modules(database, config, modules)
+}
+
+// Like `app`, for forks that bring their own modules alongside (or instead of) the built-in ones
+pub fn app_with_modules(
+    database: Database,
+    config: Config,
+    modules: Vec<Box<dyn AppModule>>,
+) -> Result<Router, String> {
+    let config = Arc::new(config);
+    let cors_layer = cors_layer(&config);
+
+    let warm_up_state = WarmUp::default();
+    let activity = ActivityFeed::default();
+    tokio::spawn(activity.clone().report_requests());
+    let authenticator = Authenticator::new(&config);
+    let state = AppState {
+        database: database.clone(),
+        tenants: Tenants::new(database.clone(), &config.tenants),
+        config: Arc::clone(&config),
+        counter: CounterStore::new(&database, CounterCache::new(1), activity.clone()),
+        ip_rules: IpRules::new(&config.admin_allowlist),
+        read_only: ReadOnly::new(&config.read_only),
+        min_versions: MinVersions::new(&config.clients),
+        deprecations: Deprecations::new(&config.versioning),
+        // Checked by `Config::validate`, there is a secret when routes are signed
+        signer: RequestSigner::new(
+            RotatingSecret::new(
+                config
+                    .signing_secret
+                    .as_deref()
+                    .unwrap_or_default()
+                    .as_bytes(),
+            ),
+            &config.signed_routes,
+        ),
+        health: HealthRegistry::default(),
+        traffic_guard: TrafficGuard::new(&config.anomaly, authenticator.clone())?,
+        field_cipher: FieldCipher::new(config.field_encryption_keys.as_deref())?,
+        authenticator,
+        metrics: prometheus_handle(),
+        funnel: AuthFunnel::default(),
+        analytics: Analytics::default(),
+        activity: activity.clone(),
+        http_client: media::image_client(&config.media.image_hosts)
+            .map_err(|e| format!("Error building the image client : {}", e))?,
+        image_cache: media::ImageCache::default(),
+    };
+    tokio::spawn(warm_up(
+        database.clone(),
+        state.ip_rules.clone(),
+        state.counter.clone(),
+        warm_up_state.clone(),
+    ));
+    if let Some(provider) = secrets::provider(&config.secrets)? {
+        tokio::spawn(refresh_secrets(
+            provider,
+            Duration::from_secs(config.secrets.refresh_secs),
+            config.min_secret_length(),
+            state.authenticator.jwt_secret.clone(),
+            state.signer.secret.clone(),
+            config.mongodb_uri.clone(),
+            config.field_encryption_keys.clone(),
+        ));
+    }
+
+    state.health.register(MongoHealthCheck(database));
+    state.health.register(WarmUpHealthCheck(warm_up_state));
+
+    let user_router = Router::new().route("/profile", get(basics::profile));
+    let about_router = Router::new().route("/about", get(basics::about));
+    let another_nested_shared_router =
+        Router::new().route("/new", get(basics::nested_shared_route));
+
+    // Probes sit outside the route groups so they can't be configured away
+    let mut health_router = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz));
+    if config.route_groups.metrics {
+        health_router = health_router.route("/metrics", get(metrics_endpoint));
+    }
+
+    let mut router: Router<AppState> = Router::new()
+        .route("/", get(basics::hello_world))
+        .route("/client-config", get(client::client_config))
+        .nest("/user", user_router)
+        .merge(about_router)
+        .route(
+            "/hello",
+            get(basics::hello).route_layer(from_fn(middleware_to_request)),
+        )
+        .route("/wildcard/{*rest}", get(basics::wildcard_route))
+        .route(
+            "/{id}",
+            get(basics::call_with_id).route_layer(from_fn(call_with_id_middleware)),
+        )
+        .route("/id", get(basics::call_with_query_params))
+        .route("/identity", post(basics::parse_json))
+        .route("/headers", post(basics::parse_headers))
+        .route("/status-code", post(basics::returns_with_status_code))
+        .fallback(static_files::fallback)
+        .layer(from_fn(global_middleware))
+        .route("/redirect-to-hello", get(basics::redirect))
+        .route("/a/big/uri", get(basics::get_uri))
+        .route("/submit-form", post(basics::submit_form))
+        .nest("/nested", another_nested_shared_router)
+        .merge(health_router);
+
+    let mut api: Router<AppState> = Router::new()
+        .route(
+            "/counter",
+            // The signature layer only wraps the mutating methods registered before it, and
+            // only checks those in `signed_routes`
+            post(counter::increase_counter)
+                .put(counter::put_counter)
+                .delete(counter::delete_counter)
+                .route_layer(from_fn_with_state(state.signer.clone(), verify_signature))
+                .get(counter::get_counter),
+        )
+        .route("/counter/stats", get(counter::counter_stats))
+        .route(
+            "/counter/{name}",
+            post(counter::create_named_counter)
+                .put(counter::set_named_counter)
+                .delete(counter::delete_named_counter)
+                .route_layer(from_fn_with_state(state.signer.clone(), verify_signature))
+                .get(counter::get_named_counter),
+        )
+        .route(
+            "/counter/{name}/increment",
+            post(counter::increment_named_counter)
+                .route_layer(from_fn_with_state(state.signer.clone(), verify_signature)),
+        )
+        .route("/ws/counter", get(counter::counter_updates))
+        .route("/events", get(events));
+
+    let mut route_table = BASE_ROUTES.to_vec();
+    if config.route_groups.metrics {
+        route_table.insert(2, route("GET", "/metrics", Access::Public));
+    }
+    let mut api_table: Vec<RouteInfo> = API_ROUTES
+        .iter()
+        .map(|&route| {
+            if state.signer.signs(route.methods, route.path) {
+                RouteInfo {
+                    access: Access::Signed,
+                    ..route
+                }
+            } else {
+                route
+            }
+        })
+        .collect();
+    if config.static_files.enabled {
+        router = router.merge(static_router(&config.static_files));
+        route_table.push(route("GET", "/static/{*path}", Access::Public));
+    }
+    // Layered per module rather than around everything, so a module can get looser limits
+    let limits = &config.limits;
+    let default_limits = |router: Router<AppState>| {
+        router
+            .layer(from_fn_with_state(
+                limits.default_timeout(),
+                attach_deadline,
+            ))
+            .layer(from_fn_with_state(
+                limits.default_timeout(),
+                enforce_timeout,
+            ))
+            .layer(RequestBodyLimitLayer::new(limits.body_limit))
+    };
+    router = default_limits(router);
+    api = default_limits(api);
+    for module in modules {
+        info!("Loading module : {}", module.name());
+        module.on_startup(&state);
+        let routes = module
+            .routes(&state)
+            .layer(from_fn_with_state(
+                limits.timeout_for(module.name()),
+                attach_deadline,
+            ))
+            .layer(from_fn_with_state(
+                limits.timeout_for(module.name()),
+                enforce_timeout,
+            ))
+            .layer(RequestBodyLimitLayer::new(
+                limits.body_limit_for(module.name()),
+            ));
+        api = api.merge(routes);
+        api_table.extend_from_slice(module.route_table());
+    }
+    if !config.profile_requirements.is_empty() {
+        api = api.route_layer(from_fn_with_state(state.clone(), require_profile_fields));
+    }
+    // Also enforces throttles set through `/admin/throttles` while detection is off
+    api = api.layer(from_fn_with_state(
+        state.traffic_guard.clone(),
+        guard_traffic,
+    ));
+
+    let latest = ApiVersion::LATEST;
+    route_table.extend(api_table.iter().map(|route| route.under(latest.prefix())));
+    if config.versioning.legacy_routes {
+        if state.deprecations.is_deprecated(ApiVersion::Legacy) {
+            info!(
+                "Serving legacy routes at unversioned paths, deprecated in favour of {}",
+                latest.prefix()
+            );
+        }
+        router = router.merge(api.clone().layer(from_fn_with_state(
+            Versioned {
+                version: ApiVersion::Legacy,
+                deprecations: state.deprecations.clone(),
+                authenticator: state.authenticator.clone(),
+            },
+            api_version,
+        )));
+    }
+    router = router.nest(
+        latest.prefix(),
+        api.layer(from_fn_with_state(
+            Versioned {
+                version: latest,
+                deprecations: state.deprecations.clone(),
+                authenticator: state.authenticator.clone(),
+            },
+            api_version,
+        )),
+    );
+    // Built from the final route table, so disabled groups aren't documented
+    router = router.merge(docs_router(&spec(&route_table)));
+    log_route_table(&route_table);
+    let decompression = decompression_layer(&config.compression);
+    let compression = compression_layer(&config.compression);
+
+    Ok(router
+        .layer(from_fn_with_state(
+            state.read_only.clone(),
+            enforce_read_only,
+        ))
+        .layer(from_fn_with_state(
+            state.min_versions.clone(),
+            enforce_min_version,
+        ))
+        .layer(from_fn_with_state(RouteMetrics::default(), record_metrics))
+        .layer(from_fn_with_state(activity, count_requests))
+        .layer(from_fn_with_state(
+            BodyThroughput::from(&config.connections),
+            enforce_body_throughput,
+        ))
+        .layer(from_fn_with_state(state.clone(), record_analytics))
+        .layer(from_fn_with_state(state.ip_rules.clone(), deny_listed_ips))
+        .with_state(state)
+        .layer(decompression)
+        .layer(compression)
+        .layer(cors_layer)
+        .layer(from_fn(scope_request_id))
+        // One span per request, closed with its status and latency
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request| {
+                    let request_id = request
+                        .headers()
+                        .get("x-request-id")
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default();
+                    info_span!(
+                        "request",
+                        method = %request.method(),
+                        path = %request.uri().path(),
+                        request_id,
+                    )
+                })
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid)))
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use jsonwebtoken::get_current_timestamp;
+use metrics::counter;
+use mongodb::{
+    bson::{self, doc, Bson, Document},
+    Collection, Database,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{error, warn};
+
+use crate::error::AppError;
+
+// One document per (collection, kind, field), counting how often it was seen
+pub const DIAGNOSTICS_COLLECTION: &str = "schema_diagnostics";
+// Drift is written there at most this often, however many reads run into it
+pub const DRIFT_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+// Distinct drifts held until the next flush, the rest only reach the metric
+const MAX_PENDING_DRIFTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+enum Drift {
+    // Stored, but no longer part of the model
+    UnknownField,
+    // Part of the model, absent from the stored document and filled with a default
+    MissingField,
+    // The document could not be decoded at all
+    DecodeError,
+}
+
+impl Drift {
+    fn as_str(self) -> &'static str {
+        match self {
+            Drift::UnknownField => "unknown_field",
+            Drift::MissingField => "missing_field",
+            Drift::DecodeError => "decode_error",
+        }
+    }
+}
+
+// Decodes a stored document, reporting fields that don't line up with `T` instead of
+// dropping them silently, so pre-migration data shows up before it breaks a request
+pub fn decode<T: DeserializeOwned + Serialize>(
+    database: &Database,
+    collection: &str,
+    document: Document,
+) -> Result<T, AppError> {
+    let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+    let stored_keys: BTreeSet<String> = document.keys().cloned().collect();
+
+    let mut unknown = Vec::new();
+    let decoded =
+        serde_ignored::deserialize(bson::Deserializer::new(Bson::Document(document)), |path| {
+            unknown.push(path.to_string())
+        });
+    let value: T = match decoded {
+        Ok(value) => value,
+        Err(e) => {
+            report(
+                database,
+                collection,
+                Drift::DecodeError,
+                &e.to_string(),
+                &id,
+            );
+            return Err(AppError::Internal(format!(
+                "Error decoding {} document {} : {}",
+                collection, id, e
+            )));
+        }
+    };
+
+    for field in unknown {
+        report(database, collection, Drift::UnknownField, &field, &id);
+    }
+    // Top level only, `None` fields are expected to be absent
+    if let Ok(expected) = bson::to_document(&value) {
+        for (field, value) in expected {
+            if !stored_keys.contains(&field) && value != Bson::Null {
+                report(database, collection, Drift::MissingField, &field, &id);
+            }
+        }
+    }
+
+    Ok(value)
+}
+
+// Per database, collection, kind and field
+type DriftKey = (String, String, &'static str, String);
+
+// Drift seen since the last flush, written out as one upsert per key
+struct PendingDrift {
+    database: Database,
+    count: i64,
+    first_seen: i64,
+    last_seen: i64,
+    last_document_id: Bson,
+}
+
+fn pending() -> &'static Mutex<HashMap<DriftKey, PendingDrift>> {
+    static PENDING: OnceLock<Mutex<HashMap<DriftKey, PendingDrift>>> = OnceLock::new();
+    PENDING.get_or_init(Mutex::default)
+}
+
+fn report(database: &Database, collection: &str, drift: Drift, field: &str, id: &Bson) {
+    // Counted on every read, only the log and the diagnostics document are batched
+    counter!(
+        "schema_drift_total",
+        "collection" => collection.to_string(),
+        "kind" => drift.as_str(),
+    )
+    .increment(1);
+
+    let key = (
+        database.name().to_string(),
+        collection.to_string(),
+        drift.as_str(),
+        field.to_string(),
+    );
+    let now = get_current_timestamp() as i64;
+    let mut pending = pending().lock().unwrap();
+    if let Some(seen) = pending.get_mut(&key) {
+        seen.count += 1;
+        seen.last_seen = now;
+        seen.last_document_id = id.clone();
+        return;
+    }
+    if pending.len() >= MAX_PENDING_DRIFTS {
+        return;
+    }
+
+    warn!(
+        "Schema drift in {} : {} `{}` (document {})",
+        collection,
+        drift.as_str(),
+        field,
+        id
+    );
+    // The first drift of a batch schedules its flush, so nothing runs while there is none
+    if pending.is_empty() {
+        tokio::spawn(async {
+            tokio::time::sleep(DRIFT_FLUSH_INTERVAL).await;
+            flush_drift().await;
+        });
+    }
+    pending.insert(
+        key,
+        PendingDrift {
+            database: database.clone(),
+            count: 1,
+            first_seen: now,
+            last_seen: now,
+            last_document_id: id.clone(),
+        },
+    );
+}
+
+async fn flush_drift() {
+    let batch = std::mem::take(&mut *pending().lock().unwrap());
+    for ((_, collection, kind, field), seen) in batch {
+        let diagnostics_collection: Collection<Document> =
+            seen.database.collection(DIAGNOSTICS_COLLECTION);
+        let filter = doc! { "collection": collection, "kind": kind, "field": field };
+        let update = doc! {
+            "$inc": { "count": seen.count },
+            "$set": { "last_seen": seen.last_seen, "last_document_id": seen.last_document_id },
+            "$setOnInsert": { "first_seen": seen.first_seen },
+        };
+        if let Err(e) = diagnostics_collection
+            .update_one(filter, update)
+            .upsert(true)
+            .await
+        {
+            error!("Error recording schema drift : {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Model {
+        name: String,
+    }
+
+    fn pending_counts(collection: &str) -> Vec<(String, i64)> {
+        let pending = pending().lock().unwrap();
+        let mut counts: Vec<(String, i64)> = pending
+            .iter()
+            .filter(|((_, pending_collection, _, _), _)| pending_collection == collection)
+            .map(|((_, _, kind, field), seen)| (format!("{} {}", kind, field), seen.count))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    #[tokio::test]
+    async fn repeated_drift_is_batched() {
+        let client = Client::with_uri_str("mongodb://127.0.0.1:9/")
+            .await
+            .unwrap();
+        let database = client.database("hello_axum_schema_test");
+
+        for _ in 0..100 {
+            let model: Model = decode(
+                &database,
+                "drift_test",
+                doc! { "name": "alice", "legacy": true },
+            )
+            .unwrap();
+            assert_eq!(model.name, "alice");
+        }
+        assert!(decode::<Model>(&database, "drift_test", doc! { "name": 1 }).is_err());
+
+        let counts = pending_counts("drift_test");
+        assert_eq!(counts.len(), 2);
+        assert!(counts[0].0.starts_with("decode_error ") && counts[0].1 == 1);
+        assert_eq!(counts[1], ("unknown_field legacy".to_string(), 100));
+    }
+}
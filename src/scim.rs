@@ -0,0 +1,967 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use mongodb::bson::{doc, Bson, Document};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::AppError,
+    id::{GroupId, RawId, UserId},
+};
+
+// SCIM 2.0 (RFC 7643 and 7644) for identity providers provisioning users and groups, see
+// `handlers::scim` for the endpoints
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const PATCH_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+pub const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+pub const SERVICE_PROVIDER_SCHEMA: &str =
+    "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+pub const SCIM_CONTENT_TYPE: &str = "application/scim+json";
+
+// Largest page a list answers with, whatever `count` asks for
+pub const MAX_COUNT: u64 = 100;
+
+// Longest `filter` a list accepts, and how deep its parentheses may nest, the parser recurses
+// once per level
+pub const MAX_FILTER_LENGTH: usize = 2048;
+const MAX_FILTER_DEPTH: usize = 32;
+
+// Rendered the way RFC 7644 section 3.12 describes, not as a `ResponseData`
+#[derive(Debug)]
+pub struct ScimError {
+    pub status: StatusCode,
+    pub scim_type: Option<&'static str>,
+    pub detail: String,
+}
+
+impl ScimError {
+    pub fn bad_request(scim_type: &'static str, detail: impl Into<String>) -> Self {
+        ScimError {
+            status: StatusCode::BAD_REQUEST,
+            scim_type: Some(scim_type),
+            detail: detail.into(),
+        }
+    }
+
+    pub fn not_found(detail: impl Into<String>) -> Self {
+        ScimError {
+            status: StatusCode::NOT_FOUND,
+            scim_type: None,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn conflict(detail: impl Into<String>) -> Self {
+        ScimError {
+            status: StatusCode::CONFLICT,
+            scim_type: Some("uniqueness"),
+            detail: detail.into(),
+        }
+    }
+}
+
+impl From<AppError> for ScimError {
+    fn from(error: AppError) -> Self {
+        let (status, detail) = error.status_and_message();
+        ScimError {
+            status,
+            scim_type: match status {
+                StatusCode::CONFLICT => Some("uniqueness"),
+                StatusCode::BAD_REQUEST => Some("invalidValue"),
+                _ => None,
+            },
+            detail,
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for ScimError {
+    fn from(error: mongodb::error::Error) -> Self {
+        AppError::from(error).into()
+    }
+}
+
+impl IntoResponse for ScimError {
+    fn into_response(self) -> Response {
+        let mut body = json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": self.status.as_u16().to_string(),
+            "detail": self.detail,
+        });
+        if let Some(scim_type) = self.scim_type {
+            body["scimType"] = json!(scim_type);
+        }
+        ScimJson(self.status, body).into_response()
+    }
+}
+
+// A SCIM resource or message, sent as `application/scim+json`
+pub struct ScimJson(pub StatusCode, pub Value);
+
+impl IntoResponse for ScimJson {
+    fn into_response(self) -> Response {
+        (self.0, [(CONTENT_TYPE, SCIM_CONTENT_TYPE)], Json(self.1)).into_response()
+    }
+}
+
+// Identity providers authenticate with a long lived bearer token, `scim.bearer_token`.
+// Digests are compared, so the time taken says nothing about the token
+#[derive(Clone)]
+pub struct ScimToken(Arc<[u8]>);
+
+impl ScimToken {
+    pub fn new(token: &str) -> Self {
+        ScimToken(Sha256::digest(token.as_bytes()).to_vec().into())
+    }
+
+    fn accepts(&self, token: &str) -> bool {
+        *Sha256::digest(token.as_bytes()) == *self.0
+    }
+}
+
+pub async fn require_scim_token(
+    State(token): State<ScimToken>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let presented = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !presented.is_some_and(|presented| token.accepts(presented)) {
+        return ScimError {
+            status: StatusCode::UNAUTHORIZED,
+            scim_type: None,
+            detail: "Missing or invalid SCIM token".to_string(),
+        }
+        .into_response();
+    }
+    next.run(request).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttributeKind {
+    // Compared case insensitively, like SCIM's `caseExact: false`
+    Text,
+    ExactText,
+    // Stored inverted, as `disabled`
+    Active,
+    UserId,
+    GroupId,
+}
+
+// How a SCIM attribute maps onto a document field, for filters
+#[derive(Debug)]
+pub struct Attribute {
+    pub name: &'static str,
+    pub field: &'static str,
+    pub kind: AttributeKind,
+}
+
+const fn attribute(name: &'static str, field: &'static str, kind: AttributeKind) -> Attribute {
+    Attribute { name, field, kind }
+}
+
+pub const USER_ATTRIBUTES: &[Attribute] = &[
+    attribute("id", "_id", AttributeKind::UserId),
+    attribute("userName", "user_name", AttributeKind::Text),
+    attribute("externalId", "external_id", AttributeKind::ExactText),
+    attribute("displayName", "display_name", AttributeKind::Text),
+    attribute("name.formatted", "display_name", AttributeKind::Text),
+    attribute("emails", "email", AttributeKind::Text),
+    attribute("emails.value", "email", AttributeKind::Text),
+    attribute("active", "disabled", AttributeKind::Active),
+];
+
+// When emails are encrypted, see `crypto::FieldCipher`
+pub const SEALED_USER_ATTRIBUTES: &[Attribute] = &[
+    attribute("id", "_id", AttributeKind::UserId),
+    attribute("userName", "user_name", AttributeKind::Text),
+    attribute("externalId", "external_id", AttributeKind::ExactText),
+    attribute("displayName", "display_name", AttributeKind::Text),
+    attribute("name.formatted", "display_name", AttributeKind::Text),
+    attribute("active", "disabled", AttributeKind::Active),
+];
+
+pub const GROUP_ATTRIBUTES: &[Attribute] = &[
+    attribute("id", "_id", AttributeKind::GroupId),
+    attribute("displayName", "display_name", AttributeKind::Text),
+    attribute("externalId", "external_id", AttributeKind::ExactText),
+    attribute("members", "members", AttributeKind::UserId),
+    attribute("members.value", "members", AttributeKind::UserId),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Co,
+    Sw,
+    Ew,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+// A parsed `filter` parameter. Value filters on multi-valued attributes, `emails[...]`,
+// are only supported in patch paths
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare {
+        attribute: String,
+        op: CompareOp,
+        value: Value,
+    },
+    Present(String),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+    Text(String),
+}
+
+fn invalid_filter(detail: impl Into<String>) -> ScimError {
+    ScimError::bad_request("invalidFilter", detail)
+}
+
+fn tokenize(filter: &str) -> Result<Vec<Token>, ScimError> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut escaped = false;
+                let mut end = None;
+                for (index, c) in chars.by_ref() {
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '"' if !escaped => {
+                            end = Some(index);
+                            break;
+                        }
+                        _ => escaped = false,
+                    }
+                }
+                let end = end.ok_or_else(|| invalid_filter("Unterminated string"))?;
+                let text = serde_json::from_str(&filter[start..=end])
+                    .map_err(|_| invalid_filter("Invalid string"))?;
+                tokens.push(Token::Text(text));
+            }
+            '[' | ']' => return Err(invalid_filter("Value filters aren't supported here")),
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(index, c)) = chars.peek() {
+                    if c.is_whitespace() || "()\"[]".contains(c) {
+                        break;
+                    }
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push(Token::Word(filter[start..end].to_string()));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.position), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn or(&mut self) -> Result<Filter, ScimError> {
+        let mut filter = self.and()?;
+        while self.peek_keyword("or") {
+            self.position += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, ScimError> {
+        let mut filter = self.unary()?;
+        while self.peek_keyword("and") {
+            self.position += 1;
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn group(&mut self) -> Result<Filter, ScimError> {
+        if self.depth == MAX_FILTER_DEPTH {
+            return Err(invalid_filter("Filter is nested too deeply"));
+        }
+        self.depth += 1;
+        let filter = self.or()?;
+        self.depth -= 1;
+        match self.next() {
+            Some(Token::Close) => Ok(filter),
+            _ => Err(invalid_filter("Missing closing parenthesis")),
+        }
+    }
+
+    fn unary(&mut self) -> Result<Filter, ScimError> {
+        if self.peek_keyword("not") {
+            self.position += 1;
+            return match self.next() {
+                Some(Token::Open) => Ok(Filter::Not(Box::new(self.group()?))),
+                _ => Err(invalid_filter("not takes a parenthesized filter")),
+            };
+        }
+        let attribute = match self.next() {
+            Some(Token::Open) => return self.group(),
+            Some(Token::Word(attribute)) => attribute,
+            _ => return Err(invalid_filter("Expected an attribute")),
+        };
+        let op = match self.next() {
+            Some(Token::Word(op)) => op.to_ascii_lowercase(),
+            _ => return Err(invalid_filter("Expected an operator")),
+        };
+        let op = match op.as_str() {
+            "pr" => return Ok(Filter::Present(attribute)),
+            "eq" => CompareOp::Eq,
+            "ne" => CompareOp::Ne,
+            "co" => CompareOp::Co,
+            "sw" => CompareOp::Sw,
+            "ew" => CompareOp::Ew,
+            "gt" => CompareOp::Gt,
+            "ge" => CompareOp::Ge,
+            "lt" => CompareOp::Lt,
+            "le" => CompareOp::Le,
+            _ => return Err(invalid_filter(format!("Unknown operator : {}", op))),
+        };
+        let value = match self.next() {
+            Some(Token::Text(text)) => Value::String(text),
+            Some(Token::Word(word)) => serde_json::from_str(&word)
+                .map_err(|_| invalid_filter(format!("Invalid value : {}", word)))?,
+            _ => return Err(invalid_filter("Expected a value")),
+        };
+        Ok(Filter::Compare {
+            attribute,
+            op,
+            value,
+        })
+    }
+}
+
+pub fn parse_filter(filter: &str) -> Result<Filter, ScimError> {
+    let mut parser = Parser {
+        tokens: tokenize(filter)?,
+        position: 0,
+        depth: 0,
+    };
+    let parsed = parser.or()?;
+    if parser.position < parser.tokens.len() {
+        return Err(invalid_filter("Unexpected input after the filter"));
+    }
+    Ok(parsed)
+}
+
+// Attribute names are case insensitive and may carry their schema, `urn:…:User:userName`
+fn find<'a>(attributes: &'a [Attribute], name: &str) -> Result<&'a Attribute, ScimError> {
+    let name = match name.strip_prefix("urn:") {
+        Some(urn) => urn.rsplit_once(':').map_or(name, |(_, name)| name),
+        None => name,
+    };
+    attributes
+        .iter()
+        .find(|attribute| attribute.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| invalid_filter(format!("Can't filter on {}", name)))
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn parse_id(kind: AttributeKind, id: &str) -> Option<RawId> {
+    match kind {
+        AttributeKind::UserId => id.parse::<UserId>().ok().map(|id| id.raw()),
+        _ => id.parse::<GroupId>().ok().map(|id| id.raw()),
+    }
+}
+
+fn compare(attribute: &Attribute, op: CompareOp, value: &Value) -> Result<Document, ScimError> {
+    let field = attribute.field;
+    let unsupported = || invalid_filter(format!("{:?} isn't supported on {}", op, attribute.name));
+    match attribute.kind {
+        AttributeKind::Active => {
+            let Value::Bool(active) = value else {
+                return Err(invalid_filter("active takes true or false"));
+            };
+            let active = match op {
+                CompareOp::Eq => *active,
+                CompareOp::Ne => !*active,
+                _ => return Err(unsupported()),
+            };
+            // Users without the field are active
+            Ok(if active {
+                doc! { field: { "$ne": true } }
+            } else {
+                doc! { field: true }
+            })
+        }
+        AttributeKind::UserId | AttributeKind::GroupId => {
+            let Value::String(id) = value else {
+                return Err(invalid_filter(format!("{} takes a string", attribute.name)));
+            };
+            // An id this server never hands out matches nothing
+            let id = parse_id(attribute.kind, id);
+            Ok(match (op, id) {
+                (CompareOp::Eq, Some(id)) => doc! { field: id },
+                (CompareOp::Eq, None) => doc! { field: { "$in": [] } },
+                (CompareOp::Ne, Some(id)) => doc! { field: { "$ne": id } },
+                (CompareOp::Ne, None) => doc! {},
+                _ => return Err(unsupported()),
+            })
+        }
+        AttributeKind::Text | AttributeKind::ExactText => {
+            let Value::String(text) = value else {
+                return Err(invalid_filter(format!("{} takes a string", attribute.name)));
+            };
+            let options = if attribute.kind == AttributeKind::Text {
+                "i"
+            } else {
+                ""
+            };
+            let pattern = |pattern: String| doc! { "$regex": pattern, "$options": options };
+            let escaped = escape_regex(text);
+            Ok(match op {
+                CompareOp::Eq => doc! { field: pattern(format!("^{}$", escaped)) },
+                CompareOp::Ne => doc! { field: { "$not": pattern(format!("^{}$", escaped)) } },
+                CompareOp::Co => doc! { field: pattern(escaped) },
+                CompareOp::Sw => doc! { field: pattern(format!("^{}", escaped)) },
+                CompareOp::Ew => doc! { field: pattern(format!("{}$", escaped)) },
+                CompareOp::Gt => doc! { field: { "$gt": text } },
+                CompareOp::Ge => doc! { field: { "$gte": text } },
+                CompareOp::Lt => doc! { field: { "$lt": text } },
+                CompareOp::Le => doc! { field: { "$lte": text } },
+            })
+        }
+    }
+}
+
+impl Filter {
+    // The MongoDB query matching the same documents, `attributes` mapping the resource
+    pub fn to_document(&self, attributes: &[Attribute]) -> Result<Document, ScimError> {
+        Ok(match self {
+            Filter::And(left, right) => doc! { "$and": [
+                left.to_document(attributes)?,
+                right.to_document(attributes)?,
+            ] },
+            Filter::Or(left, right) => doc! { "$or": [
+                left.to_document(attributes)?,
+                right.to_document(attributes)?,
+            ] },
+            Filter::Not(filter) => doc! { "$nor": [filter.to_document(attributes)?] },
+            Filter::Present(name) => {
+                let attribute = find(attributes, name)?;
+                let field = attribute.field;
+                match attribute.kind {
+                    AttributeKind::Active => doc! {},
+                    AttributeKind::Text | AttributeKind::ExactText => {
+                        doc! { field: { "$exists": true, "$nin": [Bson::Null, ""] } }
+                    }
+                    AttributeKind::UserId | AttributeKind::GroupId => {
+                        doc! { field: { "$exists": true, "$ne": [] } }
+                    }
+                }
+            }
+            Filter::Compare {
+                attribute,
+                op,
+                value,
+            } => compare(find(attributes, attribute)?, *op, value)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PatchRequest {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(rename = "Operations")]
+    pub operations: Vec<PatchOperation>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PatchOperation {
+    pub op: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub value: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PatchOp {
+    Add,
+    Replace,
+    Remove,
+}
+
+// `attribute`, `attribute.sub` or `attribute[sub eq "value"].sub`
+#[derive(Debug, PartialEq)]
+struct PatchPath {
+    attribute: String,
+    filter: Option<(String, Value)>,
+    sub: Option<String>,
+}
+
+fn invalid_path(detail: impl Into<String>) -> ScimError {
+    ScimError::bad_request("invalidPath", detail)
+}
+
+fn parse_path(path: &str) -> Result<PatchPath, ScimError> {
+    let path = match path.strip_prefix("urn:") {
+        Some(urn) => match urn.find('[') {
+            Some(_) => path,
+            None => urn.rsplit_once(':').map_or(path, |(_, path)| path),
+        },
+        None => path,
+    };
+    let Some(open) = path.find('[') else {
+        return Ok(match path.split_once('.') {
+            Some((attribute, sub)) => PatchPath {
+                attribute: attribute.to_string(),
+                filter: None,
+                sub: Some(sub.to_string()),
+            },
+            None => PatchPath {
+                attribute: path.to_string(),
+                filter: None,
+                sub: None,
+            },
+        });
+    };
+    let close = path
+        .rfind(']')
+        .filter(|&close| close > open)
+        .ok_or_else(|| invalid_path(format!("Invalid path : {}", path)))?;
+    let filter = match parse_filter(&path[open + 1..close]) {
+        Ok(Filter::Compare {
+            attribute,
+            op: CompareOp::Eq,
+            value,
+        }) => (attribute, value),
+        _ => {
+            return Err(invalid_path(
+                "Only `[attribute eq value]` filters are supported in paths",
+            ))
+        }
+    };
+    let sub = match &path[close + 1..] {
+        "" => None,
+        rest => Some(
+            rest.strip_prefix('.')
+                .ok_or_else(|| invalid_path(format!("Invalid path : {}", path)))?
+                .to_string(),
+        ),
+    };
+    Ok(PatchPath {
+        attribute: path[..open].to_string(),
+        filter: Some(filter),
+        sub,
+    })
+}
+
+// The key `name` is stored under, attribute names being case insensitive
+fn key_of(object: &Map<String, Value>, name: &str) -> String {
+    object
+        .keys()
+        .find(|key| key.eq_ignore_ascii_case(name))
+        .cloned()
+        .unwrap_or_else(|| name.to_string())
+}
+
+fn matches(element: &Value, (attribute, expected): &(String, Value)) -> bool {
+    let Value::Object(object) = element else {
+        return false;
+    };
+    match (object.get(&key_of(object, attribute)), expected) {
+        (Some(Value::String(actual)), Value::String(expected)) => {
+            actual.eq_ignore_ascii_case(expected)
+        }
+        (Some(actual), expected) => actual == expected,
+        (None, _) => false,
+    }
+}
+
+// Multi-valued attributes like `members` are removed element by element, matched on `value`
+fn same_element(element: &Value, other: &Value) -> bool {
+    match other.get("value") {
+        Some(value) => matches(element, &("value".to_string(), value.clone())),
+        None => element == other,
+    }
+}
+
+fn apply_operation(
+    resource: &mut Map<String, Value>,
+    op: PatchOp,
+    path: Option<&str>,
+    value: Option<Value>,
+) -> Result<(), ScimError> {
+    let Some(path) = path else {
+        // Without a path the value is an object of attributes, each applied in turn
+        return match (op, value) {
+            (PatchOp::Remove, _) => Err(ScimError::bad_request("noTarget", "remove needs a path")),
+            (_, Some(Value::Object(attributes))) => {
+                for (attribute, value) in attributes {
+                    apply_operation(resource, op, Some(&attribute), Some(value))?;
+                }
+                Ok(())
+            }
+            _ => Err(ScimError::bad_request(
+                "invalidValue",
+                "Without a path the value must be an object",
+            )),
+        };
+    };
+    let path = parse_path(path)?;
+    let key = key_of(resource, &path.attribute);
+    let value = match (op, value) {
+        (PatchOp::Remove, value) => value,
+        (_, Some(value)) => Some(value),
+        (_, None) => {
+            return Err(ScimError::bad_request(
+                "invalidValue",
+                format!("{:?} needs a value", op),
+            ))
+        }
+    };
+
+    match (path.filter, path.sub) {
+        (None, None) => match (op, value) {
+            (PatchOp::Remove, Some(Value::Array(removed))) => {
+                if let Some(Value::Array(elements)) = resource.get_mut(&key) {
+                    elements.retain(|element| {
+                        !removed.iter().any(|other| same_element(element, other))
+                    });
+                }
+            }
+            (PatchOp::Remove, _) => {
+                resource.remove(&key);
+            }
+            (PatchOp::Add, Some(Value::Array(added))) => match resource.get_mut(&key) {
+                Some(Value::Array(elements)) => {
+                    for element in added {
+                        if !elements.iter().any(|other| same_element(other, &element)) {
+                            elements.push(element);
+                        }
+                    }
+                }
+                _ => {
+                    resource.insert(key, Value::Array(added));
+                }
+            },
+            (PatchOp::Add, Some(Value::Object(added))) => match resource.get_mut(&key) {
+                Some(Value::Object(object)) => object.extend(added),
+                _ => {
+                    resource.insert(key, Value::Object(added));
+                }
+            },
+            (_, value) => {
+                resource.insert(key, value.unwrap_or(Value::Null));
+            }
+        },
+        (None, Some(sub)) => {
+            let parent = resource
+                .entry(key)
+                .or_insert_with(|| Value::Object(Map::new()));
+            // A multi-valued attribute without a filter means its first value
+            let target = match parent {
+                Value::Array(elements) => {
+                    if elements.is_empty() {
+                        elements.push(Value::Object(Map::new()));
+                    }
+                    &mut elements[0]
+                }
+                parent => parent,
+            };
+            apply_to_element(target, op, &sub, value)?;
+        }
+        (Some(filter), sub) => {
+            let target = resource
+                .entry(key)
+                .or_insert_with(|| Value::Array(Vec::new()));
+            let Value::Array(elements) = target else {
+                return Err(invalid_path(format!(
+                    "{} isn't multi-valued",
+                    path.attribute
+                )));
+            };
+            let matched = elements.iter().any(|element| matches(element, &filter));
+            match (op, sub) {
+                (PatchOp::Remove, None) => elements.retain(|element| !matches(element, &filter)),
+                (_, Some(sub)) if matched => {
+                    for element in elements
+                        .iter_mut()
+                        .filter(|element| matches(element, &filter))
+                    {
+                        apply_to_element(element, op, &sub, value.clone())?;
+                    }
+                }
+                (PatchOp::Remove, Some(_)) => {}
+                // Nothing matched, so the value is added with what the filter looked for
+                (_, Some(sub)) => {
+                    let mut element = Map::new();
+                    element.insert(filter.0.clone(), filter.1.clone());
+                    element.insert(sub, value.unwrap_or(Value::Null));
+                    elements.push(Value::Object(element));
+                }
+                (_, None) => {
+                    elements.retain(|element| !matches(element, &filter));
+                    elements.push(value.unwrap_or(Value::Null));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_to_element(
+    element: &mut Value,
+    op: PatchOp,
+    sub: &str,
+    value: Option<Value>,
+) -> Result<(), ScimError> {
+    let Value::Object(object) = element else {
+        return Err(invalid_path(format!(
+            "Can't reach {} in a plain value",
+            sub
+        )));
+    };
+    let key = key_of(object, sub);
+    match op {
+        PatchOp::Remove => {
+            object.remove(&key);
+        }
+        PatchOp::Add | PatchOp::Replace => {
+            object.insert(key, value.unwrap_or(Value::Null));
+        }
+    }
+    Ok(())
+}
+
+// Applies the operations of a PatchOp message to a resource in its SCIM form
+pub fn apply_patch(resource: &mut Value, request: PatchRequest) -> Result<(), ScimError> {
+    if !request.schemas.iter().any(|schema| schema == PATCH_SCHEMA) {
+        return Err(ScimError::bad_request(
+            "invalidSyntax",
+            format!("Expected the {} schema", PATCH_SCHEMA),
+        ));
+    }
+    let Value::Object(object) = resource else {
+        return Err(ScimError::bad_request(
+            "invalidValue",
+            "Resource is not an object",
+        ));
+    };
+    for operation in request.operations {
+        let op = match operation.op.to_ascii_lowercase().as_str() {
+            "add" => PatchOp::Add,
+            "replace" => PatchOp::Replace,
+            "remove" => PatchOp::Remove,
+            _ => {
+                return Err(ScimError::bad_request(
+                    "invalidSyntax",
+                    format!("Unknown patch op : {}", operation.op),
+                ))
+            }
+        };
+        apply_operation(object, op, operation.path.as_deref(), operation.value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch(resource: Value, operations: Value) -> Result<Value, ScimError> {
+        let mut resource = resource;
+        let request = serde_json::from_value(json!({
+            "schemas": [PATCH_SCHEMA],
+            "Operations": operations,
+        }))
+        .unwrap();
+        apply_patch(&mut resource, request)?;
+        Ok(resource)
+    }
+
+    #[test]
+    fn parses_filters() {
+        let filter =
+            parse_filter(r#"userName eq "alice" and (active eq true or not (emails pr))"#).unwrap();
+        assert_eq!(
+            filter,
+            Filter::And(
+                Box::new(Filter::Compare {
+                    attribute: "userName".to_string(),
+                    op: CompareOp::Eq,
+                    value: json!("alice"),
+                }),
+                Box::new(Filter::Or(
+                    Box::new(Filter::Compare {
+                        attribute: "active".to_string(),
+                        op: CompareOp::Eq,
+                        value: json!(true),
+                    }),
+                    Box::new(Filter::Not(Box::new(Filter::Present("emails".to_string())))),
+                )),
+            )
+        );
+
+        for invalid in [
+            "userName",
+            r#"userName eq"#,
+            r#"userName like "a""#,
+            r#"(userName eq "a""#,
+            r#"emails[type eq "work"]"#,
+            r#"userName eq "a" extra"#,
+        ] {
+            assert!(parse_filter(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_deeply_nested_filters() {
+        let nested = |depth: usize| {
+            format!(
+                "{}userName pr{}",
+                "not (".repeat(depth / 2) + &"(".repeat(depth - depth / 2),
+                ")".repeat(depth)
+            )
+        };
+        assert!(parse_filter(&nested(MAX_FILTER_DEPTH)).is_ok());
+
+        let error = parse_filter(&nested(MAX_FILTER_DEPTH + 1)).unwrap_err();
+        assert_eq!(error.scim_type, Some("invalidFilter"));
+        let error = parse_filter(&nested(5000)).unwrap_err();
+        assert_eq!(error.scim_type, Some("invalidFilter"));
+    }
+
+    #[test]
+    fn translates_filters_to_queries() {
+        let query = |filter: &str| {
+            parse_filter(filter)
+                .unwrap()
+                .to_document(USER_ATTRIBUTES)
+                .unwrap()
+        };
+        assert_eq!(
+            query(r#"USERNAME eq "a.b""#),
+            doc! { "user_name": { "$regex": "^a\\.b$", "$options": "i" } }
+        );
+        assert_eq!(
+            query(r#"externalId sw "x" or active eq false"#),
+            doc! { "$or": [
+                { "external_id": { "$regex": "^x", "$options": "" } },
+                { "disabled": true },
+            ] }
+        );
+        assert_eq!(
+            query(r#"urn:ietf:params:scim:schemas:core:2.0:User:active ne false"#),
+            doc! { "disabled": { "$ne": true } }
+        );
+        assert_eq!(query(r#"id eq "nope""#), doc! { "_id": { "$in": [] } });
+
+        let unknown = parse_filter(r#"title eq "x""#).unwrap();
+        assert!(unknown.to_document(USER_ATTRIBUTES).is_err());
+    }
+
+    #[test]
+    fn patches_attributes() {
+        let user = json!({
+            "userName": "alice",
+            "active": true,
+            "emails": [{ "value": "a@example.com", "type": "work" }],
+        });
+        let patched = patch(
+            user,
+            json!([
+                { "op": "Replace", "path": "active", "value": false },
+                { "op": "replace", "value": { "displayName": "Alice", "name.formatted": "Alice A" } },
+                { "op": "replace", "path": "emails[type eq \"work\"].value", "value": "b@example.com" },
+                { "op": "add", "path": "emails[type eq \"home\"].value", "value": "c@example.com" },
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            patched,
+            json!({
+                "userName": "alice",
+                "active": false,
+                "displayName": "Alice",
+                "name": { "formatted": "Alice A" },
+                "emails": [
+                    { "value": "b@example.com", "type": "work" },
+                    { "type": "home", "value": "c@example.com" },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn patches_group_members() {
+        let group = json!({ "displayName": "Staff", "members": [{ "value": "usr_1" }] });
+        let patched = patch(
+            group,
+            json!([
+                { "op": "add", "path": "members", "value": [{ "value": "usr_2" }, { "value": "usr_1" }] },
+                { "op": "remove", "path": "members[value eq \"usr_1\"]" },
+                { "op": "add", "path": "members", "value": [{ "value": "usr_3" }] },
+                { "op": "remove", "path": "members", "value": [{ "value": "usr_3" }] },
+            ]),
+        )
+        .unwrap();
+        assert_eq!(patched["members"], json!([{ "value": "usr_2" }]));
+
+        let error = patch(json!({}), json!([{ "op": "remove" }])).unwrap_err();
+        assert_eq!(error.scim_type, Some("noTarget"));
+        let error = patch(json!({}), json!([{ "op": "move", "path": "a" }])).unwrap_err();
+        assert_eq!(error.scim_type, Some("invalidSyntax"));
+    }
+}
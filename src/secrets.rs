@@ -0,0 +1,525 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures_util::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use jsonwebtoken::get_current_timestamp;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::config::{Config, SecretsConfig, SecretsProviderKind};
+
+// How long a provider gets to answer before the fetch counts as failed
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What a provider can hand out, each one optional so a store can hold just some of them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Secrets {
+    pub jwt_secret: Option<String>,
+    pub signing_secret: Option<String>,
+    // Carries the MongoDB credentials
+    pub mongodb_uri: Option<String>,
+    // See `crypto::FieldCipher`
+    pub field_encryption_keys: Option<String>,
+}
+
+impl Secrets {
+    // From a JSON object with `jwt_secret`, `signing_secret`, `mongodb_uri` and
+    // `field_encryption_keys` keys
+    fn from_json(value: &Value) -> Result<Self, String> {
+        let Value::Object(object) = value else {
+            return Err("Secret is not a JSON object".to_string());
+        };
+        let field = |name: &str| object.get(name).and_then(Value::as_str).map(str::to_string);
+        Ok(Secrets {
+            jwt_secret: field("jwt_secret"),
+            signing_secret: field("signing_secret"),
+            mongodb_uri: field("mongodb_uri"),
+            field_encryption_keys: field("field_encryption_keys"),
+        })
+    }
+
+    // Fetched values win over the file and the environment
+    pub fn apply(self, config: &mut Config) {
+        if let Some(secret) = self.jwt_secret {
+            config.jwt_secret = secret;
+        }
+        if let Some(secret) = self.signing_secret {
+            config.signing_secret = Some(secret);
+        }
+        if let Some(uri) = self.mongodb_uri {
+            config.mongodb_uri = uri;
+        }
+        if let Some(keys) = self.field_encryption_keys {
+            config.field_encryption_keys = Some(keys);
+        }
+    }
+}
+
+// Where secrets come from instead of the environment, fetched at startup and then every
+// `secrets.refresh_secs` so they can rotate without a redeploy
+pub trait SecretsProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Secrets, String>>;
+}
+
+// The configured provider, `None` when secrets come from the file and environment only
+pub fn provider(config: &SecretsConfig) -> Result<Option<Box<dyn SecretsProvider>>, String> {
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Error building the secrets client : {}", e))?;
+    let env = |name: &str| std::env::var(name).map_err(|_| format!("{} must be set", name));
+    // Checked by `Config::validate`
+    let setting = |value: &Option<String>| value.clone().unwrap_or_default();
+    Ok(match config.provider {
+        SecretsProviderKind::None => None,
+        SecretsProviderKind::Vault => Some(Box::new(VaultProvider {
+            client,
+            address: setting(&config.vault_address),
+            path: setting(&config.vault_path),
+            token: env("VAULT_TOKEN")?,
+        })),
+        SecretsProviderKind::Aws => Some(Box::new(AwsSecretsManager {
+            client,
+            region: setting(&config.aws_region),
+            secret_id: setting(&config.aws_secret_id),
+            credentials: AwsCredentials {
+                access_key_id: env("AWS_ACCESS_KEY_ID")?,
+                secret_access_key: env("AWS_SECRET_ACCESS_KEY")?,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            },
+        })),
+    })
+}
+
+// Fills `config` from its provider before it is validated, a failed fetch stops startup
+pub async fn load_secrets(config: &mut Config) -> Result<(), String> {
+    config.secrets.validate()?;
+    let Some(provider) = provider(&config.secrets)? else {
+        return Ok(());
+    };
+    let secrets = provider
+        .fetch()
+        .await
+        .map_err(|e| format!("Error fetching secrets from {} : {}", provider.name(), e))?;
+    info!("Loaded secrets from {}", provider.name());
+    secrets.apply(config);
+    Ok(())
+}
+
+// A key the app signs and verifies with. Rotating it keeps the previous value around for
+// verifying, so tokens and signatures made just before a rotation aren't refused
+#[derive(Clone)]
+pub struct RotatingSecret(Arc<RwLock<SecretVersions>>);
+
+struct SecretVersions {
+    current: Arc<[u8]>,
+    previous: Option<Arc<[u8]>>,
+}
+
+impl RotatingSecret {
+    pub fn new(secret: &[u8]) -> Self {
+        RotatingSecret(Arc::new(RwLock::new(SecretVersions {
+            current: secret.into(),
+            previous: None,
+        })))
+    }
+
+    // What new tokens and signatures are made with
+    pub fn current(&self) -> Arc<[u8]> {
+        Arc::clone(&self.0.read().unwrap().current)
+    }
+
+    // The current value first, then the one it replaced
+    pub fn verifying(&self) -> Vec<Arc<[u8]>> {
+        let versions = self.0.read().unwrap();
+        std::iter::once(Arc::clone(&versions.current))
+            .chain(versions.previous.clone())
+            .collect()
+    }
+
+    // False when `secret` is already the current value
+    pub fn rotate(&self, secret: &[u8]) -> bool {
+        let mut versions = self.0.write().unwrap();
+        if *versions.current == *secret {
+            return false;
+        }
+        let previous = std::mem::replace(&mut versions.current, secret.into());
+        versions.previous = Some(previous);
+        true
+    }
+}
+
+// Rotates `secret` to a fetched `value`, unless it is shorter than `min_length` like
+// `Config::validate` would refuse at startup. `true` when it changed
+fn rotate_fetched(name: &str, secret: &RotatingSecret, value: &str, min_length: usize) -> bool {
+    if value.len() < min_length {
+        warn!(
+            "The fetched {} is shorter than {} bytes, keeping the current one",
+            name, min_length
+        );
+        return false;
+    }
+    secret.rotate(value.as_bytes())
+}
+
+// Fetches secrets again every `refresh_secs`, rotating the keys that changed. A failed
+// fetch, or a key too short for `min_length`, keeps the values already held
+pub async fn refresh_secrets(
+    provider: Box<dyn SecretsProvider>,
+    refresh: Duration,
+    min_length: usize,
+    jwt_secret: RotatingSecret,
+    signing_secret: RotatingSecret,
+    mongodb_uri: String,
+    field_encryption_keys: Option<String>,
+) {
+    let mut interval = tokio::time::interval(refresh);
+    // The first tick is immediate, startup has just fetched them
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let secrets = match provider.fetch().await {
+            Ok(secrets) => secrets,
+            Err(e) => {
+                warn!(
+                    "Error refreshing secrets from {}, keeping the current ones : {}",
+                    provider.name(),
+                    e
+                );
+                continue;
+            }
+        };
+        if let Some(secret) = secrets.jwt_secret {
+            if rotate_fetched("JWT secret", &jwt_secret, &secret, min_length) {
+                info!("Rotated the JWT secret");
+            }
+        }
+        if let Some(secret) = secrets.signing_secret {
+            if rotate_fetched("signing secret", &signing_secret, &secret, min_length) {
+                info!("Rotated the request signing secret");
+            }
+        }
+        // The driver can't swap credentials on a live client
+        if secrets.mongodb_uri.is_some_and(|uri| uri != mongodb_uri) {
+            warn!("The MongoDB URI changed, it takes effect on the next restart");
+        }
+        if secrets.field_encryption_keys.is_some()
+            && secrets.field_encryption_keys != field_encryption_keys
+        {
+            warn!("The field encryption keys changed, they take effect on the next restart");
+        }
+    }
+}
+
+// HashiCorp Vault, a KV secret read with the token in `VAULT_TOKEN`
+pub struct VaultProvider {
+    client: reqwest::Client,
+    address: String,
+    // The API path after `/v1/`, e.g. `secret/data/hello-axum` for a KV v2 mount
+    path: String,
+    token: String,
+}
+
+impl SecretsProvider for VaultProvider {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Secrets, String>> {
+        Box::pin(async move {
+            let url = format!(
+                "{}/v1/{}",
+                self.address.trim_end_matches('/'),
+                self.path.trim_start_matches('/')
+            );
+            let response = self
+                .client
+                .get(url)
+                .header("X-Vault-Token", &self.token)
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("Vault answered {}", response.status()));
+            }
+            let body: Value =
+                serde_json::from_slice(&response.bytes().await.map_err(|e| e.to_string())?)
+                    .map_err(|e| format!("Invalid Vault response : {}", e))?;
+            // KV v2 nests the secret one level deeper than v1
+            let data = &body["data"];
+            match &data["data"] {
+                Value::Object(_) => Secrets::from_json(&data["data"]),
+                _ => Secrets::from_json(data),
+            }
+        })
+    }
+}
+
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+// AWS Secrets Manager, a JSON `SecretString` read with the usual `AWS_*` credentials
+pub struct AwsSecretsManager {
+    client: reqwest::Client,
+    region: String,
+    secret_id: String,
+    credentials: AwsCredentials,
+}
+
+impl SecretsProvider for AwsSecretsManager {
+    fn name(&self) -> &'static str {
+        "aws"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Secrets, String>> {
+        Box::pin(async move {
+            let host = format!("secretsmanager.{}.amazonaws.com", self.region);
+            let body = serde_json::json!({ "SecretId": self.secret_id }).to_string();
+            let amz_date = amz_date(get_current_timestamp());
+
+            let mut headers = vec![
+                ("content-type", "application/x-amz-json-1.1".to_string()),
+                ("host", host.clone()),
+                ("x-amz-date", amz_date.clone()),
+                ("x-amz-target", "secretsmanager.GetSecretValue".to_string()),
+            ];
+            if let Some(token) = &self.credentials.session_token {
+                headers.push(("x-amz-security-token", token.clone()));
+            }
+            let authorization = sign_v4(&SigningRequest {
+                method: "POST",
+                path: "/",
+                query: "",
+                headers: &headers,
+                payload: body.as_bytes(),
+                service: "secretsmanager",
+                region: &self.region,
+                amz_date: &amz_date,
+                credentials: &self.credentials,
+            });
+
+            let mut request = self
+                .client
+                .post(format!("https://{}/", host))
+                .header("authorization", authorization)
+                .body(body);
+            for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+                request = request.header(*name, value);
+            }
+            let response = request.send().await.map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("Secrets Manager answered {}", response.status()));
+            }
+            let body: Value =
+                serde_json::from_slice(&response.bytes().await.map_err(|e| e.to_string())?)
+                    .map_err(|e| format!("Invalid Secrets Manager response : {}", e))?;
+            let secret = body["SecretString"]
+                .as_str()
+                .ok_or_else(|| "Secret has no SecretString".to_string())?;
+            let secret: Value = serde_json::from_str(secret)
+                .map_err(|e| format!("SecretString is not JSON : {}", e))?;
+            Secrets::from_json(&secret)
+        })
+    }
+}
+
+// `YYYYMMDDTHHMMSSZ`, the timestamp format Signature Version 4 signs
+fn amz_date(unix_secs: u64) -> String {
+    let days = (unix_secs / 86_400) as i64;
+    let secs = unix_secs % 86_400;
+    // Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+struct SigningRequest<'a> {
+    method: &'a str,
+    path: &'a str,
+    // Already in canonical form, sorted and encoded
+    query: &'a str,
+    // Lower case names, `host` included
+    headers: &'a [(&'a str, String)],
+    payload: &'a [u8],
+    service: &'a str,
+    region: &'a str,
+    amz_date: &'a str,
+    credentials: &'a AwsCredentials,
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// The `Authorization` header of an AWS Signature Version 4 request
+fn sign_v4(request: &SigningRequest) -> String {
+    let mut headers: Vec<&(&str, String)> = request.headers.iter().collect();
+    headers.sort_by_key(|(name, _)| *name);
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request.method,
+        request.path,
+        request.query,
+        canonical_headers,
+        signed_headers,
+        hex::encode(Sha256::digest(request.payload))
+    );
+
+    let date = &request.amz_date[..8];
+    let scope = format!(
+        "{}/{}/{}/aws4_request",
+        date, request.region, request.service
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        request.amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = format!("AWS4{}", request.credentials.secret_access_key);
+    let key = hmac_sha256(key.as_bytes(), date);
+    let key = hmac_sha256(&key, request.region);
+    let key = hmac_sha256(&key, request.service);
+    let key = hmac_sha256(&key, "aws4_request");
+    let signature = hex::encode(hmac_sha256(&key, &string_to_sign));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        request.credentials.access_key_id, scope, signed_headers, signature
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_amz_dates() {
+        assert_eq!(amz_date(0), "19700101T000000Z");
+        assert_eq!(amz_date(1_440_938_160), "20150830T123600Z");
+        assert_eq!(amz_date(1_709_208_000), "20240229T120000Z");
+    }
+
+    // The example from the AWS Signature Version 4 documentation
+    #[test]
+    fn signs_like_aws() {
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let headers = [
+            (
+                "content-type",
+                "application/x-www-form-urlencoded; charset=utf-8".to_string(),
+            ),
+            ("host", "iam.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        let authorization = sign_v4(&SigningRequest {
+            method: "GET",
+            path: "/",
+            query: "Action=ListUsers&Version=2010-05-08",
+            headers: &headers,
+            payload: b"",
+            service: "iam",
+            region: "us-east-1",
+            amz_date: "20150830T123600Z",
+            credentials: &credentials,
+        });
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+    }
+
+    #[test]
+    fn rotation_keeps_the_previous_secret_verifiable() {
+        let secret = RotatingSecret::new(b"first");
+        assert!(!secret.rotate(b"first"));
+        assert!(secret.rotate(b"second"));
+        assert_eq!(&*secret.current(), b"second");
+        assert_eq!(secret.verifying().len(), 2);
+        assert!(secret.rotate(b"third"));
+        assert_eq!(
+            secret
+                .verifying()
+                .iter()
+                .map(|key| key.to_vec())
+                .collect::<Vec<_>>(),
+            [b"third".to_vec(), b"second".to_vec()]
+        );
+    }
+
+    #[test]
+    fn keeps_the_current_secret_over_a_short_one() {
+        let secret = RotatingSecret::new(b"first-secret");
+        assert!(!rotate_fetched("JWT secret", &secret, "", 8));
+        assert!(!rotate_fetched("JWT secret", &secret, "short", 8));
+        assert_eq!(&*secret.current(), b"first-secret");
+        assert!(rotate_fetched("JWT secret", &secret, "second-secret", 8));
+        assert_eq!(&*secret.current(), b"second-secret");
+    }
+
+    #[test]
+    fn reads_vault_and_aws_payloads() {
+        let secrets = Secrets::from_json(&serde_json::json!({
+            "jwt_secret": "jwt",
+            "mongodb_uri": "mongodb://user:pass@db/",
+        }))
+        .unwrap();
+        assert_eq!(
+            secrets,
+            Secrets {
+                jwt_secret: Some("jwt".to_string()),
+                signing_secret: None,
+                mongodb_uri: Some("mongodb://user:pass@db/".to_string()),
+                field_encryption_keys: None,
+            }
+        );
+        assert!(Secrets::from_json(&serde_json::json!("jwt")).is_err());
+    }
+}
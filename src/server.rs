@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    serve::Listener,
+    Router,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+};
+use tower::{service_fn, ServiceExt};
+use tracing::{info, warn};
+
+use crate::config::ConnectionsConfig;
+
+// How long shutdown waits for in-flight requests before giving up on them
+pub const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionCounts {
+    pub open: usize,
+    pub per_ip: HashMap<IpAddr, usize>,
+}
+
+// Open connection counts, shared between the accept loop and `/admin/runtime`
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    counts: Arc<Mutex<ConnectionCounts>>,
+    pub max_connections: usize,
+    pub max_per_ip: usize,
+}
+
+impl ConnectionStats {
+    pub fn counts(&self) -> ConnectionCounts {
+        self.counts.lock().unwrap().clone()
+    }
+
+    fn open(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let per_ip = counts.per_ip.entry(ip).or_default();
+        if *per_ip >= self.max_per_ip {
+            return None;
+        }
+        *per_ip += 1;
+        counts.open += 1;
+
+        Some(ConnectionGuard {
+            stats: self.clone(),
+            ip,
+        })
+    }
+}
+
+// Gives the connection's slot back when the stream is dropped
+#[derive(Debug)]
+struct ConnectionGuard {
+    stats: ConnectionStats,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.stats.counts.lock().unwrap();
+        counts.open -= 1;
+        if let Some(per_ip) = counts.per_ip.get_mut(&self.ip) {
+            *per_ip -= 1;
+            if *per_ip == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+pub struct LimitedListener {
+    listener: TcpListener,
+    permits: Arc<Semaphore>,
+    stats: ConnectionStats,
+}
+
+impl LimitedListener {
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.clone()
+    }
+
+    pub fn new(listener: TcpListener, config: &ConnectionsConfig) -> Self {
+        LimitedListener {
+            listener,
+            permits: Arc::new(Semaphore::new(config.max_connections)),
+            stats: ConnectionStats {
+                counts: Arc::default(),
+                max_connections: config.max_connections,
+                max_per_ip: config.max_per_ip,
+            },
+        }
+    }
+}
+
+impl Listener for LimitedListener {
+    type Io = LimitedStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            // Stop accepting while every slot is taken, leaving new clients in the backlog
+            let permit = Arc::clone(&self.permits).acquire_owned().await.unwrap();
+            let (stream, addr) = Listener::accept(&mut self.listener).await;
+
+            // Dropping the stream closes a connection over the per-IP cap
+            if let Some(guard) = self.stats.open(addr.ip()) {
+                let stream = LimitedStream {
+                    stream,
+                    _permit: permit,
+                    _guard: guard,
+                };
+                return (stream, addr);
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+pub struct LimitedStream {
+    stream: TcpStream,
+    _permit: OwnedSemaphorePermit,
+    _guard: ConnectionGuard,
+}
+
+impl LimitedStream {
+    pub async fn readable(&self) -> io::Result<()> {
+        self.stream.readable().await
+    }
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Peer address of a connection accepted by `LimitedListener`
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
+// Like `axum::serve`, but with hyper's header read timeout and HTTP/2 keep-alive pings
+// switched on. Once `shutdown` resolves no new connections are accepted and open ones are
+// given `SHUTDOWN_DRAIN_TIMEOUT` to finish their in-flight requests.
+pub async fn serve<F>(
+    mut listener: LimitedListener,
+    app: Router,
+    config: &ConnectionsConfig,
+    shutdown: F,
+) where
+    F: Future<Output = ()>,
+{
+    let header_read_timeout = Duration::from_secs(config.header_read_timeout_secs);
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(header_read_timeout);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(Duration::from_secs(config.http2_keep_alive_interval_secs))
+        .keep_alive_timeout(Duration::from_secs(config.http2_keep_alive_timeout_secs));
+
+    // Connections watch `signal_rx` for the shutdown, and hold `close_rx` until they are done
+    let (signal_tx, signal_rx) = watch::channel(());
+    let (close_tx, close_rx) = watch::channel(());
+
+    let connection_stats = listener.stats.clone();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let app = app.clone();
+        let connection_stats = connection_stats.clone();
+        let service = service_fn(move |mut request: Request<Incoming>| {
+            let extensions = request.extensions_mut();
+            extensions.insert(ConnectInfo(ClientAddr(addr)));
+            extensions.insert(connection_stats.clone());
+            app.clone().oneshot(request)
+        });
+
+        let builder = builder.clone();
+        let mut signal_rx = signal_rx.clone();
+        let close_rx = close_rx.clone();
+        tokio::spawn(async move {
+            // hyper's header timeout is armed whenever it waits for a request head, so again
+            // after every response, which makes it the keep-alive idle timeout as well. But it
+            // is HTTP/1 only, and the auto builder first reads the connection to tell HTTP/1
+            // from HTTP/2 with no timer at all. This bounds that for clients that send nothing
+            if tokio::time::timeout(header_read_timeout, stream.readable())
+                .await
+                .is_err()
+            {
+                return;
+            }
+            let connection = builder.serve_connection_with_upgrades(
+                TokioIo::new(stream),
+                TowerToHyperService::new(service),
+            );
+            tokio::pin!(connection);
+
+            // Timed out and reset connections aren't worth reporting
+            let _ = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = signal_rx.changed() => {
+                    // Lets the current request finish, then closes instead of keeping alive
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            drop(close_rx);
+        });
+    }
+
+    drop(listener);
+    info!(
+        "Shutting down : draining {} open connection(s)",
+        connection_stats.counts().open
+    );
+    let _ = signal_tx.send(());
+    drop(close_rx);
+
+    if tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, close_tx.closed())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Shutting down : gave up on {} connection(s) after {:?}",
+            connection_stats.counts().open,
+            SHUTDOWN_DRAIN_TIMEOUT
+        );
+    } else {
+        info!("Shutting down : all connections closed");
+    }
+}
+
+// Resolves on Ctrl+C, or on SIGTERM where there is one
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl+C"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use axum::extract::FromRef;
+use metrics_exporter_prometheus::PrometheusHandle;
+use mongodb::Database;
+
+use crate::{
+    activity::ActivityFeed,
+    analytics::Analytics,
+    anomaly::TrafficGuard,
+    auth::Authenticator,
+    client::MinVersions,
+    config::Config,
+    counter::{CounterCache, CounterStore},
+    crypto::FieldCipher,
+    db::Tenants,
+    funnel::AuthFunnel,
+    handlers::media::ImageCache,
+    health::HealthRegistry,
+    middleware::{IpRules, ReadOnly, RequestSigner},
+    versioning::Deprecations,
+};
+
+// The one state the whole router runs with. Handlers and modules take just the part they
+// need as `State<T>`, through the `FromRef` impls below. Extractors like `AuthUser` and
+// `TenantDb` work under any state that can hand them their part the same way
+#[derive(Clone)]
+pub struct AppState {
+    // The default database, tenant aware handlers use `TenantDb` instead
+    pub database: Database,
+    // Picks the database of the requesting tenant
+    pub tenants: Tenants,
+    pub config: Arc<Config>,
+    pub counter: CounterStore,
+    pub ip_rules: IpRules,
+    pub read_only: ReadOnly,
+    pub min_versions: MinVersions,
+    pub deprecations: Deprecations,
+    pub signer: RequestSigner,
+    // Checks registered here are part of `/readyz`
+    pub health: HealthRegistry,
+    // Holds the JWT keys and the logout denylist
+    pub authenticator: Authenticator,
+    pub metrics: PrometheusHandle,
+    pub funnel: AuthFunnel,
+    pub analytics: Analytics,
+    pub traffic_guard: TrafficGuard,
+    // Seals personal fields before they are stored
+    pub field_cipher: FieldCipher,
+    pub activity: ActivityFeed,
+    // For outgoing requests, e.g. `/img`
+    pub http_client: reqwest::Client,
+    pub image_cache: ImageCache,
+}
+
+impl FromRef<AppState> for Database {
+    fn from_ref(state: &AppState) -> Self {
+        state.database.clone()
+    }
+}
+
+impl FromRef<AppState> for Tenants {
+    fn from_ref(state: &AppState) -> Self {
+        state.tenants.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        Arc::clone(&state.config)
+    }
+}
+
+impl FromRef<AppState> for CounterStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.counter.clone()
+    }
+}
+
+impl FromRef<AppState> for CounterCache {
+    fn from_ref(state: &AppState) -> Self {
+        state.counter.cache.clone()
+    }
+}
+
+impl FromRef<AppState> for IpRules {
+    fn from_ref(state: &AppState) -> Self {
+        state.ip_rules.clone()
+    }
+}
+
+impl FromRef<AppState> for ReadOnly {
+    fn from_ref(state: &AppState) -> Self {
+        state.read_only.clone()
+    }
+}
+
+impl FromRef<AppState> for MinVersions {
+    fn from_ref(state: &AppState) -> Self {
+        state.min_versions.clone()
+    }
+}
+
+impl FromRef<AppState> for RequestSigner {
+    fn from_ref(state: &AppState) -> Self {
+        state.signer.clone()
+    }
+}
+
+impl FromRef<AppState> for TrafficGuard {
+    fn from_ref(state: &AppState) -> Self {
+        state.traffic_guard.clone()
+    }
+}
+
+impl FromRef<AppState> for FieldCipher {
+    fn from_ref(state: &AppState) -> Self {
+        state.field_cipher.clone()
+    }
+}
+
+impl FromRef<AppState> for Deprecations {
+    fn from_ref(state: &AppState) -> Self {
+        state.deprecations.clone()
+    }
+}
+
+impl FromRef<AppState> for HealthRegistry {
+    fn from_ref(state: &AppState) -> Self {
+        state.health.clone()
+    }
+}
+
+impl FromRef<AppState> for Authenticator {
+    fn from_ref(state: &AppState) -> Self {
+        state.authenticator.clone()
+    }
+}
+
+impl FromRef<AppState> for PrometheusHandle {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for AuthFunnel {
+    fn from_ref(state: &AppState) -> Self {
+        state.funnel.clone()
+    }
+}
+
+impl FromRef<AppState> for Analytics {
+    fn from_ref(state: &AppState) -> Self {
+        state.analytics.clone()
+    }
+}
+
+impl FromRef<AppState> for ActivityFeed {
+    fn from_ref(state: &AppState) -> Self {
+        state.activity.clone()
+    }
+}
+
+impl FromRef<AppState> for reqwest::Client {
+    fn from_ref(state: &AppState) -> Self {
+        state.http_client.clone()
+    }
+}
+
+impl FromRef<AppState> for ImageCache {
+    fn from_ref(state: &AppState) -> Self {
+        state.image_cache.clone()
+    }
+}
@@ -0,0 +1,69 @@
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{ACCEPT, CACHE_CONTROL},
+        HeaderValue, Method,
+    },
+    response::{IntoResponse, Response},
+    Router,
+};
+use tower::ServiceExt;
+use tower_http::{
+    services::{ServeDir, ServeFile},
+    set_header::SetResponseHeaderLayer,
+};
+use tracing::warn;
+
+use crate::{
+    config::{Config, StaticConfig},
+    handlers::basics,
+    state::AppState,
+};
+
+fn cache_control(config: &StaticConfig) -> SetResponseHeaderLayer<HeaderValue> {
+    let value = format!("public, max-age={}", config.max_age_secs);
+    SetResponseHeaderLayer::if_not_present(CACHE_CONTROL, HeaderValue::try_from(value).unwrap())
+}
+
+// Mounted at `/static`, ServeDir answers missing files with an empty 404
+pub fn static_router(config: &StaticConfig) -> Router<AppState> {
+    if !config.dir.is_dir() {
+        warn!("Static directory {} does not exist", config.dir.display());
+    }
+    let serve_dir = ServeDir::new(&config.dir)
+        .precompressed_br()
+        .precompressed_gzip();
+    Router::new()
+        .nest_service("/static", serve_dir)
+        .layer(cache_control(config))
+}
+
+// Replaces `basics::not_found` as the fallback. With SPA mode, browser navigations to
+// client side routes get `index.html`, while API clients still get a 404
+pub async fn fallback(State(config): State<Arc<Config>>, request: Request) -> Response {
+    let static_files = &config.static_files;
+    let wants_html = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if !static_files.spa || !matches!(*request.method(), Method::GET | Method::HEAD) || !wants_html
+    {
+        return basics::not_found().await.into_response();
+    }
+
+    // `index.html` must not be cached, it points at the current asset names
+    let result: Result<_, Infallible> = ServeFile::new(static_files.dir.join("index.html"))
+        .oneshot(request)
+        .await;
+    let mut response = match result {
+        Ok(response) => response.into_response(),
+        Err(never) => match never {},
+    };
+    response
+        .headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response
+}
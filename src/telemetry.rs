@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock},
+    time::Instant,
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header::CONTENT_TYPE, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::{counter, gauge, histogram, Counter, Histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tracing::warn;
+
+use crate::{
+    counter::CounterCache,
+    funnel::{AuthFunnel, FunnelStage},
+    server::ConnectionStats,
+};
+
+// Seconds, from a fast cache hit up to a request that ran into its deadline
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// The recorder is process wide, so every `app()` shares the first one installed
+pub fn prometheus_handle() -> PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+    HANDLE
+        .get_or_init(|| {
+            let recorder = PrometheusBuilder::new()
+                .set_buckets_for_metric(
+                    Matcher::Full("http_request_duration_seconds".to_string()),
+                    LATENCY_BUCKETS,
+                )
+                .expect("latency buckets are not empty")
+                .build_recorder();
+            let handle = recorder.handle();
+            if let Err(e) = metrics::set_global_recorder(recorder) {
+                warn!(
+                    "Metrics recorder already installed, /metrics will be empty : {}",
+                    e
+                );
+            }
+            handle
+        })
+        .clone()
+}
+
+// Methods outside this list share one label, so made-up methods can't add series
+const METHOD_LABELS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS", "CONNECT", "TRACE",
+];
+
+fn method_label(method: &Method) -> &'static str {
+    METHOD_LABELS
+        .iter()
+        .find(|label| **label == method.as_str())
+        .copied()
+        .unwrap_or("OTHER")
+}
+
+// The series of one route template
+#[derive(Default)]
+struct RouteSeries {
+    requests: HashMap<(&'static str, u16), Counter>,
+    durations: HashMap<&'static str, Histogram>,
+}
+
+// Metric handles per label set, registered on a route's first request so later ones
+// neither format nor allocate their labels. Routes are keyed by template, which keeps
+// `/users/{id}` a single series instead of one per id
+#[derive(Clone, Default)]
+pub struct RouteMetrics(Arc<RwLock<HashMap<Box<str>, RouteSeries>>>);
+
+impl RouteMetrics {
+    fn record(&self, path: &str, method: &'static str, status: u16, duration: f64) {
+        {
+            let routes = self.0.read().unwrap();
+            if let Some(series) = routes.get(path) {
+                if let (Some(requests), Some(durations)) = (
+                    series.requests.get(&(method, status)),
+                    series.durations.get(method),
+                ) {
+                    requests.increment(1);
+                    durations.record(duration);
+                    return;
+                }
+            }
+        }
+
+        let mut routes = self.0.write().unwrap();
+        let series = routes.entry(path.into()).or_default();
+        series
+            .requests
+            .entry((method, status))
+            .or_insert_with(|| {
+                counter!(
+                    "http_requests_total",
+                    "method" => method,
+                    "path" => path.to_string(),
+                    "status" => status.to_string(),
+                )
+            })
+            .increment(1);
+        series
+            .durations
+            .entry(method)
+            .or_insert_with(|| {
+                histogram!(
+                    "http_request_duration_seconds",
+                    "method" => method,
+                    "path" => path.to_string(),
+                )
+            })
+            .record(duration);
+    }
+
+    // Label sets registered so far, by metric
+    #[cfg(test)]
+    fn series_count(&self) -> (usize, usize) {
+        let routes = self.0.read().unwrap();
+        routes
+            .values()
+            .fold((0, 0), |(requests, durations), series| {
+                (
+                    requests + series.requests.len(),
+                    durations + series.durations.len(),
+                )
+            })
+    }
+}
+
+pub async fn record_metrics(
+    State(metrics): State<RouteMetrics>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.extensions().get::<MatchedPath>().cloned();
+    let method = method_label(request.method());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    metrics.record(
+        path.as_ref().map_or("unmatched", |path| path.as_str()),
+        method,
+        response.status().as_u16(),
+        started.elapsed().as_secs_f64(),
+    );
+
+    response
+}
+
+// Gauges are sampled at scrape time rather than kept in sync on every change
+pub async fn metrics_endpoint(
+    State(handle): State<PrometheusHandle>,
+    State(counter): State<CounterCache>,
+    State(funnel): State<AuthFunnel>,
+    request: Request,
+) -> impl IntoResponse {
+    // Only present when served through `server::serve`
+    if let Some(connection_stats) = request.extensions().get::<ConnectionStats>() {
+        gauge!("http_active_connections").set(connection_stats.counts().open as f64);
+    }
+    gauge!("counter_value").set(counter.get() as f64);
+    for (stage, count) in FunnelStage::ALL.into_iter().zip(funnel.window_counts()) {
+        gauge!("auth_funnel_window", "stage" => stage.as_str()).set(count as f64);
+    }
+
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        handle.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn send(app: &Router, method: &str, path: &str) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn labels_stay_bounded() {
+        let metrics = RouteMetrics::default();
+        let app = Router::new()
+            .route("/users/{id}", get(|| async { "user" }))
+            .layer(from_fn_with_state(metrics.clone(), record_metrics));
+
+        for id in 0..100 {
+            send(&app, "GET", &format!("/users/{}", id)).await;
+            send(&app, "GET", &format!("/nowhere/{}", id)).await;
+            send(&app, &format!("X-METHOD-{}", id), "/users/1").await;
+        }
+
+        let routes = metrics.0.read().unwrap();
+        let mut paths: Vec<&str> = routes.keys().map(|path| &**path).collect();
+        paths.sort();
+        assert_eq!(paths, ["/users/{id}", "unmatched"]);
+        let users = &routes["/users/{id}"];
+        let mut requests: Vec<_> = users.requests.keys().copied().collect();
+        requests.sort();
+        assert_eq!(requests, [("GET", 200), ("OTHER", 405)]);
+        drop(routes);
+        // One 404 series for every unknown path
+        assert_eq!(metrics.series_count(), (3, 3));
+    }
+}
@@ -0,0 +1,162 @@
+use std::future::Future;
+
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
+
+use crate::middleware::{current_request_id, REQUEST_ID};
+
+// The request some work came from : its span, so logs of the work nest under the
+// request's, and its id. Outside of a request both are empty
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    pub span: Span,
+    pub request_id: Option<String>,
+}
+
+impl TraceContext {
+    pub fn current() -> Self {
+        TraceContext {
+            span: Span::current(),
+            request_id: current_request_id(),
+        }
+    }
+
+    // Runs `future` as part of the request, in its span and with its id
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        let future = future.instrument(self.span);
+        match self.request_id {
+            Some(request_id) => REQUEST_ID.scope(request_id, future).await,
+            None => future.await,
+        }
+    }
+}
+
+// A message handed to other tasks, like WebSocket and `/events` pushes, along with the
+// request that caused it
+#[derive(Debug, Clone)]
+pub struct Traced<T> {
+    pub message: T,
+    pub context: TraceContext,
+}
+
+impl<T> Traced<T> {
+    pub fn current(message: T) -> Self {
+        Traced {
+            message,
+            context: TraceContext::current(),
+        }
+    }
+
+    // What clients get, the message with a `requestId` to match it to the request's logs
+    pub fn payload(&self) -> TracedPayload<'_, T> {
+        TracedPayload {
+            message: &self.message,
+            request_id: self.context.request_id.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TracedPayload<'a, T> {
+    #[serde(flatten)]
+    message: &'a T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+// `tokio::spawn` for jobs a request starts, so they are traced as part of it
+pub fn spawn_traced<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(TraceContext::current().scope(future))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::json;
+    use tracing::{info, info_span};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_jobs_in_the_request_trace() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let span = info_span!("request", request_id = "req-1");
+        let job = REQUEST_ID.scope(
+            "req-1".to_string(),
+            async {
+                spawn_traced(async {
+                    info!("Sending email");
+                    current_request_id()
+                })
+                .await
+                .unwrap()
+            }
+            .instrument(span),
+        );
+        let request_id = job.await;
+        assert_eq!(request_id.as_deref(), Some("req-1"));
+
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("request{request_id=\"req-1\"}: "), "{}", logs);
+        assert!(logs.contains("Sending email"));
+
+        // Outside of a request there is nothing to carry along
+        assert_eq!(
+            spawn_traced(async { current_request_id() }).await.unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn tags_messages_with_their_request() {
+        let context = TraceContext {
+            span: Span::none(),
+            request_id: Some("req-2".to_string()),
+        };
+        let traced = Traced {
+            message: json!({ "type": "userSignedUp", "id": "usr_1" }),
+            context,
+        };
+        assert_eq!(
+            serde_json::to_value(traced.payload()).unwrap(),
+            json!({ "type": "userSignedUp", "id": "usr_1", "requestId": "req-2" })
+        );
+
+        let untraced = Traced::current(json!({ "value": 1 }));
+        assert_eq!(
+            serde_json::to_value(untraced.payload()).unwrap(),
+            json!({ "value": 1 })
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use axum::{
+    extract::{FromRequest, Request},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    error::AppError,
+    models::{ChangePasswordRequest, Credentials, Identity, ResetPasswordRequest},
+};
+
+pub const USER_NAME_LENGTH: std::ops::RangeInclusive<usize> = 3..=32;
+pub const PASSWORD_LENGTH: std::ops::RangeInclusive<usize> = 8..=128;
+pub const MAX_AGE: u32 = 150;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+// Checks beyond what deserializing already guarantees, every broken rule is reported at once
+pub trait Validate {
+    fn validate(&self) -> Vec<FieldError>;
+}
+
+pub fn check(value: &impl Validate) -> Result<(), AppError> {
+    let errors = value.validate();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::InvalidFields(errors))
+    }
+}
+
+// Like `Json`, but answers 422 with per field errors when the body doesn't validate
+pub struct ValidJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ValidJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        check(&value).map_err(IntoResponse::into_response)?;
+        Ok(ValidJson(value))
+    }
+}
+
+fn field_error(field: &'static str, message: impl Into<String>) -> FieldError {
+    FieldError {
+        field,
+        message: message.into(),
+    }
+}
+
+fn check_password_policy(password: &str, field: &'static str, errors: &mut Vec<FieldError>) {
+    if !PASSWORD_LENGTH.contains(&password.chars().count()) {
+        errors.push(field_error(
+            field,
+            format!(
+                "Must be {} to {} characters",
+                PASSWORD_LENGTH.start(),
+                PASSWORD_LENGTH.end()
+            ),
+        ));
+    }
+    if !password.chars().any(char::is_alphabetic) || !password.chars().any(|c| c.is_ascii_digit()) {
+        errors.push(field_error(
+            field,
+            "Needs at least one letter and one digit",
+        ));
+    }
+}
+
+// Only enforced on signup, accounts created before the policy can still sign in
+impl Validate for Credentials {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+
+        if !USER_NAME_LENGTH.contains(&self.user_name.chars().count()) {
+            errors.push(field_error(
+                "userName",
+                format!(
+                    "Must be {} to {} characters",
+                    USER_NAME_LENGTH.start(),
+                    USER_NAME_LENGTH.end()
+                ),
+            ));
+        }
+        if !self
+            .user_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        {
+            errors.push(field_error(
+                "userName",
+                "Only letters, digits, `_`, `-` and `.` are allowed",
+            ));
+        }
+
+        check_password_policy(&self.password, "password", &mut errors);
+        if self.password.eq_ignore_ascii_case(&self.user_name) {
+            errors.push(field_error(
+                "password",
+                "Can't be the same as the user name",
+            ));
+        }
+
+        errors
+    }
+}
+
+impl Validate for ResetPasswordRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_password_policy(&self.new_password, "newPassword", &mut errors);
+        errors
+    }
+}
+
+impl Validate for ChangePasswordRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_password_policy(&self.new_password, "newPassword", &mut errors);
+        if self.new_password == self.current_password {
+            errors.push(field_error(
+                "newPassword",
+                "Must differ from the current password",
+            ));
+        }
+        errors
+    }
+}
+
+impl Validate for Identity {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.name.trim().is_empty() {
+            errors.push(field_error("name", "Can't be empty"));
+        }
+        if self.age > MAX_AGE {
+            errors.push(field_error("age", format!("Can't be above {}", MAX_AGE)));
+        }
+        errors
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{FromRequestParts, MatchedPath, OriginalUri, Request, State},
+    http::{
+        header::{HeaderName, LINK},
+        request::Parts,
+        HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+use jsonwebtoken::get_current_timestamp;
+use metrics::counter;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    analytics::client_key,
+    auth::Authenticator,
+    config::{DeprecationConfig, VersioningConfig},
+};
+
+pub const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+pub const SUNSET: HeaderName = HeaderName::from_static("sunset");
+// Past this many client and path pairs, new ones are only counted in the metrics
+pub const MAX_TRACKED_DEPRECATED_CALLS: usize = 10_000;
+
+// The API version a business route was called through. A future `/api/v2` adds a variant
+// and its prefix, the routes it changes are then registered for it alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ApiVersion {
+    // The unversioned paths the routes had before `/api/v1`
+    Legacy,
+    V1,
+}
+
+impl ApiVersion {
+    pub const LATEST: ApiVersion = ApiVersion::V1;
+    pub const ALL: &[ApiVersion] = &[ApiVersion::Legacy, ApiVersion::V1];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ApiVersion::Legacy => "legacy",
+            ApiVersion::V1 => "v1",
+        }
+    }
+
+    pub fn prefix(self) -> &'static str {
+        match self {
+            ApiVersion::Legacy => "",
+            ApiVersion::V1 => "/api/v1",
+        }
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ApiVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ApiVersion::ALL
+            .iter()
+            .copied()
+            .find(|version| version.name() == s)
+            .ok_or_else(|| format!("Unknown API version : {:?}", s))
+    }
+}
+
+// Routes outside the versioned API, like `/healthz`, follow the latest version
+impl<S: Send + Sync> FromRequestParts<S> for ApiVersion {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<ApiVersion>()
+            .copied()
+            .unwrap_or(ApiVersion::LATEST))
+    }
+}
+
+// `/api/v1/counter` and `/counter` are both `/counter`, for checks that shouldn't care
+pub fn unversioned(path: &str) -> &str {
+    ApiVersion::ALL
+        .iter()
+        .filter(|version| !version.prefix().is_empty())
+        .find_map(|version| {
+            path.strip_prefix(version.prefix())
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .map_or(path, |rest| if rest.is_empty() { "/" } else { rest })
+}
+
+// `YYYY-MM-DD` as Unix seconds at midnight UTC
+pub fn parse_date(date: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid date {:?}, expected YYYY-MM-DD", date);
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<u32>().ok());
+    let (Some(Some(year)), Some(Some(month)), Some(Some(day))) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [
+        31,
+        if leap { 29 } else { 28 },
+        31,
+        30,
+        31,
+        30,
+        31,
+        31,
+        30,
+        31,
+        30,
+        31,
+    ];
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) {
+        return Err(invalid());
+    }
+    if day == 0 || day > month_days[month as usize - 1] {
+        return Err(invalid());
+    }
+
+    let years_days: u64 = (1970..year)
+        .map(|year| {
+            if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) {
+                366
+            } else {
+                365
+            }
+        })
+        .sum();
+    let months_days: u64 = month_days[..month as usize - 1]
+        .iter()
+        .map(|&days| days as u64)
+        .sum();
+    Ok((years_days + months_days + day as u64 - 1) * 24 * 60 * 60)
+}
+
+#[derive(Debug, Clone)]
+struct Notice {
+    deprecation: HeaderValue,
+    sunset: Option<HeaderValue>,
+}
+
+impl Notice {
+    // Dates were checked by `Config::validate`
+    fn new(config: &DeprecationConfig) -> Notice {
+        let date = |date: &Option<String>| date.as_deref().and_then(|date| parse_date(date).ok());
+        // RFC 9745 takes a structured date, `true` is the older draft's "no date given"
+        let deprecation = match date(&config.deprecated_on) {
+            Some(secs) => HeaderValue::try_from(format!("@{}", secs)).unwrap(),
+            None => HeaderValue::from_static("true"),
+        };
+        let sunset = date(&config.sunset_on).map(|secs| {
+            let http_date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs));
+            HeaderValue::try_from(http_date).unwrap()
+        });
+        Notice {
+            deprecation,
+            sunset,
+        }
+    }
+}
+
+// A version, a route and a client
+type CallsKey = (ApiVersion, String, String);
+
+#[derive(Debug, Clone, Copy)]
+struct Calls {
+    requests: u64,
+    last_seen: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeprecatedCalls {
+    pub version: String,
+    pub path: String,
+    // A username, or `ip:<address>` for anonymous callers
+    pub client: String,
+    pub requests: u64,
+    // Unix seconds
+    pub last_seen: u64,
+}
+
+// The deprecated versions from `versioning.deprecations`, and who still calls them since
+// the instance started
+#[derive(Debug, Clone, Default)]
+pub struct Deprecations {
+    notices: Arc<BTreeMap<ApiVersion, Notice>>,
+    calls: Arc<Mutex<HashMap<CallsKey, Calls>>>,
+}
+
+impl Deprecations {
+    pub fn new(config: &VersioningConfig) -> Self {
+        let notices = config
+            .deprecations
+            .iter()
+            .filter_map(|(version, deprecation)| {
+                Some((version.parse().ok()?, Notice::new(deprecation)))
+            })
+            .collect();
+        Deprecations {
+            notices: Arc::new(notices),
+            calls: Arc::default(),
+        }
+    }
+
+    pub fn is_deprecated(&self, version: ApiVersion) -> bool {
+        self.notices.contains_key(&version)
+    }
+
+    fn record(&self, version: ApiVersion, path: &str, client: String) {
+        counter!("deprecated_requests_total", "version" => version.name()).increment(1);
+        let mut calls = self.calls.lock().unwrap();
+        let key = (version, path.to_string(), client);
+        let now = get_current_timestamp();
+        if let Some(calls) = calls.get_mut(&key) {
+            calls.requests += 1;
+            calls.last_seen = now;
+            return;
+        }
+        if calls.len() >= MAX_TRACKED_DEPRECATED_CALLS {
+            return;
+        }
+        info!(
+            version = version.name(),
+            path,
+            client = key.2,
+            "Deprecated API called"
+        );
+        calls.insert(
+            key,
+            Calls {
+                requests: 1,
+                last_seen: now,
+            },
+        );
+    }
+
+    // Busiest first
+    pub fn report(&self) -> Vec<DeprecatedCalls> {
+        let mut report: Vec<DeprecatedCalls> = self
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((version, path, client), calls)| DeprecatedCalls {
+                version: version.to_string(),
+                path: path.clone(),
+                client: client.clone(),
+                requests: calls.requests,
+                last_seen: calls.last_seen,
+            })
+            .collect();
+        report.sort_by_key(|calls| std::cmp::Reverse(calls.requests));
+        report
+    }
+}
+
+// State for `api_version`, one per mounted version
+#[derive(Clone)]
+pub struct Versioned {
+    pub version: ApiVersion,
+    pub deprecations: Deprecations,
+    // Tells signed in callers apart in the deprecation report
+    pub authenticator: Authenticator,
+}
+
+// Layered on the business routes of each version. Tags the request for the `ApiVersion`
+// extractor, and answers calls to a deprecated version with `Deprecation`, `Sunset` and a
+// `Link` to the same route in the latest version
+pub async fn api_version(
+    State(versioned): State<Versioned>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let version = versioned.version;
+    request.extensions_mut().insert(version);
+    let Some(notice) = versioned.deprecations.notices.get(&version).cloned() else {
+        return next.run(request).await;
+    };
+
+    // Nested routers see a stripped URI, the original has the version prefix
+    let path = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or(request.uri().path(), |uri| uri.path());
+    let successor = (version != ApiVersion::LATEST)
+        .then(|| {
+            let link = format!(
+                "<{}{}>; rel=\"successor-version\"",
+                ApiVersion::LATEST.prefix(),
+                unversioned(path)
+            );
+            HeaderValue::try_from(link).ok()
+        })
+        .flatten();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(path, MatchedPath::as_str);
+    versioned.deprecations.record(
+        version,
+        unversioned(route),
+        client_key(&request, &versioned.authenticator),
+    );
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(DEPRECATION, notice.deprecation);
+    if let Some(sunset) = notice.sunset {
+        headers.insert(SUNSET, sunset);
+    }
+    if let Some(successor) = successor {
+        headers.append(LINK, successor);
+    }
+    response
+}
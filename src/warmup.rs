@@ -0,0 +1,66 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use mongodb::{bson::doc, Database};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::{counter::CounterStore, db::reload_ip_rules, middleware::IpRules};
+
+// Between attempts while MongoDB can't be reached yet
+const WARM_UP_RETRY: Duration = Duration::from_secs(2);
+
+// Set once `warm_up` finished, `/readyz` reports not ready until then
+#[derive(Debug, Clone, Default)]
+pub struct WarmUp(Arc<AtomicBool>);
+
+impl WarmUp {
+    pub fn is_done(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    fn done(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+// Does the slow first-time work before traffic arrives instead of on the first requests
+pub async fn warm_up(
+    database: Database,
+    ip_rules: IpRules,
+    counter: CounterStore,
+    warm_up: WarmUp,
+) {
+    let started = Instant::now();
+
+    // Opens the first pooled connection, with server selection and the handshake behind it
+    while let Err(e) = database.run_command(doc! { "ping": 1 }).await {
+        warn!("Warm-up waiting for MongoDB : {}", e);
+        sleep(WARM_UP_RETRY).await;
+    }
+
+    // Until they are loaded every address passes the denylist
+    while let Err(e) = reload_ip_rules(&database, &ip_rules).await {
+        warn!("Warm-up waiting for IP rules : {}", e);
+        sleep(WARM_UP_RETRY).await;
+    }
+
+    // Until then `/metrics` and `/admin/counters` report the in-memory starting value
+    while let Err(e) = counter.load().await {
+        warn!("Warm-up waiting for the counter : {:?}", e);
+        sleep(WARM_UP_RETRY).await;
+    }
+
+    // Without it the history is never pruned, which doesn't stop anything from working
+    if let Err(e) = counter.create_history_indexes().await {
+        warn!("Error creating counter history indexes : {}", e);
+    }
+
+    warm_up.done();
+    info!("Warm-up done in {}ms", started.elapsed().as_millis());
+}
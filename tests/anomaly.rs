@@ -0,0 +1,200 @@
+mod common;
+
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    Router,
+};
+use common::{send, test_app, test_config, TestResponse, JWT_SECRET};
+use hello_axum::{
+    auth::generate_token,
+    config::{AnomalyConfig, Config, RateLimitConfig},
+    models::Role,
+};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+const PATH: &str = "/api/v1/auth/protected";
+
+// A client is throttled to 3 a minute on its 5th request, there's no baseline to learn yet
+fn watched_config() -> Config {
+    Config {
+        anomaly: AnomalyConfig {
+            enabled: true,
+            factor: 2.0,
+            min_per_minute: 5,
+            warmup_minutes: 0,
+            throttled_per_minute: 3,
+            throttle_secs: 60,
+            notify_url: None,
+        },
+        ..admin_config()
+    }
+}
+
+fn admin_config() -> Config {
+    Config {
+        admin_allowlist: vec!["127.0.0.1".to_string()],
+        auth_rate_limit: RateLimitConfig {
+            burst: 100,
+            per_minute: 100,
+            max_queue_ms: 0,
+        },
+        ..test_config()
+    }
+}
+
+async fn request_as(
+    app: &Router,
+    method: &str,
+    path: &str,
+    user: &str,
+    role: Role,
+    body: Option<Value>,
+) -> TestResponse {
+    let token = generate_token(user, role, None, JWT_SECRET.as_bytes()).unwrap();
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .header("content-type", "application/json");
+    let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+    send(app, request.body(body).unwrap()).await
+}
+
+async fn get_as(app: &Router, user: &str) -> StatusCode {
+    request_as(app, "GET", PATH, user, Role::User, None)
+        .await
+        .status
+}
+
+#[test]
+fn refuses_a_factor_without_effect() {
+    let mut config = watched_config();
+    config.anomaly.factor = 1.0;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn refuses_an_endless_throttle() {
+    let mut config = watched_config();
+    config.anomaly.throttle_secs = u64::MAX;
+    assert!(config.validate().is_err());
+}
+
+#[tokio::test]
+async fn throttles_a_client_past_its_baseline() {
+    let app = test_app(watched_config()).await;
+    for _ in 0..4 {
+        assert_eq!(get_as(&app, "alice").await, StatusCode::OK);
+    }
+    let response = request_as(&app, "GET", PATH, "alice", Role::User, None).await;
+    assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers.contains_key("retry-after"));
+    assert_eq!(get_as(&app, "bob").await, StatusCode::OK);
+
+    let response = request_as(
+        &app,
+        "GET",
+        "/api/v1/admin/throttles",
+        "root",
+        Role::Admin,
+        None,
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let throttled = &response.json()["data"][0];
+    assert_eq!(throttled["client"], "alice");
+    assert_eq!(throttled["perMinute"], 3);
+    assert_eq!(throttled["manual"], false);
+    assert_eq!(throttled["observedPerMinute"], 5);
+
+    // Lifting it also keeps the detection off for the client a while
+    let lift = "/api/v1/admin/throttles/alice";
+    let response = request_as(&app, "DELETE", lift, "root", Role::Admin, None).await;
+    assert_eq!(response.status, StatusCode::OK);
+    for _ in 0..5 {
+        assert_eq!(get_as(&app, "alice").await, StatusCode::OK);
+    }
+    let response = request_as(&app, "DELETE", lift, "root", Role::Admin, None).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn lets_operators_throttle_with_detection_off() {
+    let app = test_app(admin_config()).await;
+    let response = request_as(
+        &app,
+        "PUT",
+        "/api/v1/admin/throttles/bob",
+        "root",
+        Role::Admin,
+        Some(json!({ "perMinute": 1, "durationSecs": 60 })),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    assert_eq!(response.json()["data"][0]["manual"], true);
+
+    assert_eq!(get_as(&app, "bob").await, StatusCode::OK);
+    assert_eq!(get_as(&app, "bob").await, StatusCode::TOO_MANY_REQUESTS);
+    // Legacy paths count against the same limit
+    let response = request_as(&app, "GET", "/auth/protected", "bob", Role::User, None).await;
+    assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+    for _ in 0..5 {
+        assert_eq!(get_as(&app, "alice").await, StatusCode::OK);
+    }
+
+    let response = request_as(
+        &app,
+        "PUT",
+        "/api/v1/admin/throttles/carol",
+        "root",
+        Role::Admin,
+        Some(json!({ "perMinute": 1, "durationSecs": u64::MAX })),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert_eq!(get_as(&app, "carol").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn notifies_the_operator() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = watched_config();
+    config.anomaly.notify_url = Some(format!("http://{}/hook", listener.local_addr().unwrap()));
+    let app = test_app(config).await;
+    for _ in 0..5 {
+        get_as(&app, "alice").await;
+    }
+
+    let received = tokio::time::timeout(Duration::from_secs(5), async {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 4096];
+        while !String::from_utf8_lossy(&received).ends_with('}') {
+            let read = socket.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "connection closed early");
+            received.extend_from_slice(&buffer[..read]);
+        }
+        socket
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        String::from_utf8(received).unwrap()
+    })
+    .await
+    .expect("a notification is sent");
+
+    let (head, body) = received.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("POST /hook "));
+    let notice: Value = serde_json::from_str(body).unwrap();
+    assert_eq!(notice["event"], "clientThrottled");
+    assert_eq!(notice["client"], "alice");
+    assert_eq!(notice["throttledPerMinute"], 3);
+    assert!(notice["text"].as_str().unwrap().contains("alice"));
+}
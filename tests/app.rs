@@ -0,0 +1,86 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, Request, StatusCode},
+};
+use common::{get, post_json, send, test_app, test_config, JWT_SECRET};
+use hello_axum::{auth::generate_token, models::Role};
+use serde_json::json;
+
+#[tokio::test]
+async fn serves_the_basic_routes() {
+    let app = test_app(test_config()).await;
+
+    let response = get(&app, "/").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "Hello World!");
+
+    let response = get(&app, "/healthz").await;
+    assert_eq!(response.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn tags_responses_with_a_request_id() {
+    let app = test_app(test_config()).await;
+    let response = get(&app, "/").await;
+    assert!(response.headers.contains_key("x-request-id"));
+}
+
+#[tokio::test]
+async fn documents_the_versioned_routes() {
+    let app = test_app(test_config()).await;
+    let response = get(&app, "/api-docs/openapi.json").await;
+    assert_eq!(response.status, StatusCode::OK);
+    let spec = response.json();
+    assert!(spec["paths"]["/api/v1/counter"].is_object());
+}
+
+#[tokio::test]
+async fn runs_only_the_swagger_ui_scripts_it_serves() {
+    let app = test_app(test_config()).await;
+    let nonce = |response: &common::TestResponse| {
+        let policy = response.headers["content-security-policy"]
+            .to_str()
+            .unwrap();
+        assert!(!policy.contains("script-src 'unsafe-inline'"), "{}", policy);
+        let nonce = policy
+            .split("'nonce-")
+            .nth(1)
+            .and_then(|rest| rest.split_once('\''))
+            .map(|(nonce, _)| nonce.to_string())
+            .expect("script-src has a nonce");
+        assert!(response
+            .text()
+            .contains(&format!("<script nonce=\"{}\">", nonce)));
+        nonce
+    };
+    let first = get(&app, "/swagger-ui").await;
+    assert_eq!(first.status, StatusCode::OK);
+    let second = get(&app, "/swagger-ui").await;
+    assert_ne!(nonce(&first), nonce(&second));
+}
+
+#[tokio::test]
+async fn rejects_malformed_json() {
+    let app = test_app(test_config()).await;
+    let response = post_json(&app, "/identity", json!({ "name": "alice" })).await;
+    assert!(response.status.is_client_error());
+}
+
+#[tokio::test]
+async fn refuses_pages_past_the_end_of_the_offsets() {
+    let app = test_app(test_config()).await;
+    let token = generate_token("alice", Role::User, None, JWT_SECRET.as_bytes()).unwrap();
+    let request = Request::get(format!("/api/v1/users?page={}&perPage=100", u64::MAX))
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap();
+    let response = send(&app, request).await;
+    assert_eq!(
+        response.status,
+        StatusCode::BAD_REQUEST,
+        "{}",
+        response.text()
+    );
+}
@@ -0,0 +1,226 @@
+mod common;
+
+use std::collections::BTreeMap;
+
+use axum::{body::Body, http::Request, http::StatusCode, Router};
+use hello_axum::{
+    auth::generate_token,
+    config::{ApiKeyConfig, AuthConfig, Config, LdapConfig, MtlsConfig, ServiceIdentity},
+    models::Role,
+};
+
+use common::{send, test_app, test_config, TestResponse, JWT_SECRET};
+
+const PATH: &str = "/api/v1/auth/protected";
+// SHA-256 of "test"
+const KEY_HASH: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+fn identity(user: &str) -> ServiceIdentity {
+    ServiceIdentity {
+        user: user.to_string(),
+        role: Role::User,
+        tenant: None,
+    }
+}
+
+// Trusting the proxy at `proxy`, and knowing one certificate
+fn mtls(proxy: &str) -> MtlsConfig {
+    MtlsConfig {
+        subject_header: Some("x-client-cert-subject".to_string()),
+        trusted_proxies: vec![proxy.to_string()],
+        identities: BTreeMap::from([("CN=reports,O=Example".to_string(), identity("reports"))]),
+        ..MtlsConfig::default()
+    }
+}
+
+fn with_auth(auth: AuthConfig) -> Config {
+    Config {
+        auth,
+        ..test_config()
+    }
+}
+
+async fn get_with(app: &Router, headers: &[(&str, &str)]) -> TestResponse {
+    let mut request = Request::get(PATH);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    send(app, request.body(Body::empty()).unwrap()).await
+}
+
+#[tokio::test]
+async fn signs_in_with_api_keys() {
+    let app = test_app(with_auth(AuthConfig {
+        api_keys: vec![ApiKeyConfig {
+            sha256: KEY_HASH.to_string(),
+            identity: identity("billing"),
+        }],
+        ..AuthConfig::default()
+    }))
+    .await;
+
+    let response = get_with(&app, &[("x-api-key", "test")]).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "Hello billing");
+    let response = get_with(&app, &[("x-api-key", "wrong")]).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn signs_in_with_the_session_cookie() {
+    let app = test_app(with_auth(AuthConfig {
+        session_cookie: Some("session".to_string()),
+        ..AuthConfig::default()
+    }))
+    .await;
+    let token = generate_token("alice", Role::User, None, JWT_SECRET.as_bytes()).unwrap();
+
+    let cookie = format!("theme=dark; session={}", token);
+    let response = get_with(&app, &[("cookie", &cookie)]).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "Hello alice");
+    let response = get_with(&app, &[("cookie", "session=not-a-token")]).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+
+    // Without the setting cookies are ignored
+    let app = test_app(test_config()).await;
+    let response = get_with(&app, &[("cookie", &cookie)]).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn refuses_session_cookies_on_cross_site_changes() {
+    let app = test_app(with_auth(AuthConfig {
+        session_cookie: Some("session".to_string()),
+        ..AuthConfig::default()
+    }))
+    .await;
+    let logout = |headers: &[(&str, &str)]| {
+        let token = generate_token("alice", Role::User, None, JWT_SECRET.as_bytes()).unwrap();
+        let mut request = Request::post("/api/v1/auth/logout")
+            .header("cookie", format!("session={}", token))
+            .header("host", "app.example.com");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        send(&app, request.body(Body::empty()).unwrap())
+    };
+
+    assert_eq!(logout(&[]).await.status, StatusCode::UNAUTHORIZED);
+    let cross_site = logout(&[("sec-fetch-site", "cross-site")]).await;
+    assert_eq!(cross_site.status, StatusCode::UNAUTHORIZED);
+    let other_origin = logout(&[("origin", "https://evil.example.com")]).await;
+    assert_eq!(other_origin.status, StatusCode::UNAUTHORIZED);
+
+    let same_origin = logout(&[("sec-fetch-site", "same-origin")]).await;
+    assert_eq!(same_origin.status, StatusCode::OK, "{}", same_origin.text());
+    let own_origin = logout(&[("origin", "https://app.example.com")]).await;
+    assert_eq!(own_origin.status, StatusCode::OK, "{}", own_origin.text());
+}
+
+#[tokio::test]
+async fn signs_in_with_verified_client_certificates() {
+    let app = test_app(with_auth(AuthConfig {
+        mtls: mtls("127.0.0.1"),
+        ..AuthConfig::default()
+    }))
+    .await;
+
+    let subject = ("x-client-cert-subject", "CN=reports,O=Example");
+    let response = get_with(&app, &[subject, ("x-client-verify", "SUCCESS")]).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "Hello reports");
+
+    let response = get_with(&app, &[subject, ("x-client-verify", "FAILED:expired")]).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    let response = get_with(
+        &app,
+        &[
+            ("x-client-cert-subject", "CN=other"),
+            ("x-client-verify", "SUCCESS"),
+        ],
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn ignores_client_certificates_from_untrusted_peers() {
+    // Tests connect from 127.0.0.1, which isn't the proxy
+    let app = test_app(with_auth(AuthConfig {
+        mtls: mtls("10.0.0.5"),
+        ..AuthConfig::default()
+    }))
+    .await;
+
+    let forged = [
+        ("x-client-cert-subject", "CN=reports,O=Example"),
+        ("x-client-verify", "SUCCESS"),
+    ];
+    let response = get_with(&app, &forged).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_ne!(response.text(), "Hello reports");
+}
+
+#[test]
+fn checks_the_provider_settings() {
+    let bad_hash = with_auth(AuthConfig {
+        api_keys: vec![ApiKeyConfig {
+            sha256: "test".to_string(),
+            identity: identity("billing"),
+        }],
+        ..AuthConfig::default()
+    });
+    assert!(bad_hash.validate().is_err());
+
+    let no_identities = with_auth(AuthConfig {
+        mtls: MtlsConfig {
+            identities: BTreeMap::new(),
+            ..mtls("10.0.0.5")
+        },
+        ..AuthConfig::default()
+    });
+    assert!(no_identities.validate().is_err());
+
+    let no_proxies = with_auth(AuthConfig {
+        mtls: MtlsConfig {
+            trusted_proxies: Vec::new(),
+            ..mtls("10.0.0.5")
+        },
+        ..AuthConfig::default()
+    });
+    assert!(no_proxies.validate().is_err());
+    let bad_proxy = with_auth(AuthConfig {
+        mtls: mtls("proxy.internal"),
+        ..AuthConfig::default()
+    });
+    assert!(bad_proxy.validate().is_err());
+}
+
+#[test]
+fn checks_the_directory_settings() {
+    let ldap = |ldap: LdapConfig| {
+        with_auth(AuthConfig {
+            ldap,
+            ..AuthConfig::default()
+        })
+        .validate()
+    };
+    let directory = LdapConfig {
+        url: Some("ldaps://ldap.example.com".to_string()),
+        ..LdapConfig::default()
+    };
+    assert_eq!(ldap(directory.clone()).is_ok(), cfg!(feature = "ldap"));
+    if cfg!(feature = "ldap") {
+        let no_placeholder = LdapConfig {
+            bind_dn: "cn=admin,dc=example,dc=com".to_string(),
+            ..directory.clone()
+        };
+        assert!(ldap(no_placeholder).is_err());
+        let guests = LdapConfig {
+            group_roles: BTreeMap::from([("cn=visitors".to_string(), Role::Guest)]),
+            ..directory
+        };
+        assert!(ldap(guests).is_err());
+    }
+}
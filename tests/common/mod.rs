@@ -0,0 +1,110 @@
+// Shared by the integration tests, each test file only uses part of it
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::ConnectInfo,
+    http::{HeaderMap, Request, StatusCode},
+    Router,
+};
+use hello_axum::{
+    app, app_with_modules, config::Config, db, modules::AppModule, server::ClientAddr,
+};
+use serde_json::Value;
+use tower::ServiceExt;
+
+pub const JWT_SECRET: &str = "test-jwt-secret-0123456789abcdef0123";
+pub const SIGNING_SECRET: &str = "test-signing-secret-0123456789abcdef";
+
+// Nothing listens on this port, so database calls fail fast instead of hanging
+const MONGODB_URI: &str =
+    "mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=200&connectTimeoutMS=200";
+
+// The defaults with test secrets, and a database that can't be reached
+pub fn test_config() -> Config {
+    Config {
+        mongodb_uri: MONGODB_URI.to_string(),
+        database_name: "hello_axum_test".to_string(),
+        jwt_secret: JWT_SECRET.to_string(),
+        signing_secret: Some(SIGNING_SECRET.to_string()),
+        ..Config::default()
+    }
+}
+
+pub async fn test_app(config: Config) -> Router {
+    config.validate().expect("test config is valid");
+    let database = db(&config)
+        .await
+        .expect("client is built without connecting");
+    app(database, config).expect("app is built")
+}
+
+pub async fn test_app_with_modules(config: Config, modules: Vec<Box<dyn AppModule>>) -> Router {
+    config.validate().expect("test config is valid");
+    let database = db(&config)
+        .await
+        .expect("client is built without connecting");
+    app_with_modules(database, config, modules).expect("app is built")
+}
+
+// Tests that need a real MongoDB are ignored, run them with `--ignored` and MONGODB_TEST_URI
+// pointing at one. Each run gets a database of its own
+pub fn mongo_config(test: &str) -> Config {
+    let uri = std::env::var("MONGODB_TEST_URI").expect("MONGODB_TEST_URI is set");
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    Config {
+        mongodb_uri: uri,
+        database_name: format!("hello_axum_test_{}_{}", test, nanos),
+        ..test_config()
+    }
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).expect("response body is JSON")
+    }
+}
+
+// Sends a request as if it came from `127.0.0.1`, like a connection accepted by `serve`
+pub async fn send(app: &Router, mut request: Request<Body>) -> TestResponse {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 40000));
+    request
+        .extensions_mut()
+        .insert(ConnectInfo(ClientAddr(addr)));
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    TestResponse {
+        status,
+        headers,
+        body,
+    }
+}
+
+pub async fn get(app: &Router, path: &str) -> TestResponse {
+    send(app, Request::get(path).body(Body::empty()).unwrap()).await
+}
+
+pub async fn post_json(app: &Router, path: &str, body: Value) -> TestResponse {
+    let request = Request::post(path)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(app, request).await
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use axum::{body::Body, http::Request, http::StatusCode};
+use common::{get, mongo_config, post_json, send, test_app};
+use hello_axum::db;
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn resets_the_default_counter_when_deleted() {
+    let config = mongo_config("counter_delete");
+    let database = db(&config).await.unwrap();
+    let app = test_app(config).await;
+
+    let request = Request::delete("/api/v1/counter/default")
+        .body(Body::empty())
+        .unwrap();
+    let response = send(&app, request).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    assert_eq!(response.json()["message"], "Counter reset");
+
+    // Not re-created at its initial value
+    let response = get(&app, "/api/v1/counter/default").await;
+    assert_eq!(response.json()["data"]["value"], 0);
+    let response = get(&app, "/api/v1/counter").await;
+    assert_eq!(response.text(), "The count is : Counter { value: 0 }");
+
+    database.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn keeps_the_default_counter_within_the_legacy_route() {
+    let config = mongo_config("counter_range");
+    let database = db(&config).await.unwrap();
+    let app = test_app(config).await;
+    let put = |value: u64| {
+        Request::put("/api/v1/counter/default")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({ "value": value }).to_string()))
+            .unwrap()
+    };
+
+    let response = send(&app, put(u64::from(u32::MAX) + 1)).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    let response = send(&app, put(u64::from(u32::MAX) - 1)).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let increment = "/api/v1/counter/default/increment";
+    let response = post_json(&app, increment, json!({ "by": 2 })).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    let response = post_json(&app, increment, json!({ "by": 1 })).await;
+    assert_eq!(response.json()["data"]["value"], u32::MAX);
+
+    let response = get(&app, "/api/v1/counter").await;
+    assert_eq!(response.status, StatusCode::OK);
+
+    database.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn starts_the_default_counter_at_its_initial_value() {
+    let config = mongo_config("counter_first_increment");
+    let database = db(&config).await.unwrap();
+    let app = test_app(config).await;
+
+    // Nothing has read the counter yet, so the increment is what creates it
+    let increment = "/api/v1/counter/default/increment";
+    let response = post_json(&app, increment, json!({ "by": 1 })).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    assert_eq!(response.json()["data"]["value"], 2);
+    let response = get(&app, "/api/v1/counter/default").await;
+    assert_eq!(response.json()["data"]["value"], 2);
+
+    database.drop().await.unwrap();
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    Router,
+};
+use common::{mongo_config, post_json, send, test_app, test_config, TestResponse};
+use hello_axum::{config::Config, db};
+use mongodb::bson::{doc, Document};
+use serde_json::{json, Value};
+
+const KEYS: &str = "k1:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+fn encrypting(config: Config) -> Config {
+    Config {
+        field_encryption_keys: Some(KEYS.to_string()),
+        ..config
+    }
+}
+
+async fn with_token(
+    app: &Router,
+    method: &str,
+    path: &str,
+    token: &str,
+    body: Value,
+) -> TestResponse {
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(app, request).await
+}
+
+#[test]
+fn checks_the_keys() {
+    assert!(encrypting(test_config()).validate().is_ok());
+    let short = Config {
+        field_encryption_keys: Some("k1:c2hvcnQ=".to_string()),
+        ..test_config()
+    };
+    assert!(short.validate().is_err());
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn stores_emails_sealed() {
+    let config = mongo_config("field_encryption");
+    let config = encrypting(config);
+    let app = test_app(config.clone()).await;
+    let credentials = json!({ "userName": "sealed", "password": "sealed-password-1" });
+    let response = post_json(&app, "/api/v1/auth/signup", credentials.clone()).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let id = response.json()["data"].as_str().unwrap().to_string();
+    let response = post_json(&app, "/api/v1/auth/signin", credentials).await;
+    let token = response.json()["data"]["accessToken"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let path = format!("/api/v1/users/{}", id);
+    let email = json!({ "email": "sealed@example.com" });
+    let response = with_token(&app, "PATCH", &path, &token, email).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    assert_eq!(response.json()["data"]["email"], "sealed@example.com");
+
+    let database = db(&config).await.unwrap();
+    let stored = database
+        .collection::<Document>("users")
+        .find_one(doc! { "user_name": "sealed" })
+        .await
+        .unwrap()
+        .unwrap();
+    let sealed = stored.get_str("email").unwrap();
+    assert!(sealed.starts_with("enc:k1:"), "{}", sealed);
+
+    let response = with_token(&app, "GET", &path, &token, json!({})).await;
+    assert_eq!(response.json()["data"]["email"], "sealed@example.com");
+    database.drop().await.unwrap();
+}
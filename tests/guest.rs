@@ -0,0 +1,148 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    Router,
+};
+use common::{get, mongo_config, post_json, send, test_app, test_config, TestResponse};
+use hello_axum::config::Config;
+use serde_json::{json, Value};
+
+async fn with_token(
+    app: &Router,
+    method: &str,
+    path: &str,
+    token: &str,
+    body: Value,
+) -> TestResponse {
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(app, request).await
+}
+
+async fn guest_token(app: &Router) -> String {
+    let response = post_json(app, "/api/v1/auth/guest", json!({})).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let data = response.json()["data"].clone();
+    assert!(data["guestId"].as_str().unwrap().starts_with("guest:"));
+    assert_eq!(data["expiresIn"], 1800);
+    data["accessToken"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn keeps_guests_out_of_account_routes() {
+    let app = test_app(test_config()).await;
+    let token = guest_token(&app).await;
+
+    let response = with_token(&app, "GET", "/api/v1/auth/protected", &token, json!({})).await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+    let response = with_token(&app, "GET", "/api/v1/auth/usage", &token, json!({})).await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn hands_demo_visitors_a_guest_cookie() {
+    let app = test_app(Config {
+        demo_mode: true,
+        ..test_config()
+    })
+    .await;
+
+    let response = get(&app, "/api/v1/sandbox/counter").await;
+    assert_ne!(response.status, StatusCode::UNAUTHORIZED);
+    let cookie = response.headers["set-cookie"].to_str().unwrap();
+    assert!(cookie.starts_with("demo_session="), "{}", cookie);
+    assert!(cookie.contains("HttpOnly"));
+
+    // A visitor coming back keeps their guest
+    let session = cookie.split(';').next().unwrap();
+    let request = Request::get("/api/v1/sandbox/counter")
+        .header("cookie", session)
+        .body(Body::empty())
+        .unwrap();
+    let response = send(&app, request).await;
+    assert_ne!(response.status, StatusCode::UNAUTHORIZED);
+    assert!(!response.headers.contains_key("set-cookie"));
+
+    // Three back to back by default, per address
+    get(&app, "/api/v1/sandbox/counter").await;
+    let response = get(&app, "/api/v1/sandbox/counter").await;
+    assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+
+    // Off by default
+    let app = test_app(test_config()).await;
+    let response = get(&app, "/api/v1/sandbox/counter").await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert!(!response.headers.contains_key("set-cookie"));
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn claims_a_guest_account_with_its_sandbox() {
+    let config = mongo_config("guest");
+    let app = test_app(config).await;
+    let token = guest_token(&app).await;
+
+    for _ in 0..2 {
+        let response = with_token(
+            &app,
+            "POST",
+            "/api/v1/sandbox/counter",
+            &token,
+            json!({ "by": 2 }),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    }
+
+    let credentials = json!({ "userName": "guest-claimer", "password": "claimed-password-1" });
+    let response = with_token(
+        &app,
+        "POST",
+        "/api/v1/auth/claim",
+        &token,
+        credentials.clone(),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let access_token = response.json()["data"]["accessToken"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // The guest token is spent, the account has the counter
+    let response = with_token(&app, "GET", "/api/v1/sandbox/counter", &token, json!({})).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    let response = with_token(
+        &app,
+        "GET",
+        "/api/v1/sandbox/counter",
+        &access_token,
+        json!({}),
+    )
+    .await;
+    assert_eq!(response.json()["data"]["value"], 4);
+
+    let response = with_token(
+        &app,
+        "POST",
+        "/api/v1/auth/claim",
+        &access_token,
+        credentials,
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+    let response = post_json(
+        &app,
+        "/api/v1/auth/signin",
+        json!({ "userName": "guest-claimer", "password": "claimed-password-1" }),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK);
+}
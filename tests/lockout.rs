@@ -0,0 +1,43 @@
+mod common;
+
+use axum::http::{header::RETRY_AFTER, StatusCode};
+use common::{mongo_config, post_json, test_app};
+use hello_axum::config::{Config, LockoutConfig};
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn locks_accounts_after_repeated_failures() {
+    let config = Config {
+        account_lockout: LockoutConfig {
+            max_failures: 2,
+            lock_secs: 300,
+        },
+        ..mongo_config("lockout")
+    };
+    let app = test_app(config).await;
+
+    let credentials = json!({ "userName": "alice", "password": "right-password-1" });
+    let wrong = json!({ "userName": "alice", "password": "wrong-password-1" });
+    let response = post_json(&app, "/api/v1/auth/signup", credentials.clone()).await;
+    assert!(response.status.is_success(), "{}", response.text());
+
+    for _ in 0..2 {
+        let response = post_json(&app, "/api/v1/auth/signin", wrong.clone()).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    }
+
+    // A wrong password can't tell a locked account from any other
+    let response = post_json(&app, "/api/v1/auth/signin", wrong).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert!(!response.headers.contains_key(RETRY_AFTER));
+
+    let response = post_json(&app, "/api/v1/auth/signin", credentials).await;
+    assert_eq!(response.status, StatusCode::LOCKED, "{}", response.text());
+    let retry_after: u64 = response.headers[RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 300, "{}", retry_after);
+}
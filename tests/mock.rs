@@ -0,0 +1,63 @@
+mod common;
+
+use axum::{body::Body, http::Request, http::StatusCode};
+use common::{get, post_json, send, test_config};
+use hello_axum::mock::mock_app;
+use serde_json::json;
+
+#[tokio::test]
+async fn answers_with_documented_examples() {
+    let app = mock_app(&test_config());
+    let response = post_json(
+        &app,
+        "/api/v1/auth/signin",
+        json!({ "userName": "anyone", "password": "x" }),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["x-mock"], "true");
+    assert_eq!(response.headers["content-type"], "application/json");
+    let body = response.json();
+    assert_eq!(body["status"], 200);
+    assert!(body["data"]["accessToken"]
+        .as_str()
+        .unwrap()
+        .starts_with("eyJ"));
+
+    let response = post_json(&app, "/api/v1/auth/signup", json!({})).await;
+    assert_eq!(response.json()["data"], "usr_0123456789abcdef01234567");
+
+    let response = get(&app, "/api/v1/counter/visits").await;
+    assert_eq!(
+        response.json()["data"],
+        json!({ "name": "visits", "value": 42 })
+    );
+}
+
+#[tokio::test]
+async fn sends_text_examples_as_text() {
+    let app = mock_app(&test_config());
+    let response = get(&app, "/api/v1/counter").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["content-type"], "text/plain");
+    assert_eq!(response.text(), "The count is : Counter { value: 42 }");
+}
+
+#[tokio::test]
+async fn only_mocks_documented_routes() {
+    let app = mock_app(&test_config());
+    assert_eq!(
+        get(&app, "/api/v1/users").await.status,
+        StatusCode::NOT_FOUND
+    );
+    let request = Request::patch("/api/v1/counter")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(
+        send(&app, request).await.status,
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+
+    let spec = get(&app, "/api-docs/openapi.json").await.json();
+    assert!(spec["paths"]["/api/v1/auth/signin"]["post"].is_object());
+}
@@ -0,0 +1,164 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use common::{mongo_config, post_json, test_app, test_app_with_modules, test_config};
+use hello_axum::{
+    config::{Config, Environment, PasswordResetConfig},
+    db,
+    delivery::{MemoryDelivery, TokenDelivery, WebhookDelivery},
+    modules::{AppModule, AuthModule},
+};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+fn auth_module(delivery: &Arc<MemoryDelivery>) -> Vec<Box<dyn AppModule>> {
+    vec![Box::new(AuthModule {
+        delivery: Some(delivery.clone()),
+    })]
+}
+
+#[tokio::test]
+async fn sends_nothing_when_the_lookup_fails() {
+    let delivery = Arc::new(MemoryDelivery::default());
+    let app = test_app_with_modules(test_config(), auth_module(&delivery)).await;
+
+    let response = post_json(
+        &app,
+        "/api/v1/auth/forgot-password",
+        json!({ "userName": "alice" }),
+    )
+    .await;
+    assert!(response.status.is_server_error());
+    assert_eq!(delivery.sent_count(), 0);
+}
+
+#[tokio::test]
+async fn needs_a_delivery_outside_development() {
+    let forgot = |config: Config| async move {
+        let app = test_app(config).await;
+        let body = json!({ "userName": "alice" });
+        post_json(&app, "/api/v1/auth/forgot-password", body)
+            .await
+            .status
+    };
+    // Redacted logs would hide the token, so it isn't logged in production
+    assert!(forgot(test_config()).await.is_client_error());
+
+    // Served, failing on the database lookup
+    let webhook = Config {
+        password_reset: PasswordResetConfig {
+            webhook_url: Some("https://hooks.example.com/reset".to_string()),
+        },
+        ..test_config()
+    };
+    assert!(forgot(webhook).await.is_server_error());
+    let development = Config {
+        environment: Environment::Development,
+        ..test_config()
+    };
+    assert!(forgot(development).await.is_server_error());
+}
+
+#[tokio::test]
+async fn posts_reset_tokens_to_the_webhook() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let delivery =
+        WebhookDelivery::new(&format!("http://{}/reset", listener.local_addr().unwrap()));
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 4096];
+        while !String::from_utf8_lossy(&received).ends_with('}') {
+            let read = socket.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "connection closed early");
+            received.extend_from_slice(&buffer[..read]);
+        }
+        socket
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        String::from_utf8(received).unwrap()
+    });
+    let sent = delivery.send_reset_token("alice", Some("alice@example.com"), "token-1");
+    tokio::time::timeout(Duration::from_secs(5), sent)
+        .await
+        .expect("the webhook answers")
+        .unwrap();
+
+    let received = server.await.unwrap();
+    let (head, body) = received.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("POST /reset "));
+    let event: Value = serde_json::from_str(body).unwrap();
+    assert_eq!(event["event"], "passwordReset");
+    assert_eq!(event["userName"], "alice");
+    assert_eq!(event["email"], "alice@example.com");
+    assert_eq!(event["resetToken"], "token-1");
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn resets_a_password_with_the_delivered_token() {
+    let config = mongo_config("password_reset");
+    let database = db(&config).await.unwrap();
+    let delivery = Arc::new(MemoryDelivery::default());
+    let app = test_app_with_modules(config, auth_module(&delivery)).await;
+
+    let credentials = json!({ "userName": "alice", "password": "first-password-1" });
+    let response = post_json(&app, "/api/v1/auth/signup", credentials.clone()).await;
+    assert!(response.status.is_success(), "{}", response.text());
+
+    // Unknown accounts get the same answer, but no token
+    let response = post_json(
+        &app,
+        "/api/v1/auth/forgot-password",
+        json!({ "userName": "nobody" }),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(delivery.sent_count(), 0);
+
+    let response = post_json(
+        &app,
+        "/api/v1/auth/forgot-password",
+        json!({ "userName": "alice" }),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK);
+    // Delivered after the answer
+    let mut token = None;
+    for _ in 0..50 {
+        token = delivery.last_token("alice");
+        if token.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let token = token.expect("a token was delivered");
+
+    let reset = json!({ "token": token, "newPassword": "second-password-2" });
+    let response = post_json(&app, "/api/v1/auth/reset-password", reset.clone()).await;
+    assert!(response.status.is_success(), "{}", response.text());
+
+    // Tokens are single use
+    let response = post_json(&app, "/api/v1/auth/reset-password", reset).await;
+    assert!(response.status.is_client_error());
+
+    let response = post_json(&app, "/api/v1/auth/signin", credentials).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    let response = post_json(
+        &app,
+        "/api/v1/auth/signin",
+        json!({ "userName": "alice", "password": "second-password-2" }),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.json()["data"]["accessToken"].is_string());
+
+    database.drop().await.unwrap();
+}
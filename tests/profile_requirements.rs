@@ -0,0 +1,89 @@
+mod common;
+
+use std::collections::BTreeMap;
+
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    Router,
+};
+use common::{get, mongo_config, post_json, send, test_app, test_config, TestResponse};
+use hello_axum::{config::Config, models::ProfileField};
+use serde_json::{json, Value};
+
+fn requiring_email(config: Config) -> Config {
+    Config {
+        profile_requirements: BTreeMap::from([(
+            "GET /auth/protected".to_string(),
+            vec![ProfileField::Email, ProfileField::DisplayName],
+        )]),
+        ..config
+    }
+}
+
+async fn with_token(
+    app: &Router,
+    method: &str,
+    path: &str,
+    token: &str,
+    body: Value,
+) -> TestResponse {
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(app, request).await
+}
+
+#[test]
+fn refuses_malformed_routes() {
+    let config = Config {
+        profile_requirements: BTreeMap::from([(
+            "/auth/protected".to_string(),
+            vec![ProfileField::Email],
+        )]),
+        ..test_config()
+    };
+    assert!(config.validate().is_err());
+    assert!(requiring_email(test_config()).validate().is_ok());
+}
+
+#[tokio::test]
+async fn still_needs_a_login() {
+    let app = test_app(requiring_email(test_config())).await;
+    let response = get(&app, "/api/v1/auth/protected").await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn lists_missing_fields_until_the_profile_is_complete() {
+    let config = mongo_config("profile_requirements");
+    let app = test_app(requiring_email(config)).await;
+    let credentials = json!({ "userName": "profiler", "password": "profile-password-1" });
+    let response = post_json(&app, "/api/v1/auth/signup", credentials.clone()).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let id = response.json()["data"].as_str().unwrap().to_string();
+    let response = post_json(&app, "/api/v1/auth/signin", credentials).await;
+    let token = response.json()["data"]["accessToken"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let response = with_token(&app, "GET", "/api/v1/auth/protected", &token, json!({})).await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+    assert_eq!(
+        response.json()["data"],
+        json!({ "code": "PROFILE_INCOMPLETE", "missingFields": ["email", "displayName"] })
+    );
+
+    let path = format!("/api/v1/users/{}", id);
+    let profile = json!({ "email": "profiler@example.com", "displayName": "Profiler" });
+    let response = with_token(&app, "PATCH", &path, &token, profile).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let response = with_token(&app, "GET", "/api/v1/auth/protected", &token, json!({})).await;
+    assert_eq!(response.status, StatusCode::OK);
+}
@@ -0,0 +1,134 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    Router,
+};
+use hello_axum::{
+    auth::{generate_guest_token, generate_token},
+    config::{Config, RateLimitConfig},
+    models::Role,
+};
+
+use common::{get, send, test_app, test_config, TestResponse, JWT_SECRET};
+
+const PATH: &str = "/api/v1/auth/protected";
+
+// Two requests back to back, then one a second, premium users wait up to 1.5s
+fn limited_config() -> Config {
+    Config {
+        auth_rate_limit: RateLimitConfig {
+            burst: 2,
+            per_minute: 60,
+            max_queue_ms: 1500,
+        },
+        ..test_config()
+    }
+}
+
+async fn get_as(app: &Router, user: &str, role: Role) -> TestResponse {
+    let token = generate_token(user, role, None, JWT_SECRET.as_bytes()).unwrap();
+    get_with_token(app, &token).await
+}
+
+async fn get_with_token(app: &Router, token: &str) -> TestResponse {
+    let request = Request::get(PATH)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap();
+    send(app, request).await
+}
+
+#[tokio::test]
+async fn refuses_anonymous_callers_at_the_limit() {
+    let app = test_app(limited_config()).await;
+    for _ in 0..2 {
+        assert_eq!(get(&app, PATH).await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    let response = get(&app, PATH).await;
+    assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers["retry-after"], "1");
+}
+
+#[tokio::test]
+async fn limits_users_by_their_address() {
+    let app = test_app(limited_config()).await;
+    assert_eq!(get(&app, PATH).await.status, StatusCode::UNAUTHORIZED);
+
+    // Every token from one address draws on the same bucket as the anonymous request did
+    assert_eq!(
+        get_as(&app, "alice", Role::User).await.status,
+        StatusCode::OK
+    );
+    let response = get_as(&app, "bob", Role::User).await;
+    assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+    let response = get_as(&app, "carol", Role::User).await;
+    assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn limits_guests_by_their_address() {
+    let app = test_app(limited_config()).await;
+    let get_as_guest = || async {
+        let (_, token) = generate_guest_token(None, JWT_SECRET.as_bytes()).unwrap();
+        get_with_token(&app, &token).await.status
+    };
+    // Signed in, but guests can't use the route
+    for _ in 0..2 {
+        assert_eq!(get_as_guest().await, StatusCode::FORBIDDEN);
+    }
+
+    // A fresh guest doesn't get a fresh bucket
+    assert_eq!(get_as_guest().await, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(get(&app, PATH).await.status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn queues_premium_users_for_a_bounded_delay() {
+    let app = test_app(limited_config()).await;
+    for _ in 0..2 {
+        let response = get_as(&app, "carol", Role::Premium).await;
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    // The first waits a second for the next token, the second would need two and is refused
+    let started = Instant::now();
+    let (queued, refused) = tokio::join!(
+        get_as(&app, "carol", Role::Premium),
+        get_as(&app, "carol", Role::Premium)
+    );
+    let mut statuses = [queued.status, refused.status];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]);
+    assert!(started.elapsed() >= Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn limits_qr_codes_apart_from_auth() {
+    let app = test_app(Config {
+        media_rate_limit: RateLimitConfig {
+            burst: 2,
+            per_minute: 60,
+            max_queue_ms: 0,
+        },
+        ..limited_config()
+    })
+    .await;
+    for _ in 0..2 {
+        assert_eq!(get(&app, PATH).await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    for _ in 0..2 {
+        let response = get(&app, "/api/v1/qr?data=hello").await;
+        assert_eq!(response.status, StatusCode::OK);
+    }
+    let response = get(&app, "/api/v1/qr?data=hello").await;
+    assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+    // Image fetches count against the same buckets, the host check never runs
+    let response = get(&app, "/api/v1/img?url=https://example.com/a.png").await;
+    assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use axum::{http::StatusCode, Router};
+use common::{get, post_json, test_app, test_config};
+use hello_axum::config::{Config, RouteGroups};
+use serde_json::json;
+
+async fn app_with(groups: RouteGroups) -> Router {
+    let config = Config {
+        route_groups: groups,
+        ..test_config()
+    };
+    test_app(config).await
+}
+
+// One request per group that only a missing route answers with 404
+async fn served(app: &Router) -> [bool; 6] {
+    [
+        post_json(app, "/api/v1/auth/signin", json!({}))
+            .await
+            .status,
+        get(app, "/api/v1/users").await.status,
+        get(app, "/api/v1/admin/runtime").await.status,
+        post_json(app, "/api/v1/files", json!({})).await.status,
+        get(app, "/api/v1/qr?data=hello").await.status,
+        get(app, "/api/v1/settings").await.status,
+    ]
+    .map(|status| status != StatusCode::NOT_FOUND)
+}
+
+// `/metrics` is otherwise taken by the `/{id}` demo route
+async fn serves_metrics(app: &Router) -> bool {
+    let response = get(app, "/metrics").await;
+    response.headers["content-type"] == "text/plain; version=0.0.4"
+}
+
+async fn documented(app: &Router, path: &str) -> bool {
+    let spec = get(app, "/api-docs/openapi.json").await.json();
+    spec["paths"][path].is_object()
+}
+
+#[tokio::test]
+async fn full_profile_serves_every_group() {
+    let app = app_with(RouteGroups::default()).await;
+    assert_eq!(served(&app).await, [true; 6]);
+    assert!(documented(&app, "/api/v1/auth/signin").await);
+    assert!(serves_metrics(&app).await);
+}
+
+#[tokio::test]
+async fn public_profile_only_serves_media() {
+    let app = app_with(RouteGroups {
+        auth: false,
+        users: false,
+        admin: false,
+        files: false,
+        media: true,
+        settings: false,
+        scim: false,
+        metrics: false,
+    })
+    .await;
+    assert_eq!(
+        served(&app).await,
+        [false, false, false, false, true, false]
+    );
+    assert_eq!(
+        get(&app, "/api/v1/qr?data=hello").await.status,
+        StatusCode::OK
+    );
+    assert!(!documented(&app, "/api/v1/auth/signin").await);
+    assert!(documented(&app, "/api/v1/counter").await);
+    // Probes stay, metrics go with the other groups
+    assert!(!serves_metrics(&app).await);
+    assert_ne!(get(&app, "/healthz").await.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn no_admin_profile_drops_only_admin() {
+    let app = app_with(RouteGroups {
+        admin: false,
+        ..RouteGroups::default()
+    })
+    .await;
+    assert_eq!(served(&app).await, [true, true, false, true, true, true]);
+    // Nor are the legacy copies left behind
+    assert_eq!(
+        get(&app, "/admin/runtime").await.status,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn read_only_node_profile_drops_admin_and_uploads() {
+    let app = app_with(RouteGroups {
+        admin: false,
+        files: false,
+        settings: false,
+        ..RouteGroups::default()
+    })
+    .await;
+    assert_eq!(served(&app).await, [true, true, false, false, true, false]);
+}
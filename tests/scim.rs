@@ -0,0 +1,170 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    Router,
+};
+use common::{mongo_config, post_json, send, test_app, test_config, TestResponse};
+use hello_axum::config::{Config, RouteGroups, ScimConfig};
+use serde_json::{json, Value};
+
+const TOKEN: &str = "test-scim-token-0123456789abcdef01234";
+const BASE: &str = "/api/v1/scim/v2";
+
+fn with_scim(config: Config) -> Config {
+    Config {
+        route_groups: RouteGroups {
+            scim: true,
+            ..RouteGroups::default()
+        },
+        scim: ScimConfig {
+            bearer_token: Some(TOKEN.to_string()),
+        },
+        ..config
+    }
+}
+
+async fn scim(app: &Router, method: &str, path: &str, body: Option<Value>) -> TestResponse {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("{}{}", BASE, path))
+        .header(AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .header("content-type", "application/scim+json");
+    let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+    send(app, request.body(body).unwrap()).await
+}
+
+fn patch_op(operations: Value) -> Option<Value> {
+    Some(json!({
+        "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+        "Operations": operations,
+    }))
+}
+
+#[test]
+fn needs_a_token_to_be_served() {
+    let config = Config {
+        scim: ScimConfig::default(),
+        ..with_scim(test_config())
+    };
+    assert!(config.validate().is_err());
+}
+
+#[tokio::test]
+async fn refuses_requests_without_the_token() {
+    let app = test_app(with_scim(test_config())).await;
+    let request = Request::get(format!("{}/Users", BASE))
+        .header(AUTHORIZATION, "Bearer wrong")
+        .body(Body::empty())
+        .unwrap();
+    let response = send(&app, request).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers["content-type"], "application/scim+json");
+    assert_eq!(
+        response.json()["schemas"][0],
+        "urn:ietf:params:scim:api:messages:2.0:Error"
+    );
+}
+
+#[tokio::test]
+async fn answers_in_scim_form() {
+    let app = test_app(with_scim(test_config())).await;
+    let response = scim(&app, "GET", "/ServiceProviderConfig", None).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.json()["patch"]["supported"], true);
+
+    let response = scim(&app, "GET", "/Users?filter=userName%20like%20%22a%22", None).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert_eq!(response.json()["scimType"], "invalidFilter");
+}
+
+#[tokio::test]
+async fn is_off_by_default() {
+    let app = test_app(test_config()).await;
+    let response = scim(&app, "GET", "/ServiceProviderConfig", None).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn provisions_and_deprovisions_users() {
+    let config = mongo_config("scim");
+    let app = test_app(with_scim(config)).await;
+
+    let user = json!({
+        "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+        "userName": "alice",
+        "externalId": "idp-1",
+        "name": { "givenName": "Alice", "familyName": "Smith" },
+        "emails": [{ "value": "alice@example.com", "primary": true }],
+        "password": "first-password-1",
+    });
+    let response = scim(&app, "POST", "/Users", Some(user.clone())).await;
+    assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+    let created = response.json();
+    let id = created["id"].as_str().unwrap().to_string();
+    assert_eq!(created["displayName"], "Alice Smith");
+    assert_eq!(created["active"], true);
+
+    let response = scim(&app, "POST", "/Users", Some(user)).await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+
+    let response = scim(
+        &app,
+        "GET",
+        "/Users?filter=userName%20eq%20%22ALICE%22",
+        None,
+    )
+    .await;
+    let list = response.json();
+    assert_eq!(list["totalResults"], 1);
+    assert_eq!(list["Resources"][0]["id"], id.as_str());
+
+    // Groups only take stored users as members
+    let response = scim(
+        &app,
+        "POST",
+        "/Groups",
+        Some(json!({ "displayName": "Staff", "members": [{ "value": id }] })),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+    let group_id = response.json()["id"].as_str().unwrap().to_string();
+    let response = scim(
+        &app,
+        "POST",
+        "/Groups",
+        Some(json!({ "displayName": "Other", "members": [{ "value": "usr_0123456789abcdef01234567" }] })),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+
+    let credentials = json!({ "userName": "alice", "password": "first-password-1" });
+    let response = post_json(&app, "/api/v1/auth/signin", credentials.clone()).await;
+    assert_eq!(response.status, StatusCode::OK);
+
+    let response = scim(
+        &app,
+        "PATCH",
+        &format!("/Users/{}", id),
+        patch_op(json!([{ "op": "Replace", "path": "active", "value": false }])),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    assert_eq!(response.json()["active"], false);
+    let response = post_json(&app, "/api/v1/auth/signin", credentials).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    // Same as for a user name nobody has
+    let unknown = json!({ "userName": "nobody", "password": "first-password-1" });
+    let unknown = post_json(&app, "/api/v1/auth/signin", unknown).await;
+    assert_eq!(unknown.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(unknown.text(), response.text());
+
+    let response = scim(&app, "DELETE", &format!("/Users/{}", id), None).await;
+    assert_eq!(response.status, StatusCode::NO_CONTENT);
+    let response = scim(&app, "GET", &format!("/Users/{}", id), None).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    let response = scim(&app, "GET", &format!("/Groups/{}", group_id), None).await;
+    assert_eq!(response.json()["members"], json!([]));
+}
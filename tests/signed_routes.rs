@@ -0,0 +1,94 @@
+mod common;
+
+use axum::{body::Body, http::Request, http::StatusCode, Router};
+use hello_axum::{config::Config, middleware::RequestSigner};
+use hmac::Mac;
+use jsonwebtoken::get_current_timestamp;
+
+use common::{get, send, test_app, test_config, TestResponse, SIGNING_SECRET};
+
+const BODY: &str = r#"{"value":3}"#;
+const NONCE: &str = "0123456789abcdef";
+
+fn signing(routes: &[&str]) -> Config {
+    Config {
+        signed_routes: routes.iter().map(|route| route.to_string()).collect(),
+        ..test_config()
+    }
+}
+
+async fn put(app: &Router, signature: Option<(u64, &str)>) -> TestResponse {
+    let mut request = Request::put("/api/v1/counter").header("content-type", "application/json");
+    if let Some((timestamp, signature)) = signature {
+        request = request
+            .header("x-timestamp", timestamp.to_string())
+            .header("x-nonce", NONCE)
+            .header("x-signature", signature);
+    }
+    send(app, request.body(Body::from(BODY)).unwrap()).await
+}
+
+#[tokio::test]
+async fn only_checks_the_signed_routes() {
+    let app = test_app(signing(&["PUT /counter"])).await;
+    let response = put(&app, None).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.text(), "Missing request signature");
+
+    let timestamp = get_current_timestamp();
+    let mac = RequestSigner::sign(
+        SIGNING_SECRET.as_bytes(),
+        timestamp,
+        NONCE,
+        "PUT",
+        "/api/v1/counter",
+        BODY.as_bytes(),
+    );
+    let signature = hex::encode(mac.finalize().into_bytes());
+    let response = put(&app, Some((timestamp, &signature))).await;
+    assert_ne!(response.status, StatusCode::UNAUTHORIZED);
+    // Once only
+    let response = put(&app, Some((timestamp, &signature))).await;
+    assert_eq!(response.text(), "Replayed request signature");
+
+    // Plain clients of everything else, and of every route by default
+    let request = Request::delete("/api/v1/counter")
+        .body(Body::empty())
+        .unwrap();
+    assert_ne!(send(&app, request).await.status, StatusCode::UNAUTHORIZED);
+    let app = test_app(test_config()).await;
+    assert_ne!(put(&app, None).await.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn documents_signatures_where_they_are_checked() {
+    let secured = |spec: &serde_json::Value, method: &str| {
+        spec["paths"]["/api/v1/counter"][method]["security"].is_array()
+    };
+
+    let spec = get(&test_app(test_config()).await, "/api-docs/openapi.json")
+        .await
+        .json();
+    assert!(!secured(&spec, "put"));
+
+    let app = test_app(signing(&["PUT /counter"])).await;
+    let spec = get(&app, "/api-docs/openapi.json").await.json();
+    assert!(secured(&spec, "put"));
+    assert!(spec["paths"]["/api/v1/counter"]["put"]["responses"]["401"].is_object());
+    assert!(!secured(&spec, "delete"));
+}
+
+#[test]
+fn needs_a_secret_for_signed_routes() {
+    let no_secret = Config {
+        signing_secret: None,
+        ..signing(&["PUT /counter"])
+    };
+    assert!(no_secret.validate().is_err());
+    let no_routes = Config {
+        signing_secret: None,
+        ..test_config()
+    };
+    assert!(no_routes.validate().is_ok());
+    assert!(signing(&["GET /counter"]).validate().is_err());
+}
@@ -0,0 +1,162 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::StatusCode,
+    middleware::from_fn_with_state,
+    routing::{get, post},
+    Router,
+};
+use hello_axum::{
+    config::ConnectionsConfig,
+    middleware::{enforce_body_throughput, BodyThroughput},
+    server::{serve, LimitedListener},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+const HEADER_READ_TIMEOUT_SECS: u64 = 1;
+const BODY_STALL_TIMEOUT_SECS: u64 = 1;
+
+async fn echo(body: Body) -> StatusCode {
+    match to_bytes(body, usize::MAX).await {
+        Ok(_) => StatusCode::OK,
+        Err(_) => StatusCode::REQUEST_TIMEOUT,
+    }
+}
+
+// Serves a small app on a random port with short timeouts
+async fn start(max_per_ip: usize) -> SocketAddr {
+    let config = ConnectionsConfig {
+        max_connections: 16,
+        max_per_ip,
+        header_read_timeout_secs: HEADER_READ_TIMEOUT_SECS,
+        body_stall_timeout_secs: BODY_STALL_TIMEOUT_SECS,
+        body_throughput_grace_secs: 1,
+        min_body_bytes_per_sec: 64,
+        ..ConnectionsConfig::default()
+    };
+    let app = Router::new()
+        .route("/", get(|| async { "ok" }))
+        .route("/echo", post(echo))
+        .layer(from_fn_with_state(
+            BodyThroughput::from(&config),
+            enforce_body_throughput,
+        ));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let listener = LimitedListener::new(listener, &config);
+    tokio::spawn(async move { serve(listener, app, &config, std::future::pending()).await });
+    addr
+}
+
+// Reads until the server closes the connection, returning what it sent
+async fn read_until_closed(stream: &mut TcpStream, within: Duration) -> String {
+    let mut response = Vec::new();
+    timeout(within, stream.read_to_end(&mut response))
+        .await
+        .expect("server kept the connection open")
+        .unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[tokio::test]
+async fn closes_connections_that_send_nothing() {
+    let addr = start(8).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    let started = Instant::now();
+    let response = read_until_closed(&mut stream, Duration::from_secs(5)).await;
+    assert!(response.is_empty());
+    assert!(started.elapsed() >= Duration::from_secs(HEADER_READ_TIMEOUT_SECS));
+}
+
+#[tokio::test]
+async fn closes_connections_with_partial_headers() {
+    let addr = start(8).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n")
+        .await
+        .unwrap();
+
+    let response = read_until_closed(&mut stream, Duration::from_secs(5)).await;
+    assert!(!response.contains("200 OK"));
+}
+
+#[tokio::test]
+async fn closes_idle_keep_alive_connections() {
+    let addr = start(8).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+
+    let response = read_until_closed(&mut stream, Duration::from_secs(5)).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+}
+
+#[tokio::test]
+async fn fails_stalled_bodies() {
+    let addr = start(8).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\nabc")
+        .await
+        .unwrap();
+
+    let started = Instant::now();
+    let mut response = [0; 64];
+    let read = timeout(Duration::from_secs(5), stream.read(&mut response))
+        .await
+        .expect("stalled body wasn't failed")
+        .unwrap();
+    assert!(String::from_utf8_lossy(&response[..read]).starts_with("HTTP/1.1 408"));
+    assert!(started.elapsed() >= Duration::from_secs(BODY_STALL_TIMEOUT_SECS));
+}
+
+#[tokio::test]
+async fn fails_dripped_bodies() {
+    let addr = start(8).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000\r\n\r\n")
+        .await
+        .unwrap();
+
+    // A byte every 100ms never stalls, but stays far below 64 bytes a second
+    let mut response = [0; 64];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        assert!(Instant::now() < deadline, "dripped body wasn't failed");
+        if stream.write_all(b"a").await.is_err() {
+            break;
+        }
+        if let Ok(read) = timeout(Duration::from_millis(100), stream.read(&mut response)).await {
+            let read = read.unwrap();
+            assert!(String::from_utf8_lossy(&response[..read]).starts_with("HTTP/1.1 408"));
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn closes_connections_over_the_per_ip_cap() {
+    let addr = start(2).await;
+    let _first = TcpStream::connect(addr).await.unwrap();
+    let _second = TcpStream::connect(addr).await.unwrap();
+    // Lets the server accept both before the third arrives
+    sleep(Duration::from_millis(100)).await;
+
+    let mut third = TcpStream::connect(addr).await.unwrap();
+    let response = read_until_closed(&mut third, Duration::from_millis(500)).await;
+    assert!(response.is_empty());
+}
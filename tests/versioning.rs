@@ -0,0 +1,122 @@
+mod common;
+
+use std::collections::BTreeMap;
+
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    Router,
+};
+use common::{get, send, test_app, test_config, TestResponse, JWT_SECRET};
+use hello_axum::{
+    auth::generate_token,
+    config::{Config, DeprecationConfig, VersioningConfig},
+    models::Role,
+};
+use serde_json::json;
+
+async fn get_as(app: &Router, path: &str, user: &str, role: Role) -> TestResponse {
+    let token = generate_token(user, role, None, JWT_SECRET.as_bytes()).unwrap();
+    let request = Request::get(path)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap();
+    send(app, request).await
+}
+
+fn with_dates(config: Config) -> Config {
+    Config {
+        versioning: VersioningConfig {
+            legacy_routes: true,
+            deprecations: BTreeMap::from([(
+                "legacy".to_string(),
+                DeprecationConfig {
+                    deprecated_on: Some("2026-01-01".to_string()),
+                    sunset_on: Some("2027-01-01".to_string()),
+                },
+            )]),
+        },
+        ..config
+    }
+}
+
+#[tokio::test]
+async fn marks_legacy_routes_deprecated() {
+    let app = test_app(test_config()).await;
+    let response = get(&app, "/auth/protected").await;
+    assert_eq!(response.headers["deprecation"], "true");
+    assert!(!response.headers.contains_key("sunset"));
+    assert_eq!(
+        response.headers["link"],
+        "</api/v1/auth/protected>; rel=\"successor-version\""
+    );
+
+    let response = get(&app, "/api/v1/auth/protected").await;
+    assert!(!response.headers.contains_key("deprecation"));
+    assert!(!response.headers.contains_key("link"));
+}
+
+#[tokio::test]
+async fn sends_configured_dates() {
+    let app = test_app(with_dates(test_config())).await;
+    let response = get(&app, "/auth/protected").await;
+    assert_eq!(response.headers["deprecation"], "@1767225600");
+    assert_eq!(response.headers["sunset"], "Fri, 01 Jan 2027 00:00:00 GMT");
+}
+
+#[test]
+fn refuses_malformed_dates() {
+    let mut config = with_dates(test_config());
+    config
+        .versioning
+        .deprecations
+        .get_mut("legacy")
+        .unwrap()
+        .sunset_on = Some("2027-02-30".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[tokio::test]
+async fn drops_legacy_routes_when_disabled() {
+    let config = test_config();
+    let app = test_app(Config {
+        versioning: VersioningConfig {
+            legacy_routes: false,
+            ..config.versioning.clone()
+        },
+        ..config
+    })
+    .await;
+    assert_eq!(
+        get(&app, "/auth/protected").await.status,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn reports_who_calls_deprecated_routes() {
+    let app = test_app(Config {
+        admin_allowlist: vec!["127.0.0.1".to_string()],
+        ..test_config()
+    })
+    .await;
+    for _ in 0..2 {
+        get_as(&app, "/auth/protected", "alice", Role::User).await;
+    }
+    get(&app, "/auth/protected").await;
+    get_as(&app, "/api/v1/auth/protected", "bob", Role::User).await;
+
+    let response = get_as(&app, "/api/v1/admin/deprecations", "root", Role::Admin).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let mut report = response.json()["data"].clone();
+    for calls in report.as_array_mut().unwrap() {
+        calls.as_object_mut().unwrap().remove("lastSeen");
+    }
+    assert_eq!(
+        report,
+        json!([
+            { "version": "legacy", "path": "/auth/protected", "client": "alice", "requests": 2 },
+            { "version": "legacy", "path": "/auth/protected", "client": "ip:127.0.0.1", "requests": 1 },
+        ])
+    );
+}